@@ -1,11 +1,20 @@
 extern crate gl_generator;
 extern crate pkg_config;
+extern crate wayland_scanner;
 
 
 use gl_generator::{Registry, Api, Profile, Fallbacks, GlobalGenerator};
 use std::env;
 use std::fs::File;
 use std::path::Path;
+use wayland_scanner::Side;
+
+/// Protocols wayland-protocols does not ship, from protocols/
+static PROTOCOLS: &[&str] = &[
+    "wlr-output-management-unstable-v1",
+    "fractional-scale-v1",
+    "weston-debug",
+];
 
 fn generate_gl(api: Api,  version: (u8, u8), filename: &str) {
     let dest = env::var("OUT_DIR").unwrap();
@@ -21,11 +30,22 @@ fn generate_mods() {
     generate_gl(Api::Egl, (1, 5), "egl_bindings.rs");
 }
 
+fn generate_protocols() {
+    let dest = env::var("OUT_DIR").unwrap();
+    for name in PROTOCOLS {
+        let file = Path::new("protocols").join(format!("{}.xml", name));
+        println!("cargo:rerun-if-changed={}", file.display());
+        wayland_scanner::generate_c_interfaces(&file, Path::new(&dest).join(format!("{}_c_interfaces.rs", name)));
+        wayland_scanner::generate_c_code(&file, Path::new(&dest).join(format!("{}_c_server_api.rs", name)), Side::Server);
+    }
+}
+
 fn link_c_libraries() {
     pkg_config::find_library("libsystemd").unwrap();
 }
 
 fn main() {
     generate_mods();
+    generate_protocols();
     link_c_libraries();
 }
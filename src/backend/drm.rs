@@ -1,182 +1,559 @@
 use backend;
 use backend::Backend;
-use launcher::Launcher;
-use compositor::Compositor;
+use backend::kms;
+use launcher::{DeviceHandle, Launcher, LauncherError, SessionEvent};
+use compositor::{Compositor, Cursor};
+use dmabuf::Dmabuf;
 
+use gbm;
 use libudev;
 use libc;
+use libc::c_int;
 
 use libdrm;
-use std::borrow::Borrow;
+use std::any::Any;
+use std::cell::{Ref, RefCell};
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
+use std::mem;
+use std::rc::Rc;
 use std::os::unix::io::RawFd;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::string::String;
-// use renderer::Renderer;
-// use renderer::egl::EGLRenderer;
+use std::time::Duration;
+use output::{Head, HeadConfiguration, HeadState, Mode, Output};
+use region::Region;
+use renderer::{Renderer, RendererError, Scanout};
+use renderer::egl::EGLRenderer;
 use renderer::gbm::GBMRenderer;
-// use renderer::pixman::PixmanRenderer;
-
-pub struct DRMBackend {
+use renderer::pixman::PixmanRenderer;
+use renderer::software::DamageHistory;
+use surface::{Buffer, Surface};
+use wayland::output::Outputs;
+use wayland::presentation;
+use wayland_server::protocol::{wl_output, wl_shm};
+
+pub struct DRMBackend<'a> {
     //compositor
     use_pixman: bool,
     use_egldevice: bool,
     //egl_device: EGLDeviceEXT,
     //udev_context: libudev::Context,
-    drm_device: DRMDevice,
-    interface: Box<dyn Launcher>,
-    cursor_with: u64,
-    cursor_height: u64,
-    compositor: Compositor,
-    renderer: Box<GBMRenderer>,
+    interface: &'a dyn Launcher,
+    /// largest cursor image the cursor planes take
+    cursor_width: u32,
+    cursor_height: u32,
+    /// DRM_CLIENT_CAP_ATOMIC is set, page flips go through atomic commits
+    atomic_modeset: bool,
+    /// false while the session is in the background, nothing gets rendered
+    session_active: bool,
+    compositor: Rc<RefCell<Compositor>>,
+    renderer: Box<dyn Renderer>,
+    /// the connected connectors, an output drives the enabled ones
+    heads: Vec<DRMHead>,
+    outputs: Vec<DRMOutput>,
+    /// allocates the cursor images, `None` if GBM is not available
+    gbm: Option<gbm::Device<DRMDeviceFd>>,
+    // dropped after everything that uses the fd
+    drm_device: DRMDevice<'a>,
+}
+
+/// A crtc driving a connector, with the mode it is set to
+pub struct DRMOutput {
+    base: Output,
+    connector: libdrm::control::connector::Handle,
+    crtc: libdrm::control::crtc::Handle,
+    mode: libdrm::control::Mode,
+    /// primary plane, only known with atomic mode setting
+    plane: Option<kms::Properties>,
+    /// the bits of the primary plane "rotation" property, 0 without it
+    plane_rotations: u64,
+    /// cursor plane images, double buffered, none without a cursor plane
+    cursor_bos: Vec<gbm::BufferObject<()>>,
+    current_cursor: usize,
+    /// where the cursor plane shows the image, `None` while the cursor is
+    /// composited into the frames instead
+    cursor_position: Option<(i32, i32)>,
+    // pixman scanout buffers, EGLStream outputs use the first one for the mode set
+    dumb: Vec<(libdrm::control::dumbbuffer::DumbBuffer, libdrm::control::framebuffer::Info)>,
+    current_dumb: usize,
+    /// what the dumb buffers miss of the frames since they were written
+    dumb_damage: DamageHistory,
+    mode_set: bool,
+    page_flip_pending: bool,
+    /// What the crtc showed before we took it, put back on shutdown
+    saved_crtc: Option<libdrm::control::crtc::Info>,
+}
+
+/// What an output holds for one renderer, set aside while another renderer
+/// makes its own
+#[derive(Default)]
+struct OutputRendererState {
+    renderer_state: Option<Box<dyn Any>>,
+    dumb: Vec<(libdrm::control::dumbbuffer::DumbBuffer, libdrm::control::framebuffer::Info)>,
+    current_dumb: usize,
+    dumb_damage: DamageHistory,
+    mode_set: bool,
+}
+
+/// An output configuration as one atomic request, the heads it enables show
+/// a black dumb buffer until their first frame
+#[derive(Default)]
+struct AtomicConfiguration {
+    request: kms::AtomicRequest,
+    /// the "MODE_ID" blobs
+    blobs: Vec<u32>,
+    framebuffers: Vec<(libdrm::control::dumbbuffer::DumbBuffer, libdrm::control::framebuffer::Info)>,
+}
+
+impl AtomicConfiguration {
+    fn commit(&self, drm_device: &DRMDevice, flags: u32) -> Result<(), DRMBackendError> {
+        if self.request.is_empty() {
+            return Ok(());
+        }
+        self.request.commit(drm_device, flags, 0)
+            .map_err(|message| DRMBackendError::kms(drm_device, message))
+    }
+
+    /// Once nothing scans the framebuffers out any more
+    fn release(self, drm_device: &DRMDevice) {
+        use libdrm::control::{framebuffer, ResourceInfo};
+
+        for blob in self.blobs {
+            kms::destroy_blob(drm_device, blob);
+        }
+        for (dumb, fb_info) in self.framebuffers {
+            let _ = framebuffer::destroy(drm_device, fb_info.handle());
+            let _ = dumb.destroy(drm_device);
+        }
+    }
+}
+
+/// Output scales an output configuration may set
+const MIN_SCALE: f64 = 0.25;
+const MAX_SCALE: f64 = 8.0;
+
+/// Where an output configuration puts an enabled head
+#[derive(Debug, Clone, Copy)]
+struct OutputPlan {
+    crtc: libdrm::control::crtc::Handle,
+    /// index in `DRMHead::modes`
+    mode: usize,
+    x: i32,
+    y: i32,
+    scale: f64,
+    transform: wl_output::Transform,
+}
+
+/// A connected connector and the modes of the monitor on it
+struct DRMHead {
+    connector: libdrm::control::connector::Handle,
+    name: String,
+    /// in millimeters
+    physical_size: (u32, u32),
+    modes: Vec<libdrm::control::Mode>,
+    /// per mode
+    preferred: Vec<bool>,
+    /// the crtcs that can drive it, the one already driving it first
+    crtcs: Vec<libdrm::control::crtc::Handle>,
+}
+
+impl DRMHead {
+    fn mode(&self, index: usize) -> Mode {
+        let (width, height) = self.modes[index].size();
+        Mode {
+            width: width.into(),
+            height: height.into(),
+            refresh: mode_refresh(&self.modes[index]),
+            preferred: self.preferred.get(index).cloned().unwrap_or(false),
+        }
+    }
+
+    /// The mode closest to `mode`, same size and within 1Hz
+    fn find_mode(&self, mode: &Mode) -> Option<usize> {
+        let distance = |index: usize| (i64::from(mode_refresh(&self.modes[index])) - i64::from(mode.refresh)).abs();
+        (0..self.modes.len())
+            .filter(|&index| {
+                let (width, height) = self.modes[index].size();
+                i32::from(width) == mode.width && i32::from(height) == mode.height
+                    && (mode.refresh == 0 || distance(index) <= 1000)
+            })
+            .min_by_key(|&index| if mode.refresh == 0 { 0 } else { distance(index) })
+    }
+
+    /// The preferred mode, or the first
+    fn default_mode(&self) -> usize {
+        self.preferred.iter().position(|&preferred| preferred).unwrap_or(0)
+    }
+}
+
+/// The plane "rotation" value showing an upright frame with `transform`.
+///
+/// `None` for the transforms that swap width and height, the framebuffers
+/// would have to be rotated too.
+fn plane_rotation(transform: wl_output::Transform) -> Option<u64> {
+    use wayland_server::protocol::wl_output::Transform::*;
+
+    match transform {
+        Normal => Some(kms::DRM_MODE_ROTATE_0),
+        _180 => Some(kms::DRM_MODE_ROTATE_180),
+        Flipped => Some(kms::DRM_MODE_ROTATE_0 | kms::DRM_MODE_REFLECT_X),
+        Flipped180 => Some(kms::DRM_MODE_ROTATE_0 | kms::DRM_MODE_REFLECT_Y),
+        _90 | _270 | Flipped90 | Flipped270 => None,
+    }
+}
+
+/// The cursor image as the cursor plane takes it, ARGB8888 `width`x`height`.
+///
+/// `None` if the plane can't show it on `output`: the plane shows shm
+/// content pixel for pixel, not rotated and no bigger than its buffers.
+fn cursor_image(surface: &Surface, output: &Output, width: u32, height: u32) -> Option<Vec<u8>> {
+    if output.transform != wl_output::Transform::Normal || !surface.is_pixel_aligned(output.scale) {
+        return None;
+    }
+    let shm = match surface.live_buffer() {
+        Some(&Buffer::Shm(ref shm)) => shm.clone(),
+        _ => return None,
+    };
+    let opaque = match shm.format() {
+        Some(wl_shm::Format::Argb8888) => false,
+        Some(wl_shm::Format::Xrgb8888) => true,
+        _ => return None,
+    };
+    let (x, y, source_width, source_height) = surface.source_rect();
+    let (x, y) = (x as usize, y as usize);
+    let (source_width, source_height) = (source_width as usize, source_height as usize);
+    if source_width > width as usize || source_height > height as usize {
+        return None;
+    }
+
+    let stride = shm.stride() as usize;
+    let row_len = width as usize * 4;
+    let mut image = vec![0u8; row_len * height as usize];
+    shm.with_data(|data| {
+        for row in 0..source_height {
+            let start = (y + row) * stride + x * 4;
+            let destination = &mut image[row * row_len..row * row_len + source_width * 4];
+            destination.copy_from_slice(&data[start..start + source_width * 4]);
+            if opaque {
+                for pixel in destination.chunks_mut(4) {
+                    pixel[3] = 0xff;
+                }
+            }
+        }
+    });
+    Some(image)
+}
+
+/// Refresh rate of `mode` in mHz (drm_refresh_rate_mHz)
+fn mode_refresh(mode: &libdrm::control::Mode) -> u32 {
+    let (_, _, htotal) = mode.hsync();
+    let (_, _, vtotal) = mode.vsync();
+    if htotal == 0 || vtotal == 0 {
+        return mode.vrefresh() * 1000;
+    }
+    let mut refresh = (u64::from(mode.clock()) * 1_000_000 / u64::from(htotal) + u64::from(vtotal) / 2) / u64::from(vtotal);
+    if mode.vscan() > 1 {
+        refresh /= u64::from(mode.vscan());
+    }
+    refresh as u32
+}
+
+/// The name of a connector type as the kernel spells it
+fn connector_type_name(kind: libdrm::control::connector::Type) -> &'static str {
+    use libdrm::control::connector::Type;
+
+    match kind {
+        Type::Unknown => "Unknown",
+        Type::VGA => "VGA",
+        Type::DVII => "DVI-I",
+        Type::DVID => "DVI-D",
+        Type::DVIA => "DVI-A",
+        Type::Composite => "Composite",
+        Type::SVideo => "SVIDEO",
+        Type::LVDS => "LVDS",
+        Type::Component => "Component",
+        Type::NinePinDIN => "DIN",
+        Type::DisplayPort => "DP",
+        Type::HDMIA => "HDMI-A",
+        Type::HDMIB => "HDMI-B",
+        Type::TV => "TV",
+        Type::EmbeddedDisplayPort => "eDP",
+        Type::Virtual => "Virtual",
+        Type::DSI => "DSI",
+        Type::DPI => "DPI",
+    }
+}
+
+impl DRMOutput {
+    /// Rotate and flip with the primary plane when it can, in the renderer
+    /// otherwise
+    fn set_transform(&mut self, transform: wl_output::Transform) {
+        self.base.transform = transform;
+        self.base.hardware_transform = transform != wl_output::Transform::Normal
+            && self.plane.is_some()
+            && plane_rotation(transform).map_or(false, |rotation| rotation & !self.plane_rotations == 0);
+        self.base.damage_all();
+    }
+
+    /// Put `state` in place of what the output holds for its renderer
+    fn swap_renderer_state(&mut self, state: OutputRendererState) -> OutputRendererState {
+        OutputRendererState {
+            renderer_state: mem::replace(&mut self.base.renderer_state, state.renderer_state),
+            dumb: mem::replace(&mut self.dumb, state.dumb),
+            current_dumb: mem::replace(&mut self.current_dumb, state.current_dumb),
+            dumb_damage: mem::replace(&mut self.dumb_damage, state.dumb_damage),
+            mode_set: mem::replace(&mut self.mode_set, state.mode_set),
+        }
+    }
+
+    pub fn crtc_id(&self) -> u32 {
+        self.crtc.into()
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        self.mode.size()
+    }
+
+    pub fn base(&self) -> &Output {
+        &self.base
+    }
+
+    pub fn base_mut(&mut self) -> &mut Output {
+        &mut self.base
+    }
 }
 
-#[derive(Clone)]
-pub struct DRMDevice {
-    fd: RawFd,
+pub struct DRMDevice<'a> {
+    handle: DeviceHandle<'a>,
     filename: PathBuf,
 }
 
-impl DRMDevice {
-    fn new(fd: RawFd, filename: PathBuf) -> DRMDevice {
+impl<'a> DRMDevice<'a> {
+    fn new(handle: DeviceHandle<'a>, filename: PathBuf) -> DRMDevice<'a> {
         DRMDevice {
-            fd,
+            handle,
             filename,
         }
     }
 
-    //TODO remove
-    pub fn rawfd(&self) -> RawFd {
-        self.fd
-    }
-
     pub fn dev_path(&self) -> &Path {
         &self.filename
     }
+
+    /// A file descriptor of its own for what cannot borrow the device (the
+    /// GBM devices), it shares the open file and DRM master with the device
+    pub fn device_fd(&self) -> io::Result<DRMDeviceFd> {
+        let fd = unsafe { libc::fcntl(self.handle.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DRMDeviceFd(fd))
+    }
 }
 
-impl AsRawFd for DRMDevice {
+impl<'a> AsRawFd for DRMDevice<'a> {
     fn as_raw_fd(&self) -> RawFd {
-        self.fd
+        self.handle.as_raw_fd()
+    }
+}
+
+impl<'a> libdrm::Device for DRMDevice<'a> {}
+impl<'a> libdrm::control::Device for DRMDevice<'a> {}
+
+/// Duplicate of the file descriptor of a `DRMDevice`, closed when dropped
+#[derive(Debug)]
+pub struct DRMDeviceFd(RawFd);
+
+impl AsRawFd for DRMDeviceFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for DRMDeviceFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
     }
 }
 
-impl libdrm::Device for DRMDevice {}
-impl libdrm::control::Device for DRMDevice {}
+impl libdrm::Device for DRMDeviceFd {}
+impl libdrm::control::Device for DRMDeviceFd {}
 
 
 #[derive(Debug)]
-struct DRMBackendError {
-    description: String
+pub enum DRMBackendError {
+    /// Opening the device through the launcher failed
+    Launcher(LauncherError),
+    /// Scanning udev for the GPU failed
+    Udev(libudev::Error),
+    /// No boot GPU on the seat
+    NoDevice {
+        seat: String,
+    },
+    /// A libdrm call on the device failed
+    Drm {
+        path: PathBuf,
+        context: String,
+        source: libdrm::result::Error,
+    },
+    /// A property or atomic commit problem on the device
+    Kms {
+        path: PathBuf,
+        message: String,
+    },
+    /// No connector is connected to a usable crtc
+    NoOutput {
+        path: PathBuf,
+    },
+    /// An output configuration the device can't take
+    Configuration(String),
+    Renderer(RendererError),
+    Compositor(String),
+    /// logind closed the session, the compositor has to exit
+    SessionRemoved,
+    /// The renderer switch has no GL renderer to switch to or from
+    NoGlRenderer,
+}
+
+impl DRMBackendError {
+    fn drm(drm_device: &DRMDevice, context: &str, source: libdrm::result::Error) -> Self {
+        DRMBackendError::Drm {
+            path: drm_device.dev_path().to_path_buf(),
+            context: context.to_string(),
+            source,
+        }
+    }
+
+    fn kms(drm_device: &DRMDevice, message: String) -> Self {
+        DRMBackendError::Kms {
+            path: drm_device.dev_path().to_path_buf(),
+            message,
+        }
+    }
+
+    /// The errno of the failed system call, if it came to that
+    pub fn errno(&self) -> Option<c_int> {
+        use libdrm::result::ErrorKind;
+
+        match *self {
+            DRMBackendError::Launcher(ref e) => e.errno(),
+            DRMBackendError::Drm { ref source, .. } => match *source.kind() {
+                ErrorKind::Unix(ref e) => Some(e.errno() as c_int),
+                ErrorKind::Io(ref e) => e.raw_os_error(),
+                _ => None,
+            },
+            DRMBackendError::Renderer(ref e) => e.errno(),
+            _ => None,
+        }
+    }
+
+    /// The DRM device the error is about
+    pub fn device_path(&self) -> Option<&Path> {
+        match *self {
+            DRMBackendError::Launcher(ref e) => e.device_path(),
+            DRMBackendError::Drm { ref path, .. }
+            | DRMBackendError::Kms { ref path, .. }
+            | DRMBackendError::NoOutput { ref path } => Some(path),
+            _ => None,
+        }
+    }
 }
 
 impl StdError for DRMBackendError {
-    fn description(&self) -> &str {
-        return self.description.borrow();
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            DRMBackendError::Launcher(ref e) => Some(e),
+            DRMBackendError::Udev(ref e) => Some(e),
+            DRMBackendError::Drm { ref source, .. } => Some(source),
+            DRMBackendError::Renderer(ref e) => Some(e),
+            _ => None,
+        }
     }
 }
 
 impl fmt::Display for DRMBackendError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", &self.description)
+        match *self {
+            DRMBackendError::Launcher(ref e) => write!(f, "{}", e),
+            DRMBackendError::Udev(ref e) => write!(f, "udev: {}", e),
+            DRMBackendError::NoDevice { ref seat } => write!(f, "No DRM device found on {}", seat),
+            DRMBackendError::Drm { ref path, ref context, ref source } =>
+                write!(f, "{}: {}: {}", path.as_os_str().to_string_lossy(), context, source),
+            DRMBackendError::Kms { ref path, ref message } =>
+                write!(f, "{}: {}", path.as_os_str().to_string_lossy(), message),
+            DRMBackendError::NoOutput { ref path } =>
+                write!(f, "{}: No connected output found", path.as_os_str().to_string_lossy()),
+            DRMBackendError::Configuration(ref message) => write!(f, "Invalid output configuration: {}", message),
+            DRMBackendError::Renderer(ref e) => write!(f, "{}", e),
+            DRMBackendError::Compositor(ref message) => write!(f, "{}", message),
+            DRMBackendError::SessionRemoved => write!(f, "The session was closed"),
+            DRMBackendError::NoGlRenderer =>
+                write!(f, "No GL renderer on GBM yet, both renderers would composite in system memory"),
+        }
     }
 }
 
-impl Backend for DRMBackend {
+impl<'a> Backend for DRMBackend<'a> {
 }
 
-// mod ffi {
-// #[link(name = "drm")]
-// #[allow(dead_code)]
-//     use libc::*;
-//     use std::os::unix::io::RawFd;
-//     extern {
-//         fn drmGetCap(fd: c_int, capability: uint64_t, value: *mut uint64_t) -> c_int;
-//     }
-
-//     /// Check if a capability is set.
-//     pub fn get_cap(fd: RawFd, cap: u32) -> Result<u64, i32> {
-//         let mut value = 0;
-//         let result = unsafe { drmGetCap(fd, cap as u64, &mut value) };
-
-//         if result == 0 { Ok(value) } else { Err(result) }
-//     }
-// }
-
-impl DRMBackend {
-    pub fn new(tty: Option<u32>, use_pixman: bool, use_egldevice: bool) -> backend::Result<Box<Self>> {
+impl<'a> DRMBackend<'a> {
+    /// `launcher` has to be connected already, the backend opens its devices
+    /// through it
+    pub fn new(launcher: &'a dyn Launcher, use_pixman: bool, use_egldevice: bool) -> backend::Result<Box<Self>> {
         let udev_context = libudev::Context::new().unwrap();
-        let device_devnode_path = DRMBackend::find_primary_gpu(&udev_context, "seat0")?;
-
-        use launcher::logind::LogindLauncher;
-        let mut launcher = match LogindLauncher::new(tty, "".to_string(), false) {
-            Ok(l) => Box::new(l),
-            Err(e) => return Err(Box::new(DRMBackendError {
-                description: e
-            })),
-        };
-
-        match launcher.connect().err() {
-            Some(e) =>  return Err(Box::new(DRMBackendError {
-                description: e
-            })),
-            None => {},
-        }
+        let device_devnode_path = DRMBackend::find_primary_gpu(&udev_context, &launcher.seat_name())?;
 
         use libc::O_RDWR;
-        let fd = match launcher.open(&device_devnode_path, O_RDWR) {
-            Ok(fd) => fd,
-            Err(e) => return Err(Box::new(DRMBackendError {
-                description: e
-            })),
+        let handle = match launcher.open(&device_devnode_path, O_RDWR) {
+            Ok(handle) => handle,
+            Err(e) => return Err(Box::new(DRMBackendError::Launcher(e))),
         };
 
-        let drm_device = DRMDevice::new(fd, device_devnode_path);
-        const DRM_CAP_TIMESTAMP_MONOTONIC: u32 = 6;
-        const DRM_CAP_CURSOR_WIDTH: u32 = 8;
-        const DRM_CAP_CURSOR_HEIGHT: u32 = 9;
-
-        // use libdrm::ffi::drm_get_cap as get_cap;
-        // let clock_type = match ffi::get_cap(drm_device.as_raw_fd(), DRM_CAP_TIMESTAMP_MONOTONIC) {
-        //     Ok(cap) => {
-        //         if cap == 1 {
-        //             libc::CLOCK_MONOTONIC
-        //         } else {
-        //             libc::CLOCK_REALTIME
-        //         }
-        //     },
-        //     Err(e) => {
-        //         println!("drm get capabilities failed with return code {}", e);
-        //         libc::CLOCK_REALTIME
-        //     },
-        // };
-        let clock_type = libc::CLOCK_REALTIME;
+        let drm_device = DRMDevice::new(handle, device_devnode_path);
+        // the page flip timestamps are in the presentation clock
+        let clock_type = match kms::get_cap(&drm_device, kms::DRM_CAP_TIMESTAMP_MONOTONIC) {
+            Ok(1) => libc::CLOCK_MONOTONIC,
+            Ok(_) => libc::CLOCK_REALTIME,
+            Err(e) => {
+                log_warn!("drm", "{}, assuming CLOCK_REALTIME timestamps", e);
+                libc::CLOCK_REALTIME
+            },
+        };
+
+        let atomic_modeset = {
+            use libdrm::{ClientCapability, Device};
+            drm_device.set_client_cap(ClientCapability::Atomic, true).is_ok()
+        };
+        log_info!("drm", "atomic mode setting {}", if atomic_modeset { "supported" } else { "not supported" });
 
         let compositor = match Compositor::new(clock_type) {
             Ok(c) => c,
-            Err(e) => return Err(Box::new(DRMBackendError {
-                description: e
-            })),
+            Err(e) => return Err(Box::new(DRMBackendError::Compositor(e))),
         };
 
+        let cursor_width = kms::get_cap(&drm_device, kms::DRM_CAP_CURSOR_WIDTH).unwrap_or(64) as u32;
+        let cursor_height = kms::get_cap(&drm_device, kms::DRM_CAP_CURSOR_HEIGHT).unwrap_or(64) as u32;
+        let gbm = match drm_device.device_fd().and_then(gbm::Device::new) {
+            Ok(gbm) => Some(gbm),
+            Err(e) => {
+                log_warn!("drm", "Could not create a GBM device, the cursor gets composited: {}", e);
+                None
+            },
+        };
 
-        // let cursor_with = match ffi::get_cap(drm_device.rawfd(), DRM_CAP_CURSOR_WIDTH) {
-        //     Ok(cap) => cap,
-        //     Err(_) => 64,
-        // };
-        let cursor_with = 64;
-
-        // let cursor_height = match ffi::get_cap(drm_device.rawfd(), DRM_CAP_CURSOR_HEIGHT) {
-        //     Ok(cap) => cap,
-        //     Err(_) => 64,
-        // };
-        let cursor_height = 64;
-
-        let renderer = DRMBackend::init_egl_renderer(&drm_device, use_pixman, use_egldevice)?;
-        renderer.test(&drm_device);
+        let mut renderer = DRMBackend::init_renderer(&drm_device, use_pixman, use_egldevice)?;
+        let heads = DRMBackend::find_heads(&drm_device)?;
+        let mut outputs = Vec::new();
+        // the first head only, the others wait for an output configuration
+        let mut output = DRMBackend::create_output(&drm_device, &mut *renderer, &heads[0], heads[0].crtcs[0],
+                                                   heads[0].default_mode(), use_pixman, use_egldevice, atomic_modeset)?;
+        DRMBackend::output_repaint(&drm_device, &mut *renderer, &mut output, &[])?;
+        DRMBackend::output_init_cursor(&drm_device, gbm.as_ref(), (cursor_width, cursor_height), atomic_modeset, &mut output);
+        outputs.push(output);
 
         // b->base.destroy = drm_destroy;
         // b->base.repaint_begin = drm_repaint_begin;
@@ -200,11 +577,6 @@ impl DRMBackend {
         //     goto err_udev_input;
         // }
 
-        // /* A this point we have some idea of whether or not we have a working
-        // * cursor plane. */
-        // if (!b->cursors_are_broken)
-        //     compositor->capabilities |= WESTON_CAP_CURSOR_PLANE;
-
         // loop = wl_display_get_event_loop(compositor->wl_display);
         // b->drm_source =
         //     wl_event_loop_add_fd(loop, b->drm.fd,
@@ -239,6 +611,7 @@ impl DRMBackend {
         //                     recorder_binding, b);
         // weston_compositor_add_debug_binding(compositor, KEY_W,
         //                     renderer_switch_binding, b);
+        // SIGUSR1 calls DRMBackend::switch_renderer instead
 
         // if (compositor->renderer->import_dmabuf) {
         //     if (linux_dmabuf_setup(compositor) < 0)
@@ -282,29 +655,30 @@ impl DRMBackend {
             use_pixman,
             use_egldevice,
             //udev_context: udev_context,
-            drm_device,
             interface: launcher,
-            cursor_with,
+            cursor_width,
             cursor_height,
-            compositor,
+            atomic_modeset,
+            session_active: true,
+            compositor: Rc::new(RefCell::new(compositor)),
             renderer,
+            heads,
+            outputs,
+            gbm,
+            drm_device,
         }))
     }
 
-    fn find_primary_gpu(udev_context: &libudev::Context, seat: &str) -> backend::Result<PathBuf> {
-        let mut enumerator = match libudev::Enumerator::new(&udev_context) {
-            Ok(enumerator) => enumerator,
-            Err(e) => return Err(Box::new(e)),
-        };
+    fn find_primary_gpu(udev_context: &libudev::Context, seat: &str) -> Result<PathBuf, DRMBackendError> {
+        let mut enumerator = libudev::Enumerator::new(&udev_context)
+            .map_err(DRMBackendError::Udev)?;
 
-        enumerator.match_subsystem("drm")?;
-        enumerator.match_sysname("card[0-9]*")?;
+        enumerator.match_subsystem("drm").map_err(DRMBackendError::Udev)?;
+        enumerator.match_sysname("card[0-9]*").map_err(DRMBackendError::Udev)?;
 
         let default_seat = "seat0";
-        let device_list = match enumerator.scan_devices() {
-            Ok(device_list) => device_list,
-            Err(e) => return Err(Box::new(e)),
-        };
+        let device_list = enumerator.scan_devices()
+            .map_err(DRMBackendError::Udev)?;
 
         let device_option = device_list.into_iter().find(|device| {
 
@@ -338,16 +712,12 @@ impl DRMBackend {
 
         let device =  match device_option {
             Some(dev) => dev,
-            None => return Err(Box::new(DRMBackendError {
-                description: "No device found!".to_string(),
-            })),
+            None => return Err(DRMBackendError::NoDevice { seat: seat.to_string() }),
         };
 
         let devnode = match device.devnode() {
             Some(devnode) => devnode,
-            None => return Err(Box::new(DRMBackendError {
-                description: "Error getting device devnode!".to_string(),
-            })),
+            None => return Err(DRMBackendError::NoDevice { seat: seat.to_string() }),
         };
 
         PrintUDEVDeviceInfo(&device);
@@ -355,63 +725,1401 @@ impl DRMBackend {
         Ok(devnode.to_path_buf())
     }
 
-    fn init_egl_renderer(drm_device: &DRMDevice, use_pixman: bool, use_egldevice: bool) -> Result<Box<GBMRenderer>, DRMBackendError> {
-        let renderer_result =
+    fn init_renderer(drm_device: &DRMDevice, use_pixman: bool, use_egldevice: bool) -> Result<Box<dyn Renderer>, DRMBackendError> {
+        let renderer_result: Result<Box<dyn Renderer>, RendererError> =
             if use_pixman {
-                Err("PixmanRenderer not supported yet".to_string())
-                // PixmanRenderer::new()
-                //     .map(|renderer| renderer as Box<Renderer>)
+                Ok(PixmanRenderer::new())
             } else { // use egl
                 if use_egldevice { // use eglstream (NVIDIA)
-                    Err("EGLStream not supported yet".to_string())
-                    // EGLRenderer::from_drm_device_file(drm_device)
-                        // .map(|renderer| renderer as Box<Renderer>)
-                        // .map_err(|e| format!("{}", e))
+                    EGLRenderer::from_drm_device_file(drm_device)
+                        .map(|renderer| renderer as Box<dyn Renderer>)
+                        .map_err(RendererError::Egl)
                 } else {  // use GBM (mesa)
-                    // Err("GBMRenderer not supported yet".to_string())
-                    GBMRenderer::new(drm_device.clone())
-                        // .map(|renderer| renderer as Box<Renderer>)
+                    drm_device.device_fd()
+                        .map_err(|e| RendererError::gbm("Could not duplicate the DRM fd", e))
+                        .and_then(GBMRenderer::new)
+                        .map(|renderer| renderer as Box<dyn Renderer>)
                 }
             };
 
         renderer_result
-            .map_err(|e| DRMBackendError {
-                description: format!("{}", e)
+            .map_err(DRMBackendError::Renderer)
+    }
+
+    /// The connected connectors with modes and a crtc that can drive them
+    fn find_heads(drm_device: &DRMDevice) -> Result<Vec<DRMHead>, DRMBackendError> {
+        use libdrm::control::{connector, encoder};
+        use libdrm::control::Device as ControlDevice;
+
+        let res_handles = drm_device.resource_handles()
+            .map_err(|e| DRMBackendError::drm(drm_device, "Could not read drm resources", e))?;
+
+        let mut heads = Vec::new();
+        for &con in res_handles.connectors() {
+            let connector_info: connector::Info = match drm_device.resource_info(con) {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+
+            if connector_info.connection_state() != connector::State::Connected
+                || connector_info.modes().is_empty() {
+                continue;
+            }
+
+            // prefer the crtc already driving the connector
+            let mut crtcs = Vec::new();
+            for encoder_info in connector_info.encoders().iter()
+                .filter_map(|&enc| drm_device.resource_info::<encoder::Info>(enc).ok())
+            {
+                if let Some(crtc) = encoder_info.current_crtc() {
+                    crtcs.retain(|&other| other != crtc);
+                    crtcs.insert(0, crtc);
+                }
+                for &crtc in res_handles.filter_crtcs(encoder_info.possible_crtcs()).iter() {
+                    if !crtcs.contains(&crtc) {
+                        crtcs.push(crtc);
+                    }
+                }
+            }
+            if crtcs.is_empty() {
+                continue;
+            }
+
+            let connector_id: u32 = con.into();
+            let details = match kms::connector_details(drm_device, connector_id) {
+                Ok(details) => details,
+                Err(e) => {
+                    log_warn!("drm", "{}", e);
+                    continue;
+                },
+            };
+            let name = format!("{}-{}", connector_type_name(connector_info.connector_type()), details.type_id);
+            log_info!("drm", "found head {} with {} modes", name, connector_info.modes().len());
+
+            heads.push(DRMHead {
+                connector: con,
+                name,
+                physical_size: details.physical_size,
+                modes: connector_info.modes().to_vec(),
+                preferred: details.preferred,
+                crtcs,
+            });
+        }
+
+        if heads.is_empty() {
+            return Err(DRMBackendError::NoOutput { path: drm_device.dev_path().to_path_buf() });
+        }
+        Ok(heads)
+    }
+
+    fn create_dumb_framebuffer(drm_device: &DRMDevice, width: u16, height: u16)
+        -> Result<(libdrm::control::dumbbuffer::DumbBuffer, libdrm::control::framebuffer::Info), DRMBackendError>
+    {
+        use libdrm::buffer::PixelFormat;
+        use libdrm::control::framebuffer;
+        use libdrm::control::dumbbuffer::DumbBuffer;
+
+        let dumb = DumbBuffer::create_from_device(drm_device, (width.into(), height.into()), PixelFormat::XRGB8888)
+            .map_err(|e| DRMBackendError::drm(drm_device, "Could not create dumb buffer", e))?;
+        let fb_info = framebuffer::create(drm_device, &dumb)
+            .map_err(|e| DRMBackendError::drm(drm_device, "Could not create framebuffer", e))?;
+        Ok((dumb, fb_info))
+    }
+
+    /// Index of `crtc` in the drm resources, what the planes refer to it by
+    fn crtc_index(drm_device: &DRMDevice, crtc: libdrm::control::crtc::Handle) -> Result<usize, DRMBackendError> {
+        use libdrm::control::Device as ControlDevice;
+
+        let res_handles = drm_device.resource_handles()
+            .map_err(|e| DRMBackendError::drm(drm_device, "Could not read drm resources", e))?;
+        res_handles.crtcs().iter().position(|&handle| handle == crtc)
+            .ok_or_else(|| DRMBackendError::kms(drm_device, "crtc not found in the drm resources".to_string()))
+    }
+
+    /// Primary plane of `crtc`, for the atomic commits
+    fn find_primary_plane(drm_device: &DRMDevice, crtc: libdrm::control::crtc::Handle) -> Result<kms::Properties, DRMBackendError> {
+        let crtc_index = DRMBackend::crtc_index(drm_device, crtc)?;
+        kms::find_primary_plane(drm_device, crtc_index)
+            .map_err(|message| DRMBackendError::kms(drm_device, message))
+    }
+
+    // create_output_for_connector + drm_output_enable
+    fn create_output(drm_device: &DRMDevice, renderer: &mut dyn Renderer, head: &DRMHead, crtc_handle: libdrm::control::crtc::Handle,
+                     mode_index: usize, use_pixman: bool, use_egldevice: bool, atomic_modeset: bool) -> Result<DRMOutput, DRMBackendError> {
+        use libdrm::control::crtc;
+        use libdrm::control::Device as ControlDevice;
+
+        let mode = head.modes[mode_index];
+        let (width, height) = mode.size();
+        let crtc_id: u32 = crtc_handle.into();
+        let saved_crtc = drm_device.resource_info::<crtc::Info>(crtc_handle).ok();
+
+        // the EGLStream consumer drives the plane itself
+        let plane = if atomic_modeset && !use_egldevice {
+            match DRMBackend::find_primary_plane(drm_device, crtc_handle) {
+                Ok(plane) => Some(plane),
+                Err(e) => {
+                    log_warn!("drm", "Falling back to legacy page flips: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let plane_rotations = match plane {
+            Some(ref plane) => kms::property_bitmask(drm_device, plane, "rotation").unwrap_or_else(|e| {
+                log_warn!("drm", "{}", e);
+                0
+            }),
+            None => 0,
+        };
+
+        let mut output = DRMOutput {
+            base: Output::new(
+                head.name.clone(),
+                crtc_id,
+                width.into(),
+                height.into(),
+                mode_refresh(&mode),
+            ),
+            connector: head.connector,
+            crtc: crtc_handle,
+            mode,
+            plane,
+            plane_rotations,
+            cursor_bos: Vec::new(),
+            current_cursor: 0,
+            cursor_position: None,
+            dumb: Vec::new(),
+            current_dumb: 0,
+            dumb_damage: DamageHistory::default(),
+            mode_set: false,
+            page_flip_pending: false,
+            saved_crtc,
+        };
+
+        DRMBackend::output_init_renderer(drm_device, renderer, &mut output, use_pixman, use_egldevice)?;
+
+        Ok(output)
+    }
+
+    /// Allocate the cursor plane images of `output`, `size` as big as the
+    /// device takes. Without them the cursor is composited.
+    fn output_init_cursor(drm_device: &DRMDevice, gbm: Option<&gbm::Device<DRMDeviceFd>>, size: (u32, u32),
+                          atomic_modeset: bool, output: &mut DRMOutput) {
+        use gbm::{BufferObjectFlags, Format};
+
+        let gbm = match gbm {
+            Some(gbm) => gbm,
+            None => return,
+        };
+        // without universal planes there is no telling, the first set_cursor finds out
+        if atomic_modeset {
+            let plane = DRMBackend::crtc_index(drm_device, output.crtc)
+                .and_then(|index| kms::find_cursor_plane(drm_device, index)
+                    .map_err(|message| DRMBackendError::kms(drm_device, message)));
+            match plane {
+                Ok(Some(_)) => {},
+                Ok(None) => {
+                    log_info!("drm", "{}: no cursor plane, the cursor gets composited", output.base.name);
+                    return;
+                },
+                Err(e) => {
+                    log_warn!("drm", "{}: {}, the cursor gets composited", output.base.name, e);
+                    return;
+                },
+            }
+        }
+
+        for _ in 0..2 {
+            match gbm.create_buffer_object::<()>(size.0, size.1, Format::ARGB8888, BufferObjectFlags::CURSOR | BufferObjectFlags::WRITE) {
+                Ok(bo) => output.cursor_bos.push(bo),
+                Err(e) => {
+                    log_warn!("drm", "{}: could not create a cursor buffer, the cursor gets composited: {}", output.base.name, e);
+                    output.cursor_bos.clear();
+                    return;
+                },
+            }
+        }
+    }
+
+    // drm_output_init_pixman / drm_output_init_egl
+    fn output_init_renderer(drm_device: &DRMDevice, renderer: &mut dyn Renderer, output: &mut DRMOutput, use_pixman: bool, use_egldevice: bool) -> Result<(), DRMBackendError> {
+        use libdrm::control::{crtc, ResourceInfo};
+
+        let (width, height) = output.mode.size();
+        output.mode_set = false;
+
+        if use_pixman {
+            for _ in 0..2 {
+                output.dumb.push(DRMBackend::create_dumb_framebuffer(drm_device, width, height)?);
+            }
+        } else if use_egldevice {
+            // The stream can only be attached to a crtc with a mode already set,
+            // so do the first mode set with a dumb buffer.
+            let (dumb, fb_info) = DRMBackend::create_dumb_framebuffer(drm_device, width, height)?;
+            crtc::set(drm_device, output.crtc, fb_info.handle(), &[output.connector], (0, 0), Some(output.mode))
+                .map_err(|e| DRMBackendError::drm(drm_device, "Could not set mode", e))?;
+            output.dumb.push((dumb, fb_info));
+            output.mode_set = true;
+        }
+
+        renderer.output_create(&mut output.base)
+            .map_err(DRMBackendError::Renderer)
+    }
+
+    // drm_output_fini_pixman / drm_output_fini_egl
+    fn output_fini_renderer(drm_device: &DRMDevice, renderer: &mut dyn Renderer, output: &mut DRMOutput) {
+        use libdrm::control::{framebuffer, ResourceInfo};
+
+        renderer.output_destroy(&mut output.base);
+
+        for (dumb, fb_info) in output.dumb.drain(..) {
+            let _ = framebuffer::destroy(drm_device, fb_info.handle());
+            let _ = dumb.destroy(drm_device);
+        }
+        output.current_dumb = 0;
+        output.dumb_damage.reset();
+    }
+
+    /// Give the crtc back the framebuffer and mode it had before we took it,
+    /// so the console comes back as it was
+    fn output_restore_crtc(drm_device: &DRMDevice, output: &DRMOutput) {
+        use libdrm::control::crtc;
+
+        if output.cursor_position.is_some() {
+            let _ = crtc::clear_cursor(drm_device, output.crtc);
+        }
+
+        let saved = match output.saved_crtc {
+            Some(saved) => saved,
+            None => return,
+        };
+        let connectors: &[libdrm::control::connector::Handle] = match saved.mode() {
+            Some(_) => &[output.connector],
+            None => &[],
+        };
+        if let Err(e) = crtc::set(drm_device, output.crtc, saved.fb(), connectors, saved.position(), saved.mode()) {
+            log_warn!("drm", "could not restore the mode of crtc {}: {}", output.crtc_id(), e);
+        }
+    }
+
+    /// Copy what the next dumb buffer misses of the frame of a software
+    /// renderer, `damage` is the damage of the frame
+    fn output_copy_to_dumb(drm_device: &DRMDevice, renderer: &mut dyn Renderer, output: &mut DRMOutput, damage: &Region)
+        -> Result<libdrm::control::framebuffer::Handle, DRMBackendError>
+    {
+        use libdrm::buffer::Buffer as DRMBuffer;
+        use libdrm::control::ResourceInfo;
+
+        if output.dumb.is_empty() {
+            return Err(DRMBackendError::kms(drm_device, "Output has no dumb buffers".to_string()));
+        }
+        output.current_dumb = (output.current_dumb + 1) % output.dumb.len();
+
+        let space = output.base.space();
+        let missing = output.dumb_damage.next_frame(space.region_to_pixels(damage), output.dumb.len(), space.bounds());
+
+        let (ref mut dumb, ref fb_info) = output.dumb[output.current_dumb];
+        let pitch = dumb.pitch() as usize;
+        let mut mapping = dumb.map(drm_device)
+            .map_err(|e| DRMBackendError::drm(drm_device, "Could not map dumb buffer", e))?;
+        let mut pixels = Vec::new();
+        for rect in missing.rects() {
+            let row_len = rect.width as usize * 4;
+            pixels.resize(row_len * rect.height as usize, 0);
+            renderer.read_pixels(&output.base, wl_shm::Format::Xrgb8888, *rect, &mut pixels)
+                .map_err(DRMBackendError::Renderer)?;
+            for (row, src) in pixels.chunks(row_len).enumerate() {
+                let start = (rect.y as usize + row) * pitch + rect.x as usize * 4;
+                mapping.as_mut()[start..start + row_len].copy_from_slice(src);
+            }
+        }
+
+        Ok(fb_info.handle())
+    }
+
+    /// Put the cursor on the cursor plane of `output` if the plane can show
+    /// it, take it off otherwise.
+    ///
+    /// Returns whether the frames of `output` have to composite the cursor.
+    fn output_update_cursor(drm_device: &DRMDevice, output: &mut DRMOutput, cursor: &Cursor, image_changed: bool, size: (u32, u32)) -> bool {
+        use libdrm::control::crtc;
+
+        let was_on_plane = output.cursor_position.is_some();
+        let visible = cursor.geometry().intersect(&output.base.geometry());
+        let position = match visible {
+            Some(_) if !output.cursor_bos.is_empty() =>
+                DRMBackend::output_show_cursor(drm_device, output, cursor, image_changed || !was_on_plane, size),
+            _ => None,
+        };
+        if was_on_plane && position.is_none() {
+            if let Err(e) = crtc::clear_cursor(drm_device, output.crtc) {
+                log_warn!("drm", "{}: could not hide the cursor: {}", output.base.name, e);
+            }
+        }
+        output.cursor_position = position;
+
+        // the frames start or stop showing it
+        if was_on_plane != position.is_some() {
+            if let Some(rect) = visible {
+                output.base.damage.union_rect(rect);
+            }
+        }
+        position.is_none()
+    }
+
+    /// Upload the cursor image if `upload` and move the cursor plane under
+    /// the pointer, `None` if the plane can't show the image
+    fn output_show_cursor(drm_device: &DRMDevice, output: &mut DRMOutput, cursor: &Cursor, upload: bool, size: (u32, u32)) -> Option<(i32, i32)> {
+        use libdrm::control::crtc;
+
+        let surface = cursor.surface.as_ref()?.borrow();
+        let space = output.base.space();
+        let rect = space.to_pixels(&surface.geometry());
+        let position = (rect.x, rect.y);
+
+        if upload {
+            let image = cursor_image(&surface, &output.base, size.0, size.1)?;
+            output.current_cursor = (output.current_cursor + 1) % output.cursor_bos.len();
+            let bo = &mut output.cursor_bos[output.current_cursor];
+            match bo.write(&image) {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => {
+                    log_warn!("drm", "{}: could not write the cursor image: {}", output.base.name, e);
+                    return None;
+                },
+                Err(e) => {
+                    log_warn!("drm", "{}: could not write the cursor image: {}", output.base.name, e);
+                    return None;
+                },
+            }
+            // only virtual machines care, the position is the top left corner
+            let hotspot = ((f64::from(cursor.hotspot_x) * space.scale).round() as i32,
+                           (f64::from(cursor.hotspot_y) * space.scale).round() as i32);
+            if let Err(e) = crtc::set_cursor2(drm_device, output.crtc, &*bo, hotspot) {
+                log_warn!("drm", "{}: cursor plane not working, compositing the cursor from now on: {}", output.base.name, e);
+                output.cursor_bos.clear();
+                return None;
+            }
+        }
+        if upload || output.cursor_position != Some(position) {
+            if let Err(e) = crtc::move_cursor(drm_device, output.crtc, position) {
+                log_warn!("drm", "{}: cursor plane not working, compositing the cursor from now on: {}", output.base.name, e);
+                output.cursor_bos.clear();
+                return None;
+            }
+        }
+        Some(position)
+    }
+
+    /// Repaint the damage of `output` and queue the new frame.
+    ///
+    /// Returns false if the output could not take a new frame yet.
+    fn output_repaint(drm_device: &DRMDevice, renderer: &mut dyn Renderer, output: &mut DRMOutput, surfaces: &[&Surface]) -> Result<bool, DRMBackendError> {
+        use libdrm::control::crtc;
+
+        if output.page_flip_pending {
+            return Ok(false);
+        }
+
+        let damage = mem::replace(&mut output.base.damage, Region::new());
+        let scanout = renderer.repaint_output(&mut output.base, surfaces, &damage)
+            .map_err(DRMBackendError::Renderer)?;
+
+        let framebuffer = match scanout {
+            Scanout::Framebuffer(framebuffer) => framebuffer,
+            Scanout::ReadPixels => DRMBackend::output_copy_to_dumb(drm_device, renderer, output, &damage)?,
+            Scanout::Queued => {
+                output.page_flip_pending = true;
+                return Ok(true);
+            },
+            Scanout::Busy => {
+                output.base.damage.union(&damage);
+                return Ok(false);
+            },
+        };
+
+        let fence = match scanout {
+            Scanout::Framebuffer(_) => renderer.create_fence_fd(&output.base),
+            _ => None,
+        };
+
+        let result = if !output.mode_set {
+            // implicit sync is enough for the blocking mode set
+            crtc::set(drm_device, output.crtc, framebuffer, &[output.connector], (0, 0), Some(output.mode))
+                .map(|_| output.mode_set = true)
+                .map_err(|e| DRMBackendError::drm(drm_device, "Could not set mode", e))
+        } else {
+            let atomic_result = match output.plane {
+                Some(_) => Some(DRMBackend::output_atomic_flip(drm_device, output, framebuffer, fence)),
+                None => None,
+            };
+            match atomic_result {
+                Some(Ok(())) => Ok(()),
+                _ => {
+                    if let Some(Err(e)) = atomic_result {
+                        log_warn!("drm", "{}, using a legacy page flip", e);
+                        // the frame goes out upright this once
+                        if output.base.hardware_transform {
+                            log_warn!("drm", "{}: rotating in the renderer from now on", output.base.name);
+                            output.base.hardware_transform = false;
+                            output.base.damage_all();
+                        }
+                    }
+                    crtc::page_flip(drm_device, output.crtc, framebuffer, &[crtc::PageFlipFlags::PageFlipEvent])
+                        .map_err(|e| DRMBackendError::drm(drm_device, "Page Flip schedule failed", e))
+                }
+            }.map(|_| output.page_flip_pending = true)
+        };
+
+        if let Some(fd) = fence {
+            unsafe { libc::close(fd) };
+        }
+        result.map(|_| true)
+    }
+
+    /// Show `framebuffer` on the primary plane at the next vblank.
+    ///
+    /// The display engine waits for `fence` (IN_FENCE_FD) before scanning out,
+    /// so the commit can be queued while the GPU is still rendering.
+    fn output_atomic_flip(drm_device: &DRMDevice, output: &DRMOutput, framebuffer: libdrm::control::framebuffer::Handle, fence: Option<RawFd>)
+        -> Result<(), DRMBackendError>
+    {
+        let plane = output.plane.as_ref()
+            .ok_or_else(|| DRMBackendError::kms(drm_device, "Output has no primary plane".to_string()))?;
+        let (width, height) = output.mode.size();
+        let (width, height) = (u64::from(width), u64::from(height));
+        let framebuffer_id: u32 = framebuffer.into();
+
+        let mut request = kms::AtomicRequest::new();
+        let mut add = |name: &str, value: u64| request.add(plane, name, value);
+        add("FB_ID", framebuffer_id.into())
+            .and_then(|_| add("CRTC_ID", output.crtc_id().into()))
+            .and_then(|_| add("SRC_X", 0))
+            .and_then(|_| add("SRC_Y", 0))
+            .and_then(|_| add("SRC_W", width << 16))
+            .and_then(|_| add("SRC_H", height << 16))
+            .and_then(|_| add("CRTC_X", 0))
+            .and_then(|_| add("CRTC_Y", 0))
+            .and_then(|_| add("CRTC_W", width))
+            .and_then(|_| add("CRTC_H", height))
+            .map_err(|message| DRMBackendError::kms(drm_device, message))?;
+        if output.plane_rotations != 0 {
+            let rotation = if output.base.hardware_transform {
+                plane_rotation(output.base.transform).unwrap_or(kms::DRM_MODE_ROTATE_0)
+            } else {
+                kms::DRM_MODE_ROTATE_0
+            };
+            add("rotation", rotation)
+                .map_err(|message| DRMBackendError::kms(drm_device, message))?;
+        }
+        if let Some(fd) = fence {
+            // without the property the kernel falls back to implicit fencing
+            if plane.id("IN_FENCE_FD").is_some() {
+                add("IN_FENCE_FD", fd as u64)
+                    .map_err(|message| DRMBackendError::kms(drm_device, message))?;
+            }
+        }
+
+        request.commit(drm_device, kms::DRM_MODE_ATOMIC_NONBLOCK | kms::DRM_MODE_PAGE_FLIP_EVENT, output.crtc_id().into())
+            .map_err(|message| DRMBackendError::kms(drm_device, message))
+    }
+
+    /// Read the pending events on the DRM file descriptor
+    pub fn on_drm_input(&mut self) -> backend::Result<()> {
+        use libdrm::control::crtc;
+
+        let events = crtc::receive_events(&self.drm_device)?;
+        for event in events {
+            match event {
+                crtc::Event::PageFlip(event) => {
+                    let crtc_id: u32 = event.crtc.into();
+                    if let Some(output) = self.outputs.iter_mut().find(|output| output.crtc_id() == crtc_id) {
+                        output.page_flip_pending = false;
+
+                        let mut flags = presentation::Kind::Vsync.to_raw() | presentation::Kind::HwCompletion.to_raw();
+                        // some drivers leave the timestamp out
+                        let time = if event.duration.as_secs() == 0 && event.duration.subsec_nanos() == 0 {
+                            self.compositor.borrow().read_presentation_clock()
+                        } else {
+                            flags |= presentation::Kind::HwClock.to_raw();
+                            libc::timespec {
+                                tv_sec: event.duration.as_secs() as libc::time_t,
+                                tv_nsec: libc::c_long::from(event.duration.subsec_nanos()),
+                            }
+                        };
+                        let refresh = output.base.refresh_nsec();
+                        presentation::presented(&mut output.base.presentation_feedback, &output.base.wl_outputs, &time, refresh, event.frame.into(), flags);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// File descriptor of the DRM device, `on_drm_input` when readable
+    pub fn drm_fd(&self) -> RawFd {
+        self.drm_device.as_raw_fd()
+    }
+
+    /// File descriptor of the launcher, `on_launcher_input` when readable
+    pub fn launcher_fd(&self) -> Option<RawFd> {
+        self.interface.event_fd()
+    }
+
+    /// Longest the event loop may sleep before `on_launcher_input` has to
+    /// run even without input
+    pub fn launcher_timeout(&self) -> Option<Duration> {
+        self.interface.timeout()
+    }
+
+    /// Follow the session changes (VT switches) the launcher received
+    /// (session_notify in weston).
+    ///
+    /// Fails when the session is gone, the compositor has to exit.
+    pub fn on_launcher_input(&mut self) -> backend::Result<()> {
+        self.follow_session(|launcher, handler| launcher.dispatch(handler)).map(|_| ())
+    }
+
+    /// Follow what the session and seat monitors of the launcher saw, true
+    /// when the heads changed and have to be published again.
+    ///
+    /// Fails when the session is gone, the compositor has to exit.
+    pub fn on_session_change(&mut self) -> backend::Result<bool> {
+        self.follow_session(|launcher, handler| launcher.check_session(handler))
+    }
+
+    fn follow_session<F>(&mut self, dispatch: F) -> backend::Result<bool>
+        where F: FnOnce(&dyn Launcher, &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError>
+    {
+        let mut resume = false;
+        let mut removed = false;
+        let mut devices_changed = false;
+        {
+            let DRMBackend { interface, ref drm_device, ref mut outputs, ref mut session_active, .. } = *self;
+            dispatch(interface, &mut |event| match event {
+                SessionEvent::Deactivate => {
+                    // before the launcher acknowledges the pause
+                    if *session_active {
+                        DRMBackend::session_pause(drm_device, outputs);
+                        *session_active = false;
+                    }
+                    resume = false;
+                },
+                SessionEvent::Activate => {
+                    if !*session_active {
+                        *session_active = true;
+                        resume = true;
+                    }
+                },
+                SessionEvent::Removed => removed = true,
+                SessionEvent::DevicesChanged => devices_changed = true,
+            }).map_err(DRMBackendError::Launcher)?;
+        }
+
+        if removed {
+            self.session_active = false;
+            return Err(Box::new(DRMBackendError::SessionRemoved));
+        }
+        if resume {
+            self.session_resume()?;
+        }
+        if devices_changed && self.session_active {
+            return self.update_heads();
+        }
+
+        Ok(false)
+    }
+
+    /// Look for the connected connectors again: the outputs of the heads that
+    /// went away are disabled, the new heads start disabled. True when the
+    /// heads changed.
+    fn update_heads(&mut self) -> backend::Result<bool> {
+        let heads = match DRMBackend::find_heads(&self.drm_device) {
+            Ok(heads) => heads,
+            Err(DRMBackendError::NoOutput { .. }) => Vec::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let same = heads.len() == self.heads.len()
+            && heads.iter().zip(self.heads.iter())
+                .all(|(head, old)| head.connector == old.connector && head.modes == old.modes);
+        if same {
+            return Ok(false);
+        }
+
+        // an output keeps its head only with the same modes
+        let gone: Vec<HeadConfiguration> = self.heads.iter()
+            .filter(|old| !heads.iter().any(|head| head.connector == old.connector && head.modes == old.modes))
+            .filter(|old| self.outputs.iter().any(|output| output.connector == old.connector))
+            .map(|old| HeadConfiguration { name: old.name.clone(), state: None })
+            .collect();
+        if !gone.is_empty() {
+            self.apply_output_configuration(&gone)?;
+        }
+        log_info!("drm", "the heads changed, {} connected", heads.len());
+        self.heads = heads;
+
+        Ok(true)
+    }
+
+    fn session_pause(drm_device: &DRMDevice, outputs: &mut [DRMOutput]) {
+        use libdrm::Device;
+
+        log_info!("drm", "session paused");
+        // the flips in flight may never complete
+        for output in outputs.iter_mut() {
+            presentation::discard(&mut output.base.presentation_feedback);
+        }
+        // logind revokes master itself, fine if it already did
+        if let Err(e) = drm_device.drop_master() {
+            log_warn!("drm", "could not drop master: {}", e);
+        }
+    }
+
+    /// Set the modes back, whoever had the VT could have changed them, and
+    /// repaint everything.
+    fn session_resume(&mut self) -> backend::Result<()> {
+        use libdrm::Device;
+        use libdrm::control::{crtc, ResourceInfo};
+
+        log_info!("drm", "session resumed");
+        if let Err(e) = self.drm_device.set_master() {
+            log_warn!("drm", "could not set master: {}", e);
+        }
+
+        let drm_device = &self.drm_device;
+        for output in self.outputs.iter_mut() {
+            // the flips queued before the switch will not complete
+            output.page_flip_pending = false;
+            // and the cursor plane is whatever the other session left
+            output.cursor_position = None;
+            if self.use_egldevice {
+                // the stream stays attached, only the mode needs to come back
+                if let Some(&(_, ref fb_info)) = output.dumb.first() {
+                    crtc::set(drm_device, output.crtc, fb_info.handle(), &[output.connector], (0, 0), Some(output.mode))
+                        .map_err(|e| DRMBackendError::drm(drm_device, "Could not restore mode", e))?;
+                }
+            } else {
+                output.mode_set = false;
+            }
+            let geometry = output.base.geometry();
+            output.base.damage.union_rect(geometry);
+        }
+
+        self.repaint()
+    }
+
+    /// Swap between GL and pixman rendering without touching the clients
+    /// (renderer_switch_binding in weston), on SIGUSR1.
+    ///
+    /// The outputs keep the old renderer until the new one made their
+    /// framebuffers, if it can't they stay as they were. The surfaces and
+    /// dmabufs then get their renderer state rebuilt from the attached
+    /// buffers and the outputs are repainted whole.
+    ///
+    /// Only EGLStream outputs have GL, on GBM the switch is refused.
+    pub fn switch_renderer(&mut self) -> backend::Result<()> {
+        use libdrm::control::{crtc, ResourceInfo};
+
+        if !self.use_egldevice {
+            return Err(Box::new(DRMBackendError::NoGlRenderer));
+        }
+        let use_pixman = !self.use_pixman;
+        let mut renderer = DRMBackend::init_renderer(&self.drm_device, use_pixman, self.use_egldevice)?;
+
+        // the old framebuffers may still be in flight
+        self.wait_page_flips();
+
+        let mut old_states = Vec::new();
+        let mut result = Ok(());
+        for output in self.outputs.iter_mut() {
+            old_states.push(output.swap_renderer_state(OutputRendererState::default()));
+            result = DRMBackend::output_init_renderer(&self.drm_device, &mut *renderer, output, use_pixman, self.use_egldevice);
+            if result.is_err() {
+                break;
+            }
+        }
+        if let Err(e) = result {
+            for (output, old_state) in self.outputs.iter_mut().zip(old_states) {
+                DRMBackend::output_fini_renderer(&self.drm_device, &mut *renderer, output);
+                output.swap_renderer_state(old_state);
+                // the EGLStream mode set showed a dumb buffer that is gone now
+                if self.use_egldevice && output.mode_set {
+                    if let Some(&(_, ref fb_info)) = output.dumb.first() {
+                        if let Err(e) = crtc::set(&self.drm_device, output.crtc, fb_info.handle(), &[output.connector], (0, 0), Some(output.mode)) {
+                            log_warn!("drm", "{}: could not restore the mode: {}", output.base.name, e);
+                        }
+                    }
+                }
+                output.base.damage_all();
+            }
+            log_error!("drm", "Could not switch to the {} renderer, keeping {}", renderer.name(), self.renderer.name());
+            return Err(e.into());
+        }
+
+        let compositor = self.compositor.clone();
+        let compositor = RefCell::borrow(&compositor);
+
+        // release what the old renderer created while it is still alive
+        for (output, old_state) in self.outputs.iter_mut().zip(old_states) {
+            let new_state = output.swap_renderer_state(old_state);
+            DRMBackend::output_fini_renderer(&self.drm_device, &mut *self.renderer, output);
+            output.swap_renderer_state(new_state);
+        }
+        for surface in compositor.surfaces.iter().chain(compositor.cursor.surface.iter()) {
+            surface.borrow_mut().renderer_state = None;
+        }
+        for dmabuf in compositor.dmabufs() {
+            dmabuf.renderer_state.borrow_mut().take();
+        }
+        mem::swap(&mut self.renderer, &mut renderer);
+        drop(renderer);
+        self.use_pixman = use_pixman;
+
+        log_info!("drm", "Switched to {} renderer", self.renderer.name());
+
+        for dmabuf in compositor.dmabufs() {
+            if let Err(e) = self.renderer.import_dmabuf(&dmabuf) {
+                log_error!("drm", "Could not import dmabuf in the {} renderer: {}", self.renderer.name(), e);
+            }
+        }
+        for surface in compositor.surfaces.iter().chain(compositor.cursor.surface.iter()) {
+            let mut surface = surface.borrow_mut();
+            if surface.live_buffer().is_none() {
+                continue;
+            }
+            if let Err(e) = self.renderer.attach(&mut surface) {
+                log_error!("drm", "Could not attach surface buffer to the {} renderer: {}", self.renderer.name(), e);
+            }
+        }
+        drop(compositor);
+
+        self.repaint()
+    }
+
+    /// Import a client dmabuf, it is imported again if the renderer changes
+    pub fn import_dmabuf(&mut self, dmabuf: &Rc<Dmabuf>) -> backend::Result<()> {
+        self.renderer.import_dmabuf(dmabuf)
+            .map_err(DRMBackendError::Renderer)?;
+        self.compositor.borrow_mut().add_dmabuf(dmabuf);
+        Ok(())
+    }
+
+    /// The dmabuf formats and modifiers the renderer imports
+    pub fn dmabuf_formats(&self) -> Vec<(u32, Vec<u64>)> {
+        self.renderer.query_dmabuf_formats().into_iter()
+            .map(|format| (format, self.renderer.query_dmabuf_modifiers(format)))
+            .collect()
+    }
+
+    pub fn compositor(&self) -> Rc<RefCell<Compositor>> {
+        self.compositor.clone()
+    }
+
+    /// The seat the devices are taken from
+    pub fn seat_name(&self) -> String {
+        self.interface.seat_name()
+    }
+
+    /// The wl_shm formats the renderer takes besides argb8888 and xrgb8888
+    pub fn shm_formats(&self) -> Vec<wl_shm::Format> {
+        self.renderer.shm_formats()
+    }
+
+    /// Share the wl_output resources of the outputs' globals, for the
+    /// presentation feedback
+    pub fn set_output_resources(&mut self, outputs: &Outputs) {
+        for output in self.outputs.iter_mut() {
+            if let Some(resources) = outputs.resources(&output.base.name) {
+                output.base.wl_outputs = resources;
+            }
+        }
+    }
+
+    /// The connected heads, with the mode and position of the enabled ones
+    pub fn heads(&self) -> Vec<Head> {
+        self.heads.iter().map(|head| {
+            let state = self.outputs.iter()
+                .find(|output| output.connector == head.connector)
+                .map(|output| HeadState {
+                    mode: head.mode(head.modes.iter().position(|&mode| mode == output.mode).unwrap_or(0)),
+                    x: output.base.x,
+                    y: output.base.y,
+                    transform: output.base.transform,
+                    scale: output.base.scale,
+                });
+            Head {
+                name: head.name.clone(),
+                description: format!("Monitor on {}", head.name),
+                physical_width: head.physical_size.0 as i32,
+                physical_height: head.physical_size.1 as i32,
+                modes: (0..head.modes.len()).map(|index| head.mode(index)).collect(),
+                state,
+            }
+        }).collect()
+    }
+
+    /// Check `configuration` against the heads and give each head that ends
+    /// up enabled a crtc. The heads it does not mention stay as they are.
+    fn plan_configuration(&self, configuration: &[HeadConfiguration]) -> Result<Vec<Option<OutputPlan>>, DRMBackendError> {
+        let mut plan: Vec<Option<OutputPlan>> = self.heads.iter()
+            .map(|head| {
+                self.outputs.iter()
+                    .find(|output| output.connector == head.connector)
+                    .map(|output| OutputPlan {
+                        crtc: output.crtc,
+                        mode: head.modes.iter().position(|&mode| mode == output.mode).unwrap_or(0),
+                        x: output.base.x,
+                        y: output.base.y,
+                        scale: output.base.scale,
+                        transform: output.base.transform,
+                    })
             })
+            .collect();
+        // the heads to enable that have no output yet
+        let mut needs_crtc = Vec::new();
+
+        for head_configuration in configuration.iter() {
+            let index = self.heads.iter().position(|head| head.name == head_configuration.name)
+                .ok_or_else(|| DRMBackendError::Configuration(format!("no head called {}", head_configuration.name)))?;
+            let head = &self.heads[index];
+            let state = match head_configuration.state {
+                Some(ref state) => state,
+                None => {
+                    plan[index] = None;
+                    continue;
+                },
+            };
+
+            if !(state.scale >= MIN_SCALE && state.scale <= MAX_SCALE) {
+                return Err(DRMBackendError::Configuration(format!("{}: scale {} out of range", head.name, state.scale)));
+            }
+            let mode = head.find_mode(&state.mode)
+                .ok_or_else(|| DRMBackendError::Configuration(format!("{}: no {}x{}@{}mHz mode",
+                    head.name, state.mode.width, state.mode.height, state.mode.refresh)))?;
+
+            plan[index] = match plan[index] {
+                Some(current) => Some(OutputPlan {
+                    mode,
+                    x: state.x,
+                    y: state.y,
+                    scale: state.scale,
+                    transform: state.transform,
+                    ..current
+                }),
+                None => {
+                    needs_crtc.push(index);
+                    Some(OutputPlan {
+                        crtc: head.crtcs[0],
+                        mode,
+                        x: state.x,
+                        y: state.y,
+                        scale: state.scale,
+                        transform: state.transform,
+                    })
+                },
+            };
+        }
+
+        // the heads keeping their output keep its crtc
+        let mut taken: Vec<_> = plan.iter().enumerate()
+            .filter(|&(index, _)| !needs_crtc.contains(&index))
+            .filter_map(|(_, target)| target.map(|target| target.crtc))
+            .collect();
+        for index in needs_crtc {
+            let head = &self.heads[index];
+            let crtc = head.crtcs.iter().find(|crtc| !taken.contains(crtc)).cloned()
+                .ok_or_else(|| DRMBackendError::Configuration(format!("{}: no free crtc", head.name)))?;
+            taken.push(crtc);
+            if let Some(ref mut target) = plan[index] {
+                target.crtc = crtc;
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Whether the heads of `configuration` can be set like that, the kernel
+    /// checks it too with atomic mode setting
+    pub fn test_output_configuration(&self, configuration: &[HeadConfiguration]) -> backend::Result<()> {
+        let plan = self.plan_configuration(configuration)?;
+        // only the DRM master can ask
+        if !self.session_active {
+            return Ok(());
+        }
+        if let Some(atomic) = self.atomic_configuration(&plan)? {
+            let result = atomic.commit(&self.drm_device, kms::DRM_MODE_ATOMIC_TEST_ONLY | kms::DRM_MODE_ATOMIC_ALLOW_MODESET);
+            atomic.release(&self.drm_device);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Set the heads as `configuration` says, the ones it does not mention
+    /// stay as they are. If a head fails the previous configuration is
+    /// restored.
+    pub fn apply_output_configuration(&mut self, configuration: &[HeadConfiguration]) -> backend::Result<()> {
+        if !self.session_active {
+            return Err(Box::new(DRMBackendError::Configuration("the session is in the background".to_string())));
+        }
+        let plan = self.plan_configuration(configuration)?;
+
+        let previous: Vec<HeadConfiguration> = self.heads().into_iter()
+            .map(|head| HeadConfiguration { name: head.name, state: head.state })
+            .collect();
+        if let Err(e) = self.apply_plan(&plan) {
+            log_warn!("drm", "{}, restoring the previous output configuration", e);
+            let restored = match self.plan_configuration(&previous) {
+                Ok(plan) => self.apply_plan(&plan),
+                Err(e) => Err(Box::new(e) as Box<dyn StdError>),
+            };
+            if let Err(e) = restored {
+                log_error!("drm", "could not restore the output configuration: {}", e);
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// The crtc, connector and plane changes of `plan` as one atomic request,
+    /// `None` without atomic mode setting or when a head has no primary plane
+    fn atomic_configuration(&self, plan: &[Option<OutputPlan>]) -> Result<Option<AtomicConfiguration>, DRMBackendError> {
+        // the EGLStream outputs set their modes themselves
+        if !self.atomic_modeset || self.use_egldevice {
+            return Ok(None);
+        }
+
+        let mut atomic = AtomicConfiguration::default();
+        match self.add_atomic_configuration(plan, &mut atomic) {
+            Ok(true) => Ok(Some(atomic)),
+            Ok(false) => {
+                atomic.release(&self.drm_device);
+                Ok(None)
+            },
+            Err(e) => {
+                atomic.release(&self.drm_device);
+                Err(e)
+            },
+        }
+    }
+
+    /// Only the heads turned off, on or to another mode are in the request,
+    /// false if one of them has no primary plane
+    fn add_atomic_configuration(&self, plan: &[Option<OutputPlan>], atomic: &mut AtomicConfiguration) -> Result<bool, DRMBackendError> {
+        use libdrm::ffi;
+        use libdrm::control::ResourceInfo;
+
+        let drm_device = &self.drm_device;
+        let kms_error = |message| DRMBackendError::kms(drm_device, message);
+
+        for (index, target) in plan.iter().enumerate() {
+            let head = &self.heads[index];
+            let output = self.outputs.iter().find(|output| output.connector == head.connector);
+            let connector = kms::Properties::load(drm_device, head.connector.into(), ffi::DRM_MODE_OBJECT_CONNECTOR)
+                .map_err(kms_error)?;
+
+            let target = match *target {
+                Some(target) => target,
+                None => {
+                    let output = match output {
+                        Some(output) => output,
+                        None => continue,
+                    };
+                    let plane = match output.plane {
+                        Some(ref plane) => plane,
+                        None => return Ok(false),
+                    };
+                    let crtc = kms::Properties::load(drm_device, output.crtc_id(), ffi::DRM_MODE_OBJECT_CRTC)
+                        .map_err(kms_error)?;
+                    atomic.request.add(&connector, "CRTC_ID", 0)
+                        .and_then(|_| atomic.request.add(&crtc, "ACTIVE", 0))
+                        .and_then(|_| atomic.request.add(&crtc, "MODE_ID", 0))
+                        .and_then(|_| atomic.request.add(plane, "FB_ID", 0))
+                        .and_then(|_| atomic.request.add(plane, "CRTC_ID", 0))
+                        .map_err(kms_error)?;
+                    // no plane may stay on a crtc going off
+                    if output.cursor_position.is_some() {
+                        let crtc_index = DRMBackend::crtc_index(drm_device, output.crtc)?;
+                        if let Some(cursor) = kms::find_cursor_plane(drm_device, crtc_index).map_err(kms_error)? {
+                            atomic.request.add(&cursor, "FB_ID", 0)
+                                .and_then(|_| atomic.request.add(&cursor, "CRTC_ID", 0))
+                                .map_err(kms_error)?;
+                        }
+                    }
+                    continue;
+                },
+            };
+
+            let mode = head.modes[target.mode];
+            let plane = match output {
+                Some(output) if output.mode == mode => continue,
+                Some(output) => match output.plane {
+                    Some(ref plane) => plane.clone(),
+                    None => return Ok(false),
+                },
+                None => match DRMBackend::find_primary_plane(drm_device, target.crtc) {
+                    Ok(plane) => plane,
+                    Err(e) => {
+                        log_warn!("drm", "{}: {}, setting the mode without atomic mode setting", head.name, e);
+                        return Ok(false);
+                    },
+                },
+            };
+            let crtc_id: u32 = target.crtc.into();
+            let crtc = kms::Properties::load(drm_device, crtc_id, ffi::DRM_MODE_OBJECT_CRTC)
+                .map_err(kms_error)?;
+
+            let blob = kms::create_mode_blob(drm_device, &mode).map_err(kms_error)?;
+            atomic.blobs.push(blob);
+            let (width, height) = mode.size();
+            let framebuffer = DRMBackend::create_dumb_framebuffer(drm_device, width, height)?;
+            let framebuffer_id: u32 = framebuffer.1.handle().into();
+            atomic.framebuffers.push(framebuffer);
+
+            let (width, height) = (u64::from(width), u64::from(height));
+            let request = &mut atomic.request;
+            request.add(&connector, "CRTC_ID", crtc_id.into())
+                .and_then(|_| request.add(&crtc, "MODE_ID", blob.into()))
+                .and_then(|_| request.add(&crtc, "ACTIVE", 1))
+                .and_then(|_| request.add(&plane, "FB_ID", framebuffer_id.into()))
+                .and_then(|_| request.add(&plane, "CRTC_ID", crtc_id.into()))
+                .and_then(|_| request.add(&plane, "SRC_X", 0))
+                .and_then(|_| request.add(&plane, "SRC_Y", 0))
+                .and_then(|_| request.add(&plane, "SRC_W", width << 16))
+                .and_then(|_| request.add(&plane, "SRC_H", height << 16))
+                .and_then(|_| request.add(&plane, "CRTC_X", 0))
+                .and_then(|_| request.add(&plane, "CRTC_Y", 0))
+                .and_then(|_| request.add(&plane, "CRTC_W", width))
+                .and_then(|_| request.add(&plane, "CRTC_H", height))
+                .map_err(kms_error)?;
+            if plane.id("rotation").is_some() {
+                request.add(&plane, "rotation", kms::DRM_MODE_ROTATE_0)
+                    .map_err(kms_error)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Set the modes of `plan` in one atomic commit when the device can, one
+    /// head after the other otherwise
+    fn apply_plan(&mut self, plan: &[Option<OutputPlan>]) -> backend::Result<()> {
+        // the renderer state of the outputs goes, nothing may scan it out
+        self.wait_page_flips();
+
+        let atomic = self.atomic_configuration(plan)?;
+        if let Some(ref atomic) = atomic {
+            if let Err(e) = atomic.commit(&self.drm_device, kms::DRM_MODE_ATOMIC_ALLOW_MODESET) {
+                return Err(Box::new(e));
+            }
+        }
+        let result = self.apply_outputs(plan, atomic.is_some());
+        if let Some(atomic) = atomic {
+            // the first frames replace the dumb buffers
+            self.wait_page_flips();
+            atomic.release(&self.drm_device);
+        }
+        result
+    }
+
+    /// Make the outputs of `plan`, `modes_set` if an atomic commit already
+    /// set the modes of their crtcs
+    fn apply_outputs(&mut self, plan: &[Option<OutputPlan>], modes_set: bool) -> backend::Result<()> {
+        // disable first, the crtcs they free can go to other heads
+        for (index, target) in plan.iter().enumerate() {
+            if target.is_some() {
+                continue;
+            }
+            let connector = self.heads[index].connector;
+            if let Some(position) = self.outputs.iter().position(|output| output.connector == connector) {
+                let mut output = self.outputs.remove(position);
+                self.output_disable(&mut output);
+            }
+        }
+
+        for (index, target) in plan.iter().enumerate() {
+            let target = match *target {
+                Some(target) => target,
+                None => continue,
+            };
+            let head = &self.heads[index];
+            let mode = head.modes[target.mode];
+
+            match self.outputs.iter().position(|output| output.connector == head.connector) {
+                Some(position) => {
+                    let output = &mut self.outputs[position];
+                    if output.mode != mode {
+                        log_info!("drm", "{}: setting mode {}x{}@{}mHz", head.name, mode.size().0, mode.size().1, mode_refresh(&mode));
+                        DRMBackend::output_fini_renderer(&self.drm_device, &mut *self.renderer, output);
+                        let (width, height) = mode.size();
+                        output.mode = mode;
+                        output.base.width = width.into();
+                        output.base.height = height.into();
+                        output.base.refresh = mode_refresh(&mode);
+                        DRMBackend::output_init_renderer(&self.drm_device, &mut *self.renderer, output, self.use_pixman, self.use_egldevice)?;
+                        output.mode_set = modes_set;
+                        DRMBackend::output_repaint(&self.drm_device, &mut *self.renderer, output, &[])?;
+                    }
+                    output.base.x = target.x;
+                    output.base.y = target.y;
+                    output.base.scale = target.scale;
+                    output.set_transform(target.transform);
+                },
+                None => {
+                    log_info!("drm", "{}: enabling on crtc {}", head.name, u32::from(target.crtc));
+                    let mut output = DRMBackend::create_output(&self.drm_device, &mut *self.renderer, head, target.crtc,
+                                                               target.mode, self.use_pixman, self.use_egldevice, self.atomic_modeset)?;
+                    output.mode_set = modes_set;
+                    DRMBackend::output_repaint(&self.drm_device, &mut *self.renderer, &mut output, &[])?;
+                    DRMBackend::output_init_cursor(&self.drm_device, self.gbm.as_ref(), (self.cursor_width, self.cursor_height),
+                                                   self.atomic_modeset, &mut output);
+                    output.base.x = target.x;
+                    output.base.y = target.y;
+                    output.base.scale = target.scale;
+                    output.set_transform(target.transform);
+                    self.outputs.push(output);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turn the crtc of a head disabled by an output configuration off
+    fn output_disable(&mut self, output: &mut DRMOutput) {
+        use libdrm::control::{crtc, framebuffer};
+
+        log_info!("drm", "{}: disabling", output.base.name);
+        DRMBackend::output_fini_renderer(&self.drm_device, &mut *self.renderer, output);
+        if output.cursor_position.take().is_some() {
+            let _ = crtc::clear_cursor(&self.drm_device, output.crtc);
+        }
+        presentation::discard(&mut output.base.presentation_feedback);
+        if let Err(e) = crtc::set(&self.drm_device, output.crtc, framebuffer::Handle::from(0), &[], (0, 0), None) {
+            log_warn!("drm", "could not disable crtc {}: {}", output.crtc_id(), e);
+        }
+    }
+
+    /// Block until the queued page flips completed, for at most a second each
+    fn wait_page_flips(&mut self) {
+        while self.outputs.iter().any(|output| output.page_flip_pending) {
+            let mut pollfd = libc::pollfd {
+                fd: self.drm_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pollfd, 1, 1000) } <= 0 {
+                log_warn!("drm", "page flip did not complete, giving up on it");
+                for output in self.outputs.iter_mut() {
+                    output.page_flip_pending = false;
+                }
+                break;
+            }
+            if let Err(e) = self.on_drm_input() {
+                log_error!("drm", "{}", e);
+            }
+        }
+    }
+
+    /// Give the committed buffers and damage of the surfaces to the renderer
+    /// and add what changed to the damage of the outputs.
+    fn flush_surfaces(&mut self) {
+        let mut compositor = self.compositor.borrow_mut();
+        let mut damage = mem::replace(&mut compositor.damage, Region::new());
+        for surface in compositor.surfaces.iter() {
+            DRMBackend::flush_surface(&mut *self.renderer, &mut surface.borrow_mut(), &mut damage);
+        }
+
+        // only the outputs compositing the cursor repaint for it
+        let mut cursor_damage = mem::replace(&mut compositor.cursor.damage, Region::new());
+        if let Some(ref surface) = compositor.cursor.surface {
+            DRMBackend::flush_surface(&mut *self.renderer, &mut surface.borrow_mut(), &mut cursor_damage);
+        }
+
+        for output in self.outputs.iter_mut() {
+            let geometry = output.base.geometry();
+            output.base.damage.union(&damage.intersect_rect(&geometry));
+            if output.cursor_position.is_none() {
+                output.base.damage.union(&cursor_damage.intersect_rect(&geometry));
+            }
+        }
+    }
+
+    /// Attach or flush the damage of `surface`, the area that changed is
+    /// added to `damage`
+    fn flush_surface(renderer: &mut dyn Renderer, surface: &mut Surface, damage: &mut Region) {
+        if surface.buffer_attached {
+            surface.buffer_attached = false;
+            if surface.live_buffer().is_none() {
+                surface.buffer = None;
+            }
+            damage.union_rect(surface.geometry());
+            if let Err(e) = renderer.attach(surface) {
+                log_error!("drm", "Could not attach surface buffer: {}", e);
+            }
+            surface.update_size();
+            damage.union_rect(surface.geometry());
+            surface.damage_all();
+        } else if !surface.damage.is_empty() && surface.live_buffer().is_some() {
+            if let Err(e) = renderer.flush_damage(surface) {
+                log_error!("drm", "Could not flush surface damage: {}", e);
+            }
+        }
+
+        let buffer_damage = mem::replace(&mut surface.damage, Region::new());
+        for rect in buffer_damage.rects() {
+            let rect = surface.buffer_to_surface_rect(rect);
+            damage.union_rect(rect.translate(surface.x, surface.y));
+        }
+    }
+
+    pub fn repaint(&mut self) -> backend::Result<()> {
+        self.flush_surfaces();
+        // the damage waits for the session to come back
+        if !self.session_active {
+            return Ok(());
+        }
+
+        let image_changed = mem::replace(&mut self.compositor.borrow_mut().cursor.image_changed, false);
+        let compositor = self.compositor.clone();
+        let compositor = RefCell::borrow(&compositor);
+        let mut repainted = Vec::new();
+        {
+            let surfaces: Vec<Ref<Surface>> = compositor.surfaces.iter()
+                .map(|surface| RefCell::borrow(surface))
+                .collect();
+            let surfaces: Vec<&Surface> = surfaces.iter().map(|surface| &**surface).collect();
+            // the cursor on top when there is no cursor plane for it
+            let cursor = compositor.cursor.surface.as_ref().map(|surface| RefCell::borrow(surface));
+            let mut with_cursor = surfaces.clone();
+            with_cursor.extend(cursor.as_ref().map(|surface| &**surface));
+
+            for (index, output) in self.outputs.iter_mut().enumerate() {
+                let composite_cursor = DRMBackend::output_update_cursor(&self.drm_device, output, &compositor.cursor,
+                                                                        image_changed, (self.cursor_width, self.cursor_height));
+                if output.base.damage.is_empty() {
+                    continue;
+                }
+                let surfaces = if composite_cursor { &with_cursor } else { &surfaces };
+                if DRMBackend::output_repaint(&self.drm_device, &mut *self.renderer, output, surfaces)? {
+                    repainted.push(index);
+                }
+            }
+        }
+        if repainted.is_empty() {
+            // a cursor on the cursor planes shows without a repaint
+            if let Some(ref surface) = compositor.cursor.surface {
+                surface.borrow_mut().send_frame_done(compositor.frame_time());
+            }
+            // commits without damage: no frame is coming for them, unless an
+            // output still waits for a flip or has damage for the next round
+            let idle = self.outputs.iter().all(|output| !output.page_flip_pending && output.base.damage.is_empty());
+            if idle {
+                let time = compositor.frame_time();
+                for surface in compositor.surfaces.iter() {
+                    surface.borrow_mut().send_frame_done(time);
+                }
+            }
+            return Ok(());
+        }
+
+        // the buffers are in use until the GPU is done with the new frames
+        for &index in repainted.iter() {
+            let output = &mut self.outputs[index].base;
+            let fence = self.renderer.create_fence_fd(output);
+            for surface in compositor.surfaces.iter() {
+                let mut surface = surface.borrow_mut();
+                if surface.geometry().intersect(&output.geometry()).is_none() {
+                    continue;
+                }
+                output.presentation_feedback.extend(surface.presentation_feedback.drain(..));
+                let release_fence = fence.and_then(|fd| {
+                    let fd = unsafe { libc::dup(fd) };
+                    if fd < 0 { None } else { Some(fd) }
+                });
+                surface.set_release_fence(release_fence);
+            }
+            if let Some(fd) = fence {
+                unsafe { libc::close(fd) };
+            }
+        }
+
+        // the blocking mode set has no page flip event, the frame is on
+        // screen already, since the vblank it waited for
+        for &index in repainted.iter() {
+            let output = &mut self.outputs[index];
+            if !output.page_flip_pending {
+                let time = compositor.read_presentation_clock();
+                let refresh = output.base.refresh_nsec();
+                let flags = presentation::Kind::Vsync.to_raw();
+                presentation::presented(&mut output.base.presentation_feedback, &output.base.wl_outputs, &time, refresh, 0, flags);
+            }
+        }
+
+        let time = compositor.frame_time();
+        for surface in compositor.surfaces.iter().chain(compositor.cursor.surface.iter()) {
+            surface.borrow_mut().send_frame_done(time);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Drop for DRMBackend<'a> {
+    /// Outputs first, the renderer and then the device (giving up DRM master)
+    /// follow in field order
+    fn drop(&mut self) {
+        log_info!("drm", "shutting down {}", self.drm_device.dev_path().as_os_str().to_string_lossy());
+        for output in self.outputs.iter_mut() {
+            DRMBackend::output_fini_renderer(&self.drm_device, &mut *self.renderer, output);
+            // without master the mode can't be set, whoever has the VT now owns it
+            if self.session_active {
+                DRMBackend::output_restore_crtc(&self.drm_device, output);
+            }
+        }
     }
 }
 
 #[allow(non_snake_case)]
 fn PrintUDEVDeviceInfo(device: &libudev::Device) {
-    println!("\n##########################################################");
-    println!("initialized: {:?}", device.is_initialized());
-    println!("     devnum: {:?}", device.devnum());
-    println!("    syspath: {:?}", device.syspath());
-    println!("    devpath: {:?}", device.devpath());
-    println!("  subsystem: {:?}", device.subsystem());
-    println!("    sysname: {:?}", device.sysname());
-    println!("     sysnum: {:?}", device.sysnum());
-    println!("    devtype: {:?}", device.devtype());
-    println!("     driver: {:?}", device.driver());
-    println!("    devnode: {:?}", device.devnode());
+    // walks all the properties and attributes, skip it if nobody listens
+    if !::log::is_enabled("drm", ::log::Level::Debug) {
+        return;
+    }
+
+    log_debug!("drm", "##########################################################");
+    log_debug!("drm", "initialized: {:?}", device.is_initialized());
+    log_debug!("drm", "     devnum: {:?}", device.devnum());
+    log_debug!("drm", "    syspath: {:?}", device.syspath());
+    log_debug!("drm", "    devpath: {:?}", device.devpath());
+    log_debug!("drm", "  subsystem: {:?}", device.subsystem());
+    log_debug!("drm", "    sysname: {:?}", device.sysname());
+    log_debug!("drm", "     sysnum: {:?}", device.sysnum());
+    log_debug!("drm", "    devtype: {:?}", device.devtype());
+    log_debug!("drm", "     driver: {:?}", device.driver());
+    log_debug!("drm", "    devnode: {:?}", device.devnode());
 
     if let Some(parent) = device.parent() {
-        println!("     parent: {:?}", parent.syspath());
+        log_debug!("drm", "     parent: {:?}", parent.syspath());
     } else {
-        println!("     parent: None");
+        log_debug!("drm", "     parent: None");
     }
 
-    println!("  [device.properties]");
+    log_debug!("drm", "  [device.properties]");
     for property in device.properties() {
-        println!("    - {:?} \t{:?}", property.name(), property.value());
+        log_debug!("drm", "    - {:?} \t{:?}", property.name(), property.value());
     }
 
-    println!("  [device.attributes]");
+    log_debug!("drm", "  [device.attributes]");
     for attribute in device.attributes() {
-        println!("    - {:?} \t{:?}", attribute.name(), attribute.value());
+        log_debug!("drm", "    - {:?} \t{:?}", attribute.name(), attribute.value());
     }
 
-    println!("\n##########################################################");
+    log_debug!("drm", "##########################################################");
 }
 
 //trait SubSystemSearchable {
@@ -0,0 +1,288 @@
+//! Atomic mode setting, the parts the drm crate does not cover yet
+//! (drmModeAtomic* and drmModeObjectGetProperties in libdrm).
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use libdrm::control::Mode;
+use libdrm::ffi;
+
+pub const DRM_MODE_PAGE_FLIP_EVENT: u32 = 0x01;
+pub const DRM_MODE_ATOMIC_TEST_ONLY: u32 = 0x0100;
+pub const DRM_MODE_ATOMIC_NONBLOCK: u32 = 0x0200;
+pub const DRM_MODE_ATOMIC_ALLOW_MODESET: u32 = 0x0400;
+
+pub const DRM_CAP_TIMESTAMP_MONOTONIC: u64 = 0x6;
+pub const DRM_CAP_CURSOR_WIDTH: u64 = 0x8;
+pub const DRM_CAP_CURSOR_HEIGHT: u64 = 0x9;
+
+// bits of the plane "rotation" property, rotations are counter-clockwise
+pub const DRM_MODE_ROTATE_0: u64 = 1 << 0;
+pub const DRM_MODE_ROTATE_90: u64 = 1 << 1;
+pub const DRM_MODE_ROTATE_180: u64 = 1 << 2;
+pub const DRM_MODE_ROTATE_270: u64 = 1 << 3;
+pub const DRM_MODE_REFLECT_X: u64 = 1 << 4;
+pub const DRM_MODE_REFLECT_Y: u64 = 1 << 5;
+
+const DRM_PLANE_TYPE_PRIMARY: u64 = 1;
+const DRM_PLANE_TYPE_CURSOR: u64 = 2;
+const DRM_MODE_TYPE_PREFERRED: u32 = 1 << 3;
+
+/// The value of a device capability (drmGetCap)
+pub fn get_cap<D: AsRawFd>(device: &D, capability: u64) -> Result<u64, String> {
+    let mut raw: ffi::drm_get_cap = Default::default();
+    raw.capability = capability;
+    unsafe { ffi::ioctl_get_cap(device.as_raw_fd(), &mut raw) }
+        .map_err(|e| format!("Could not get capability {}: {}", capability, e))?;
+    Ok(raw.value)
+}
+
+/// The properties of a KMS object, by name
+#[derive(Debug, Clone)]
+pub struct Properties {
+    object_id: u32,
+    /// name -> (property id, value when loaded)
+    props: HashMap<String, (u32, u64)>,
+}
+
+impl Properties {
+    pub fn load<D: AsRawFd>(device: &D, object_id: u32, object_type: u32) -> Result<Self, String> {
+        let fd = device.as_raw_fd();
+
+        let mut raw: ffi::drm_mode_obj_get_properties = Default::default();
+        raw.obj_id = object_id;
+        raw.obj_type = object_type;
+        unsafe { ffi::ioctl_mode_obj_getproperties(fd, &mut raw) }
+            .map_err(|e| format!("Could not get properties of object {}: {}", object_id, e))?;
+
+        let mut ids: Vec<u32> = vec![0; raw.count_props as usize];
+        let mut values: Vec<u64> = vec![0; raw.count_props as usize];
+        raw.props_ptr = ids.as_mut_ptr() as u64;
+        raw.prop_values_ptr = values.as_mut_ptr() as u64;
+        unsafe { ffi::ioctl_mode_obj_getproperties(fd, &mut raw) }
+            .map_err(|e| format!("Could not get properties of object {}: {}", object_id, e))?;
+        ids.truncate(raw.count_props as usize);
+
+        let mut props = HashMap::new();
+        for (&id, &value) in ids.iter().zip(values.iter()) {
+            let mut property: ffi::drm_mode_get_property = Default::default();
+            property.prop_id = id;
+            if unsafe { ffi::ioctl_mode_getproperty(fd, &mut property) }.is_err() {
+                continue;
+            }
+            let name = unsafe { CStr::from_ptr(property.name.as_ptr()) };
+            props.insert(name.to_string_lossy().into_owned(), (id, value));
+        }
+
+        Ok(Self {
+            object_id,
+            props,
+        })
+    }
+
+    pub fn object_id(&self) -> u32 {
+        self.object_id
+    }
+
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.props.get(name).map(|&(id, _)| id)
+    }
+
+    pub fn value(&self, name: &str) -> Option<u64> {
+        self.props.get(name).map(|&(_, value)| value)
+    }
+}
+
+/// The bits the bitmask property `name` of `properties` accepts, 0 if the
+/// object does not have it
+pub fn property_bitmask<D: AsRawFd>(device: &D, properties: &Properties, name: &str) -> Result<u64, String> {
+    let fd = device.as_raw_fd();
+    let property_id = match properties.id(name) {
+        Some(property_id) => property_id,
+        None => return Ok(0),
+    };
+
+    let mut property: ffi::drm_mode_get_property = Default::default();
+    property.prop_id = property_id;
+    unsafe { ffi::ioctl_mode_getproperty(fd, &mut property) }
+        .map_err(|e| format!("Could not get property {}: {}", name, e))?;
+
+    let mut enums: Vec<ffi::drm_mode_property_enum> = vec![Default::default(); property.count_enum_blobs as usize];
+    property.count_values = 0;
+    property.enum_blob_ptr = enums.as_mut_ptr() as u64;
+    unsafe { ffi::ioctl_mode_getproperty(fd, &mut property) }
+        .map_err(|e| format!("Could not get the values of property {}: {}", name, e))?;
+    enums.truncate(property.count_enum_blobs as usize);
+
+    // the enum values of a bitmask are bit numbers
+    Ok(enums.iter()
+        .filter(|entry| entry.value < 64)
+        .fold(0, |mask, entry| mask | 1 << entry.value))
+}
+
+/// A property blob holding `mode`, for the crtc "MODE_ID" property
+/// (drmModeCreatePropertyBlob)
+pub fn create_mode_blob<D: AsRawFd>(device: &D, mode: &Mode) -> Result<u32, String> {
+    // Mode only wraps the drm_mode_modeinfo and does not give it out
+    let modeinfo: ffi::drm_mode_modeinfo = unsafe { mem::transmute(*mode) };
+
+    let mut raw: ffi::drm_mode_create_blob = Default::default();
+    raw.data = &modeinfo as *const ffi::drm_mode_modeinfo as u64;
+    raw.length = mem::size_of::<ffi::drm_mode_modeinfo>() as u32;
+    unsafe { ffi::ioctl_mode_createpropblob(device.as_raw_fd(), &mut raw) }
+        .map_err(|e| format!("Could not create a mode blob: {}", e))?;
+    Ok(raw.blob_id)
+}
+
+/// The crtcs using the blob keep it until they drop it
+pub fn destroy_blob<D: AsRawFd>(device: &D, blob_id: u32) {
+    let mut raw = ffi::drm_mode_destroy_blob { blob_id };
+    let _ = unsafe { ffi::ioctl_mode_destroypropblob(device.as_raw_fd(), &mut raw) };
+}
+
+/// What `connector::Info` leaves out
+#[derive(Debug, Clone)]
+pub struct ConnectorDetails {
+    /// number among the connectors of the same type, the 1 of "HDMI-A-1"
+    pub type_id: u32,
+    /// in millimeters, 0 if unknown
+    pub physical_size: (u32, u32),
+    /// per mode, in the order the kernel lists them
+    pub preferred: Vec<bool>,
+}
+
+/// The type id, physical size and preferred modes of a connector
+/// (drmModeGetConnector)
+pub fn connector_details<D: AsRawFd>(device: &D, connector_id: u32) -> Result<ConnectorDetails, String> {
+    let fd = device.as_raw_fd();
+
+    let mut raw: ffi::drm_mode_get_connector = Default::default();
+    raw.connector_id = connector_id;
+    unsafe { ffi::ioctl_mode_getconnector(fd, &mut raw) }
+        .map_err(|e| format!("Could not get connector {}: {}", connector_id, e))?;
+
+    let mut modes: Vec<ffi::drm_mode_modeinfo> = vec![Default::default(); raw.count_modes as usize];
+    let mut raw_modes: ffi::drm_mode_get_connector = Default::default();
+    raw_modes.connector_id = connector_id;
+    raw_modes.count_modes = raw.count_modes;
+    raw_modes.modes_ptr = modes.as_mut_ptr() as u64;
+    unsafe { ffi::ioctl_mode_getconnector(fd, &mut raw_modes) }
+        .map_err(|e| format!("Could not get the modes of connector {}: {}", connector_id, e))?;
+    modes.truncate(raw_modes.count_modes as usize);
+
+    Ok(ConnectorDetails {
+        type_id: raw.connector_type_id,
+        physical_size: (raw.mm_width, raw.mm_height),
+        preferred: modes.iter().map(|mode| mode.type_ & DRM_MODE_TYPE_PREFERRED != 0).collect(),
+    })
+}
+
+/// The primary plane that can be used with the crtc at `crtc_index` of the
+/// resource handles.
+///
+/// Needs the universal planes client capability.
+pub fn find_primary_plane<D: AsRawFd>(device: &D, crtc_index: usize) -> Result<Properties, String> {
+    find_plane(device, crtc_index, DRM_PLANE_TYPE_PRIMARY)
+        .and_then(|plane| plane.ok_or_else(|| format!("No primary plane for crtc {}", crtc_index)))
+}
+
+/// The cursor plane of the crtc at `crtc_index`, if it has one
+pub fn find_cursor_plane<D: AsRawFd>(device: &D, crtc_index: usize) -> Result<Option<Properties>, String> {
+    find_plane(device, crtc_index, DRM_PLANE_TYPE_CURSOR)
+}
+
+fn find_plane<D: AsRawFd>(device: &D, crtc_index: usize, plane_type: u64) -> Result<Option<Properties>, String> {
+    let fd = device.as_raw_fd();
+
+    let mut resources: ffi::drm_mode_get_plane_res = Default::default();
+    unsafe { ffi::ioctl_mode_getplaneresources(fd, &mut resources) }
+        .map_err(|e| format!("Could not get plane resources: {}", e))?;
+    let mut plane_ids: Vec<u32> = vec![0; resources.count_planes as usize];
+    resources.plane_id_ptr = plane_ids.as_mut_ptr() as u64;
+    unsafe { ffi::ioctl_mode_getplaneresources(fd, &mut resources) }
+        .map_err(|e| format!("Could not get plane resources: {}", e))?;
+    plane_ids.truncate(resources.count_planes as usize);
+
+    for plane_id in plane_ids {
+        let mut plane: ffi::drm_mode_get_plane = Default::default();
+        plane.plane_id = plane_id;
+        if unsafe { ffi::ioctl_mode_getplane(fd, &mut plane) }.is_err() {
+            continue;
+        }
+        if plane.possible_crtcs & (1 << crtc_index) == 0 {
+            continue;
+        }
+
+        let props = Properties::load(device, plane_id, ffi::DRM_MODE_OBJECT_PLANE)?;
+        if props.value("type") == Some(plane_type) {
+            return Ok(Some(props));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Property changes applied together by `commit` (drmModeAtomicReq)
+#[derive(Debug, Default)]
+pub struct AtomicRequest {
+    /// object id -> (property id, value)
+    objects: Vec<(u32, Vec<(u32, u64)>)>,
+}
+
+impl AtomicRequest {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+        }
+    }
+
+    pub fn add_property(&mut self, object_id: u32, property_id: u32, value: u64) {
+        match self.objects.iter().position(|&(id, _)| id == object_id) {
+            Some(index) => self.objects[index].1.push((property_id, value)),
+            None => self.objects.push((object_id, vec![(property_id, value)])),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Look the property up by name, fails if `properties` does not have it
+    pub fn add(&mut self, properties: &Properties, name: &str, value: u64) -> Result<(), String> {
+        let property_id = properties.id(name)
+            .ok_or_else(|| format!("Object {} has no {} property", properties.object_id(), name))?;
+        self.add_property(properties.object_id(), property_id, value);
+        Ok(())
+    }
+
+    /// `user_data` comes back in the page flip event
+    pub fn commit<D: AsRawFd>(&self, device: &D, flags: u32, user_data: u64) -> Result<(), String> {
+        let mut objects: Vec<u32> = Vec::with_capacity(self.objects.len());
+        let mut count_props: Vec<u32> = Vec::with_capacity(self.objects.len());
+        let mut props: Vec<u32> = Vec::new();
+        let mut values: Vec<u64> = Vec::new();
+        for &(object_id, ref properties) in self.objects.iter() {
+            objects.push(object_id);
+            count_props.push(properties.len() as u32);
+            for &(property_id, value) in properties.iter() {
+                props.push(property_id);
+                values.push(value);
+            }
+        }
+
+        let mut raw: ffi::drm_mode_atomic = Default::default();
+        raw.flags = flags;
+        raw.count_objs = objects.len() as u32;
+        raw.objs_ptr = objects.as_ptr() as u64;
+        raw.count_props_ptr = count_props.as_ptr() as u64;
+        raw.props_ptr = props.as_ptr() as u64;
+        raw.prop_values_ptr = values.as_ptr() as u64;
+        raw.user_data = user_data;
+
+        unsafe { ffi::ioctl_mode_atomic(device.as_raw_fd(), &mut raw) }
+            .map(|_| ())
+            .map_err(|e| format!("Atomic commit failed: {}", e))
+    }
+}
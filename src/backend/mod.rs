@@ -1,4 +1,5 @@
 pub mod drm;
+pub mod kms;
 
 use std::error::Error as StdError;
 use std::result::Result as StdResult;
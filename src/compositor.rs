@@ -1,10 +1,100 @@
+use std::cell::RefCell;
 use std::mem;
+use std::rc::{Rc, Weak};
 
 use libc;
 use libc::clockid_t;
 
+use dmabuf::Dmabuf;
+use region::{Rectangle, Region};
+use surface::Surface;
+
+/// The pointer image, a surface given the cursor role by `wl_pointer.set_cursor`
+pub struct Cursor {
+    /// pointer position in the global space, the hotspot goes there
+    pub x: i32,
+    pub y: i32,
+    pub surface: Option<Rc<RefCell<Surface>>>,
+    /// in surface coordinates
+    pub hotspot_x: i32,
+    pub hotspot_y: i32,
+    /// the image changed since the backend last uploaded it
+    pub image_changed: bool,
+    /// where the cursor was or is now, for the outputs compositing it,
+    /// taken by the backend on repaint
+    pub damage: Region,
+}
+
+impl Cursor {
+    fn new() -> Self {
+        Cursor {
+            x: 0,
+            y: 0,
+            surface: None,
+            hotspot_x: 0,
+            hotspot_y: 0,
+            image_changed: false,
+            damage: Region::new(),
+        }
+    }
+
+    /// The area of the global space the image covers, empty without one
+    pub fn geometry(&self) -> Rectangle {
+        match self.surface {
+            Some(ref surface) if surface.borrow().buffer.is_some() => surface.borrow().geometry(),
+            _ => Rectangle::new(self.x, self.y, 0, 0),
+        }
+    }
+
+    pub fn is_surface(&self, surface: &Rc<RefCell<Surface>>) -> bool {
+        self.surface.as_ref().map_or(false, |cursor| Rc::ptr_eq(cursor, surface))
+    }
+
+    /// Show `surface` with its hotspot at the pointer position, hide the
+    /// cursor if `None`
+    pub fn set(&mut self, surface: Option<Rc<RefCell<Surface>>>, hotspot_x: i32, hotspot_y: i32) {
+        let geometry = self.geometry();
+        self.damage.union_rect(geometry);
+
+        self.surface = surface;
+        self.hotspot_x = hotspot_x;
+        self.hotspot_y = hotspot_y;
+        self.place();
+        self.image_changed = true;
+    }
+
+    /// The client attached a new image to the cursor surface, moved by the
+    /// attach offset
+    pub fn committed(&mut self, old_geometry: Rectangle, dx: i32, dy: i32) {
+        self.damage.union_rect(old_geometry);
+        self.hotspot_x -= dx;
+        self.hotspot_y -= dy;
+        self.place();
+        self.image_changed = true;
+    }
+
+    /// Put the surface where the hotspot lands on the pointer
+    fn place(&mut self) {
+        if let Some(ref surface) = self.surface {
+            let mut surface = surface.borrow_mut();
+            surface.x = self.x - self.hotspot_x;
+            surface.y = self.y - self.hotspot_y;
+        }
+        let geometry = self.geometry();
+        self.damage.union_rect(geometry);
+    }
+}
+
 pub struct Compositor {
     presentation_clock: clockid_t,
+    /// mapped surfaces, bottom to top
+    pub surfaces: Vec<Rc<RefCell<Surface>>>,
+    /// areas of the global space to repaint that no surface damage covers
+    /// (unmapped surfaces), taken by the backend on repaint
+    pub damage: Region,
+    pub cursor: Cursor,
+    /// client dmabufs, kept to re-import them when the renderer changes
+    dmabufs: Vec<Weak<Dmabuf>>,
 }
 
 impl Compositor {
@@ -19,6 +109,55 @@ impl Compositor {
 
         Ok(Compositor {
             presentation_clock: clock_id,
+            surfaces: Vec::new(),
+            damage: Region::new(),
+            cursor: Cursor::new(),
+            dmabufs: Vec::new(),
         })
     }
+
+    /// The clock of the frame and presentation timestamps
+    pub fn presentation_clock(&self) -> clockid_t {
+        self.presentation_clock
+    }
+
+    /// weston_compositor_read_presentation_clock
+    pub fn read_presentation_clock(&self) -> libc::timespec {
+        let mut time: libc::timespec = unsafe {mem::zeroed()};
+        unsafe {libc::clock_gettime(self.presentation_clock, &mut time as *mut libc::timespec)};
+        time
+    }
+
+    /// Timestamp for frame callbacks, in milliseconds
+    pub fn frame_time(&self) -> u32 {
+        let time = self.read_presentation_clock();
+        (time.tv_sec as u64 * 1000 + time.tv_nsec as u64 / 1_000_000) as u32
+    }
+
+    pub fn add_surface(&mut self, surface: &Rc<RefCell<Surface>>) {
+        if !self.surfaces.iter().any(|s| Rc::ptr_eq(s, surface)) {
+            self.surfaces.push(surface.clone());
+        }
+    }
+
+    /// Stop showing `surface`, the area it covered gets repainted
+    pub fn remove_surface(&mut self, surface: &Rc<RefCell<Surface>>) {
+        if let Some(index) = self.surfaces.iter().position(|s| Rc::ptr_eq(s, surface)) {
+            let surface = self.surfaces.remove(index);
+            let geometry = surface.borrow().geometry();
+            self.damage.union_rect(geometry);
+        }
+    }
+
+    pub fn add_dmabuf(&mut self, dmabuf: &Rc<Dmabuf>) {
+        self.dmabufs.retain(|dmabuf| dmabuf.upgrade().is_some());
+        self.dmabufs.push(Rc::downgrade(dmabuf));
+    }
+
+    /// The dmabufs still alive
+    pub fn dmabufs(&self) -> Vec<Rc<Dmabuf>> {
+        self.dmabufs.iter()
+            .filter_map(|dmabuf| dmabuf.upgrade())
+            .collect()
+    }
 }
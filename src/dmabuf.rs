@@ -0,0 +1,65 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+pub const MAX_DMABUF_PLANES: usize = 4;
+
+/// DRM_FORMAT_MOD_INVALID, the client did not specify a modifier
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+pub const DRM_FORMAT_ARGB8888: u32 = 0x3432_5241; // AR24
+pub const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258; // XR24
+
+#[derive(Debug)]
+pub struct DmabufPlane {
+    pub fd: RawFd,
+    pub offset: u32,
+    pub stride: u32,
+    pub modifier: u64,
+}
+
+/// A client buffer made of dmabuf planes (linux_dmabuf_buffer in weston).
+///
+/// It owns the plane file descriptors, they are closed on drop.
+pub struct Dmabuf {
+    pub width: i32,
+    pub height: i32,
+    /// DRM fourcc format
+    pub format: u32,
+    pub flags: u32,
+    pub planes: Vec<DmabufPlane>,
+    /// set by `Renderer::import_dmabuf`
+    pub renderer_state: RefCell<Option<Box<dyn Any>>>,
+}
+
+impl Dmabuf {
+    pub fn new(width: i32, height: i32, format: u32, flags: u32, planes: Vec<DmabufPlane>) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            flags,
+            planes,
+            renderer_state: RefCell::new(None),
+        }
+    }
+
+    pub fn modifier(&self) -> u64 {
+        self.planes.first()
+            .map(|plane| plane.modifier)
+            .unwrap_or(DRM_FORMAT_MOD_INVALID)
+    }
+}
+
+impl Drop for Dmabuf {
+    fn drop(&mut self) {
+        // renderer objects may reference the fds, release them first
+        self.renderer_state.borrow_mut().take();
+        for plane in self.planes.iter() {
+            unsafe { libc::close(plane.fd) };
+        }
+    }
+}
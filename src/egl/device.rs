@@ -26,7 +26,7 @@ impl EGLDevice {
         let extensions = Extensions::query(None)?;
         
         //TODO EGL_EXT_device_query and EGL_EXT_device_enumeration not supported on nvidia?
-        if !extensions.contains("EGL_EXT_device_base") { return Err(EGLError::from_str("EGL_EXT_device_base not supported")); }
+        if !extensions.contains("EGL_EXT_device_base") { return Err(EGLError::MissingExtension("EGL_EXT_device_base")); }
         // if !extensions.contains("EGL_EXT_device_query") { return Err(EGLError::from_str("EGL_EXT_device_query not supported")); }
         // if !extensions.contains("EGL_EXT_device_enumeration") { return Err(EGLError::from_str("EGL_EXT_device_enumeration not supported")); }
 
@@ -52,7 +52,7 @@ impl EGLDevice {
             .any(|ext| ext.eq("EGL_EXT_device_drm"));
 
         if !found_EGL_EXT_device_drm {
-            return Err(EGLError::from_str("EGL_EXT_device_drm not supported"));
+            return Err(EGLError::MissingExtension("EGL_EXT_device_drm"));
         }
 
         let drm_device_filename = unsafe {
@@ -76,7 +76,7 @@ pub fn get_egl_devices() ->Result<Vec<EGLDevice>, EGLError> {
     let extensions = Extensions::query(None)?;
 
     //TODO EGL_EXT_device_query and EGL_EXT_device_enumeration not supported on nvidia?
-    if !extensions.contains("EGL_EXT_device_base") { return Err(EGLError::from_str("EGL_EXT_device_base not supported")); }
+    if !extensions.contains("EGL_EXT_device_base") { return Err(EGLError::MissingExtension("EGL_EXT_device_base")); }
     // if !extensions.contains("EGL_EXT_device_query") { return Err(EGLError::from_str("EGL_EXT_device_query not supported")); }
     // if !extensions.contains("EGL_EXT_device_enumeration") { return Err(EGLError::from_str("EGL_EXT_device_enumeration not supported")); }
 
@@ -90,9 +90,9 @@ pub fn get_egl_devices() ->Result<Vec<EGLDevice>, EGLError> {
     }
 
     if num_devices < 1 {
-        return Err(EGLError::from_str("No device found!"));
+        return Err(EGLError::NoDevice { path: None });
     } else {
-        println!("egl devices found: {}", num_devices);
+        log_debug!("egl", "egl devices found: {}", num_devices);
     }
 
     let mut devices: Vec<EGLDeviceEXT> = Vec::with_capacity(num_devices as usize);
@@ -4,6 +4,7 @@ use egl::EGLError;
 use egl::ffi::types::{EGLint, EGLenum, EGLConfig};
 use egl::device::EGLDevice;
 use backend::drm::DRMDevice;
+use std::os::unix::io::AsRawFd;
 use egl::extensions::Extensions;
 
 #[derive(Debug)]
@@ -30,7 +31,7 @@ impl EGLDisplay {
 
     pub fn from_platform(platform: EGLenum, drm_device: &DRMDevice, egl_device: &EGLDevice) -> Result<Self, EGLError> {
         let device_platform_attribs: Vec<EGLint> = vec![
-			egl::ffi::DRM_MASTER_FD_EXT as EGLint, drm_device.rawfd(),
+			egl::ffi::DRM_MASTER_FD_EXT as EGLint, drm_device.as_raw_fd(),
 			egl::ffi::NONE as EGLint
         ];
 
@@ -110,7 +111,7 @@ impl EGLDisplay {
             let config = visual_ids.iter()
                 .filter_map(|visual_id| {
                     visual_id_counter += 1;
-                    println!("AAAAA visual_id_counter={}", visual_id_counter);
+                    log_debug!("egl", "trying EGL visual {}", visual_id_counter);
                     self.match_config_to_visual(*visual_id, &configs)
                 })
                 .nth(0)
@@ -118,7 +119,7 @@ impl EGLDisplay {
                 .ok_or_else(|| EGLError::from_str("could not find egl configuration"));
 
             if config.is_ok() && visual_id_counter > 1 {
-                println!("Unable to use first choice EGL config with id 0x{:x}, succeeded with alternate id 0x{:x}.", visual_ids[0], visual_ids[visual_id_counter]);
+                log_warn!("egl", "Unable to use first choice EGL config with id 0x{:x}, succeeded with alternate id 0x{:x}.", visual_ids[0], visual_ids[visual_id_counter]);
             }
 
             config
@@ -150,7 +151,7 @@ impl Drop for EGLDisplay {
     fn drop(&mut self) {
         let r = unsafe {egl::ffi::Terminate(self.display_raw)} == egl::ffi::TRUE;
         if !r {
-            println!("FAILED TO TERMINATE DISPLAY \"{:?}\"", self.display_raw);
+            log_error!("egl", "FAILED TO TERMINATE DISPLAY \"{:?}\"", self.display_raw);
         }
     }
 }
\ No newline at end of file
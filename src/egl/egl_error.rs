@@ -1,41 +1,78 @@
+use std::error::Error as StdError;
 use std::ffi::CStr;
 use std::fmt;
+use std::path::PathBuf;
 use egl;
 use egl::ffi::types::{EGLint, EGLenum};
 
 #[derive(Debug)]
-pub struct EGLError {
-    message: String,
-    code: EGLint,
+pub enum EGLError {
+    /// An EGL call failed, `code` is what eglGetError said right after
+    Call {
+        message: String,
+        code: EGLint,
+    },
+    /// libEGL or one of its functions could not be loaded
+    Load {
+        symbol: String,
+        message: String,
+    },
+    /// The implementation lacks an extension the renderer needs
+    MissingExtension(&'static str),
+    /// No EGLDevice (for the DRM device at `path`)
+    NoDevice {
+        path: Option<PathBuf>,
+    },
+    /// A shader did not compile or link
+    Shader {
+        message: String,
+        log: String,
+    },
 }
 
 impl EGLError {
     pub fn from_string(message: String) -> Self {
-        Self {
+        EGLError::Call {
             message,
             code: get_error(),
         }
     }
 
     pub fn from_str(message: &str) -> Self {
-        Self {
-            message: message.to_string(),
-            code: get_error(),
-        }
+        Self::from_string(message.to_string())
     }
 
     pub fn from_cstr(message: &CStr) -> Self {
-        //TODO make this better
-        Self {
-            message: format!("{:?}", message),
-            code: get_error(),
+        Self::from_string(message.to_string_lossy().into_owned())
+    }
+
+    /// The EGL error code of a failed call
+    pub fn code(&self) -> Option<EGLint> {
+        match *self {
+            EGLError::Call { code, .. } => Some(code),
+            _ => None,
         }
     }
 }
 
+impl StdError for EGLError {}
+
 impl fmt::Display for EGLError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "EGLError {}: {}", self.message, egl_error_string(self.code))
+        match *self {
+            EGLError::Call { ref message, code } =>
+                write!(f, "EGLError {}: {}", message, egl_error_string(code)),
+            EGLError::Load { ref symbol, ref message } =>
+                write!(f, "EGLError could not load {}: {}", symbol, message),
+            EGLError::MissingExtension(extension) =>
+                write!(f, "EGLError {} not supported", extension),
+            EGLError::NoDevice { path: Some(ref path) } =>
+                write!(f, "EGLError no EGLDevice for {}", path.as_os_str().to_string_lossy()),
+            EGLError::NoDevice { path: None } =>
+                write!(f, "EGLError no EGLDevice found"),
+            EGLError::Shader { ref message, ref log } =>
+                write!(f, "EGLError {}: {}", message, log),
+        }
     }
 }
 
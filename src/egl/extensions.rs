@@ -8,7 +8,7 @@ pub struct Extensions(HashSet<String>);
 
 impl Extensions {
     pub fn query<'a>(display: Option<&EGLDisplay>) -> Result<Self, EGLError> {
-        println!("EXTENSIONS FOR DISPLAY {:?}", display);
+        log_debug!("egl", "EXTENSIONS FOR DISPLAY {:?}", display);
         let display = display.map(|d| d.raw_ref().clone())
             .unwrap_or(ffi::NO_DISPLAY);
 
@@ -16,6 +16,48 @@ define_enum!(PLATFORM_DEVICE_EXT, 0x313F);
 define_enum!(PLATFORM_GBM_KHR,    0x31D7);
 define_enum!(DRM_DEVICE_FILE_EXT, 0x3233);
 define_enum!(DRM_MASTER_FD_EXT,   0x333C);
+define_enum!(DRM_CRTC_EXT,        0x3234);
+define_enum!(DRM_PLANE_EXT,       0x3235);
+define_enum!(CONSUMER_AUTO_ACQUIRE_EXT, 0x332B);
+define_enum!(DRM_FLIP_EVENT_DATA_NV,    0x333E);
+define_enum!(RESOURCE_BUSY_EXT,         0x3353);
+define_enum!(STREAM_STATE_KHR,          0x3214);
+define_enum!(STREAM_STATE_NEW_FRAME_AVAILABLE_KHR, 0x3217);
+define_enum!(WAYLAND_EGLSTREAM_WL,      0x334B);
+define_enum!(WAYLAND_BUFFER_WL,         0x31D5);
+define_enum!(WAYLAND_PLANE_WL,          0x31D6);
+define_enum!(TEXTURE_EXTERNAL_WL,       0x31DA);
+define_enum!(WAYLAND_Y_INVERTED_WL,     0x31DB);
+define_enum!(BUFFER_AGE_EXT,            0x313D);
+
+// EGL_EXT_image_dma_buf_import(_modifiers)
+define_enum!(LINUX_DMA_BUF_EXT,         0x3270);
+define_enum!(LINUX_DRM_FOURCC_EXT,      0x3271);
+define_enum!(DMA_BUF_PLANE0_FD_EXT,     0x3272);
+define_enum!(DMA_BUF_PLANE0_OFFSET_EXT, 0x3273);
+define_enum!(DMA_BUF_PLANE0_PITCH_EXT,  0x3274);
+define_enum!(DMA_BUF_PLANE1_FD_EXT,     0x3275);
+define_enum!(DMA_BUF_PLANE1_OFFSET_EXT, 0x3276);
+define_enum!(DMA_BUF_PLANE1_PITCH_EXT,  0x3277);
+define_enum!(DMA_BUF_PLANE2_FD_EXT,     0x3278);
+define_enum!(DMA_BUF_PLANE2_OFFSET_EXT, 0x3279);
+define_enum!(DMA_BUF_PLANE2_PITCH_EXT,  0x327A);
+define_enum!(DMA_BUF_PLANE3_FD_EXT,     0x3440);
+define_enum!(DMA_BUF_PLANE3_OFFSET_EXT, 0x3441);
+define_enum!(DMA_BUF_PLANE3_PITCH_EXT,  0x3442);
+define_enum!(DMA_BUF_PLANE0_MODIFIER_LO_EXT, 0x3443);
+define_enum!(DMA_BUF_PLANE0_MODIFIER_HI_EXT, 0x3444);
+define_enum!(DMA_BUF_PLANE1_MODIFIER_LO_EXT, 0x3445);
+define_enum!(DMA_BUF_PLANE1_MODIFIER_HI_EXT, 0x3446);
+define_enum!(DMA_BUF_PLANE2_MODIFIER_LO_EXT, 0x3447);
+define_enum!(DMA_BUF_PLANE2_MODIFIER_HI_EXT, 0x3448);
+define_enum!(DMA_BUF_PLANE3_MODIFIER_LO_EXT, 0x3449);
+define_enum!(DMA_BUF_PLANE3_MODIFIER_HI_EXT, 0x344A);
+
+// EGL_ANDROID_native_fence_sync
+define_enum!(SYNC_NATIVE_FENCE_ANDROID,    0x3144);
+define_enum!(SYNC_NATIVE_FENCE_FD_ANDROID, 0x3145);
+#[allow(dead_code)] pub const NO_NATIVE_FENCE_FD_ANDROID: types::EGLint = -1;
 
 
 macro_rules! define_ext_funs {
@@ -73,7 +115,10 @@ unsafe fn load_with_GetProcAddress(function_name: &str) -> Result<*const __gl_im
     let fun_ptr = super::GetProcAddress(function_name_cstring.as_ptr()) as *const __gl_imports::raw::c_void;
 
     if fun_ptr.is_null() {
-        Err(EGLError::from_string(format!("could not get address of {}", function_name)))
+        Err(EGLError::Load {
+            symbol: function_name.to_string(),
+            message: "eglGetProcAddress returned NULL".to_string(),
+        })
     } else {
         Ok(fun_ptr)
     }
@@ -197,6 +242,16 @@ define_ext_funs!{
         dpy: types::EGLDisplay,
         stream: types::EGLStreamKHR
     ) -> types::EGLBoolean
+
+    StreamConsumerAcquireKHR(
+        dpy: types::EGLDisplay,
+        stream: types::EGLStreamKHR
+    ) -> types::EGLBoolean
+
+    CreateStreamFromFileDescriptorKHR(
+        dpy: types::EGLDisplay,
+        file_descriptor: types::EGLNativeFileDescriptorKHR
+    ) -> types::EGLStreamKHR
     
     CreatePlatformWindowSurfaceEXT(
         dpy: types::EGLDisplay,
@@ -250,5 +305,11 @@ define_ext_funs!{
         dpy: types::EGLDisplay,
         sync: types::EGLSyncKHR
     ) -> types::EGLint
+
+    WaitSyncKHR(
+        dpy: types::EGLDisplay,
+        sync: types::EGLSyncKHR,
+        flags: types::EGLint
+    ) -> types::EGLint
     
 }
\ No newline at end of file
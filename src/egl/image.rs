@@ -62,7 +62,7 @@ impl<'a> Drop for Image<'a> {
         )};
 
         if r != egl::ffi::TRUE {
-            println!("FAILED TO DESTROY IMAGE {:?}", self)
+            log_error!("egl", "FAILED TO DESTROY IMAGE {:?}", self)
         }
     }
 }
\ No newline at end of file
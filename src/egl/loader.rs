@@ -9,8 +9,9 @@ static mut EGL_LOADER: Option<EglLoader> = None;
 pub fn load_EGL(lib_path: &str) -> Result<(), EGLError> {
     let egl_loader = unsafe { &mut EGL_LOADER };
     if egl_loader.is_none() {
-        let _egl_loader = EglLoader::new(lib_path).map_err(|e| {
-            EGLError::from_string(format!("Impossible to load EGL Library: {:?}", e))
+        let _egl_loader = EglLoader::new(lib_path).map_err(|e| EGLError::Load {
+            symbol: lib_path.to_string(),
+            message: e.to_string_lossy().into_owned(),
         })?;
 
         ::egl::ffi::load_with(|s| _egl_loader.load_fn(s) as *const os::raw::c_void);
@@ -6,6 +6,8 @@ mod egl_error;
 mod display;
 mod image;
 mod extensions;
+mod stream;
+mod shader;
 
 pub use self::egl_error::EGLError;
 
@@ -3,21 +3,59 @@ use egl::display::EGLDisplay;
 use egl::EGLError;
 use egl::ffi::types::*;
 use egl::device::{EGLDevice, get_egl_devices};
+use egl::stream::{OutputStream, ClientStream};
+use egl::shader::{self, Shader, ShaderKind};
 use backend::drm::DRMDevice;
-// use renderer::Renderer;
-use std::path::Path;
+use dmabuf::{self, Dmabuf};
+use gl;
+use gl::types::{GLenum, GLfloat, GLuint};
+use output::{Output, OutputSpace};
+use region::{Rectangle, Region};
+use renderer::{Renderer, RendererError, Scanout};
+use surface::{Buffer, ShmBuffer, Surface};
+use std::collections::VecDeque;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use libc;
+use wayland_server::protocol::{wl_output, wl_shm};
+use wayland_server::sys::server::wl_resource;
 // use libc;
 // use renderer;
 // use wayland::list::List as WLlist;
 use wayland::display::Display as WaylandDisplay;
 // use wayland::signal::Signal as WaylandSignal;
 
+// glEGLImageTargetTexture2DOES, GL_OES_EGL_image
+type ImageTargetTexture2DOES = extern "system" fn(GLenum, *const c_void);
+
+// missing from the GL 4.5 core bindings
+const TEXTURE_EXTERNAL_OES: GLenum = 0x8D65;
+
+/// frames of damage kept for EGL_EXT_buffer_age, older back buffers are repainted whole
+const BUFFER_DAMAGE_COUNT: usize = 2;
+
+/// Entry point loaded for swapping with damage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SwapWithDamage {
+    Ext,
+    Khr,
+}
+
 #[derive(Debug)]
 pub struct EGLRenderer {
     egl_device: EGLDevice,
     display: EGLDisplay,
     egl_config: EGLConfig,
+    context: EGLContext,
     use_eglstream: bool,
+    texture_shader_rgba: Option<Shader>,
+    texture_shader_rgbx: Option<Shader>,
+    texture_shader_egl_external: Option<Shader>,
+    solid_shader: Option<Shader>,
+    image_target_texture_2d: Option<ImageTargetTexture2DOES>,
 //    opaque_attribs: egl::EGLint,
 //    alpha_attribs: egl::EGLint,
 //    opaque_stream_attribs: egl::EGLint,
@@ -28,11 +66,13 @@ pub struct EGLRenderer {
     has_egl_buffer_age: bool,
     has_egl_ext_swap_buffers_with_damage: bool,
     has_egl_khr_swap_buffers_with_damage: bool,
+    swap_with_damage: Option<SwapWithDamage>,
     has_configless_context: bool,
     has_surfaceless_context: bool,
     has_dmabuf_import: bool,
     has_dmabuf_import_modifiers: bool,
     has_native_fence_sync: bool,
+    has_wait_sync: bool,
     has_egl_output_base: bool,
     has_egl_output_drm: bool,
     has_egl_output_drm_flip_event: bool,
@@ -50,7 +90,6 @@ impl EGLRenderer {
     fn new(egl_device: EGLDevice, drm_device: &DRMDevice) -> Result<Box<EGLRenderer>, EGLError> {
         let (display, egl_config) = Self::display_create(drm_device, &egl_device)?;
 
-        // ec->capabilities |= WESTON_CAP_ROTATION_ANY;
         // ec->capabilities |= WESTON_CAP_CAPTURE_YFLIP;
         // ec->capabilities |= WESTON_CAP_VIEW_CLIP_MASK;
         
@@ -58,18 +97,26 @@ impl EGLRenderer {
             egl_device,
             display,
             egl_config,
+            context: egl::ffi::NO_CONTEXT,
             use_eglstream: true,
+            texture_shader_rgba: None,
+            texture_shader_rgbx: None,
+            texture_shader_egl_external: None,
+            solid_shader: None,
+            image_target_texture_2d: None,
             // support stuff, will be overwritten by setup_egl_extensions
             has_context_priority: false,
             has_bind_display: false,
             has_egl_buffer_age: false,
             has_egl_ext_swap_buffers_with_damage: false,
             has_egl_khr_swap_buffers_with_damage: false,
+            swap_with_damage: None,
             has_configless_context: false,
             has_surfaceless_context: false,
             has_dmabuf_import: false,
             has_dmabuf_import_modifiers: false,
             has_native_fence_sync: false,
+            has_wait_sync: false,
             has_egl_output_base: false,
             has_egl_output_drm: false,
             has_egl_output_drm_flip_event: false,
@@ -85,7 +132,7 @@ impl EGLRenderer {
 
         renderer.setup_egl_extensions()?;
 
-        println!("EGLRenderer: {:?}", renderer);
+        log_debug!("egl", "EGLRenderer: {:?}", renderer);
 
         // check support for extensions:
         if !renderer.has_egl_output_base
@@ -118,22 +165,18 @@ impl EGLRenderer {
         if !renderer.has_egl_stream_consumer_gltexture
         || !renderer.has_egl_wayland_eglstream
         {
-            println!(
-                "WARNING! following required extensions for EGL client frame presentation through EGLDevice not supported:
-                has_egl_stream_consumer_gltexture: {}
-                has_egl_wayland_eglstream: {}",
+            log_warn!("egl",
+                "following required extensions for EGL client frame presentation through EGLDevice not supported: \
+                has_egl_stream_consumer_gltexture: {}, has_egl_wayland_eglstream: {}",
                 renderer.has_egl_stream_consumer_gltexture,
                 renderer.has_egl_wayland_eglstream,
             )
         }
         
-        if !renderer.has_egl_output_drm_flip_event
-        {
-            println!(
-                "WARNING! EGL page flip event notification not supported:
-                has_egl_output_drm_flip_event: {}",
-                renderer.has_egl_output_drm_flip_event,
-            )
+        // without it no DRM event ends the flips, the outputs would wait for
+        // them forever
+        if !renderer.has_egl_output_drm_flip_event {
+            return Err(EGLError::from_str("EGL_NV_output_drm_flip_event is mandatory for the page flip events"));
         }
 
         // let dmabuf_images = WLlist::new();
@@ -147,7 +190,7 @@ impl EGLRenderer {
         // }
 
         if renderer.has_surfaceless_context {
-            println!("EGL_KHR_surfaceless_context available\n");
+            log_info!("egl", "EGL_KHR_surfaceless_context available");
             // gr->dummy_surface = EGL_NO_SURFACE;
         } else {
             return Err(EGLError::from_str("renderer.has_surfaceless_context is mandatory for the moment"))
@@ -179,13 +222,14 @@ impl EGLRenderer {
         //                             gr->dummy_surface);
         //     goto fail_with_error;
         // }
+        renderer.setup()?;
 
         Ok(renderer)
     }
 
     pub fn from_drm_device_file(drm_device: &DRMDevice) -> Result<Box<EGLRenderer>, EGLError> {
         load_EGL()?;
-        println!("Creating EGL Renderer at {:?}", drm_device.dev_path());
+        log_info!("egl", "Creating EGL Renderer at {:?}", drm_device.dev_path());
         
         let egl_device = find_egldevice(drm_device.dev_path())?;
         Self::new(egl_device, drm_device)
@@ -198,7 +242,7 @@ impl EGLRenderer {
             let egl_device = devices.into_iter().nth(0).unwrap();
             Self::new(egl_device, drm_device)
         } else {
-            Err(EGLError::from_str("No EGLDevice found"))
+            Err(EGLError::NoDevice { path: None })
         }
     }
 
@@ -217,7 +261,7 @@ impl EGLRenderer {
             .or_else(|_| EGLDisplay::new(egl_device))?;
 
         let (egl_major, egl_minor) = display.initialize()?;
-        println!("Initialized Display with EGL {}.{}", egl_major, egl_minor);
+        log_info!("egl", "Initialized Display with EGL {}.{}", egl_major, egl_minor);
 
         // egl_choose_config(gr, config_attribs, visual_id, n_ids, &gr->egl_config)
         //weston_log("failed to choose EGL config\n");
@@ -247,6 +291,8 @@ impl EGLRenderer {
         egl::ffi::load_StreamConsumerAcquireAttribNV()?;
         // #endif
         egl::ffi::load_StreamConsumerGLTextureExternalKHR()?;
+        egl::ffi::load_StreamConsumerAcquireKHR()?;
+        egl::ffi::load_CreateStreamFromFileDescriptorKHR()?;
 
         let extensions = self.display.extensions()?;
 
@@ -261,7 +307,7 @@ impl EGLRenderer {
 
         self.has_egl_buffer_age = extensions.contains("EGL_EXT_buffer_age");
         if !self.has_egl_buffer_age {
-            println!("warning: EGL_EXT_buffer_age not supported. Performance could be affected");
+            log_warn!("egl", "EGL_EXT_buffer_age not supported. Performance could be affected");
         }
 
         self.has_egl_ext_swap_buffers_with_damage = extensions.contains("EGL_EXT_swap_buffers_with_damage");
@@ -269,12 +315,13 @@ impl EGLRenderer {
 
         if self.has_egl_ext_swap_buffers_with_damage {
             egl::ffi::load_SwapBuffersWithDamageEXT()?;
+            self.swap_with_damage = Some(SwapWithDamage::Ext);
         } else if self.has_egl_khr_swap_buffers_with_damage {
             egl::ffi::load_SwapBuffersWithDamageKHR()?;
+            self.swap_with_damage = Some(SwapWithDamage::Khr);
         } else {
-            println!("warning: neither EGL_EXT_swap_buffers_with_damage or EGL_KHR_swap_buffers_with_damage is supported. Performance could be affected.");
+            log_warn!("egl", "neither EGL_EXT_swap_buffers_with_damage or EGL_KHR_swap_buffers_with_damage is supported. Performance could be affected.");
         }
-        //TODO memorize which one is loaded
    
         self.has_configless_context = extensions.contains("EGL_KHR_no_config_context") 
             || extensions.contains("EGL_MESA_configless_context");
@@ -297,7 +344,14 @@ impl EGLRenderer {
                 egl::ffi::load_DestroySyncKHR()?;
                 egl::ffi::load_DupNativeFenceFDANDROID()?;
         } else {
-            println!("warning: Disabling render GPU timeline due to missing EGL_KHR_fence_sync or EGL_ANDROID_native_fence_sync extension");
+            log_warn!("egl", "Disabling render GPU timeline due to missing EGL_KHR_fence_sync or EGL_ANDROID_native_fence_sync extension");
+        }
+
+        self.has_wait_sync = extensions.contains("EGL_KHR_wait_sync");
+        if self.has_wait_sync {
+            egl::ffi::load_WaitSyncKHR()?;
+        } else {
+            log_warn!("egl", "Disabling explicit synchronization due to missing EGL_KHR_wait_sync extension");
         }
 
         self.has_egl_output_base = extensions.contains("EGL_EXT_output_base");
@@ -326,25 +380,96 @@ impl EGLRenderer {
         if self.has_egl_platform_base {
             egl::ffi::load_CreatePlatformWindowSurfaceEXT()?;
         } else {
-            println!("warning: EGL_EXT_platform_base not supported.");
+            log_warn!("egl", "EGL_EXT_platform_base not supported.");
         }
 
         Ok(self.has_egl_platform_base)
     }
 
-    pub fn display(&self) /*-> egl::EGLDisplay*/ {
+    // gl_renderer_setup
+    fn setup(&mut self) -> Result<(), EGLError> {
+        let r = unsafe { egl::ffi::BindAPI(egl::ffi::OPENGL_ES_API) };
+        if r != egl::ffi::TRUE {
+            return Err(EGLError::from_str("failed to bind EGL_OPENGL_ES_API"));
+        }
 
-    }
+        let context_attribs: Vec<EGLint> = vec![
+            egl::ffi::CONTEXT_CLIENT_VERSION as EGLint, 2,
+            egl::ffi::NONE as EGLint,
+        ];
 
-    pub fn output_window_create() {
+        let config = if self.has_configless_context {
+            0 as EGLConfig // EGL_NO_CONFIG_KHR
+        } else {
+            self.egl_config
+        };
+
+        self.context = unsafe { egl::ffi::CreateContext(
+            self.display.raw_ref().clone(),
+            config,
+            egl::ffi::NO_CONTEXT,
+            context_attribs.as_ptr(),
+        )};
+        if self.context == egl::ffi::NO_CONTEXT {
+            return Err(EGLError::from_str("failed to create context"));
+        }
 
+        // surfaceless, the outputs will bind their own surface when repainting
+        self.make_current(egl::ffi::NO_SURFACE)?;
+
+        gl::load_with(|name| {
+            let name = ::std::ffi::CString::new(name).unwrap();
+            unsafe { egl::ffi::GetProcAddress(name.as_ptr()) as *const c_void }
+        });
+
+        let image_target_texture_2d = unsafe {
+            egl::ffi::GetProcAddress(b"glEGLImageTargetTexture2DOES\0".as_ptr() as *const _)
+        };
+        self.image_target_texture_2d = if image_target_texture_2d.is_null() {
+            log_warn!("egl", "glEGLImageTargetTexture2DOES not available, EGL and dmabuf client buffers disabled");
+            None
+        } else {
+            Some(unsafe { mem::transmute(image_target_texture_2d) })
+        };
+
+        self.texture_shader_rgba = Some(Shader::new(ShaderKind::Rgba)?);
+        self.texture_shader_rgbx = Some(Shader::new(ShaderKind::Rgbx)?);
+        self.texture_shader_egl_external = Shader::new(ShaderKind::EglExternal)
+            .map_err(|e| log_warn!("egl", "no external texture shader: {}", e))
+            .ok();
+        self.solid_shader = Some(Shader::new(ShaderKind::Solid)?);
+
+        Ok(())
     }
 
-    pub fn output_destroy() {
+    fn shader(&self, kind: ShaderKind) -> Option<&Shader> {
+        match kind {
+            ShaderKind::Rgba => self.texture_shader_rgba.as_ref(),
+            ShaderKind::Rgbx => self.texture_shader_rgbx.as_ref(),
+            ShaderKind::EglExternal => self.texture_shader_egl_external.as_ref(),
+            ShaderKind::Solid => self.solid_shader.as_ref(),
+        }
+    }
 
+    fn make_current(&self, surface: EGLSurface) -> Result<(), EGLError> {
+        let r = unsafe { egl::ffi::MakeCurrent(
+            self.display.raw_ref().clone(),
+            surface,
+            surface,
+            self.context,
+        )};
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_str("Failed to make context current"))
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn output_surface() /*-> egl::EGLSurface*/ {
+    pub fn display(&self) -> &EGLDisplay {
+        &self.display
+    }
+
+    pub fn output_window_create() {
 
     }
 
@@ -353,7 +478,7 @@ impl EGLRenderer {
     }
 
     pub fn print_egl_error_state() {
-
+        log_error!("egl", "{}", egl::egl_error::egl_error_state());
     }
 
     pub fn get_devices() {
@@ -364,15 +489,149 @@ impl EGLRenderer {
 
     }
 
-    pub fn output_stream_flip() {
+    /// Create an EGLStream for the given DRM crtc.
+    ///
+    /// The stream consumer is the EGLOutputLayer of the crtc, the producer is a
+    /// `width`x`height` surface the output will be rendered into.
+    pub fn output_stream_create(&self, crtc_id: u32, width: EGLint, height: EGLint) -> Result<OutputStream, EGLError> {
+        if !self.use_eglstream {
+            return Err(EGLError::from_str("EGLRenderer not using EGLStreams"));
+        }
+
+        let layer_attribs: Vec<EGLAttrib> = vec![
+            egl::ffi::DRM_CRTC_EXT as EGLAttrib, crtc_id as EGLAttrib,
+            egl::ffi::NONE as EGLAttrib,
+        ];
+
+        let layers = self.get_output_layers(&layer_attribs, 1)?;
+        let layer = match layers.into_iter().nth(0) {
+            Some(layer) => layer,
+            None => return Err(EGLError::from_string(format!("Unable to find output layers for crtc {}", crtc_id))),
+        };
+
+        // we acquire frames manually, to pass the page flip data along
+        let stream_attribs: Vec<EGLAttrib> = vec![
+            egl::ffi::CONSUMER_AUTO_ACQUIRE_EXT as EGLAttrib, egl::ffi::FALSE as EGLAttrib,
+            egl::ffi::NONE as EGLAttrib,
+        ];
+        let stream = self.create_stream_attrib(&stream_attribs)?;
+
+        if let Err(e) = self.stream_consumer_output(stream, layer) {
+            self.destroy_stream(stream);
+            return Err(e);
+        }
 
+        let producer_attribs: Vec<EGLint> = vec![
+            egl::ffi::WIDTH as EGLint, width,
+            egl::ffi::HEIGHT as EGLint, height,
+            egl::ffi::NONE as EGLint,
+        ];
+        let surface = match self.create_stream_producer_surface(stream, &producer_attribs) {
+            Ok(surface) => surface,
+            Err(e) => {
+                self.destroy_stream(stream);
+                return Err(e);
+            }
+        };
+
+        Ok(OutputStream::new(
+            self.display.raw_ref().clone(),
+            layer,
+            stream,
+            surface,
+            width,
+            height,
+        ))
+    }
+
+    /// Present the last frame rendered into the crtc stream.
+    ///
+    /// `flip_data` is delivered back as user data of the DRM page flip event
+    /// (EGL_NV_output_drm_flip_event). Returns `Ok(false)` when the display engine is still
+    /// busy with the previous frame and the flip has to be retried.
+    pub fn output_stream_flip(&self, output_stream: &OutputStream, flip_data: *mut c_void) -> Result<bool, EGLError> {
+        let acquire_attribs: Vec<EGLAttrib> = vec![
+            egl::ffi::DRM_FLIP_EVENT_DATA_NV as EGLAttrib, flip_data as EGLAttrib,
+            egl::ffi::NONE as EGLAttrib,
+        ];
+
+        let r = unsafe { egl::ffi::StreamConsumerAcquireAttribNV(
+            self.display.raw_ref().clone(),
+            output_stream.stream(),
+            acquire_attribs.as_ptr(),
+        )};
+
+        if r != egl::ffi::TRUE {
+            let code = unsafe { egl::ffi::GetError() };
+            if code as EGLenum == egl::ffi::RESOURCE_BUSY_EXT {
+                return Ok(false);
+            }
+            return Err(EGLError::from_str("Failed to flip output stream"));
+        }
+
+        Ok(true)
+    }
+
+    /// Import a client buffer created with EGL_WL_wayland_eglstream.
+    ///
+    /// Returns `Ok(None)` if the buffer is not an EGLStream buffer.
+    pub fn attach_eglstream(&self, buffer: *mut wl_resource) -> Result<Option<ClientStream>, EGLError> {
+        if !self.has_egl_stream_consumer_gltexture || !self.has_egl_wayland_eglstream {
+            return Ok(None);
+        }
+
+        let mut stream_fd: EGLint = -1;
+        let r = unsafe { egl::ffi::QueryWaylandBufferWL(
+            self.display.raw_ref().clone(),
+            buffer,
+            egl::ffi::WAYLAND_EGLSTREAM_WL as EGLint,
+            &mut stream_fd,
+        )};
+        if r != egl::ffi::TRUE {
+            return Ok(None);
+        }
+
+        let stream = unsafe {
+            egl::ffi::CreateStreamFromFileDescriptorKHR(self.display.raw_ref().clone(), stream_fd)
+        };
+        unsafe { libc::close(stream_fd) };
+
+        if stream.is_null() {
+            return Err(EGLError::from_str("Failed to create EGLStream from client file descriptor"));
+        }
+
+        self.make_current(egl::ffi::NO_SURFACE)?;
+        ClientStream::new(self.display.raw_ref().clone(), stream).map(Some)
+    }
+}
+
+impl Drop for EGLRenderer {
+    fn drop(&mut self) {
+        if self.context == egl::ffi::NO_CONTEXT {
+            return;
+        }
+
+        // the shader programs need the context to be deleted
+        let _ = self.make_current(egl::ffi::NO_SURFACE);
+        self.texture_shader_rgba = None;
+        self.texture_shader_rgbx = None;
+        self.texture_shader_egl_external = None;
+        self.solid_shader = None;
+
+        unsafe {
+            let display = self.display.raw_ref().clone();
+            egl::ffi::MakeCurrent(display, egl::ffi::NO_SURFACE, egl::ffi::NO_SURFACE, egl::ffi::NO_CONTEXT);
+            if egl::ffi::DestroyContext(display, self.context) != egl::ffi::TRUE {
+                log_error!("egl", "FAILED TO DESTROY CONTEXT {:?}", self.context);
+            }
+        }
     }
 }
 
 #[allow(non_snake_case)]
 fn load_EGL() -> Result<(), EGLError> {
     let library_path = "libEGL.so";
-    println!("Loading EGL Library from {:?}", library_path);
+    log_info!("egl", "Loading EGL Library from {:?}", library_path);
     egl::loader::load_EGL(library_path)
 }
 
@@ -392,21 +651,19 @@ fn find_egldevice(filename: &Path) -> Result<EGLDevice, EGLError> {
                         filename.eq(&drm_device.to_string_lossy())
                     },
                     Err(e) => {
-                        println!("ERROR READING DRM FILE FOR EGLDEVICE: {:?}", e);
+                        log_error!("egl", "ERROR READING DRM FILE FOR EGLDEVICE: {:?}", e);
                         false
                     }
                 }
             })
             .ok_or_else(|| {
-                EGLError::from_string(format!("Device {:?} not found", filename))
+                EGLError::NoDevice { path: Some(PathBuf::from(filename)) }
             })
     }
 }
 
 impl EGLRenderer {
 
-    fn image_target_texture_2d(&self) {}
-
     fn create_image<'display>(
         &'display self,
         context: Option<EGLContext>, 
@@ -420,15 +677,52 @@ impl EGLRenderer {
         egl::image::Image::new(display, context, target, buffer, attrib_list)
     }
 
-    fn swap_buffers_with_damage(&self) {
-    //         {
-    //             .extension = "EGL_EXT_swap_buffers_with_damage",
-    //             .entrypoint = "eglSwapBuffersWithDamageEXT",
-    //         },
-    //         {
-    //             .extension = "EGL_KHR_swap_buffers_with_damage",
-    //             .entrypoint = "eglSwapBuffersWithDamageKHR",
-    //         },
+    /// Age of the back buffer of `surface` in frames, 0 if its content is unknown
+    fn buffer_age(&self, surface: EGLSurface) -> usize {
+        if !self.has_egl_buffer_age {
+            return 0;
+        }
+
+        let mut age: EGLint = 0;
+        let r = unsafe { egl::ffi::QuerySurface(
+            self.display.raw_ref().clone(), surface, egl::ffi::BUFFER_AGE_EXT as EGLint, &mut age)
+        };
+        if r != egl::ffi::TRUE {
+            log_error!("egl", "Failed to query buffer age: {}", egl::egl_error::egl_error_state());
+            return 0;
+        }
+        age.max(0) as usize
+    }
+
+    /// Swap `surface` telling the driver only `damage` (surface coordinates,
+    /// y down) changed since the previous frame.
+    fn swap_buffers_with_damage(&self, surface: EGLSurface, height: EGLint, damage: &Region) -> Result<(), EGLError> {
+        let display = self.display.raw_ref().clone();
+        let r = match self.swap_with_damage {
+            None => unsafe { egl::ffi::SwapBuffers(display, surface) },
+            Some(entrypoint) => {
+                // EGL rectangles have the origin at the bottom left
+                let mut rects: Vec<EGLint> = Vec::with_capacity(damage.rects().len() * 4);
+                for rect in damage.rects() {
+                    rects.extend_from_slice(&[rect.x, height - rect.y2(), rect.width, rect.height]);
+                }
+                let n_rects = damage.rects().len() as EGLint;
+                match entrypoint {
+                    SwapWithDamage::Ext => unsafe {
+                        egl::ffi::SwapBuffersWithDamageEXT(display, surface, rects.as_mut_ptr(), n_rects)
+                    },
+                    SwapWithDamage::Khr => unsafe {
+                        egl::ffi::SwapBuffersWithDamageKHR(display, surface, rects.as_mut_ptr(), n_rects)
+                    },
+                }
+            },
+        };
+
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_string(format!("Failed to swap buffers: {}", egl::egl_error::egl_error_state())))
+        } else {
+            Ok(())
+        }
     }
 
     // extension required EGL_EXT_platform_base
@@ -485,48 +779,141 @@ impl EGLRenderer {
     // const has_egl_output_base: bool;
 	// const has_egl_output_drm: bool;
 	// const has_egl_output_drm_flip_event: bool;
-    fn get_output_layers(&self) {
-        // egl::ffi::GetOutputLayersEXT()
+    fn get_output_layers(&self, attrib_list: &[EGLAttrib], max_layers: EGLint) -> Result<Vec<EGLOutputLayerEXT>, EGLError> {
+        let mut layers: Vec<EGLOutputLayerEXT> = Vec::with_capacity(max_layers as usize);
+        layers.resize(max_layers as usize, 0 as EGLOutputLayerEXT);
+        let mut num_layers: EGLint = 0;
+
+        let r = unsafe { egl::ffi::GetOutputLayersEXT(
+            self.display.raw_ref().clone(),
+            attrib_list.as_ptr(),
+            layers.as_mut_ptr(),
+            max_layers,
+            &mut num_layers,
+        )};
+        if r != egl::ffi::TRUE {
+            return Err(EGLError::from_str("Failed to query output layers"));
+        }
+
+        layers.truncate(num_layers as usize);
+        Ok(layers)
     }
-    fn query_output_layer_attrib(&self) {
-        // egl::ffi::QueryOutputLayerAttribEXT()
+    fn query_output_layer_attrib(&self, layer: EGLOutputLayerEXT, attribute: EGLint) -> Result<EGLAttrib, EGLError> {
+        let mut value: EGLAttrib = 0;
+        let r = unsafe { egl::ffi::QueryOutputLayerAttribEXT(
+            self.display.raw_ref().clone(),
+            layer,
+            attribute,
+            &mut value,
+        )};
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_string(format!("Failed to query output layer attribute 0x{:x}", attribute)))
+        } else {
+            Ok(value)
+        }
     }
 
     // const has_egl_stream: bool;
-    fn create_stream(&self) {
-        // egl::ffi::CreateStreamKHR()
+    fn create_stream(&self, attrib_list: &[EGLint]) -> Result<EGLStreamKHR, EGLError> {
+        let stream = unsafe { egl::ffi::CreateStreamKHR(
+            self.display.raw_ref().clone(),
+            attrib_list.as_ptr(),
+        )};
+        if stream.is_null() {
+            Err(EGLError::from_str("Failed to create EGLStream"))
+        } else {
+            Ok(stream)
+        }
     }
-    fn destroy_stream(&self) {
-        // egl::ffi::DestroyStreamKHR()
+    fn destroy_stream(&self, stream: EGLStreamKHR) {
+        let r = unsafe { egl::ffi::DestroyStreamKHR(self.display.raw_ref().clone(), stream) };
+        if r != egl::ffi::TRUE {
+            log_error!("egl", "FAILED TO DESTROY STREAM {:?}", stream);
+        }
     }
-    fn query_stream(&self) {
-        // egl::ffi::QueryStreamKHR()
+    fn query_stream(&self, stream: EGLStreamKHR, attribute: EGLenum) -> Result<EGLint, EGLError> {
+        let mut value: EGLint = 0;
+        let r = unsafe { egl::ffi::QueryStreamKHR(
+            self.display.raw_ref().clone(),
+            stream,
+            attribute,
+            &mut value,
+        )};
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_string(format!("Failed to query stream attribute 0x{:x}", attribute)))
+        } else {
+            Ok(value)
+        }
     }
 
     // const has_egl_stream_producer_eglsurface: bool;
-    fn create_stream_producer_surface(&self) {
-        // egl::ffi::CreateStreamProducerSurfaceKHR()
+    fn create_stream_producer_surface(&self, stream: EGLStreamKHR, attrib_list: &[EGLint]) -> Result<EGLSurface, EGLError> {
+        let surface = unsafe { egl::ffi::CreateStreamProducerSurfaceKHR(
+            self.display.raw_ref().clone(),
+            self.egl_config,
+            stream,
+            attrib_list.as_ptr(),
+        )};
+        if surface == egl::ffi::NO_SURFACE {
+            Err(EGLError::from_str("Failed to create EGLStream producer surface"))
+        } else {
+            Ok(surface)
+        }
     }
 
     // const has_egl_stream_consumer_egloutput: bool;
-    fn stream_consumer_output(&self) {
-        // egl::ffi::StreamConsumerOutputEXT()
+    fn stream_consumer_output(&self, stream: EGLStreamKHR, layer: EGLOutputLayerEXT) -> Result<(), EGLError> {
+        let r = unsafe { egl::ffi::StreamConsumerOutputEXT(
+            self.display.raw_ref().clone(),
+            stream,
+            layer,
+        )};
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_str("Failed to set EGLOutputLayer as EGLStream consumer"))
+        } else {
+            Ok(())
+        }
     }
 
     //nvidia egl stream attrib
     // const has_egl_stream_attrib: bool;
-    fn create_stream_attrib(&self) {
-        // egl::ffi::CreateStreamAttribNV()
+    fn create_stream_attrib(&self, attrib_list: &[EGLAttrib]) -> Result<EGLStreamKHR, EGLError> {
+        let stream = unsafe { egl::ffi::CreateStreamAttribNV(
+            self.display.raw_ref().clone(),
+            attrib_list.as_ptr(),
+        )};
+        if stream.is_null() {
+            Err(EGLError::from_str("Failed to create EGLStream"))
+        } else {
+            Ok(stream)
+        }
     }
     // const has_egl_stream_acquire_mode: bool;
-    fn stream_consumer_acquire_attrib(&self) {
-        // egl::ffi::StreamConsumerAcquireAttribNV()
+    fn stream_consumer_acquire_attrib(&self, stream: EGLStreamKHR, attrib_list: &[EGLAttrib]) -> Result<(), EGLError> {
+        let r = unsafe { egl::ffi::StreamConsumerAcquireAttribNV(
+            self.display.raw_ref().clone(),
+            stream,
+            attrib_list.as_ptr(),
+        )};
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_str("Failed to acquire EGLStream frame"))
+        } else {
+            Ok(())
+        }
     }
 
     // const has_egl_stream_consumer_gltexture: bool;
     // const has_egl_wayland_eglstream: bool;
-    fn stream_consumer_gltexture(&self) {
-        // egl::ffi::StreamConsumerGLTextureExternalKHR()
+    fn stream_consumer_gltexture(&self, stream: EGLStreamKHR) -> Result<(), EGLError> {
+        let r = unsafe { egl::ffi::StreamConsumerGLTextureExternalKHR(
+            self.display.raw_ref().clone(),
+            stream,
+        )};
+        if r != egl::ffi::TRUE {
+            Err(EGLError::from_str("Failed to set GL texture as EGLStream consumer"))
+        } else {
+            Ok(())
+        }
     }
 
     // const has_dmabuf_import: bool;
@@ -546,22 +933,716 @@ impl EGLRenderer {
 	// fn destroy_signal_ref(&self) -> &wayland::signal::Signal { &self.destroy_signal }
     // fn output_destroy_listener_ref(&self) -> &wayland::listener::Listener { &self.output_destroy_listener }
 
-    // const has_dmabuf_import_modifiers: bool;
-    fn query_dmabuf_formats(&self) {
-        // egl::ffi::QueryDmaBufFormatsEXT()
+    /// Create a native fence sync object.
+    ///
+    /// With `fence_fd` set to `NO_NATIVE_FENCE_FD_ANDROID` the fence signals when
+    /// the commands queued so far are done, otherwise it wraps `fence_fd` and
+    /// takes its ownership on success.
+    fn create_sync(&self, fence_fd: RawFd) -> Result<EGLSyncKHR, EGLError> {
+        if !self.has_native_fence_sync {
+            return Err(EGLError::MissingExtension("EGL_ANDROID_native_fence_sync"));
+        }
+
+        let attribs: [EGLint; 3] = [
+            egl::ffi::SYNC_NATIVE_FENCE_FD_ANDROID as EGLint, fence_fd,
+            egl::ffi::NONE as EGLint,
+        ];
+        let sync = unsafe { egl::ffi::CreateSyncKHR(
+            self.display.raw_ref().clone(),
+            egl::ffi::SYNC_NATIVE_FENCE_ANDROID,
+            attribs.as_ptr(),
+        )};
+        if sync.is_null() {
+            Err(EGLError::from_string(format!("Failed to create native fence sync: {}", egl::egl_error::egl_error_state())))
+        } else {
+            Ok(sync)
+        }
+    }
+
+    fn destroy_sync(&self, sync: EGLSyncKHR) {
+        let r = unsafe { egl::ffi::DestroySyncKHR(self.display.raw_ref().clone(), sync) };
+        if r != egl::ffi::TRUE {
+            log_error!("egl", "FAILED TO DESTROY SYNC {:?}", sync);
+        }
+    }
+
+    /// A new sync_file fd for `sync`, valid once the commands before it are flushed
+    fn dup_native_fence_fd(&self, sync: EGLSyncKHR) -> Option<RawFd> {
+        let fd = unsafe { egl::ffi::DupNativeFenceFDANDROID(self.display.raw_ref().clone(), sync) };
+        if fd == egl::ffi::NO_NATIVE_FENCE_FD_ANDROID {
+            log_error!("egl", "Failed to duplicate native fence fd: {}", egl::egl_error::egl_error_state());
+            None
+        } else {
+            Some(fd)
+        }
+    }
+
+    /// Make the GPU wait for `fence_fd` before running the next commands,
+    /// the CPU does not block. `fence_fd` stays owned by the caller.
+    fn wait_fence(&self, fence_fd: RawFd) -> Result<(), EGLError> {
+        if !self.has_wait_sync {
+            return Err(EGLError::MissingExtension("EGL_KHR_wait_sync"));
+        }
+
+        let fd = unsafe { libc::dup(fence_fd) };
+        if fd < 0 {
+            return Err(EGLError::from_str("Failed to duplicate acquire fence"));
+        }
+        let sync = match self.create_sync(fd) {
+            Ok(sync) => sync,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+        let r = unsafe { egl::ffi::WaitSyncKHR(self.display.raw_ref().clone(), sync, 0) };
+        self.destroy_sync(sync);
+
+        if r == egl::ffi::TRUE as EGLint {
+            Ok(())
+        } else {
+            Err(EGLError::from_string(format!("Failed to wait on acquire fence: {}", egl::egl_error::egl_error_state())))
+        }
+    }
+}
+
+/// What the renderer hangs on `Output::renderer_state`
+struct EGLOutputState {
+    display: egl::ffi::types::EGLDisplay,
+    stream: OutputStream,
+    /// fence of the last repaint, null if there is none
+    render_sync: EGLSyncKHR,
+    /// damage of the last frames in global coordinates, newest first
+    damage_history: VecDeque<Region>,
+}
+
+impl Drop for EGLOutputState {
+    fn drop(&mut self) {
+        if !self.render_sync.is_null() {
+            unsafe { egl::ffi::DestroySyncKHR(self.display, self.render_sync) };
+        }
+    }
+}
+
+/// EGLImage owned by a surface or a dmabuf, destroyed with it
+#[derive(Debug)]
+struct ImageHandle {
+    display: egl::ffi::types::EGLDisplay,
+    image: EGLImageKHR,
+}
+
+impl Drop for ImageHandle {
+    fn drop(&mut self) {
+        let r = unsafe { egl::ffi::DestroyImageKHR(self.display, self.image) };
+        if r != egl::ffi::TRUE {
+            log_error!("egl", "FAILED TO DESTROY IMAGE {:?}", self.image);
+        }
     }
-    fn query_dmabuf_modifiers(&self) {
-        // egl::ffi::QueryDmaBufModifiersEXT()
+}
+
+/// gl_surface_state
+struct EGLSurfaceState {
+    shader: ShaderKind,
+    texture: GLuint,
+    target: GLenum,
+    color: [GLfloat; 4],
+    // shm upload parameters
+    gl_format: GLenum,
+    gl_pixel_type: GLenum,
+    // the client buffer the texture was made from, and what keeps it alive
+    buffer: Option<*mut wl_resource>,
+    image: Option<ImageHandle>,
+    stream: Option<ClientStream>,
+}
+
+impl EGLSurfaceState {
+    fn new(shader: ShaderKind, target: GLenum) -> Self {
+        let mut texture: GLuint = 0;
+        if shader != ShaderKind::Solid {
+            unsafe {
+                gl::GenTextures(1, &mut texture);
+                gl::BindTexture(target, texture);
+                gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+                gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            }
+        }
+
+        Self {
+            shader,
+            texture,
+            target,
+            color: [0.0; 4],
+            gl_format: 0,
+            gl_pixel_type: 0,
+            buffer: None,
+            image: None,
+            stream: None,
+        }
     }
+}
 
-    // const has_native_fence_sync: bool;
-    fn create_sync(&self) {
-        // egl::ffi::CreateSyncKHR()
+impl Drop for EGLSurfaceState {
+    fn drop(&mut self) {
+        // the client stream owns its texture
+        if self.stream.is_none() && self.texture != 0 {
+            unsafe { gl::DeleteTextures(1, &self.texture) };
+        }
     }
-    fn destroy_sync(&self) {
-        // egl::ffi::DestroySyncKHR()
+}
+
+impl EGLRenderer {
+    fn bind_image(&self, target: GLenum, image: EGLImageKHR) -> Result<(), RendererError> {
+        match self.image_target_texture_2d {
+            Some(image_target_texture_2d) => {
+                image_target_texture_2d(target, image as *const c_void);
+                Ok(())
+            },
+            None => Err(RendererError::unsupported("glEGLImageTargetTexture2DOES not available".to_string())),
+        }
     }
-    fn dup_native_fence_fd(&self) {
-        // egl::ffi::DupNativeFenceFDANDROID()
+
+    fn query_wayland_buffer(&self, buffer: *mut wl_resource, attribute: EGLenum) -> Option<EGLint> {
+        let mut value: EGLint = 0;
+        let r = unsafe { egl::ffi::QueryWaylandBufferWL(
+            self.display.raw_ref().clone(),
+            buffer,
+            attribute as EGLint,
+            &mut value,
+        )};
+        if r == egl::ffi::TRUE { Some(value) } else { None }
+    }
+
+    fn upload_shm(state: &EGLSurfaceState, shm: &ShmBuffer, damage: &Region) {
+        let bpp = if state.gl_pixel_type == gl::UNSIGNED_SHORT_5_6_5 { 2 } else { 4 };
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, state.texture);
+            gl::PixelStorei(gl::UNPACK_ROW_LENGTH, shm.stride() / bpp);
+        }
+        shm.with_data(|data| {
+            let bounds = Rectangle::new(0, 0, shm.width(), shm.height());
+            for rect in damage.intersect_rect(&bounds).rects() {
+                unsafe {
+                    gl::PixelStorei(gl::UNPACK_SKIP_PIXELS, rect.x);
+                    gl::PixelStorei(gl::UNPACK_SKIP_ROWS, rect.y);
+                    gl::TexSubImage2D(gl::TEXTURE_2D, 0,
+                        rect.x, rect.y, rect.width, rect.height,
+                        state.gl_format, state.gl_pixel_type,
+                        data.as_ptr() as *const c_void);
+                }
+            }
+        });
+        unsafe {
+            gl::PixelStorei(gl::UNPACK_ROW_LENGTH, 0);
+            gl::PixelStorei(gl::UNPACK_SKIP_PIXELS, 0);
+            gl::PixelStorei(gl::UNPACK_SKIP_ROWS, 0);
+        }
+    }
+
+    fn attach_shm(&mut self, surface: &mut Surface, shm: &ShmBuffer) -> Result<(), RendererError> {
+        let (shader, gl_format, gl_pixel_type) = match shm.format() {
+            Some(wl_shm::Format::Argb8888) => (ShaderKind::Rgba, gl::BGRA, gl::UNSIGNED_BYTE),
+            Some(wl_shm::Format::Xrgb8888) => (ShaderKind::Rgbx, gl::BGRA, gl::UNSIGNED_BYTE),
+            Some(wl_shm::Format::Rgb565) => (ShaderKind::Rgbx, gl::RGB, gl::UNSIGNED_SHORT_5_6_5),
+            format => return Err(RendererError::unsupported(format!("Unsupported shm format {:?}", format))),
+        };
+        let (width, height) = (shm.width(), shm.height());
+
+        let reuse = match surface.renderer_state.as_ref().and_then(|state| state.downcast_ref::<EGLSurfaceState>()) {
+            Some(state) => state.shader == shader && state.gl_format == gl_format
+                && state.gl_pixel_type == gl_pixel_type && state.image.is_none() && state.stream.is_none()
+                && surface.buffer_width == width && surface.buffer_height == height,
+            None => false,
+        };
+
+        if !reuse {
+            let mut state = EGLSurfaceState::new(shader, gl::TEXTURE_2D);
+            state.gl_format = gl_format;
+            state.gl_pixel_type = gl_pixel_type;
+            unsafe {
+                gl::TexImage2D(gl::TEXTURE_2D, 0, gl_format as i32, width, height, 0,
+                    gl_format, gl_pixel_type, ptr::null());
+            }
+            surface.renderer_state = Some(Box::new(state));
+        }
+        surface.buffer_width = width;
+        surface.buffer_height = height;
+
+        let state = surface.renderer_state.as_ref()
+            .and_then(|state| state.downcast_ref::<EGLSurfaceState>())
+            .unwrap();
+        Self::upload_shm(state, shm, &Region::from_rect(Rectangle::new(0, 0, width, height)));
+        Ok(())
+    }
+
+    fn attach_egl(&mut self, surface: &mut Surface, buffer: *mut wl_resource) -> Result<(), RendererError> {
+        let width = self.query_wayland_buffer(buffer, egl::ffi::WIDTH);
+        let height = self.query_wayland_buffer(buffer, egl::ffi::HEIGHT);
+
+        // same EGLStream buffer as before, just latch the new frame
+        if let Some(state) = surface.renderer_state.as_mut().and_then(|state| state.downcast_mut::<EGLSurfaceState>()) {
+            if state.buffer == Some(buffer) {
+                if let Some(ref mut stream) = state.stream {
+                    stream.acquire().map_err(RendererError::Egl)?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let stream = self.attach_eglstream(buffer)
+            .map_err(RendererError::Egl)?;
+        if let Some(mut stream) = stream {
+            stream.acquire().map_err(RendererError::Egl)?;
+            let mut state = EGLSurfaceState::new(ShaderKind::Solid, TEXTURE_EXTERNAL_OES);
+            state.shader = ShaderKind::EglExternal;
+            state.texture = stream.texture();
+            state.buffer = Some(buffer);
+            state.stream = Some(stream);
+            surface.renderer_state = Some(Box::new(state));
+            surface.buffer_width = width.unwrap_or(0);
+            surface.buffer_height = height.unwrap_or(0);
+            return Ok(());
+        }
+
+        let texture_format = self.query_wayland_buffer(buffer, egl::ffi::TEXTURE_FORMAT)
+            .ok_or_else(|| RendererError::from_str("Unknown buffer type"))?;
+        let (shader, target) = match texture_format as EGLenum {
+            egl::ffi::TEXTURE_RGB => (ShaderKind::Rgbx, gl::TEXTURE_2D),
+            egl::ffi::TEXTURE_RGBA => (ShaderKind::Rgba, gl::TEXTURE_2D),
+            egl::ffi::TEXTURE_EXTERNAL_WL => (ShaderKind::EglExternal, TEXTURE_EXTERNAL_OES),
+            format => return Err(RendererError::unsupported(format!("Unsupported EGL buffer texture format {:#x}", format))),
+        };
+
+        let attribs: Vec<EGLint> = vec![
+            egl::ffi::WAYLAND_PLANE_WL as EGLint, 0,
+            egl::ffi::NONE as EGLint,
+        ];
+        let image = unsafe { egl::ffi::CreateImageKHR(
+            self.display.raw_ref().clone(),
+            egl::ffi::NO_CONTEXT,
+            egl::ffi::WAYLAND_BUFFER_WL,
+            buffer as EGLClientBuffer,
+            attribs.as_ptr(),
+        )};
+        if image.is_null() {
+            return Err(RendererError::from_str("Failed to create EGLImage from wayland buffer"));
+        }
+        let image = ImageHandle {
+            display: self.display.raw_ref().clone(),
+            image,
+        };
+
+        let mut state = EGLSurfaceState::new(shader, target);
+        self.bind_image(target, image.image)?;
+        state.buffer = Some(buffer);
+        state.image = Some(image);
+        surface.renderer_state = Some(Box::new(state));
+        surface.buffer_width = width.unwrap_or(0);
+        surface.buffer_height = height.unwrap_or(0);
+        Ok(())
+    }
+
+    fn attach_dmabuf(&mut self, surface: &mut Surface, dmabuf: &Dmabuf) -> Result<(), RendererError> {
+        let image = match dmabuf.renderer_state.borrow().as_ref().and_then(|state| state.downcast_ref::<ImageHandle>()) {
+            Some(handle) => handle.image,
+            None => return Err(RendererError::from_str("dmabuf not imported by the EGL renderer")),
+        };
+
+        // external sampling works whatever the modifier of the buffer
+        let state = EGLSurfaceState::new(ShaderKind::EglExternal, TEXTURE_EXTERNAL_OES);
+        self.bind_image(TEXTURE_EXTERNAL_OES, image)?;
+        surface.renderer_state = Some(Box::new(state));
+        surface.buffer_width = dmabuf.width;
+        surface.buffer_height = dmabuf.height;
+        Ok(())
+    }
+
+    /// Draw the part of `surface` inside `rect` (output pixels), `target`
+    /// being where the whole surface lands in the frame of `space`
+    fn draw_surface(&self, surface: &Surface, state: &EGLSurfaceState, space: &OutputSpace, target: &Rectangle, rect: &Rectangle) {
+        let shader = match self.shader(state.shader) {
+            Some(shader) => shader,
+            None => return,
+        };
+        let clip = match space.frame_rect_to_pixels(target).intersect(rect) {
+            Some(clip) => clip,
+            None => return,
+        };
+
+        // the corners of the clip go back through the output transform, the
+        // texture coordinates follow the rotation
+        let source = surface.source_rect();
+        let (buffer_width, buffer_height) = (surface.buffer_width.max(1) as f64, surface.buffer_height.max(1) as f64);
+        let tex = |x: i32, y: i32| -> [GLfloat; 2] {
+            let (frame_x, frame_y) = space.pixel_to_frame(f64::from(x), f64::from(y));
+            [
+                ((source.0 + (frame_x - f64::from(target.x)) * source.2 / f64::from(target.width)) / buffer_width) as GLfloat,
+                ((source.1 + (frame_y - f64::from(target.y)) * source.3 / f64::from(target.height)) / buffer_height) as GLfloat,
+            ]
+        };
+        let corners = [
+            (clip.x, clip.y),
+            (clip.x2(), clip.y),
+            (clip.x2(), clip.y2()),
+            (clip.x, clip.y2()),
+        ];
+        let mut positions: Vec<GLfloat> = Vec::with_capacity(8);
+        let mut texcoords: Vec<GLfloat> = Vec::with_capacity(8);
+        for &(x, y) in corners.iter() {
+            positions.push(x as GLfloat);
+            positions.push(y as GLfloat);
+            texcoords.extend_from_slice(&tex(x, y));
+        }
+
+        unsafe {
+            gl::UseProgram(shader.program());
+            gl::Uniform1f(shader.alpha_uniform, 1.0);
+            gl::Uniform4fv(shader.color_uniform, 1, state.color.as_ptr());
+            if state.texture != 0 {
+                gl::ActiveTexture(gl::TEXTURE0);
+                gl::BindTexture(state.target, state.texture);
+                gl::Uniform1i(shader.tex_uniform, 0);
+                // interpolate only when the buffer does not map pixel for pixel
+                let aligned = space.transform == wl_output::Transform::Normal && surface.is_pixel_aligned(space.scale);
+                let filter = if aligned { gl::NEAREST } else { gl::LINEAR };
+                gl::TexParameteri(state.target, gl::TEXTURE_MIN_FILTER, filter as i32);
+                gl::TexParameteri(state.target, gl::TEXTURE_MAG_FILTER, filter as i32);
+            }
+
+            gl::VertexAttribPointer(shader::POSITION_ATTRIB, 2, gl::FLOAT, gl::FALSE, 0, positions.as_ptr() as *const c_void);
+            gl::VertexAttribPointer(shader::TEXCOORD_ATTRIB, 2, gl::FLOAT, gl::FALSE, 0, texcoords.as_ptr() as *const c_void);
+            gl::EnableVertexAttribArray(shader::POSITION_ATTRIB);
+            gl::EnableVertexAttribArray(shader::TEXCOORD_ATTRIB);
+            gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+            gl::DisableVertexAttribArray(shader::TEXCOORD_ATTRIB);
+            gl::DisableVertexAttribArray(shader::POSITION_ATTRIB);
+        }
+    }
+}
+
+impl Renderer for EGLRenderer {
+    fn name(&self) -> &'static str {
+        "egl"
+    }
+
+    fn shm_formats(&self) -> Vec<wl_shm::Format> {
+        vec![wl_shm::Format::Rgb565]
+    }
+
+    fn output_create(&mut self, output: &mut Output) -> Result<(), RendererError> {
+        let output_stream = self.output_stream_create(output.id, output.width, output.height)
+            .map_err(RendererError::Egl)?;
+        output.renderer_state = Some(Box::new(EGLOutputState {
+            display: self.display.raw_ref().clone(),
+            stream: output_stream,
+            render_sync: ptr::null(),
+            damage_history: VecDeque::with_capacity(BUFFER_DAMAGE_COUNT),
+        }));
+        output.damage_all();
+        Ok(())
+    }
+
+    fn output_destroy(&mut self, output: &mut Output) {
+        // dropping the OutputStream destroys the surface and the stream
+        output.renderer_state = None;
+    }
+
+    /// The crtc id is passed as page flip data, see `output_stream_flip`
+    fn repaint_output(&mut self, output: &mut Output, surfaces: &[&Surface], damage: &Region)
+        -> Result<Scanout, RendererError>
+    {
+        let space = output.space();
+        let flip_data = output.id as usize as *mut c_void;
+        let output_state = output.renderer_state.as_mut()
+            .and_then(|state| state.downcast_mut::<EGLOutputState>())
+            .ok_or_else(|| RendererError::from_str("Output not created by the EGL renderer"))?;
+
+        self.make_current(output_state.stream.surface())
+            .map_err(RendererError::Egl)?;
+
+        // let the GPU wait for the clients still writing into their buffers
+        for surface in surfaces.iter() {
+            if let Some(fence_fd) = surface.acquire_fence {
+                if let Err(e) = self.wait_fence(fence_fd) {
+                    log_warn!("egl", "{}, waiting on the CPU", e);
+                    surface.wait_acquire_fence();
+                }
+            }
+        }
+
+        let (width, height) = (space.width, space.height);
+        // output pixels to clip space, y pointing down
+        let proj: [GLfloat; 16] = [
+            2.0 / width as GLfloat, 0.0, 0.0, 0.0,
+            0.0, -2.0 / height as GLfloat, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            -1.0, 1.0, 0.0, 1.0,
+        ];
+        for kind in [ShaderKind::Rgba, ShaderKind::Rgbx, ShaderKind::EglExternal, ShaderKind::Solid].iter() {
+            if let Some(shader) = self.shader(*kind) {
+                unsafe {
+                    gl::UseProgram(shader.program());
+                    gl::UniformMatrix4fv(shader.proj_uniform, 1, gl::FALSE, proj.as_ptr());
+                }
+            }
+        }
+
+        unsafe {
+            gl::Viewport(0, 0, width, height);
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+        }
+
+        // the back buffer misses the damage of the frames since it was last
+        // used, the history is in output pixels
+        let frame_damage = space.region_to_pixels(damage);
+        let age = self.buffer_age(output_state.stream.surface());
+        let repaint_damage = if age == 0 || age - 1 > output_state.damage_history.len() {
+            Region::from_rect(space.bounds())
+        } else {
+            let mut repaint_damage = frame_damage.clone();
+            for old_damage in output_state.damage_history.iter().take(age - 1) {
+                repaint_damage.union(old_damage);
+            }
+            repaint_damage
+        };
+        output_state.damage_history.push_front(frame_damage.clone());
+        output_state.damage_history.truncate(BUFFER_DAMAGE_COUNT);
+
+        for local in repaint_damage.rects() {
+            unsafe {
+                gl::Enable(gl::SCISSOR_TEST);
+                gl::Scissor(local.x, height - local.y2(), local.width, local.height);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+                gl::Disable(gl::SCISSOR_TEST);
+            }
+
+            for surface in surfaces.iter() {
+                if let Some(state) = surface.renderer_state.as_ref().and_then(|state| state.downcast_ref::<EGLSurfaceState>()) {
+                    let target = space.to_frame(&surface.geometry());
+                    self.draw_surface(surface, state, &space, &target, local);
+                }
+            }
+        }
+
+        // signalled when the GPU is done with this frame, see create_fence_fd
+        if !output_state.render_sync.is_null() {
+            self.destroy_sync(output_state.render_sync);
+            output_state.render_sync = ptr::null();
+        }
+        if self.has_native_fence_sync {
+            match self.create_sync(egl::ffi::NO_NATIVE_FENCE_FD_ANDROID) {
+                Ok(sync) => output_state.render_sync = sync,
+                Err(e) => log_error!("egl", "{}", e),
+            }
+        }
+
+        self.swap_buffers_with_damage(output_state.stream.surface(), height, &frame_damage)
+            .map_err(RendererError::Egl)?;
+
+        match self.output_stream_flip(&output_state.stream, flip_data) {
+            Ok(true) => Ok(Scanout::Queued),
+            Ok(false) => Ok(Scanout::Busy),
+            Err(e) => Err(RendererError::Egl(e)),
+        }
+    }
+
+    fn attach(&mut self, surface: &mut Surface) -> Result<(), RendererError> {
+        self.make_current(egl::ffi::NO_SURFACE)
+            .map_err(RendererError::Egl)?;
+
+        match surface.buffer.clone() {
+            None => {
+                surface.renderer_state = None;
+                surface.buffer_width = 0;
+                surface.buffer_height = 0;
+                Ok(())
+            },
+            Some(Buffer::Shm(shm)) => self.attach_shm(surface, &shm),
+            Some(Buffer::Egl(buffer)) => self.attach_egl(surface, buffer),
+            Some(Buffer::Dmabuf(dmabuf)) => self.attach_dmabuf(surface, &dmabuf),
+        }
+    }
+
+    fn flush_damage(&mut self, surface: &mut Surface) -> Result<(), RendererError> {
+        let shm = match surface.buffer {
+            Some(Buffer::Shm(ref shm)) => shm.clone(),
+            _ => return Ok(()),
+        };
+        if let Some(state) = surface.renderer_state.as_ref().and_then(|state| state.downcast_ref::<EGLSurfaceState>()) {
+            self.make_current(egl::ffi::NO_SURFACE)
+                .map_err(RendererError::Egl)?;
+            Self::upload_shm(state, &shm, &surface.damage);
+        }
+        Ok(())
+    }
+
+    fn surface_set_color(&mut self, surface: &mut Surface, red: f32, green: f32, blue: f32, alpha: f32) {
+        let mut state = EGLSurfaceState::new(ShaderKind::Solid, gl::TEXTURE_2D);
+        state.color = [red, green, blue, alpha];
+        surface.renderer_state = Some(Box::new(state));
+    }
+
+    /// Reads the current back buffer of the output stream, which holds the last
+    /// frame only if the driver preserves it across swaps.
+    fn read_pixels(&mut self, output: &Output, format: wl_shm::Format, rect: Rectangle, pixels: &mut [u8])
+        -> Result<(), RendererError>
+    {
+        let swap_rb = match format {
+            wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => true,
+            wl_shm::Format::Abgr8888 | wl_shm::Format::Xbgr8888 => false,
+            format => return Err(RendererError::unsupported(format!("Unsupported read_pixels format {:?}", format))),
+        };
+        let row_len = rect.width as usize * 4;
+        if pixels.len() < row_len * rect.height as usize {
+            return Err(RendererError::from_str("read_pixels destination too small"));
+        }
+        let output_state = output.renderer_state.as_ref()
+            .and_then(|state| state.downcast_ref::<EGLOutputState>())
+            .ok_or_else(|| RendererError::from_str("Output not created by the EGL renderer"))?;
+
+        self.make_current(output_state.stream.surface())
+            .map_err(RendererError::Egl)?;
+
+        // GL rows go bottom to top
+        let mut rgba = vec![0u8; row_len * rect.height as usize];
+        unsafe {
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 4);
+            gl::ReadPixels(rect.x, output.height - rect.y2(), rect.width, rect.height,
+                gl::RGBA, gl::UNSIGNED_BYTE, rgba.as_mut_ptr() as *mut c_void);
+        }
+        for (row, src) in rgba.chunks(row_len).rev().enumerate() {
+            let dst = &mut pixels[row * row_len..(row + 1) * row_len];
+            dst.copy_from_slice(src);
+            if swap_rb {
+                for pixel in dst.chunks_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The native fence of the last repaint of `output` (gl_renderer_create_fence_fd)
+    fn create_fence_fd(&mut self, output: &Output) -> Option<RawFd> {
+        let output_state = output.renderer_state.as_ref()
+            .and_then(|state| state.downcast_ref::<EGLOutputState>())?;
+        if output_state.render_sync.is_null() {
+            return None;
+        }
+        self.dup_native_fence_fd(output_state.render_sync)
+    }
+
+    fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> Result<(), RendererError> {
+        if !self.has_dmabuf_import {
+            return Err(RendererError::unsupported("EGL_EXT_image_dma_buf_import not supported".to_string()));
+        }
+        if dmabuf.planes.is_empty() || dmabuf.planes.len() > dmabuf::MAX_DMABUF_PLANES {
+            return Err(RendererError::unsupported(format!("Invalid number of dmabuf planes {}", dmabuf.planes.len())));
+        }
+
+        const PLANE_ATTRIBS: [[EGLenum; 5]; dmabuf::MAX_DMABUF_PLANES] = [
+            [egl::ffi::DMA_BUF_PLANE0_FD_EXT, egl::ffi::DMA_BUF_PLANE0_OFFSET_EXT, egl::ffi::DMA_BUF_PLANE0_PITCH_EXT,
+             egl::ffi::DMA_BUF_PLANE0_MODIFIER_LO_EXT, egl::ffi::DMA_BUF_PLANE0_MODIFIER_HI_EXT],
+            [egl::ffi::DMA_BUF_PLANE1_FD_EXT, egl::ffi::DMA_BUF_PLANE1_OFFSET_EXT, egl::ffi::DMA_BUF_PLANE1_PITCH_EXT,
+             egl::ffi::DMA_BUF_PLANE1_MODIFIER_LO_EXT, egl::ffi::DMA_BUF_PLANE1_MODIFIER_HI_EXT],
+            [egl::ffi::DMA_BUF_PLANE2_FD_EXT, egl::ffi::DMA_BUF_PLANE2_OFFSET_EXT, egl::ffi::DMA_BUF_PLANE2_PITCH_EXT,
+             egl::ffi::DMA_BUF_PLANE2_MODIFIER_LO_EXT, egl::ffi::DMA_BUF_PLANE2_MODIFIER_HI_EXT],
+            [egl::ffi::DMA_BUF_PLANE3_FD_EXT, egl::ffi::DMA_BUF_PLANE3_OFFSET_EXT, egl::ffi::DMA_BUF_PLANE3_PITCH_EXT,
+             egl::ffi::DMA_BUF_PLANE3_MODIFIER_LO_EXT, egl::ffi::DMA_BUF_PLANE3_MODIFIER_HI_EXT],
+        ];
+
+        let mut attribs: Vec<EGLint> = vec![
+            egl::ffi::WIDTH as EGLint, dmabuf.width,
+            egl::ffi::HEIGHT as EGLint, dmabuf.height,
+            egl::ffi::LINUX_DRM_FOURCC_EXT as EGLint, dmabuf.format as EGLint,
+        ];
+        for (plane, names) in dmabuf.planes.iter().zip(PLANE_ATTRIBS.iter()) {
+            attribs.extend_from_slice(&[
+                names[0] as EGLint, plane.fd,
+                names[1] as EGLint, plane.offset as EGLint,
+                names[2] as EGLint, plane.stride as EGLint,
+            ]);
+            if plane.modifier != dmabuf::DRM_FORMAT_MOD_INVALID {
+                if !self.has_dmabuf_import_modifiers {
+                    return Err(RendererError::unsupported("dmabuf modifiers not supported".to_string()));
+                }
+                attribs.extend_from_slice(&[
+                    names[3] as EGLint, (plane.modifier & 0xffff_ffff) as EGLint,
+                    names[4] as EGLint, (plane.modifier >> 32) as EGLint,
+                ]);
+            }
+        }
+        attribs.push(egl::ffi::NONE as EGLint);
+
+        let image = unsafe { egl::ffi::CreateImageKHR(
+            self.display.raw_ref().clone(),
+            egl::ffi::NO_CONTEXT,
+            egl::ffi::LINUX_DMA_BUF_EXT,
+            ptr::null_mut(),
+            attribs.as_ptr(),
+        )};
+        if image.is_null() {
+            return Err(RendererError::Egl(EGLError::from_str("Failed to import dmabuf")));
+        }
+
+        *dmabuf.renderer_state.borrow_mut() = Some(Box::new(ImageHandle {
+            display: self.display.raw_ref().clone(),
+            image,
+        }));
+        Ok(())
+    }
+
+    fn query_dmabuf_formats(&self) -> Vec<u32> {
+        if !self.has_dmabuf_import {
+            return Vec::new();
+        }
+        let fallback = vec![dmabuf::DRM_FORMAT_ARGB8888, dmabuf::DRM_FORMAT_XRGB8888];
+        if !self.has_dmabuf_import_modifiers {
+            return fallback;
+        }
+
+        let display = self.display.raw_ref().clone();
+        let mut num_formats: EGLint = 0;
+        let r = unsafe { egl::ffi::QueryDmaBufFormatsEXT(display, 0, ptr::null_mut(), &mut num_formats) };
+        if r != egl::ffi::TRUE || num_formats == 0 {
+            return fallback;
+        }
+
+        let mut formats: Vec<EGLint> = vec![0; num_formats as usize];
+        let r = unsafe { egl::ffi::QueryDmaBufFormatsEXT(display, num_formats, formats.as_mut_ptr(), &mut num_formats) };
+        if r != egl::ffi::TRUE {
+            return fallback;
+        }
+        formats.truncate(num_formats as usize);
+        formats.into_iter().map(|format| format as u32).collect()
+    }
+
+    fn query_dmabuf_modifiers(&self, format: u32) -> Vec<u64> {
+        if !self.has_dmabuf_import_modifiers {
+            return Vec::new();
+        }
+
+        let display = self.display.raw_ref().clone();
+        let mut num_modifiers: EGLint = 0;
+        let r = unsafe { egl::ffi::QueryDmaBufModifiersEXT(
+            display, format as EGLint, 0, ptr::null_mut(), ptr::null_mut(), &mut num_modifiers)
+        };
+        if r != egl::ffi::TRUE || num_modifiers == 0 {
+            return Vec::new();
+        }
+
+        let mut modifiers: Vec<EGLuint64KHR> = vec![0; num_modifiers as usize];
+        let r = unsafe { egl::ffi::QueryDmaBufModifiersEXT(
+            display, format as EGLint, num_modifiers, modifiers.as_mut_ptr(), ptr::null_mut(), &mut num_modifiers)
+        };
+        if r != egl::ffi::TRUE {
+            return Vec::new();
+        }
+        modifiers.truncate(num_modifiers as usize);
+        modifiers
     }
 }
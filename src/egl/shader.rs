@@ -0,0 +1,177 @@
+use std::ffi::CString;
+use std::ptr;
+
+use egl::EGLError;
+use gl;
+use gl::types::{GLint, GLuint, GLenum};
+
+const VERTEX_SHADER: &str = "
+uniform mat4 proj;
+attribute vec2 position;
+attribute vec2 texcoord;
+varying vec2 v_texcoord;
+void main()
+{
+    gl_Position = proj * vec4(position, 0.0, 1.0);
+    v_texcoord = texcoord;
+}
+";
+
+const TEXTURE_FRAGMENT_SHADER_RGBA: &str = "
+precision mediump float;
+varying vec2 v_texcoord;
+uniform sampler2D tex;
+uniform float alpha;
+uniform vec4 color;
+void main()
+{
+    gl_FragColor = alpha * texture2D(tex, v_texcoord);
+}
+";
+
+const TEXTURE_FRAGMENT_SHADER_RGBX: &str = "
+precision mediump float;
+varying vec2 v_texcoord;
+uniform sampler2D tex;
+uniform float alpha;
+uniform vec4 color;
+void main()
+{
+    gl_FragColor.rgb = alpha * texture2D(tex, v_texcoord).rgb;
+    gl_FragColor.a = alpha;
+}
+";
+
+const TEXTURE_FRAGMENT_SHADER_EGL_EXTERNAL: &str = "
+#extension GL_OES_EGL_image_external : require
+precision mediump float;
+varying vec2 v_texcoord;
+uniform samplerExternalOES tex;
+uniform float alpha;
+uniform vec4 color;
+void main()
+{
+    gl_FragColor = alpha * texture2D(tex, v_texcoord);
+}
+";
+
+const SOLID_FRAGMENT_SHADER: &str = "
+precision mediump float;
+uniform float alpha;
+uniform vec4 color;
+void main()
+{
+    gl_FragColor = alpha * color;
+}
+";
+
+/// Which fragment shader draws a surface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderKind {
+    Rgba,
+    Rgbx,
+    EglExternal,
+    Solid,
+}
+
+/// A linked GLES2 program (gl_shader in weston)
+#[derive(Debug)]
+pub struct Shader {
+    program: GLuint,
+    pub proj_uniform: GLint,
+    pub tex_uniform: GLint,
+    pub alpha_uniform: GLint,
+    pub color_uniform: GLint,
+}
+
+/// attribute locations, bound before linking
+pub const POSITION_ATTRIB: GLuint = 0;
+pub const TEXCOORD_ATTRIB: GLuint = 1;
+
+fn compile_shader(shader_type: GLenum, source: &str) -> Result<GLuint, EGLError> {
+    let source = CString::new(source).unwrap();
+    unsafe {
+        let shader = gl::CreateShader(shader_type);
+        gl::ShaderSource(shader, 1, &source.as_ptr(), ptr::null());
+        gl::CompileShader(shader);
+
+        let mut status: GLint = 0;
+        gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+        if status == 0 {
+            let mut log = vec![0u8; 1000];
+            let mut len = 0;
+            gl::GetShaderInfoLog(shader, log.len() as i32, &mut len, log.as_mut_ptr() as *mut _);
+            log.truncate(len as usize);
+            gl::DeleteShader(shader);
+            return Err(EGLError::Shader {
+                message: "failed to compile shader".to_string(),
+                log: String::from_utf8_lossy(&log).into_owned(),
+            });
+        }
+
+        Ok(shader)
+    }
+}
+
+impl Shader {
+    pub fn new(kind: ShaderKind) -> Result<Self, EGLError> {
+        let fragment_source = match kind {
+            ShaderKind::Rgba => TEXTURE_FRAGMENT_SHADER_RGBA,
+            ShaderKind::Rgbx => TEXTURE_FRAGMENT_SHADER_RGBX,
+            ShaderKind::EglExternal => TEXTURE_FRAGMENT_SHADER_EGL_EXTERNAL,
+            ShaderKind::Solid => SOLID_FRAGMENT_SHADER,
+        };
+
+        let vertex_shader = compile_shader(gl::VERTEX_SHADER, VERTEX_SHADER)?;
+        let fragment_shader = match compile_shader(gl::FRAGMENT_SHADER, fragment_source) {
+            Ok(shader) => shader,
+            Err(e) => {
+                unsafe { gl::DeleteShader(vertex_shader) };
+                return Err(e);
+            }
+        };
+
+        unsafe {
+            let program = gl::CreateProgram();
+            gl::AttachShader(program, vertex_shader);
+            gl::AttachShader(program, fragment_shader);
+            gl::BindAttribLocation(program, POSITION_ATTRIB, b"position\0".as_ptr() as *const _);
+            gl::BindAttribLocation(program, TEXCOORD_ATTRIB, b"texcoord\0".as_ptr() as *const _);
+            gl::LinkProgram(program);
+            gl::DeleteShader(vertex_shader);
+            gl::DeleteShader(fragment_shader);
+
+            let mut status: GLint = 0;
+            gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+            if status == 0 {
+                let mut log = vec![0u8; 1000];
+                let mut len = 0;
+                gl::GetProgramInfoLog(program, log.len() as i32, &mut len, log.as_mut_ptr() as *mut _);
+                log.truncate(len as usize);
+                gl::DeleteProgram(program);
+                return Err(EGLError::Shader {
+                    message: format!("failed to link {:?} shader program", kind),
+                    log: String::from_utf8_lossy(&log).into_owned(),
+                });
+            }
+
+            Ok(Self {
+                program,
+                proj_uniform: gl::GetUniformLocation(program, b"proj\0".as_ptr() as *const _),
+                tex_uniform: gl::GetUniformLocation(program, b"tex\0".as_ptr() as *const _),
+                alpha_uniform: gl::GetUniformLocation(program, b"alpha\0".as_ptr() as *const _),
+                color_uniform: gl::GetUniformLocation(program, b"color\0".as_ptr() as *const _),
+            })
+        }
+    }
+
+    pub fn program(&self) -> GLuint {
+        self.program
+    }
+}
+
+impl Drop for Shader {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteProgram(self.program) };
+    }
+}
@@ -0,0 +1,162 @@
+use egl;
+use egl::EGLError;
+use egl::ffi::types::{EGLDisplay, EGLOutputLayerEXT, EGLStreamKHR, EGLSurface, EGLint};
+use gl;
+use gl::types::GLuint;
+
+// missing from the GL 4.5 core bindings
+const TEXTURE_EXTERNAL_OES: gl::types::GLenum = 0x8D65;
+
+/// EGLStream feeding an EGLOutputLayer (a DRM crtc or plane).
+///
+/// The renderer draws in `surface` (the stream producer) and the frames are consumed
+/// by the display engine through `layer`. Created by `EGLRenderer::output_stream_create`.
+#[derive(Debug)]
+pub struct OutputStream {
+    display: EGLDisplay,
+    layer: EGLOutputLayerEXT,
+    stream: EGLStreamKHR,
+    surface: EGLSurface,
+    width: EGLint,
+    height: EGLint,
+}
+
+impl OutputStream {
+    pub fn new(
+        display: EGLDisplay,
+        layer: EGLOutputLayerEXT,
+        stream: EGLStreamKHR,
+        surface: EGLSurface,
+        width: EGLint,
+        height: EGLint,
+    ) -> Self {
+        Self {
+            display,
+            layer,
+            stream,
+            surface,
+            width,
+            height,
+        }
+    }
+
+    pub fn layer(&self) -> EGLOutputLayerEXT {
+        self.layer
+    }
+
+    pub fn stream(&self) -> EGLStreamKHR {
+        self.stream
+    }
+
+    pub fn surface(&self) -> EGLSurface {
+        self.surface
+    }
+
+    pub fn size(&self) -> (EGLint, EGLint) {
+        (self.width, self.height)
+    }
+}
+
+impl Drop for OutputStream {
+    fn drop(&mut self) {
+        unsafe {
+            if egl::ffi::DestroySurface(self.display, self.surface) != egl::ffi::TRUE {
+                log_error!("egl", "FAILED TO DESTROY STREAM SURFACE {:?}", self.surface);
+            }
+            if egl::ffi::DestroyStreamKHR(self.display, self.stream) != egl::ffi::TRUE {
+                log_error!("egl", "FAILED TO DESTROY OUTPUT STREAM {:?}", self.stream);
+            }
+        }
+    }
+}
+
+/// EGLStream attached to a client `wl_buffer` (EGL_WL_wayland_eglstream).
+///
+/// The client is the producer, we consume its frames as an external GL texture.
+#[derive(Debug)]
+pub struct ClientStream {
+    display: EGLDisplay,
+    stream: EGLStreamKHR,
+    texture: GLuint,
+    new_stream: bool,
+}
+
+impl ClientStream {
+    pub fn new(display: EGLDisplay, stream: EGLStreamKHR) -> Result<Self, EGLError> {
+        let mut texture: GLuint = 0;
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(TEXTURE_EXTERNAL_OES, texture);
+        }
+
+        // the stream is now connected to the texture bound to GL_TEXTURE_EXTERNAL_OES
+        let r = unsafe { egl::ffi::StreamConsumerGLTextureExternalKHR(display, stream) };
+        if r != egl::ffi::TRUE {
+            let error = EGLError::from_str("Failed to connect client EGLStream to a GL texture");
+            unsafe {
+                gl::DeleteTextures(1, &texture);
+                egl::ffi::DestroyStreamKHR(display, stream);
+            }
+            return Err(error);
+        }
+
+        Ok(Self {
+            display,
+            stream,
+            texture,
+            new_stream: true,
+        })
+    }
+
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    pub fn target(&self) -> gl::types::GLenum {
+        TEXTURE_EXTERNAL_OES
+    }
+
+    /// Latch the latest client frame into the texture, if there is one.
+    ///
+    /// Returns true if a new frame was acquired.
+    pub fn acquire(&mut self) -> Result<bool, EGLError> {
+        let mut state: EGLint = 0;
+        let r = unsafe { egl::ffi::QueryStreamKHR(
+            self.display,
+            self.stream,
+            egl::ffi::STREAM_STATE_KHR,
+            &mut state,
+        )};
+        if r != egl::ffi::TRUE {
+            return Err(EGLError::from_str("Failed to query client EGLStream state"));
+        }
+
+        if state as egl::ffi::types::EGLenum != egl::ffi::STREAM_STATE_NEW_FRAME_AVAILABLE_KHR {
+            return Ok(false);
+        }
+
+        let r = unsafe { egl::ffi::StreamConsumerAcquireKHR(self.display, self.stream) };
+        if r != egl::ffi::TRUE {
+            return Err(EGLError::from_str("Failed to acquire client EGLStream frame"));
+        }
+
+        self.new_stream = false;
+        Ok(true)
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.new_stream
+    }
+}
+
+impl Drop for ClientStream {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture);
+            if egl::ffi::DestroyStreamKHR(self.display, self.stream) != egl::ffi::TRUE {
+                log_error!("egl", "FAILED TO DESTROY CLIENT STREAM {:?}", self.stream);
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Launcher for running as root (or from a setuid helper) without logind,
+//! weston's launcher-direct.c: the devices are opened directly and the VT
+//! switches are handled with VT_PROCESS signals.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::CString;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use libc;
+use libc::{c_char, c_int, c_short, c_ulong};
+
+use launcher::{device_number, DeviceHandle, Launcher, LauncherError, SessionEvent};
+
+const TTY_MAJOR: u32 = 4;
+const DRM_MAJOR: u32 = 226;
+
+// linux/kd.h
+const KDSETMODE: c_ulong = 0x4B3A;
+const KDGETMODE: c_ulong = 0x4B3B;
+const KD_TEXT: c_int = 0x00;
+const KD_GRAPHICS: c_int = 0x01;
+const KDGKBMODE: c_ulong = 0x4B44;
+const KDSKBMODE: c_ulong = 0x4B45;
+const K_OFF: c_int = 0x04;
+const KDSKBMUTE: c_ulong = 0x4B51;
+
+// linux/vt.h
+const VT_SETMODE: c_ulong = 0x5602;
+const VT_RELDISP: c_ulong = 0x5605;
+const VT_ACTIVATE: c_ulong = 0x5606;
+const VT_WAITACTIVE: c_ulong = 0x5607;
+const VT_AUTO: c_char = 0x00;
+const VT_PROCESS: c_char = 0x01;
+const VT_ACKACQ: c_int = 0x02;
+
+// drm.h, DRM_IO(0x1e) and DRM_IO(0x1f)
+const DRM_IOCTL_SET_MASTER: c_ulong = 0x641E;
+const DRM_IOCTL_DROP_MASTER: c_ulong = 0x641F;
+
+#[repr(C)]
+struct VtMode {
+    mode: c_char,
+    waitv: c_char,
+    relsig: c_short,
+    acqsig: c_short,
+    frsig: c_short,
+}
+
+extern "C" {
+    // SIGRTMIN is a function in glibc, the value depends on the threading library
+    fn __libc_current_sigrtmin() -> c_int;
+}
+
+fn sigrtmin() -> c_int {
+    unsafe { __libc_current_sigrtmin() }
+}
+
+fn fstat(fd: RawFd) -> Result<libc::stat, LauncherError> {
+    let mut file_stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut file_stat) } < 0 {
+        return Err(LauncherError::last_os_error(format!("fail to stat file descriptor {}", fd)));
+    }
+    Ok(file_stat)
+}
+
+pub struct DirectLauncher {
+    tty_fd: RawFd,
+    vt: u32,
+    /// keyboard mode to restore
+    kb_mode: c_int,
+    /// receives the VT release and acquire signal
+    signal_fd: RawFd,
+    /// signal mask to restore, from before SIGRTMIN was blocked
+    sig_mask: libc::sigset_t,
+    /// open DRM devices, they lose master when the VT is released
+    drm_fds: RefCell<Vec<RawFd>>,
+    /// Last state reported through `SessionEvent`
+    active: Cell<bool>,
+}
+
+impl DirectLauncher {
+    /// Take over `tty`, or the terminal on stdin.
+    ///
+    /// Only root can do this, a setuid helper has to call it before dropping
+    /// the privileges.
+    pub fn new(tty: Option<u32>) -> Result<DirectLauncher, LauncherError> {
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(LauncherError::Session("direct: not running as root, cannot set up the VT".to_string()));
+        }
+
+        let tty_fd = match tty {
+            None => unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 0) },
+            Some(tty) => {
+                let path = CString::new(format!("/dev/tty{}", tty)).unwrap();
+                unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) }
+            },
+        };
+        if tty_fd < 0 {
+            return Err(LauncherError::last_os_error("direct: failed to open tty".to_string()));
+        }
+
+        match DirectLauncher::setup_tty(tty_fd) {
+            Ok(launcher) => Ok(launcher),
+            Err(e) => {
+                unsafe { libc::close(tty_fd) };
+                Err(e)
+            },
+        }
+    }
+
+    fn setup_tty(tty_fd: RawFd) -> Result<DirectLauncher, LauncherError> {
+        let tty_stat = fstat(tty_fd)?;
+        let (major, vt) = device_number(tty_stat.st_rdev);
+        if major != TTY_MAJOR || vt == 0 {
+            return Err(LauncherError::Session("direct: not a valid VT".to_string()));
+        }
+
+        let mut kd_mode: c_int = 0;
+        if unsafe { libc::ioctl(tty_fd, KDGETMODE, &mut kd_mode) } < 0 {
+            return Err(LauncherError::last_os_error("direct: failed to get VT mode".to_string()));
+        }
+        if kd_mode != KD_TEXT {
+            return Err(LauncherError::Session("direct: already in graphics mode, is another display server running?".to_string()));
+        }
+
+        unsafe {
+            libc::ioctl(tty_fd, VT_ACTIVATE, vt as c_int);
+            libc::ioctl(tty_fd, VT_WAITACTIVE, vt as c_int);
+        }
+
+        let mut kb_mode: c_int = 0;
+        if unsafe { libc::ioctl(tty_fd, KDGKBMODE, &mut kb_mode) } < 0 {
+            return Err(LauncherError::last_os_error("direct: failed to read keyboard mode".to_string()));
+        }
+        // the input devices are read directly, the keys must not reach the console
+        if unsafe { libc::ioctl(tty_fd, KDSKBMUTE, 1 as c_int) } < 0
+            && unsafe { libc::ioctl(tty_fd, KDSKBMODE, K_OFF) } < 0 {
+            return Err(LauncherError::last_os_error("direct: failed to set K_OFF keyboard mode".to_string()));
+        }
+
+        if unsafe { libc::ioctl(tty_fd, KDSETMODE, KD_GRAPHICS) } < 0 {
+            let error = LauncherError::last_os_error("direct: failed to set KD_GRAPHICS mode on tty".to_string());
+            DirectLauncher::restore_keyboard(tty_fd, kb_mode);
+            return Err(error);
+        }
+
+        // the kernel waits for us to release and acquire the VT
+        let mode = VtMode {
+            mode: VT_PROCESS,
+            waitv: 0,
+            relsig: sigrtmin() as c_short,
+            acqsig: sigrtmin() as c_short,
+            frsig: 0,
+        };
+        if unsafe { libc::ioctl(tty_fd, VT_SETMODE, &mode) } < 0 {
+            let error = LauncherError::last_os_error("direct: failed to take control of vt handling".to_string());
+            DirectLauncher::restore_tty(tty_fd, kb_mode);
+            return Err(error);
+        }
+
+        let mut sig_mask: libc::sigset_t = unsafe { mem::zeroed() };
+        let signal_fd = unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            libc::sigaddset(&mut mask, sigrtmin());
+            libc::sigprocmask(libc::SIG_BLOCK, &mask, &mut sig_mask);
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if signal_fd < 0 {
+            let error = LauncherError::last_os_error("direct: failed to create the VT signal fd".to_string());
+            DirectLauncher::restore_signal_mask(&sig_mask);
+            DirectLauncher::restore_tty(tty_fd, kb_mode);
+            return Err(error);
+        }
+
+        Ok(DirectLauncher {
+            tty_fd,
+            vt,
+            kb_mode,
+            signal_fd,
+            sig_mask,
+            drm_fds: RefCell::new(Vec::new()),
+            active: Cell::new(true),
+        })
+    }
+
+    fn restore_keyboard(tty_fd: RawFd, kb_mode: c_int) {
+        unsafe {
+            if libc::ioctl(tty_fd, KDSKBMUTE, 0 as c_int) < 0
+                && libc::ioctl(tty_fd, KDSKBMODE, kb_mode) < 0 {
+                log_error!("launcher", "direct: failed to restore keyboard mode");
+            }
+        }
+    }
+
+    /// Unblock SIGRTMIN unless it was blocked before, the signals blocked
+    /// since are left alone
+    fn restore_signal_mask(sig_mask: &libc::sigset_t) {
+        unsafe {
+            if libc::sigismember(sig_mask, sigrtmin()) == 0 {
+                let mut mask: libc::sigset_t = mem::zeroed();
+                libc::sigemptyset(&mut mask);
+                libc::sigaddset(&mut mask, sigrtmin());
+                libc::sigprocmask(libc::SIG_UNBLOCK, &mask, ::std::ptr::null_mut());
+            }
+        }
+    }
+
+    fn restore_tty(tty_fd: RawFd, kb_mode: c_int) {
+        DirectLauncher::restore_keyboard(tty_fd, kb_mode);
+
+        let mode = VtMode {
+            mode: VT_AUTO,
+            waitv: 0,
+            relsig: 0,
+            acqsig: 0,
+            frsig: 0,
+        };
+        unsafe {
+            if libc::ioctl(tty_fd, KDSETMODE, KD_TEXT) < 0 {
+                log_error!("launcher", "direct: failed to set KD_TEXT mode on tty");
+            }
+            if libc::ioctl(tty_fd, VT_SETMODE, &mode) < 0 {
+                log_error!("launcher", "direct: could not reset vt handling");
+            }
+        }
+    }
+
+    fn set_drm_master(&self, master: bool) {
+        let request = if master { DRM_IOCTL_SET_MASTER } else { DRM_IOCTL_DROP_MASTER };
+        for &fd in self.drm_fds.borrow().iter() {
+            // the backend could have done it already
+            unsafe { libc::ioctl(fd, request) };
+        }
+    }
+
+    /// The kernel asks to release the VT when active, and tells it is back
+    /// otherwise (vt_handler in weston)
+    fn vt_signal(&self, handler: &mut dyn FnMut(SessionEvent)) {
+        if self.active.get() {
+            self.active.set(false);
+            handler(SessionEvent::Deactivate);
+            self.set_drm_master(false);
+            unsafe { libc::ioctl(self.tty_fd, VT_RELDISP, 1 as c_int) };
+        } else {
+            unsafe { libc::ioctl(self.tty_fd, VT_RELDISP, VT_ACKACQ) };
+            self.set_drm_master(true);
+            self.active.set(true);
+            handler(SessionEvent::Activate);
+        }
+    }
+}
+
+impl Launcher for DirectLauncher {
+    fn seat_name(&self) -> String {
+        // without a seat manager all the devices are ours
+        "seat0".to_string()
+    }
+
+    fn connect(&self) -> Result<(), LauncherError> {
+        // everything is set up by new()
+        Ok(())
+    }
+
+    fn open<'a>(&'a self, device_path: &Path, flags: c_int) -> Result<DeviceHandle<'a>, LauncherError> {
+        let path = CString::new(device_path.as_os_str().to_str().unwrap()).unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(LauncherError::device(device_path,
+                LauncherError::last_os_error("Could not open device".to_string())));
+        }
+
+        let device_stat = match fstat(fd) {
+            Ok(device_stat) => device_stat,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(LauncherError::device(device_path, e));
+            },
+        };
+        if device_number(device_stat.st_rdev).0 == DRM_MAJOR {
+            // the first opener of a DRM device gets master, make sure we have it
+            if unsafe { libc::ioctl(fd, DRM_IOCTL_SET_MASTER) } < 0 {
+                let error = LauncherError::last_os_error("Could not become DRM master".to_string());
+                unsafe { libc::close(fd) };
+                return Err(LauncherError::device(device_path, error));
+            }
+            self.drm_fds.borrow_mut().push(fd);
+        }
+
+        log_info!("launcher", "Using device {}", device_path.as_os_str().to_string_lossy());
+
+        Ok(DeviceHandle::new(self, fd))
+    }
+
+    fn close(&self, fd: RawFd) -> Result<(), LauncherError> {
+        self.drm_fds.borrow_mut().retain(|&drm_fd| drm_fd != fd);
+        if unsafe { libc::close(fd) } < 0 {
+            return Err(LauncherError::last_os_error(format!("Could not close file descriptor {}", fd)));
+        }
+        Ok(())
+    }
+
+    fn activate_vt(&self) -> Result<(), LauncherError> {
+        if unsafe { libc::ioctl(self.tty_fd, VT_ACTIVATE, self.vt as c_int) } < 0 {
+            return Err(LauncherError::last_os_error(format!("direct: could not switch to VT {}", self.vt)));
+        }
+        Ok(())
+    }
+
+    fn restore(&self) {
+        DirectLauncher::restore_tty(self.tty_fd, self.kb_mode);
+        // back in VT_AUTO, the kernel sends no more SIGRTMIN
+        DirectLauncher::restore_signal_mask(&self.sig_mask);
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        Some(self.signal_fd)
+    }
+
+    fn dispatch(&self, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::signalfd_siginfo>();
+            let r = unsafe { libc::read(self.signal_fd, &mut info as *mut _ as *mut libc::c_void, size) };
+            if r < 0 {
+                let error = ::std::io::Error::last_os_error();
+                if error.kind() == ::std::io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                return Err(LauncherError::Io {
+                    context: "direct: could not read the VT signal".to_string(),
+                    source: error,
+                });
+            }
+            if r as usize != size {
+                return Err(LauncherError::Protocol("direct: short read on the VT signal fd".to_string()));
+            }
+            if info.ssi_signo as c_int == sigrtmin() {
+                self.vt_signal(handler);
+            }
+        }
+    }
+}
+
+impl Drop for DirectLauncher {
+    fn drop(&mut self) {
+        self.restore();
+        unsafe {
+            libc::close(self.signal_fd);
+            libc::close(self.tty_fd);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use dbus;
+use libc::c_int;
+
+use systemd::login::LoginError;
+
+#[derive(Debug)]
+pub enum LauncherError {
+    /// A system call failed, or the seatd request failed with errno
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    /// Taking, opening or giving back a device failed
+    Device {
+        path: PathBuf,
+        source: Box<LauncherError>,
+    },
+    /// Talking to logind failed
+    Dbus {
+        context: String,
+        source: dbus::Error,
+    },
+    /// The session or the seat can't be used (not active, no VT, not root...)
+    Session(String),
+    /// Another session holds the seat and did not give it up in time
+    SeatBusy(String),
+    /// Asking sd-login about the session or the seat failed
+    Login(LoginError),
+    /// The session manager sent something we don't understand
+    Protocol(String),
+    /// The file descriptor was not opened by this launcher
+    NotOpen(RawFd),
+    /// No launcher goes by that name
+    Unknown(String),
+    /// None of the launchers could be used, what each one failed with
+    NoLauncher(Vec<(&'static str, LauncherError)>),
+}
+
+impl LauncherError {
+    pub fn last_os_error(context: String) -> Self {
+        LauncherError::Io {
+            context,
+            source: io::Error::last_os_error(),
+        }
+    }
+
+    pub fn from_errno(context: String, errno: c_int) -> Self {
+        LauncherError::Io {
+            context,
+            source: io::Error::from_raw_os_error(errno),
+        }
+    }
+
+    pub fn device(path: &Path, source: LauncherError) -> Self {
+        LauncherError::Device {
+            path: path.to_path_buf(),
+            source: Box::new(source),
+        }
+    }
+
+    /// The errno of the failed system call, if it came to that
+    pub fn errno(&self) -> Option<c_int> {
+        match *self {
+            LauncherError::Io { ref source, .. } => source.raw_os_error(),
+            LauncherError::Device { ref source, .. } => source.errno(),
+            LauncherError::Login(ref e) => e.errno(),
+            _ => None,
+        }
+    }
+
+    /// The device the error is about
+    pub fn device_path(&self) -> Option<&Path> {
+        match *self {
+            LauncherError::Device { ref path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl StdError for LauncherError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            LauncherError::Io { ref source, .. } => Some(source),
+            LauncherError::Device { ref source, .. } => Some(&**source),
+            LauncherError::Dbus { ref source, .. } => Some(source),
+            LauncherError::Login(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LauncherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LauncherError::Io { ref context, ref source } => write!(f, "{}: {}", context, source),
+            LauncherError::Device { ref path, ref source } =>
+                write!(f, "device {}: {}", path.as_os_str().to_string_lossy(), source),
+            LauncherError::Dbus { ref context, ref source } =>
+                write!(f, "{}: {}", context, source.message().unwrap_or("unknown D-Bus error")),
+            LauncherError::Session(ref message) => write!(f, "{}", message),
+            LauncherError::SeatBusy(ref seat) => write!(f, "seat {} is in use by another session", seat),
+            LauncherError::Login(ref e) => write!(f, "{}", e),
+            LauncherError::Protocol(ref message) => write!(f, "{}", message),
+            LauncherError::NotOpen(fd) => write!(f, "File descriptor {} is not an open device", fd),
+            LauncherError::Unknown(ref name) => write!(f, "unknown launcher {}", name),
+            LauncherError::NoLauncher(ref errors) => {
+                write!(f, "no launcher could be used")?;
+                for &(name, ref error) in errors.iter() {
+                    write!(f, "\n{}: {}", name, error)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::ffi::CString;
 use std::mem;
 use std::os::unix::io::RawFd;
@@ -9,14 +12,19 @@ use libc;
 use libc::stat;
 use libc::c_int;
 
-use launcher::Launcher;
-use systemd::login;
+use launcher::{device_number, DeviceHandle, Launcher, LauncherError, SessionEvent};
+use systemd::login::{self, LoginMonitor, MonitorCategory};
 
-macro_rules! dbus_error_to_string_try {
-    ( $dbus_result:expr, $error_string:tt) => {
-            match $dbus_result {
+const DRM_MAJOR: u32 = 226;
+
+/// Longest a call to logind can block, a stuck logind must not hang the compositor
+const DBUS_TIMEOUT_MS: i32 = 2000;
+
+macro_rules! dbus_try {
+    ( $dbus_result:expr, $context:expr ) => {
+        match $dbus_result {
             Ok(r) => r,
-            Err(e) => return Err(format!($error_string, e)),
+            Err(e) => return Err(LauncherError::Dbus { context: $context.to_string(), source: e }),
         }
     };
 }
@@ -31,149 +39,227 @@ macro_rules! dbus_add_match {
 				    $path).as_str()
         ) {
             Ok(r) => r,
-            Err(e) => return Err(
-                format!("cannot dbus match signal \"{}\" {}", $member, e)
-            ),
+            Err(e) => return Err(LauncherError::Dbus {
+                context: format!("cannot dbus match signal \"{}\"", $member),
+                source: e,
+            }),
         }
     };
 }
 
-fn my_stat(path: &Path) -> Result<libc::stat,String> {
+fn my_stat(path: &Path) -> Result<libc::stat, LauncherError> {
     let mut file_stat: libc::stat = unsafe {mem::zeroed()};
 
     let path_string = CString::new(path.as_os_str().to_str().unwrap()).unwrap();
     let r = unsafe {stat(path_string.as_ptr(), &mut file_stat as *mut libc::stat)};
 
     if r < 0 {
-        Err(format!("fail to stat file {}", path.as_os_str().to_string_lossy()))
+        Err(LauncherError::last_os_error(format!("fail to stat file {}", path.as_os_str().to_string_lossy())))
     } else {
         Ok(file_stat)
     }
 }
 
-fn major(rdev: libc::dev_t) -> u32 {
-    (rdev >> 8) as u32
+fn is_type(mode: libc::mode_t, mask: libc::mode_t) -> bool {
+    (mode & mask) != 0
+}
+
+/// What an asynchronous method call was for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingCallKind {
+    /// Get of the Active property of the session
+    GetActive,
 }
 
-fn minor(rdev: libc::dev_t) -> u32 {
-    (rdev & 0xff) as u32
+/// A method call waiting for its reply in `dispatch`
+struct PendingCall {
+    serial: u32,
+    kind: PendingCallKind,
+    deadline: Instant,
 }
 
-fn is_type(mode: libc::mode_t, mask: libc::mode_t) -> bool {
-    (mode & mask) != 0
+/// A device taken from logind
+struct LogindDevice {
+    path: PathBuf,
+    fd: RawFd,
+    /// O_NONBLOCK was requested, kept on the fds logind sends on resume
+    nonblock: bool,
+    /// logind paused the device, it cannot be used until resumed
+    paused: bool,
 }
 
 pub struct LogindLauncher {
+    /// Follow the pause and resume of the DRM device: logind drops DRM master
+    /// when switching away and waits for us to be done with the device.
+    /// Otherwise only the Active property of the session is followed.
     sync_drm: bool,
     seat_name: String,
     session_id: String,
     vt: u32,
     dbus_path: String,
     dbus_conn: dbus::Connection,
-    device_path: Option<PathBuf>,
+    /// open devices by (major, minor)
+    devices: RefCell<HashMap<(u32, u32), LogindDevice>>,
+    /// Last state reported through `SessionEvent`
+    active: Cell<bool>,
+    pending_calls: RefCell<Vec<PendingCall>>,
+    /// sd-login's view of the session and the seat, see `check_session`
+    monitors: Vec<LoginMonitor>,
 }
 
 impl LogindLauncher {
-    pub fn new(tty: Option<u32>, seat_name: String, sync_drm: bool) -> Result<LogindLauncher, String> {
+    /// Use the logind session of this process, which has to be on `seat_name`
+    /// (any seat if empty)
+    pub fn new(tty: Option<u32>, seat_name: String, sync_drm: bool) -> Result<LogindLauncher, LauncherError> {
         //get session
-        let session_id = login::get_session()?.to_string_lossy().into_owned();
+        let session_id = login::get_session().map_err(LauncherError::Login)?;
 
         //get session seat
-        let seat_id = login::session_get_seat(&session_id)?;
+        let seat_id = login::session_get_seat(&session_id).map_err(LauncherError::Login)?;
+        if !seat_name.is_empty() && seat_name != seat_id {
+            return Err(LauncherError::Session(format!("logind: session {} is on seat {}, not on the requested seat {}",
+                    session_id, seat_id, seat_name)));
+        }
+        let seat_name = seat_id;
+
+        if !login::seat_can_graphical(&seat_name).map_err(LauncherError::Login)? {
+            return Err(LauncherError::Session(format!("logind: seat {} has no graphics device", seat_name)));
+        }
+
+        if !login::session_is_active(&session_id).map_err(LauncherError::Login)? {
+            return Err(LauncherError::Session(format!("logind: session {} is not active, start from its VT", session_id)));
+        }
 
         //session get vt and test
-        let vt = login::session_get_vt(&session_id)?;
+        let vt = login::session_get_vt(&session_id).map_err(LauncherError::Login)?;
         match tty {
             Some(tty) => {
                 if vt != tty {
-                    return Err(format!("logind: requested VT --tty={} differs from real session VT {}",
-                            tty, vt));
+                    return Err(LauncherError::Session(format!("logind: requested VT --tty={} differs from real session VT {}",
+                            tty, vt)));
                 }
             }
             None => {}
         }
 
-        let dbus_path = format!("/org/freedesktop/login1/session/{}", &session_id);
-
-        //TODO get wayland event loop
+        // the event loop watches event_fd() and calls dispatch(), nothing
+        // blocks longer than DBUS_TIMEOUT_MS
 
         //create the dbus connection
-        let dbus_conn = dbus::Connection::get_private(dbus::BusType::System).unwrap();
+        let dbus_conn = dbus_try!(
+            dbus::Connection::get_private(dbus::BusType::System),
+            "Cannot connect to the system bus"
+        );
+
+        let mut launcher = LogindLauncher::with_connection(dbus_conn, session_id, seat_name, vt, sync_drm);
+        for &category in [MonitorCategory::Session, MonitorCategory::Seat].iter() {
+            match LoginMonitor::new(category) {
+                Ok(monitor) => launcher.monitors.push(monitor),
+                Err(e) => log_warn!("launcher", "{}", e),
+            }
+        }
+        Ok(launcher)
+    }
+
+    /// Talk to the login1 service on `dbus_conn` about `session_id`, without
+    /// asking sd-login
+    fn with_connection(dbus_conn: dbus::Connection, session_id: String, seat_name: String, vt: u32, sync_drm: bool) -> LogindLauncher {
+        let dbus_path = format!("/org/freedesktop/login1/session/{}", &session_id);
 
-        Ok(LogindLauncher {
+        LogindLauncher {
             sync_drm,
             seat_name,
             session_id,
             vt,
             dbus_path,
             dbus_conn,
-            device_path: None,
-        })
+            devices: RefCell::new(HashMap::new()),
+            active: Cell::new(true),
+            pending_calls: RefCell::new(Vec::new()),
+            monitors: Vec::new(),
+        }
     }
 
-    fn take_device(&self, device_path: &Path) -> Result<(RawFd, bool), String>{
-        let mut message = dbus::Message::new_method_call(
-            "org.freedesktop.login1",
-            &self.dbus_path,
-            "org.freedesktop.login1.Session",
-            "TakeDevice")?;
-
-        let device_stat = my_stat(device_path).unwrap();
+    /// The (major, minor) logind identifies `device_path` with
+    fn device_number(device_path: &Path) -> Result<(u32, u32), LauncherError> {
+        let device_stat = my_stat(device_path)?;
 
         let is_ifchr = is_type(device_stat.st_mode, libc::S_IFCHR);
         if !is_ifchr {
-            panic!("file {} is not character device", device_path.as_os_str().to_string_lossy());
+            return Err(LauncherError::from_errno(
+                format!("file {} is not character device", device_path.as_os_str().to_string_lossy()), libc::ENODEV));
         }
 
-        let major: u32 = major(device_stat.st_rdev);
-        let minor: u32 = minor(device_stat.st_rdev);
-        message = message.append2(major, minor);
+        Ok(device_number(device_stat.st_rdev))
+    }
+
+    fn take_device(&self, major: u32, minor: u32) -> Result<(RawFd, bool), LauncherError>{
+        let message = dbus::Message::new_method_call(
+            "org.freedesktop.login1",
+            &self.dbus_path,
+            "org.freedesktop.login1.Session",
+            "TakeDevice").map_err(LauncherError::Protocol)?
+            .append2(major, minor);
 
         // send the message
-        let reply = dbus_error_to_string_try!(
-            self.dbus_conn.send_with_reply_and_block(message, -1),
-            "Error sending message \"TakeDevice\": {}"
+        let reply = dbus_try!(
+            self.dbus_conn.send_with_reply_and_block(message, DBUS_TIMEOUT_MS),
+            "Error sending message \"TakeDevice\""
         );
 
         let (fd_o, paused_o): (Option<dbus::OwnedFd>, Option<bool>) = reply.get2();
 
         let fd = match fd_o {
             Some(fd) => fd.into_fd(),
-            None => return Err("File descriptor not present in response message".to_string()),
+            None => return Err(LauncherError::Protocol("File descriptor not present in response message".to_string())),
         };
 
         if fd < 0 {
-            return Err("File desciptor invalid".to_string());
+            return Err(LauncherError::Protocol("File desciptor invalid".to_string()));
         }
 
         let paused = match paused_o {
             Some(paused) => paused,
-            None => return Err("Paused boolean value not present in response message".to_string()),
+            None => return Err(LauncherError::Protocol("Paused boolean value not present in response message".to_string())),
         };
 
         Ok((fd, paused))
     }
 
-    fn release_device(&self, device_path: &Path) -> Result<(), String> {
-        let device_stat = my_stat(device_path).unwrap();
-
-        let device_major: u32 = major(device_stat.st_rdev);
-        let device_minor: u32 = minor(device_stat.st_rdev);
-
+    fn release_device(&self, device_major: u32, device_minor: u32) -> Result<(), LauncherError> {
         let message = dbus::Message::new_method_call(
             "org.freedesktop.login1",
             &self.dbus_path,
             "org.freedesktop.login1.Session",
-            "ReleaseDevice")?
+            "ReleaseDevice").map_err(LauncherError::Protocol)?
         .append2(device_major, device_minor);
 
         match self.dbus_conn.send(message) {
             Ok(_) => Ok(()),
-            Err(_) => Err("Error sending message \"Activate\"".to_string())
+            Err(_) => Err(LauncherError::Protocol("Error sending message \"ReleaseDevice\"".to_string()))
         }
     }
 
-    fn setup_dbus(&self) -> Result<(),String> {
+    /// Close the fd of the device and give it back to logind
+    fn close_device(&self, number: (u32, u32)) -> Result<(), LauncherError> {
+        let device = match self.devices.borrow_mut().remove(&number) {
+            Some(device) => device,
+            None => return Err(LauncherError::Protocol(format!("Device {}:{} is not open", number.0, number.1))),
+        };
+
+        unsafe { libc::close(device.fd) };
+        self.release_device(number.0, number.1)
+    }
+
+    /// Whether logind paused the device behind `fd`, None if it is not one
+    /// of ours
+    pub fn is_paused(&self, fd: RawFd) -> Option<bool> {
+        self.devices.borrow().values()
+            .find(|device| device.fd == fd)
+            .map(|device| device.paused)
+    }
+
+    fn setup_dbus(&self) -> Result<(), LauncherError> {
         //rust dbus API missing: add filter
 
         //weston_dbus_add_match_signal
@@ -211,75 +297,273 @@ impl LogindLauncher {
         Ok(())
     }
 
-    fn take_control(&self) -> Result<(),String> {
+    fn take_control(&self) -> Result<(), LauncherError> {
 
         let message = dbus::Message::new_method_call(
             "org.freedesktop.login1",
             &self.dbus_path,
             "org.freedesktop.login1.Session",
-            "TakeControl")?
+            "TakeControl").map_err(LauncherError::Protocol)?
             .append1(false); // force
 
         //dbus_connection_send_with_reply_and_block
-        let _reply = dbus_error_to_string_try!(
-            self.dbus_conn.send_with_reply_and_block(message, -1),
-            "Error sending message \"TakeControl\": {}"
+        let _reply = dbus_try!(
+            self.dbus_conn.send_with_reply_and_block(message, DBUS_TIMEOUT_MS),
+            "Error sending message \"TakeControl\""
         );
 
         Ok(())
     }
 
-    fn release_control(&self) -> Result<(), String> {
+    fn release_control(&self) -> Result<(), LauncherError> {
         let message = dbus::Message::new_method_call(
             "org.freedesktop.login1",
             &self.dbus_path,
             "org.freedesktop.login1.Session",
-            "ReleaseControl")?;
+            "ReleaseControl").map_err(LauncherError::Protocol)?;
 
         match self.dbus_conn.send(message) {
             Ok(_) => Ok(()),
-            Err(_) => Err("Error sending message \"ReleaseControl\"".to_string())
+            Err(_) => Err(LauncherError::Protocol("Error sending message \"ReleaseControl\"".to_string()))
+        }
+    }
+
+    fn pause_device_complete(&self, major: u32, minor: u32) -> Result<(), LauncherError> {
+        let message = dbus::Message::new_method_call(
+            "org.freedesktop.login1",
+            &self.dbus_path,
+            "org.freedesktop.login1.Session",
+            "PauseDeviceComplete").map_err(LauncherError::Protocol)?
+            .append2(major, minor);
+
+        match self.dbus_conn.send(message) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(LauncherError::Protocol("Error sending message \"PauseDeviceComplete\"".to_string()))
+        }
+    }
+
+    /// Ask for the Active property, the answer is handled in `dispatch`
+    fn request_active(&self) -> Result<(), LauncherError> {
+        let message = dbus::Message::new_method_call(
+            "org.freedesktop.login1",
+            &self.dbus_path,
+            "org.freedesktop.DBus.Properties",
+            "Get").map_err(LauncherError::Protocol)?
+            .append2("org.freedesktop.login1.Session", "Active");
+
+        let serial = match self.dbus_conn.send(message) {
+            Ok(serial) => serial,
+            Err(_) => return Err(LauncherError::Protocol("Error sending message \"Get\"".to_string())),
+        };
+        self.pending_calls.borrow_mut().push(PendingCall {
+            serial,
+            kind: PendingCallKind::GetActive,
+            deadline: Instant::now() + Duration::from_millis(DBUS_TIMEOUT_MS as u64),
+        });
+        Ok(())
+    }
+
+    /// The reply (or error) to one of our asynchronous calls
+    fn method_reply(&self, mut message: dbus::Message, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        let serial = match message.get_reply_serial() {
+            Some(serial) => serial,
+            None => return Ok(()),
+        };
+        let kind = {
+            let mut pending_calls = self.pending_calls.borrow_mut();
+            match pending_calls.iter().position(|call| call.serial == serial) {
+                Some(index) => pending_calls.remove(index).kind,
+                None => return Ok(()),
+            }
+        };
+
+        let reply = dbus_try!(
+            message.as_result(),
+            "logind: method call failed"
+        );
+        match kind {
+            PendingCallKind::GetActive => match reply.get1::<dbus::arg::Variant<bool>>() {
+                Some(_) if self.sync_drm => {},
+                Some(active) => self.set_active(active.0, handler),
+                None => return Err(LauncherError::Protocol("Active property not present in response message".to_string())),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Forget the calls logind did not answer in time
+    fn expire_calls(&self) {
+        let now = Instant::now();
+        self.pending_calls.borrow_mut().retain(|call| {
+            if call.deadline > now {
+                return true;
+            }
+            log_warn!("launcher", "logind: no answer to {:?} in {} ms", call.kind, DBUS_TIMEOUT_MS);
+            false
+        });
+    }
+
+    fn set_active(&self, active: bool, handler: &mut dyn FnMut(SessionEvent)) {
+        if self.active.get() == active {
+            return;
+        }
+        self.active.set(active);
+        handler(if active { SessionEvent::Activate } else { SessionEvent::Deactivate });
+    }
+
+    // SessionRemoved(session id, object path)
+    fn session_removed(&self, message: &dbus::Message, handler: &mut dyn FnMut(SessionEvent)) {
+        let session_id: Option<&str> = message.get1();
+        if session_id == Some(self.session_id.as_str()) {
+            log_info!("launcher", "logind: our session got closed");
+            self.active.set(false);
+            handler(SessionEvent::Removed);
+        }
+    }
+
+    // PauseDevice(major, minor, type), type is "pause", "force" or "gone"
+    fn pause_device(&self, message: &dbus::Message, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        let (major, minor, pause_type): (Option<u32>, Option<u32>, Option<&str>) = message.get3();
+        let (major, minor, pause_type) = match (major, minor, pause_type) {
+            (Some(major), Some(minor), Some(pause_type)) => (major, minor, pause_type),
+            _ => return Err(LauncherError::Protocol("cannot parse PauseDevice dbus signal".to_string())),
+        };
+
+        if let Some(device) = self.devices.borrow_mut().get_mut(&(major, minor)) {
+            device.paused = true;
+        }
+
+        if major == DRM_MAJOR && self.sync_drm {
+            self.set_active(false, handler);
+        }
+
+        // "force" and "gone" already happened, only "pause" waits for us
+        if pause_type == "pause" {
+            self.pause_device_complete(major, minor)?;
         }
+
+        Ok(())
+    }
+
+    // ResumeDevice(major, minor, fd)
+    fn resume_device(&self, message: &dbus::Message, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        let (major, minor, fd): (Option<u32>, Option<u32>, Option<dbus::OwnedFd>) = message.get3();
+        let (major, minor, fd) = match (major, minor, fd) {
+            (Some(major), Some(minor), Some(fd)) => (major, minor, fd),
+            _ => return Err(LauncherError::Protocol("cannot parse ResumeDevice dbus signal".to_string())),
+        };
+
+        if let Some(device) = self.devices.borrow_mut().get_mut(&(major, minor)) {
+            device.paused = false;
+            // logind resumes DRM devices on the file we already have, the
+            // others were revoked: the new file takes the place of the old one
+            // so the fd the users hold stays valid. The new fd is closed when dropped.
+            if major != DRM_MAJOR {
+                use std::os::unix::io::AsRawFd;
+                use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
+
+                if unsafe { libc::dup2(fd.as_raw_fd(), device.fd) } < 0 {
+                    return Err(LauncherError::device(&device.path,
+                        LauncherError::last_os_error("cannot replace the fd of the resumed device".to_string())));
+                }
+                if device.nonblock {
+                    unsafe { fcntl(device.fd, F_SETFL, fcntl(device.fd, F_GETFL) | O_NONBLOCK) };
+                }
+            }
+        }
+
+        if major == DRM_MAJOR && self.sync_drm {
+            self.set_active(true, handler);
+        }
+
+        Ok(())
+    }
+
+    // PropertiesChanged(interface, changed properties, invalidated properties)
+    fn properties_changed(&self, message: &dbus::Message, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        use dbus::MessageItem;
+
+        // Active changes before logind resumes the DRM device, the resume says
+        // when it can be used (weston ignores Active too)
+        if self.sync_drm {
+            return Ok(());
+        }
+
+        let items = message.get_items();
+        let interface: Option<&str> = items.get(0).and_then(|item| item.inner().ok());
+        if interface != Some("org.freedesktop.login1.Session") {
+            return Ok(());
+        }
+
+        let changed: &[MessageItem] = items.get(1).and_then(|item| item.inner().ok()).unwrap_or(&[]);
+        for entry in changed {
+            let (name, value) = match entry.inner::<(&MessageItem, &MessageItem)>() {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if name.inner::<&str>() != Ok("Active") {
+                continue;
+            }
+            if let Ok(&MessageItem::Bool(active)) = value.inner::<&MessageItem>() {
+                self.set_active(active, handler);
+                return Ok(());
+            }
+        }
+
+        // only the name was sent, ask for the value
+        let invalidated: &[MessageItem] = items.get(2).and_then(|item| item.inner().ok()).unwrap_or(&[]);
+        if invalidated.iter().any(|name| name.inner::<&str>() == Ok("Active")) {
+            self.request_active()?;
+        }
+
+        Ok(())
     }
 
-    fn activate(&self) -> Result<(),String> {
+    fn activate(&self) -> Result<(), LauncherError> {
         let message = dbus::Message::new_method_call(
             "org.freedesktop.login1",
             &self.dbus_path,
             "org.freedesktop.login1.Session",
-            "Activate")?;
+            "Activate").map_err(LauncherError::Protocol)?;
 
         match self.dbus_conn.send(message) {
             Ok(_) => Ok(()),
-            Err(_) => Err("Error sending message \"Activate\"".to_string())
+            Err(_) => Err(LauncherError::Protocol("Error sending message \"Activate\"".to_string()))
         }
     }
 }
 
 impl Launcher for LogindLauncher {
-    fn connect(&self) -> Result<(), String> {
+    fn seat_name(&self) -> String {
+        self.seat_name.clone()
+    }
+
+    fn connect(&self) -> Result<(), LauncherError> {
         self.setup_dbus()?;
         self.take_control()?;
         self.activate()?;
         Ok(())
     }
 
-    //TODO return something connected to the lifetime of the launcher, because the fd...
-    // shouldn't live more than the lancher (launcher on drop closes the fd)
-    fn open(&mut self, device_path: &Path, flags: c_int) -> Result<RawFd, String> {
+    fn open<'a>(&'a self, device_path: &Path, flags: c_int) -> Result<DeviceHandle<'a>, LauncherError> {
         // logind take device
-        match self.device_path {
-            Some(ref path) => return Err(format!("Device {} already open", &path.as_os_str().to_string_lossy())),
-            None => self.device_path = Some(device_path.to_path_buf()),
+        let number = LogindLauncher::device_number(device_path)
+            .map_err(|e| LauncherError::device(device_path, e))?;
+        if self.devices.borrow().contains_key(&number) {
+            return Err(LauncherError::device(device_path,
+                LauncherError::from_errno("Device already open".to_string(), libc::EBUSY)));
         }
 
-        let (fd, _) = self.take_device(device_path)?;
+        let (fd, paused) = self.take_device(number.0, number.1)
+            .map_err(|e| LauncherError::device(device_path, e))?;
 
         //F_GETFL
         use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
         let mut file_status_flags: c_int = unsafe{fcntl(fd, F_GETFL)};
         if file_status_flags < 0 {
-            return Err("Error reading file flags from device file descriptor".to_string());
+            return Err(LauncherError::device(device_path,
+                LauncherError::last_os_error("Error reading file flags from device file descriptor".to_string())));
         }
 
         /* Compared to weston_launcher_open() we cannot specify the open-mode
@@ -295,57 +579,627 @@ impl Launcher for LogindLauncher {
         //F_SETFL to O_NONBLOCK
         let r = unsafe{fcntl(fd, F_SETFL, file_status_flags)};
         if r < 0 {
-            return Err("Error setting file flags from device file descriptor".to_string());
+            let error = LauncherError::last_os_error("Error setting file flags from device file descriptor".to_string());
+            unsafe { libc::close(fd) };
+            let _ = self.release_device(number.0, number.1);
+            return Err(LauncherError::device(device_path, error));
         }
 
-        println!("Using device {}", device_path.as_os_str().to_string_lossy());
+        log_info!("launcher", "Using device {}", device_path.as_os_str().to_string_lossy());
 
-        Ok(fd)
-    }
+        self.devices.borrow_mut().insert(number, LogindDevice {
+            path: device_path.to_path_buf(),
+            fd,
+            nonblock: flags & O_NONBLOCK != 0,
+            paused,
+        });
 
-    fn close(&mut self) {
-        let mut path = None;
-        mem::swap(&mut path, &mut self.device_path);
+        Ok(DeviceHandle::new(self, fd))
+    }
 
-        match self.release_device(&path.unwrap()).err() {
-            Some(e) => println!("Error closing logind interface: {}", e),
-            None => {}
-        }
+    fn close(&self, fd: RawFd) -> Result<(), LauncherError> {
+        let number = self.devices.borrow().iter()
+            .find(|&(_, device)| device.fd == fd)
+            .map(|(&number, _)| number)
+            .ok_or(LauncherError::NotOpen(fd))?;
+        self.close_device(number)
     }
 
-    fn activate_vt(&self) -> Result<(), String> {
+    fn activate_vt(&self) -> Result<(), LauncherError> {
         let message = dbus::Message::new_method_call(
             "org.freedesktop.login1",
             "/org/freedesktop/login1/seat/self",
             "org.freedesktop.login1.Seat",
-            "SwitchTo")?
+            "SwitchTo").map_err(LauncherError::Protocol)?
             .append1(&self.vt);
 
         match self.dbus_conn.send(message) {
             Ok(_) => Ok(()),
-            Err(_) => Err("Error sending message \"Activate\"".to_string())
+            Err(_) => Err(LauncherError::Protocol("Error sending message \"Activate\"".to_string()))
         }
     }
 
     fn restore(&self) {
 
     }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        self.dbus_conn.watch_fds().iter()
+            .find(|watch| watch.readable())
+            .map(|watch| watch.fd())
+    }
+
+    fn dispatch(&self, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        // a zero timeout only reads what is already there
+        let messages: Vec<dbus::Message> = self.dbus_conn.incoming(0).collect();
+
+        for message in messages {
+            match message.msg_type() {
+                dbus::MessageType::Signal => {},
+                dbus::MessageType::MethodReturn | dbus::MessageType::Error => {
+                    self.method_reply(message, handler)?;
+                    continue;
+                },
+                _ => continue,
+            }
+            let (_, _, interface, member) = message.headers();
+            let (interface, member) = match (interface, member) {
+                (Some(interface), Some(member)) => (interface, member),
+                _ => continue,
+            };
+
+            match (interface.as_str(), member.as_str()) {
+                ("org.freedesktop.login1.Manager", "SessionRemoved") =>
+                    self.session_removed(&message, handler),
+                ("org.freedesktop.login1.Session", "PauseDevice") =>
+                    self.pause_device(&message, handler)?,
+                ("org.freedesktop.login1.Session", "ResumeDevice") =>
+                    self.resume_device(&message, handler)?,
+                ("org.freedesktop.DBus.Properties", "PropertiesChanged") =>
+                    self.properties_changed(&message, handler)?,
+                _ => {},
+            }
+        }
+        self.expire_calls();
+
+        Ok(())
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.pending_calls.borrow().iter()
+            .map(|call| if call.deadline > now { call.deadline - now } else { Duration::from_millis(0) })
+            .min()
+    }
+
+    fn monitor_fds(&self) -> Vec<RawFd> {
+        self.monitors.iter()
+            .filter_map(|monitor| monitor.fd().map_err(|e| log_warn!("launcher", "{}", e)).ok())
+            .collect()
+    }
+
+    fn check_session(&self, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        let mut changed = Vec::new();
+        for monitor in self.monitors.iter() {
+            if monitor.is_ready().map_err(LauncherError::Login)? {
+                monitor.dispatch(&mut |category| changed.push(category)).map_err(LauncherError::Login)?;
+            }
+        }
+
+        if changed.contains(&MonitorCategory::Session) && !self.sync_drm {
+            // the signals can be late or lost, sd-login reads logind's state files
+            let active = login::session_is_active(&self.session_id).map_err(LauncherError::Login)?;
+            self.set_active(active, handler);
+        }
+        if changed.contains(&MonitorCategory::Seat) {
+            if !login::seat_can_graphical(&self.seat_name).map_err(LauncherError::Login)? {
+                log_warn!("launcher", "logind: seat {} has no graphics device anymore", self.seat_name);
+            }
+            handler(SessionEvent::DevicesChanged);
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for LogindLauncher {
     fn drop(&mut self) {
+        // the handles give their devices back before the launcher goes, nothing
+        // should be left here; what is goes back while we still have control
+        let numbers: Vec<(u32, u32)> = self.devices.borrow().keys().cloned().collect();
+        for number in numbers {
+            if let Err(e) = self.close_device(number) {
+                log_error!("launcher", "Error closing logind interface: {}", e);
+            }
+        }
+
         match self.release_control() {
-            Err(message) => println!("{}", message),
+            Err(message) => log_error!("launcher", "{}", message),
             _ => {}
-        }; 
-
-        //maybe close?
-        match self.device_path {
-            Some(_) => self.close(),
-            None => {},
-        }
+        };
 
         //self.dbus_conn is release as soon as it's dropped
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+    use std::process::{self, Child, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::env;
+
+    use dbus;
+    use libc;
+
+    use launcher::{Launcher, SessionEvent};
+    use super::*;
+
+    const SESSION_ID: &str = "7";
+
+    /// A private bus, torn down with the struct
+    struct DbusDaemon {
+        child: Child,
+        address: String,
+        config: PathBuf,
+    }
+
+    impl DbusDaemon {
+        /// Fails the test when dbus-daemon can't run, unless
+        /// RUDIMENTO_SKIP_DBUS_TESTS is set: then None and the test is skipped
+        fn start(name: &str) -> Option<DbusDaemon> {
+            let config = env::temp_dir().join(format!("rudimento-bus-{}-{}.conf", name, process::id()));
+            fs::write(&config, format!(
+                "<busconfig>\
+                   <type>session</type>\
+                   <listen>unix:tmpdir={}</listen>\
+                   <policy context=\"default\">\
+                     <allow send_destination=\"*\" eavesdrop=\"true\"/>\
+                     <allow eavesdrop=\"true\"/>\
+                     <allow own=\"*\"/>\
+                   </policy>\
+                 </busconfig>",
+                env::temp_dir().to_string_lossy())).unwrap();
+
+            let mut child = match process::Command::new("dbus-daemon")
+                .arg(format!("--config-file={}", config.to_string_lossy()))
+                .args(&["--nofork", "--nopidfile", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    let _ = fs::remove_file(&config);
+                    if env::var_os("RUDIMENTO_SKIP_DBUS_TESTS").is_some() {
+                        eprintln!("dbus-daemon not available, skipping: {}", e);
+                        return None;
+                    }
+                    panic!("cannot run dbus-daemon (set RUDIMENTO_SKIP_DBUS_TESTS to skip the logind tests): {}", e);
+                }
+            };
+
+            let mut address = String::new();
+            BufReader::new(child.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Some(DbusDaemon {
+                child,
+                address: address.trim().to_string(),
+                config,
+            })
+        }
+
+        fn connect(&self) -> dbus::Connection {
+            let conn = dbus::Connection::open_private(&self.address).unwrap();
+            conn.register().unwrap();
+            conn
+        }
+    }
+
+    impl Drop for DbusDaemon {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = fs::remove_file(&self.config);
+        }
+    }
+
+    /// A file standing in for the device, read back to tell which fd the
+    /// launcher holds
+    fn device_file(content: &str) -> RawFd {
+        let path = env::temp_dir().join(format!("rudimento-device-{}-{}", process::id(), content));
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        fs::remove_file(&path).unwrap();
+        file.into_raw_fd()
+    }
+
+    fn read_device(fd: RawFd) -> String {
+        let mut file = unsafe { fs::File::from_raw_fd(libc::dup(fd)) };
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        content
+    }
+
+    enum Signal {
+        PauseDevice(u32, u32, &'static str),
+        ResumeDevice(u32, u32, &'static str),
+        /// PropertiesChanged with the new value
+        ActiveChanged(bool),
+        /// PropertiesChanged listing Active as invalidated, the value is only
+        /// in the Get answer
+        ActiveInvalidated(bool),
+        SessionRemoved,
+    }
+
+    /// org.freedesktop.login1 for one session, recording the calls it gets
+    struct FakeLogind {
+        thread: Option<thread::JoinHandle<()>>,
+        calls: Arc<Mutex<Vec<String>>>,
+        commands: mpsc::Sender<Signal>,
+        /// stop answering method calls
+        stalled: Arc<AtomicBool>,
+        /// methods answered with an error
+        refused: Arc<Mutex<Vec<&'static str>>>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl FakeLogind {
+        fn start(bus: &DbusDaemon) -> FakeLogind {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let stalled = Arc::new(AtomicBool::new(false));
+            let refused = Arc::new(Mutex::new(Vec::new()));
+            let stop = Arc::new(AtomicBool::new(false));
+            let (commands, receiver) = mpsc::channel();
+            let (ready_sender, ready) = mpsc::channel();
+
+            let address = bus.address.clone();
+            let thread_calls = calls.clone();
+            let thread_stalled = stalled.clone();
+            let thread_refused = refused.clone();
+            let thread_stop = stop.clone();
+            let thread = thread::spawn(move || {
+                let conn = dbus::Connection::open_private(&address).unwrap();
+                conn.register().unwrap();
+                let session_path = format!("/org/freedesktop/login1/session/{}", SESSION_ID);
+                // otherwise dbus-rs answers the calls with UnknownMethod itself
+                conn.register_object_path(&session_path).unwrap();
+                conn.register_name("org.freedesktop.login1", 0).unwrap();
+                ready_sender.send(()).unwrap();
+
+                let mut active = true;
+                while !thread_stop.load(Ordering::SeqCst) {
+                    for message in conn.incoming(20) {
+                        if message.msg_type() != dbus::MessageType::MethodCall {
+                            continue;
+                        }
+                        let member = message.member().map(|member| member.to_string()).unwrap_or_default();
+                        let reply = match member.as_str() {
+                            "TakeDevice" | "ReleaseDevice" | "PauseDeviceComplete" => {
+                                let (major, minor): (Option<u32>, Option<u32>) = message.get2();
+                                thread_calls.lock().unwrap().push(format!("{} {}:{}", member, major.unwrap(), minor.unwrap()));
+                                if member == "TakeDevice" {
+                                    let fd = device_file(&format!("take-{}-{}", major.unwrap(), minor.unwrap()));
+                                    message.method_return().append2(dbus::OwnedFd::new(fd), false)
+                                } else {
+                                    message.method_return()
+                                }
+                            },
+                            "Get" => {
+                                thread_calls.lock().unwrap().push(member.clone());
+                                message.method_return().append1(dbus::arg::Variant(active))
+                            },
+                            _ => {
+                                thread_calls.lock().unwrap().push(member.clone());
+                                message.method_return()
+                            },
+                        };
+
+                        if thread_stalled.load(Ordering::SeqCst) {
+                            continue;
+                        }
+                        let reply = if thread_refused.lock().unwrap().contains(&member.as_str()) {
+                            dbus::Message::new_error(&message, "org.freedesktop.login1.NotInControl", "refused").unwrap()
+                        } else {
+                            reply
+                        };
+                        conn.send(reply).unwrap();
+                    }
+
+                    while let Ok(command) = receiver.try_recv() {
+                        let signal = match command {
+                            Signal::PauseDevice(major, minor, pause_type) =>
+                                dbus::Message::new_signal(session_path.as_str(), "org.freedesktop.login1.Session", "PauseDevice")
+                                    .unwrap().append3(major, minor, pause_type),
+                            Signal::ResumeDevice(major, minor, content) =>
+                                dbus::Message::new_signal(session_path.as_str(), "org.freedesktop.login1.Session", "ResumeDevice")
+                                    .unwrap().append3(major, minor, dbus::OwnedFd::new(device_file(content))),
+                            Signal::ActiveChanged(new_active) => {
+                                active = new_active;
+                                let mut changed = HashMap::new();
+                                changed.insert("Active", dbus::arg::Variant(active));
+                                dbus::Message::new_signal(session_path.as_str(), "org.freedesktop.DBus.Properties", "PropertiesChanged")
+                                    .unwrap().append3("org.freedesktop.login1.Session", changed, Vec::<&str>::new())
+                            },
+                            Signal::ActiveInvalidated(new_active) => {
+                                active = new_active;
+                                dbus::Message::new_signal(session_path.as_str(), "org.freedesktop.DBus.Properties", "PropertiesChanged")
+                                    .unwrap().append3("org.freedesktop.login1.Session",
+                                                      HashMap::<&str, dbus::arg::Variant<bool>>::new(), vec!["Active"])
+                            },
+                            Signal::SessionRemoved =>
+                                dbus::Message::new_signal("/org/freedesktop/login1", "org.freedesktop.login1.Manager", "SessionRemoved")
+                                    .unwrap().append2(SESSION_ID, dbus::Path::new(session_path.as_str()).unwrap()),
+                        };
+                        conn.send(signal).unwrap();
+                    }
+                }
+            });
+            ready.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            FakeLogind {
+                thread: Some(thread),
+                calls,
+                commands,
+                stalled,
+                refused,
+                stop,
+            }
+        }
+
+        fn send(&self, command: Signal) {
+            self.commands.send(command).unwrap();
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        /// Wait for `call` to arrive, the launcher sends some without waiting
+        fn wait_for(&self, call: &str) -> bool {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                if self.calls().iter().any(|c| c == call) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        }
+    }
+
+    impl Drop for FakeLogind {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn launcher(bus: &DbusDaemon) -> LogindLauncher {
+        LogindLauncher::with_connection(bus.connect(), SESSION_ID.to_string(), "seat0".to_string(), 1, false)
+    }
+
+    /// Take DRM device 226:0 as `open` would, there is no card to stat here
+    fn take_drm_device(launcher: &LogindLauncher) -> RawFd {
+        let (fd, paused) = launcher.take_device(DRM_MAJOR, 0).unwrap();
+        launcher.devices.borrow_mut().insert((DRM_MAJOR, 0), LogindDevice {
+            path: PathBuf::from("/dev/dri/card0"),
+            fd,
+            nonblock: false,
+            paused,
+        });
+        fd
+    }
+
+    /// Dispatch until some session event shows up (or 5s pass)
+    fn dispatch_until_event(launcher: &LogindLauncher) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.is_empty() && Instant::now() < deadline {
+            let mut pollfd = libc::pollfd { fd: launcher.event_fd().unwrap(), events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut pollfd, 1, 50) };
+            launcher.dispatch(&mut |event| events.push(event)).unwrap();
+        }
+        events
+    }
+
+    /// Dispatch for a while, for the effects that come without event
+    fn dispatch_for(launcher: &LogindLauncher, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            let mut pollfd = libc::pollfd { fd: launcher.event_fd().unwrap(), events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut pollfd, 1, 20) };
+            launcher.dispatch(&mut |_| {}).unwrap();
+        }
+    }
+
+    fn dev_null() -> (u32, u32) {
+        LogindLauncher::device_number(Path::new("/dev/null")).unwrap()
+    }
+
+    #[test]
+    fn take_and_release_devices() {
+        let bus = match DbusDaemon::start("lifecycle") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+        let (major, minor) = dev_null();
+
+        {
+            let launcher = launcher(&bus);
+            launcher.connect().unwrap();
+            assert!(logind.wait_for("TakeControl"));
+            assert!(logind.wait_for("Activate"));
+
+            {
+                let device = launcher.open(Path::new("/dev/null"), libc::O_RDWR | libc::O_NONBLOCK).unwrap();
+                assert_eq!(read_device(device.as_raw_fd()), format!("take-{}-{}", major, minor));
+                assert!(unsafe { libc::fcntl(device.as_raw_fd(), libc::F_GETFL) } & libc::O_NONBLOCK != 0);
+                assert_eq!(launcher.is_paused(device.as_raw_fd()), Some(false));
+                assert!(launcher.open(Path::new("/dev/null"), libc::O_RDWR).is_err());
+            }
+            assert!(logind.wait_for(&format!("ReleaseDevice {}:{}", major, minor)));
+        }
+        assert!(logind.wait_for("ReleaseControl"));
+    }
+
+    #[test]
+    fn devices_left_are_released_before_control() {
+        let bus = match DbusDaemon::start("teardown") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+
+        {
+            let launcher = launcher(&bus);
+            launcher.connect().unwrap();
+            assert!(logind.wait_for("TakeControl"));
+            take_drm_device(&launcher);
+        }
+        assert!(logind.wait_for("ReleaseControl"));
+        let calls = logind.calls();
+        let release_device = calls.iter().position(|c| *c == format!("ReleaseDevice {}:0", DRM_MAJOR));
+        let release_control = calls.iter().position(|c| c == "ReleaseControl");
+        assert!(release_device.is_some());
+        assert!(release_device < release_control);
+    }
+
+    #[test]
+    fn pause_and_resume_device() {
+        let bus = match DbusDaemon::start("pause") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+        let (major, minor) = dev_null();
+
+        let launcher = launcher(&bus);
+        launcher.connect().unwrap();
+        let device = launcher.open(Path::new("/dev/null"), libc::O_RDWR | libc::O_NONBLOCK).unwrap();
+
+        // a revoked input device: no session event, only the device state
+        logind.send(Signal::PauseDevice(major, minor, "pause"));
+        dispatch_for(&launcher, Duration::from_millis(300));
+        assert!(logind.wait_for(&format!("PauseDeviceComplete {}:{}", major, minor)));
+        assert_eq!(launcher.is_paused(device.as_raw_fd()), Some(true));
+
+        // the new file takes the place of the old one, flags included
+        logind.send(Signal::ResumeDevice(major, minor, "resumed"));
+        dispatch_for(&launcher, Duration::from_millis(300));
+        assert_eq!(launcher.is_paused(device.as_raw_fd()), Some(false));
+        assert_eq!(read_device(device.as_raw_fd()), "resumed");
+        assert!(unsafe { libc::fcntl(device.as_raw_fd(), libc::F_GETFL) } & libc::O_NONBLOCK != 0);
+
+        // "force" already happened, nothing to acknowledge
+        logind.send(Signal::PauseDevice(major, minor, "force"));
+        dispatch_for(&launcher, Duration::from_millis(300));
+        assert_eq!(launcher.is_paused(device.as_raw_fd()), Some(true));
+        assert_eq!(logind.calls().iter().filter(|call| call.starts_with("PauseDeviceComplete")).count(), 1);
+    }
+
+    #[test]
+    fn drm_device_follows_the_session() {
+        let bus = match DbusDaemon::start("drm") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+
+        let launcher = LogindLauncher::with_connection(bus.connect(), SESSION_ID.to_string(), "seat0".to_string(), 1, true);
+        launcher.connect().unwrap();
+        let fd = take_drm_device(&launcher);
+        assert!(logind.wait_for(&format!("TakeDevice {}:0", DRM_MAJOR)));
+
+        // the compositor lets go of the device before logind may take it
+        logind.send(Signal::PauseDevice(DRM_MAJOR, 0, "pause"));
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.is_empty() && Instant::now() < deadline {
+            let mut pollfd = libc::pollfd { fd: launcher.event_fd().unwrap(), events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut pollfd, 1, 50) };
+            launcher.dispatch(&mut |event| {
+                let completed = logind.calls().iter().any(|call| call.starts_with("PauseDeviceComplete"));
+                events.push((event, completed));
+            }).unwrap();
+        }
+        assert_eq!(events, vec![(SessionEvent::Deactivate, false)]);
+        assert!(logind.wait_for(&format!("PauseDeviceComplete {}:0", DRM_MAJOR)));
+        assert_eq!(launcher.is_paused(fd), Some(true));
+
+        // Active comes before the device can be used again, it is not followed
+        logind.send(Signal::ActiveChanged(true));
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_millis(300);
+        while Instant::now() < deadline {
+            let mut pollfd = libc::pollfd { fd: launcher.event_fd().unwrap(), events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut pollfd, 1, 20) };
+            launcher.dispatch(&mut |event| events.push(event)).unwrap();
+        }
+        assert_eq!(events, vec![]);
+
+        // DRM resumes on the fd we have, the one sent along is dropped
+        logind.send(Signal::ResumeDevice(DRM_MAJOR, 0, "resumed-drm"));
+        assert_eq!(dispatch_until_event(&launcher), vec![SessionEvent::Activate]);
+        assert_eq!(launcher.is_paused(fd), Some(false));
+        assert_eq!(read_device(fd), format!("take-{}-0", DRM_MAJOR));
+    }
+
+    #[test]
+    fn follow_the_active_property() {
+        let bus = match DbusDaemon::start("active") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+
+        let launcher = launcher(&bus);
+        launcher.connect().unwrap();
+
+        logind.send(Signal::ActiveChanged(false));
+        assert_eq!(dispatch_until_event(&launcher), vec![SessionEvent::Deactivate]);
+
+        // only invalidated: the value comes from an asynchronous Get
+        logind.send(Signal::ActiveInvalidated(true));
+        assert_eq!(dispatch_until_event(&launcher), vec![SessionEvent::Activate]);
+        assert!(logind.calls().iter().any(|call| call == "Get"));
+        assert_eq!(launcher.timeout(), None);
+
+        logind.send(Signal::SessionRemoved);
+        assert_eq!(dispatch_until_event(&launcher), vec![SessionEvent::Removed]);
+    }
+
+    #[test]
+    fn refused_calls_are_errors() {
+        let bus = match DbusDaemon::start("refused") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+        logind.refused.lock().unwrap().push("TakeControl");
+
+        let launcher = launcher(&bus);
+        assert!(launcher.connect().is_err());
+
+        logind.refused.lock().unwrap().push("TakeDevice");
+        assert!(launcher.open(Path::new("/dev/null"), libc::O_RDWR).is_err());
+        assert!(launcher.devices.borrow().is_empty());
+    }
+
+    #[test]
+    fn stuck_logind_does_not_block() {
+        let bus = match DbusDaemon::start("stuck") { Some(bus) => bus, None => return };
+        let logind = FakeLogind::start(&bus);
+
+        let launcher = launcher(&bus);
+        launcher.connect().unwrap();
+        logind.stalled.store(true, Ordering::SeqCst);
+
+        let start = Instant::now();
+        assert!(launcher.open(Path::new("/dev/null"), libc::O_RDWR).is_err());
+        assert!(start.elapsed() < Duration::from_millis(DBUS_TIMEOUT_MS as u64 * 2));
+
+        // the Get answer never comes, the call is given up on its deadline
+        logind.send(Signal::ActiveInvalidated(false));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while launcher.timeout().is_none() && Instant::now() < deadline {
+            dispatch_for(&launcher, Duration::from_millis(20));
+        }
+        assert!(launcher.timeout().unwrap() <= Duration::from_millis(DBUS_TIMEOUT_MS as u64));
+        dispatch_for(&launcher, Duration::from_millis(DBUS_TIMEOUT_MS as u64 + 100));
+        assert_eq!(launcher.timeout(), None);
+    }
+}
@@ -1,15 +1,106 @@
+pub mod direct;
 pub mod logind;
+pub mod seatd;
+mod error;
 
+pub use self::error::LauncherError;
+
+use std::fmt;
 use std::path::Path;
-use std::os::unix::io::RawFd;
+use std::time::Duration;
+use std::os::unix::io::{AsRawFd, RawFd};
 
+use libc;
 use libc::c_int;
 
+/// The (major, minor) of a device number, as glibc's major() and minor()
+/// encode them: the bits above 8 and 20 continue at 32 and 44.
+fn device_number(rdev: libc::dev_t) -> (u32, u32) {
+    unsafe { (libc::major(rdev), libc::minor(rdev)) }
+}
+
+/// Session changes the launcher reports while dispatching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The session is in the foreground again (VT switch back), the devices
+    /// can be used
+    Activate,
+    /// The session went to the background, the devices must be left alone
+    Deactivate,
+    /// The session was closed, the compositor has to exit
+    Removed,
+    /// Devices were added to or removed from the seat, the outputs may have
+    /// changed
+    DevicesChanged,
+}
+
 pub trait Launcher {
-    fn connect(&self) -> Result<(), String> ;
+    /// The seat the devices are taken from
+    fn seat_name(&self) -> String;
+    fn connect(&self) -> Result<(), LauncherError>;
     //fn destroy(&self);
-    fn open(&mut self, device_path: &Path, flags: c_int) -> Result<RawFd, String>;
-    fn close(&mut self);
-    fn activate_vt(&self) -> Result<(), String>;
+    fn open<'a>(&'a self, device_path: &Path, flags: c_int) -> Result<DeviceHandle<'a>, LauncherError>;
+    /// Give back a device, `DeviceHandle` does it when dropped
+    fn close(&self, fd: RawFd) -> Result<(), LauncherError>;
+    fn activate_vt(&self) -> Result<(), LauncherError>;
     fn restore(&self);
+    /// File descriptor to watch on the event loop, `dispatch` when it is readable
+    fn event_fd(&self) -> Option<RawFd>;
+    /// Process what the session manager sent.
+    ///
+    /// `handler` runs before the launcher answers the session manager, the
+    /// devices must be quiet when it returns from a `Deactivate`.
+    fn dispatch(&self, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError>;
+    /// Time left before something is due even without input (a request timing
+    /// out), `dispatch` then
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    /// File descriptors of the session and seat monitors to watch on the
+    /// event loop, `check_session` when one is readable
+    fn monitor_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+    /// Look at the session and seat again after a monitor woke up
+    fn check_session(&self, _handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        Ok(())
+    }
+}
+
+/// A device opened through a launcher.
+///
+/// The launcher owns the file descriptor: the handle gives it back when
+/// dropped and cannot outlive the launcher.
+pub struct DeviceHandle<'a> {
+    launcher: &'a dyn Launcher,
+    fd: RawFd,
+}
+
+impl<'a> DeviceHandle<'a> {
+    fn new(launcher: &'a dyn Launcher, fd: RawFd) -> Self {
+        Self {
+            launcher,
+            fd,
+        }
+    }
+}
+
+impl<'a> AsRawFd for DeviceHandle<'a> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<'a> fmt::Debug for DeviceHandle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeviceHandle({})", self.fd)
+    }
+}
+
+impl<'a> Drop for DeviceHandle<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.launcher.close(self.fd) {
+            log_error!("launcher", "Error closing device: {}", e);
+        }
+    }
 }
@@ -0,0 +1,637 @@
+//! Launcher talking to seatd over its unix socket, with the protocol libseat
+//! uses: every message is a header (opcode, payload size) followed by the
+//! payload, in native byte order. Device fds come with SCM_RIGHTS.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use libc;
+use libc::c_int;
+
+use launcher::{DeviceHandle, Launcher, LauncherError, SessionEvent};
+
+const DEFAULT_SOCKET: &str = "/run/seatd.sock";
+
+/// How long to wait for seatd to answer a request
+const REQUEST_TIMEOUT_MS: c_int = 5000;
+
+const CLIENT_OPEN_SEAT: u16 = 1;
+const CLIENT_CLOSE_SEAT: u16 = 2;
+const CLIENT_OPEN_DEVICE: u16 = 3;
+const CLIENT_CLOSE_DEVICE: u16 = 4;
+const CLIENT_DISABLE_SEAT: u16 = 5;
+const CLIENT_SWITCH_SESSION: u16 = 6;
+const CLIENT_PING: u16 = 7;
+
+const SERVER_SEAT_OPENED: u16 = 1 + (1 << 15);
+const SERVER_SEAT_CLOSED: u16 = 2 + (1 << 15);
+const SERVER_DEVICE_OPENED: u16 = 3 + (1 << 15);
+const SERVER_DEVICE_CLOSED: u16 = 4 + (1 << 15);
+const SERVER_DISABLE_SEAT: u16 = 5 + (1 << 15);
+const SERVER_ENABLE_SEAT: u16 = 6 + (1 << 15);
+const SERVER_PONG: u16 = 7 + (1 << 15);
+const SERVER_ERROR: u16 = 0x7FFF + (1 << 15);
+
+const HEADER_SIZE: usize = 4;
+/// fds a single read can bring
+const MAX_FDS: usize = 8;
+
+fn u16_at(payload: &[u8], offset: usize) -> Option<u16> {
+    payload.get(offset..offset + 2).map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+}
+
+fn i32_at(payload: &[u8], offset: usize) -> Option<i32> {
+    payload.get(offset..offset + 4).map(|bytes| i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// The socket with what was read but not consumed yet
+struct Connection {
+    socket: UnixStream,
+    in_buf: Vec<u8>,
+    in_fds: VecDeque<RawFd>,
+}
+
+impl Connection {
+    fn send(&mut self, opcode: u16, payload: &[u8]) -> Result<(), LauncherError> {
+        let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+        message.extend_from_slice(&opcode.to_ne_bytes());
+        message.extend_from_slice(&(payload.len() as u16).to_ne_bytes());
+        message.extend_from_slice(payload);
+        self.socket.write_all(&message)
+            .map_err(|e| LauncherError::Io {
+                context: format!("seatd: could not send message {}", opcode),
+                source: e,
+            })
+    }
+
+    /// Read what seatd sent, waiting at most `timeout_ms` for it.
+    ///
+    /// Returns false if nothing arrived.
+    fn read(&mut self, timeout_ms: c_int) -> Result<bool, LauncherError> {
+        let fd = self.socket.as_raw_fd();
+
+        let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        let r = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if r < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(LauncherError::Io { context: "seatd: poll failed".to_string(), source: error });
+        }
+        if r == 0 {
+            return Ok(false);
+        }
+
+        let mut data = [0u8; 4096];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let control_len = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<c_int>()) as u32) } as usize;
+        let mut control = vec![0u8; control_len];
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control_len as _;
+
+        let size = unsafe { libc::recvmsg(fd, &mut message, libc::MSG_CMSG_CLOEXEC | libc::MSG_DONTWAIT) };
+        if size < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(LauncherError::Io { context: "seatd: could not read from socket".to_string(), source: error });
+        }
+        if size == 0 {
+            return Err(LauncherError::Session("seatd: the server closed the connection".to_string()));
+        }
+        self.in_buf.extend_from_slice(&data[..size as usize]);
+
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let fds = libc::CMSG_DATA(cmsg) as *const c_int;
+                    for index in 0..data_len / mem::size_of::<c_int>() {
+                        self.in_fds.push_back(*fds.add(index));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The next complete message, (opcode, payload)
+    fn next_message(&mut self) -> Option<(u16, Vec<u8>)> {
+        let opcode = u16_at(&self.in_buf, 0)?;
+        let size = u16_at(&self.in_buf, 2)? as usize;
+        if self.in_buf.len() < HEADER_SIZE + size {
+            return None;
+        }
+        let payload = self.in_buf[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+        self.in_buf.drain(..HEADER_SIZE + size);
+        Some((opcode, payload))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for fd in self.in_fds.drain(..) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+pub struct SeatdLauncher {
+    connection: RefCell<Connection>,
+    seat_name: RefCell<String>,
+    /// session to switch back to with `activate_vt`
+    vt: Option<u32>,
+    /// fd -> seatd device id
+    devices: RefCell<HashMap<RawFd, i32>>,
+    /// seat enable and disable events that arrived while waiting for a reply
+    pending_events: RefCell<VecDeque<u16>>,
+    seat_open: Cell<bool>,
+    /// Last state reported through `SessionEvent`
+    active: Cell<bool>,
+}
+
+impl SeatdLauncher {
+    /// Connect to the socket in `SEATD_SOCK`, or the default one
+    pub fn new(tty: Option<u32>) -> Result<SeatdLauncher, LauncherError> {
+        let path = env::var_os("SEATD_SOCK")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SOCKET));
+        SeatdLauncher::with_socket(&path, tty)
+    }
+
+    pub fn with_socket(path: &Path, tty: Option<u32>) -> Result<SeatdLauncher, LauncherError> {
+        let socket = UnixStream::connect(path)
+            .map_err(|e| LauncherError::Io {
+                context: format!("seatd: could not connect to {}", path.as_os_str().to_string_lossy()),
+                source: e,
+            })?;
+
+        Ok(SeatdLauncher {
+            connection: RefCell::new(Connection {
+                socket,
+                in_buf: Vec::new(),
+                in_fds: VecDeque::new(),
+            }),
+            seat_name: RefCell::new(String::new()),
+            vt: tty,
+            devices: RefCell::new(HashMap::new()),
+            pending_events: RefCell::new(VecDeque::new()),
+            seat_open: Cell::new(false),
+            active: Cell::new(false),
+        })
+    }
+
+    /// Send a request and wait for the `reply` opcode, the events that arrive
+    /// meanwhile are kept for `dispatch`.
+    ///
+    /// Returns the payload of the reply and the fd that came with it.
+    fn request(&self, opcode: u16, payload: &[u8], reply: u16) -> Result<(Vec<u8>, Option<RawFd>), LauncherError> {
+        let mut connection = self.connection.borrow_mut();
+        connection.send(opcode, payload)?;
+
+        loop {
+            while let Some((message_opcode, message_payload)) = connection.next_message() {
+                match message_opcode {
+                    SERVER_ENABLE_SEAT | SERVER_DISABLE_SEAT =>
+                        self.pending_events.borrow_mut().push_back(message_opcode),
+                    SERVER_ERROR => {
+                        let errno = i32_at(&message_payload, 0).unwrap_or(0);
+                        return Err(LauncherError::from_errno(format!("seatd: request {} failed", opcode), errno));
+                    },
+                    _ if message_opcode == reply => {
+                        let fd = if reply == SERVER_DEVICE_OPENED { connection.in_fds.pop_front() } else { None };
+                        return Ok((message_payload, fd));
+                    },
+                    _ => return Err(LauncherError::Protocol(
+                        format!("seatd: unexpected message {} waiting for {}", message_opcode, reply))),
+                }
+            }
+            if !connection.read(REQUEST_TIMEOUT_MS)? {
+                return Err(LauncherError::Io {
+                    context: format!("seatd: no answer to request {}", opcode),
+                    source: io::Error::from(io::ErrorKind::TimedOut),
+                });
+            }
+        }
+    }
+
+    fn handle_event(&self, opcode: u16, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        match opcode {
+            SERVER_ENABLE_SEAT => {
+                if !self.active.get() {
+                    self.active.set(true);
+                    handler(SessionEvent::Activate);
+                }
+            },
+            SERVER_DISABLE_SEAT => {
+                if self.active.get() {
+                    self.active.set(false);
+                    handler(SessionEvent::Deactivate);
+                }
+                // seatd waits for this before giving the seat to someone else
+                self.connection.borrow_mut().send(CLIENT_DISABLE_SEAT, &[])?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Ask seatd to switch to another session (VT)
+    pub fn switch_session(&self, session: i32) -> Result<(), LauncherError> {
+        // a failure comes back as an error message later on
+        self.connection.borrow_mut().send(CLIENT_SWITCH_SESSION, &session.to_ne_bytes())
+    }
+
+    /// Round trip to seatd, what it sent before the pong has been read when
+    /// this returns
+    fn ping(&self) -> Result<(), LauncherError> {
+        self.request(CLIENT_PING, &[], SERVER_PONG).map(|_| ())
+    }
+
+    fn close_seat(&self) -> Result<(), LauncherError> {
+        self.request(CLIENT_CLOSE_SEAT, &[], SERVER_SEAT_CLOSED)?;
+        self.seat_open.set(false);
+        Ok(())
+    }
+}
+
+impl Launcher for SeatdLauncher {
+    fn seat_name(&self) -> String {
+        self.seat_name.borrow().clone()
+    }
+
+    fn connect(&self) -> Result<(), LauncherError> {
+        let (payload, _) = self.request(CLIENT_OPEN_SEAT, &[], SERVER_SEAT_OPENED)?;
+        self.seat_open.set(true);
+
+        let name_len = u16_at(&payload, 0).unwrap_or(0) as usize;
+        let name = payload.get(2..2 + name_len).unwrap_or(&[]);
+        let name = String::from_utf8_lossy(name).trim_end_matches('\0').to_string();
+        log_info!("launcher", "seatd: opened seat {}", name);
+        *self.seat_name.borrow_mut() = name;
+
+        // the devices can only be opened once the seat is enabled, seatd
+        // enables a free seat right away
+        self.ping()?;
+        let mut enabled = self.pending_events.borrow().back() == Some(&SERVER_ENABLE_SEAT);
+        if !enabled {
+            log_info!("launcher", "seatd: seat {} is in use, waiting up to {} ms for it",
+                self.seat_name.borrow(), REQUEST_TIMEOUT_MS);
+        }
+        while !enabled {
+            let mut connection = self.connection.borrow_mut();
+            if let Some((opcode, _)) = connection.next_message() {
+                enabled = opcode == SERVER_ENABLE_SEAT;
+                continue;
+            }
+            if !connection.read(REQUEST_TIMEOUT_MS)? {
+                return Err(LauncherError::SeatBusy(self.seat_name.borrow().clone()));
+            }
+        }
+        self.pending_events.borrow_mut().clear();
+        self.active.set(true);
+
+        Ok(())
+    }
+
+    fn open<'a>(&'a self, device_path: &Path, _flags: c_int) -> Result<DeviceHandle<'a>, LauncherError> {
+        // seatd opens with O_RDWR | O_NOCTTY | O_NOFOLLOW | O_CLOEXEC | O_NONBLOCK
+        let path = device_path.as_os_str().to_str()
+            .ok_or_else(|| LauncherError::device(device_path,
+                LauncherError::Protocol("seatd: invalid device path".to_string())))?;
+        let mut payload = Vec::with_capacity(2 + path.len() + 1);
+        payload.extend_from_slice(&(path.len() as u16 + 1).to_ne_bytes());
+        payload.extend_from_slice(path.as_bytes());
+        payload.push(0);
+
+        let (reply, fd) = self.request(CLIENT_OPEN_DEVICE, &payload, SERVER_DEVICE_OPENED)
+            .map_err(|e| LauncherError::device(device_path, e))?;
+        let device_id = i32_at(&reply, 0)
+            .ok_or_else(|| LauncherError::Protocol("seatd: device id not present in response message".to_string()))?;
+        let fd = match fd {
+            Some(fd) => fd,
+            None => return Err(LauncherError::Protocol("seatd: file descriptor not present in response message".to_string())),
+        };
+
+        log_info!("launcher", "Using device {}", path);
+        self.devices.borrow_mut().insert(fd, device_id);
+
+        Ok(DeviceHandle::new(self, fd))
+    }
+
+    fn close(&self, fd: RawFd) -> Result<(), LauncherError> {
+        let device_id = self.devices.borrow_mut().remove(&fd)
+            .ok_or(LauncherError::NotOpen(fd))?;
+        unsafe { libc::close(fd) };
+        self.request(CLIENT_CLOSE_DEVICE, &device_id.to_ne_bytes(), SERVER_DEVICE_CLOSED)?;
+        Ok(())
+    }
+
+    fn activate_vt(&self) -> Result<(), LauncherError> {
+        match self.vt {
+            Some(vt) => self.switch_session(vt as i32),
+            None => Err(LauncherError::Session("seatd: the session VT is unknown".to_string())),
+        }
+    }
+
+    fn restore(&self) {
+
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        Some(self.connection.borrow().socket.as_raw_fd())
+    }
+
+    fn dispatch(&self, handler: &mut dyn FnMut(SessionEvent)) -> Result<(), LauncherError> {
+        while self.connection.borrow_mut().read(0)? {}
+
+        loop {
+            let event = self.pending_events.borrow_mut().pop_front();
+            let opcode = match event {
+                Some(opcode) => opcode,
+                None => match self.connection.borrow_mut().next_message() {
+                    Some((SERVER_ERROR, payload)) => {
+                        let errno = i32_at(&payload, 0).unwrap_or(0);
+                        log_error!("launcher", "seatd: {}", io::Error::from_raw_os_error(errno));
+                        continue;
+                    },
+                    Some((opcode, _)) => opcode,
+                    None => return Ok(()),
+                },
+            };
+            self.handle_event(opcode, handler)?;
+        }
+    }
+}
+
+impl Drop for SeatdLauncher {
+    fn drop(&mut self) {
+        if self.seat_open.get() {
+            if let Err(e) = self.close_seat() {
+                log_error!("launcher", "{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use std::{env, fs, mem, process};
+
+    use libc;
+
+    use libc::c_int;
+
+    use launcher::{Launcher, SessionEvent};
+    use super::SeatdLauncher;
+    use self::protocol::*;
+
+    /// include/protocol.h of seatd, spelled out rather than taken from the
+    /// launcher so the fake server speaks the real protocol
+    mod protocol {
+        pub const CLIENT_OPEN_SEAT: u16 = 1;
+        pub const CLIENT_CLOSE_SEAT: u16 = 2;
+        pub const CLIENT_OPEN_DEVICE: u16 = 3;
+        pub const CLIENT_CLOSE_DEVICE: u16 = 4;
+        pub const CLIENT_DISABLE_SEAT: u16 = 5;
+        pub const CLIENT_SWITCH_SESSION: u16 = 6;
+        pub const CLIENT_PING: u16 = 7;
+
+        pub const SERVER_SEAT_OPENED: u16 = 0x8001;
+        pub const SERVER_SEAT_CLOSED: u16 = 0x8002;
+        pub const SERVER_DEVICE_OPENED: u16 = 0x8003;
+        pub const SERVER_DEVICE_CLOSED: u16 = 0x8004;
+        pub const SERVER_DISABLE_SEAT: u16 = 0x8005;
+        pub const SERVER_ENABLE_SEAT: u16 = 0x8006;
+        pub const SERVER_PONG: u16 = 0x8007;
+        pub const SERVER_ERROR: u16 = 0xFFFF;
+    }
+
+    fn send(stream: &mut UnixStream, opcode: u16, payload: &[u8], fd: Option<RawFd>) {
+        let mut message = Vec::new();
+        message.extend_from_slice(&opcode.to_ne_bytes());
+        message.extend_from_slice(&(payload.len() as u16).to_ne_bytes());
+        message.extend_from_slice(payload);
+
+        let fd = match fd {
+            Some(fd) => fd,
+            None => return stream.write_all(&message).unwrap(),
+        };
+        unsafe {
+            let mut iov = libc::iovec { iov_base: message.as_mut_ptr() as *mut libc::c_void, iov_len: message.len() };
+            let control_len = libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as usize;
+            let mut control = vec![0u8; control_len];
+            let mut header: libc::msghdr = mem::zeroed();
+            header.msg_iov = &mut iov;
+            header.msg_iovlen = 1;
+            header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            header.msg_controllen = control_len as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+            *(libc::CMSG_DATA(cmsg) as *mut c_int) = fd;
+            assert_eq!(libc::sendmsg(stream.as_raw_fd(), &header, 0), message.len() as isize);
+        }
+    }
+
+    /// A seatd answering one client, recording the opcodes of its requests
+    struct FakeSeatd {
+        path: PathBuf,
+        thread: Option<thread::JoinHandle<()>>,
+        requests: Arc<Mutex<Vec<u16>>>,
+        /// the server side of the connection, to send events
+        writer: Arc<Mutex<Option<UnixStream>>>,
+    }
+
+    impl FakeSeatd {
+        fn start(name: &str) -> FakeSeatd {
+            let path = env::temp_dir().join(format!("rudimento-seatd-{}-{}.sock", name, process::id()));
+            let _ = fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let writer = Arc::new(Mutex::new(None));
+
+            let thread_requests = requests.clone();
+            let thread_writer = writer.clone();
+            let thread = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                *thread_writer.lock().unwrap() = Some(stream.try_clone().unwrap());
+                let reply = |opcode: u16, payload: &[u8], fd: Option<RawFd>| {
+                    send(thread_writer.lock().unwrap().as_mut().unwrap(), opcode, payload, fd)
+                };
+
+                let mut next_device_id = 1i32;
+                loop {
+                    let mut header = [0u8; 4];
+                    if stream.read_exact(&mut header).is_err() {
+                        return;
+                    }
+                    let opcode = u16::from_ne_bytes([header[0], header[1]]);
+                    let size = u16::from_ne_bytes([header[2], header[3]]) as usize;
+                    let mut payload = vec![0u8; size];
+                    stream.read_exact(&mut payload).unwrap();
+                    thread_requests.lock().unwrap().push(opcode);
+
+                    match opcode {
+                        CLIENT_OPEN_SEAT => {
+                            let mut seat = 5u16.to_ne_bytes().to_vec();
+                            seat.extend_from_slice(b"seat0");
+                            reply(SERVER_SEAT_OPENED, &seat, None);
+                            reply(SERVER_ENABLE_SEAT, &[], None);
+                        },
+                        CLIENT_OPEN_DEVICE => {
+                            let path = &payload[2..payload.len() - 1];
+                            match fs::File::open(::std::str::from_utf8(path).unwrap()) {
+                                Ok(file) => {
+                                    reply(SERVER_DEVICE_OPENED, &next_device_id.to_ne_bytes(), Some(file.as_raw_fd()));
+                                    next_device_id += 1;
+                                },
+                                Err(_) => reply(SERVER_ERROR, &libc::ENOENT.to_ne_bytes(), None),
+                            }
+                        },
+                        CLIENT_CLOSE_DEVICE => reply(SERVER_DEVICE_CLOSED, &[], None),
+                        CLIENT_PING => reply(SERVER_PONG, &[], None),
+                        CLIENT_CLOSE_SEAT => {
+                            reply(SERVER_SEAT_CLOSED, &[], None);
+                            return;
+                        },
+                        _ => {},
+                    }
+                }
+            });
+
+            FakeSeatd {
+                path,
+                thread: Some(thread),
+                requests,
+                writer,
+            }
+        }
+
+        fn send_event(&self, opcode: u16) {
+            send(self.writer.lock().unwrap().as_mut().unwrap(), opcode, &[], None);
+        }
+
+        fn wait_for(&self, opcode: u16) -> bool {
+            for _ in 0..100 {
+                if self.requests.lock().unwrap().contains(&opcode) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        }
+
+        fn requests(&mut self) -> Vec<u16> {
+            self.thread.take().unwrap().join().unwrap();
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for FakeSeatd {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn dispatch_until_event(launcher: &SeatdLauncher) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            launcher.dispatch(&mut |event| events.push(event)).unwrap();
+            if !events.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        events
+    }
+
+    #[test]
+    fn opcodes_match_seatd() {
+        assert_eq!(super::CLIENT_OPEN_SEAT, CLIENT_OPEN_SEAT);
+        assert_eq!(super::CLIENT_CLOSE_SEAT, CLIENT_CLOSE_SEAT);
+        assert_eq!(super::CLIENT_OPEN_DEVICE, CLIENT_OPEN_DEVICE);
+        assert_eq!(super::CLIENT_CLOSE_DEVICE, CLIENT_CLOSE_DEVICE);
+        assert_eq!(super::CLIENT_DISABLE_SEAT, CLIENT_DISABLE_SEAT);
+        assert_eq!(super::CLIENT_SWITCH_SESSION, CLIENT_SWITCH_SESSION);
+        assert_eq!(super::CLIENT_PING, CLIENT_PING);
+        assert_eq!(super::SERVER_SEAT_OPENED, SERVER_SEAT_OPENED);
+        assert_eq!(super::SERVER_SEAT_CLOSED, SERVER_SEAT_CLOSED);
+        assert_eq!(super::SERVER_DEVICE_OPENED, SERVER_DEVICE_OPENED);
+        assert_eq!(super::SERVER_DEVICE_CLOSED, SERVER_DEVICE_CLOSED);
+        assert_eq!(super::SERVER_DISABLE_SEAT, SERVER_DISABLE_SEAT);
+        assert_eq!(super::SERVER_ENABLE_SEAT, SERVER_ENABLE_SEAT);
+        assert_eq!(super::SERVER_PONG, SERVER_PONG);
+        assert_eq!(super::SERVER_ERROR, SERVER_ERROR);
+    }
+
+    #[test]
+    fn open_and_close_devices() {
+        let mut seatd = FakeSeatd::start("devices");
+
+        let launcher = SeatdLauncher::with_socket(&seatd.path, None).unwrap();
+        launcher.connect().unwrap();
+        assert_eq!(launcher.seat_name(), "seat0");
+
+        {
+            let device = launcher.open(&PathBuf::from("/dev/null"), 0).unwrap();
+            assert!(unsafe { libc::fcntl(device.as_raw_fd(), libc::F_GETFD) } >= 0);
+            assert!(launcher.open(&PathBuf::from("/nonexistent"), 0).is_err());
+        }
+        assert!(seatd.wait_for(CLIENT_CLOSE_DEVICE));
+
+        drop(launcher);
+        assert_eq!(seatd.requests(), vec![
+            CLIENT_OPEN_SEAT, CLIENT_PING, CLIENT_OPEN_DEVICE, CLIENT_OPEN_DEVICE, CLIENT_CLOSE_DEVICE, CLIENT_CLOSE_SEAT,
+        ]);
+    }
+
+    #[test]
+    fn disable_and_enable_seat() {
+        let mut seatd = FakeSeatd::start("switch");
+
+        let launcher = SeatdLauncher::with_socket(&seatd.path, Some(3)).unwrap();
+        launcher.connect().unwrap();
+
+        seatd.send_event(SERVER_DISABLE_SEAT);
+        assert_eq!(dispatch_until_event(&launcher), vec![SessionEvent::Deactivate]);
+        assert!(seatd.wait_for(CLIENT_DISABLE_SEAT));
+
+        seatd.send_event(SERVER_ENABLE_SEAT);
+        assert_eq!(dispatch_until_event(&launcher), vec![SessionEvent::Activate]);
+
+        launcher.activate_vt().unwrap();
+        assert!(seatd.wait_for(CLIENT_SWITCH_SESSION));
+
+        drop(launcher);
+        assert_eq!(seatd.requests(), vec![
+            CLIENT_OPEN_SEAT, CLIENT_PING, CLIENT_DISABLE_SEAT, CLIENT_SWITCH_SESSION, CLIENT_CLOSE_SEAT,
+        ]);
+    }
+}
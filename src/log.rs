@@ -0,0 +1,324 @@
+//! Leveled messages in named scopes (weston-log).
+//!
+//! A message reaches the subscribers of its scope that asked for its level.
+//! `init_from_env` subscribes the default output at startup, the debugging
+//! clients of `wayland::debug` subscribe their fds at runtime.
+//!
+//! The compositor is single threaded: the scopes and subscribers are per
+//! thread, messages logged from other threads go nowhere.
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use libc;
+
+use systemd::journal;
+
+macro_rules! log_error {
+    ($scope:expr, $($arg:tt)+) => (
+        ::log::log($scope, ::log::Level::Error, file!(), line!(), format_args!($($arg)+))
+    )
+}
+
+macro_rules! log_warn {
+    ($scope:expr, $($arg:tt)+) => (
+        ::log::log($scope, ::log::Level::Warning, file!(), line!(), format_args!($($arg)+))
+    )
+}
+
+macro_rules! log_info {
+    ($scope:expr, $($arg:tt)+) => (
+        ::log::log($scope, ::log::Level::Info, file!(), line!(), format_args!($($arg)+))
+    )
+}
+
+macro_rules! log_debug {
+    ($scope:expr, $($arg:tt)+) => (
+        ::log::log($scope, ::log::Level::Debug, file!(), line!(), format_args!($($arg)+))
+    )
+}
+
+/// The scopes every thread starts with, name and description
+pub const SCOPES: &[(&str, &str)] = &[
+    ("drm", "DRM/KMS backend, GBM and pixman scanout"),
+    ("egl", "EGL and the GL renderer"),
+    ("launcher", "Session, VT and device access"),
+    ("wayland", "Wayland server, clients and surfaces"),
+    ("input", "Input devices"),
+    ("compositor", "Startup, shutdown and helper processes"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn from_str(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warning" | "warn" => Some(Level::Warning),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// syslog priority, for the journal
+    fn priority(&self) -> libc::c_int {
+        match *self {
+            Level::Error => libc::LOG_ERR,
+            Level::Warning => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug => libc::LOG_DEBUG,
+        }
+    }
+}
+
+/// A message on its way to the subscribers
+pub struct Record<'a> {
+    pub scope: &'a str,
+    pub level: Level,
+    /// CLOCK_REALTIME when logged
+    pub time: libc::timespec,
+    pub file: &'static str,
+    pub line: u32,
+    pub message: &'a str,
+}
+
+impl<'a> Record<'a> {
+    /// "[12:34:56.789] [drm] warning: message"
+    pub fn format(&self) -> String {
+        let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+        unsafe { libc::localtime_r(&self.time.tv_sec, &mut tm) };
+        let level = match self.level {
+            Level::Error | Level::Warning => format!("{}: ", self.level.name()),
+            _ => String::new(),
+        };
+        format!("[{:02}:{:02}:{:02}.{:03}] [{}] {}{}",
+                tm.tm_hour, tm.tm_min, tm.tm_sec, self.time.tv_nsec / 1_000_000,
+                self.scope, level, self.message)
+    }
+}
+
+pub trait Subscriber {
+    fn write(&mut self, record: &Record);
+}
+
+/// Writes the formatted records one per line: stderr, a file or the fd a
+/// debugging client handed over
+pub struct StreamSubscriber<W: Write> {
+    stream: W,
+}
+
+impl<W: Write> StreamSubscriber<W> {
+    pub fn new(stream: W) -> Self {
+        StreamSubscriber {
+            stream,
+        }
+    }
+}
+
+impl StreamSubscriber<io::Stderr> {
+    pub fn stderr() -> Self {
+        StreamSubscriber::new(io::stderr())
+    }
+}
+
+impl StreamSubscriber<File> {
+    /// Append to the file at `path`
+    pub fn file(path: &Path) -> io::Result<Self> {
+        OpenOptions::new().create(true).append(true).open(path)
+            .map(StreamSubscriber::new)
+    }
+}
+
+impl<W: Write> Subscriber for StreamSubscriber<W> {
+    fn write(&mut self, record: &Record) {
+        // nobody to tell if the output itself fails
+        let _ = writeln!(self.stream, "{}", record.format());
+    }
+}
+
+/// Sends the records to the journal with their scope and source location
+pub struct JournalSubscriber;
+
+impl Subscriber for JournalSubscriber {
+    fn write(&mut self, record: &Record) {
+        let priority = record.level.priority().to_string();
+        let line = record.line.to_string();
+        let _ = journal::send(&[
+            ("MESSAGE", record.message),
+            ("PRIORITY", &priority),
+            ("SYSLOG_IDENTIFIER", "rudimento"),
+            ("RUDIMENTO_SCOPE", record.scope),
+            ("CODE_FILE", record.file),
+            ("CODE_LINE", &line),
+        ]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionId(u32);
+
+struct Subscription {
+    id: SubscriptionId,
+    scope: String,
+    level: Level,
+    subscriber: Rc<RefCell<dyn Subscriber>>,
+}
+
+struct LogContext {
+    scopes: Vec<(String, String)>,
+    subscriptions: Vec<Subscription>,
+    next_id: u32,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext {
+        scopes: SCOPES.iter().map(|&(name, description)| (name.to_string(), description.to_string())).collect(),
+        subscriptions: Vec::new(),
+        next_id: 1,
+    });
+}
+
+/// Add a scope for a part of the compositor that comes and goes
+pub fn add_scope(name: &str, description: &str) {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        if !context.scopes.iter().any(|&(ref scope, _)| scope == name) {
+            context.scopes.push((name.to_string(), description.to_string()));
+        }
+    })
+}
+
+/// Names and descriptions of the scopes, to offer to debugging clients
+pub fn scopes() -> Vec<(String, String)> {
+    CONTEXT.with(|context| context.borrow().scopes.clone())
+}
+
+/// Send the messages of `scope` up to `level` to `subscriber`, the same
+/// subscriber can be subscribed to several scopes
+pub fn subscribe(scope: &str, level: Level, subscriber: Rc<RefCell<dyn Subscriber>>) -> Result<SubscriptionId, String> {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        if !context.scopes.iter().any(|&(ref name, _)| name == scope) {
+            return Err(format!("log: unknown scope \"{}\"", scope));
+        }
+        let id = SubscriptionId(context.next_id);
+        context.next_id += 1;
+        context.subscriptions.push(Subscription {
+            id,
+            scope: scope.to_string(),
+            level,
+            subscriber,
+        });
+        Ok(id)
+    })
+}
+
+pub fn unsubscribe(id: SubscriptionId) {
+    CONTEXT.with(|context| context.borrow_mut().subscriptions.retain(|subscription| subscription.id != id))
+}
+
+/// Whether anybody listens, to skip building expensive messages
+pub fn is_enabled(scope: &str, level: Level) -> bool {
+    CONTEXT.with(|context| {
+        context.borrow().subscriptions.iter()
+            .any(|subscription| subscription.scope == scope && level <= subscription.level)
+    })
+}
+
+/// Use the `log_*!` macros instead
+pub fn log(scope: &str, level: Level, file: &'static str, line: u32, args: fmt::Arguments) {
+    let subscribers: Vec<Rc<RefCell<dyn Subscriber>>> = CONTEXT.with(|context| {
+        context.borrow().subscriptions.iter()
+            .filter(|subscription| subscription.scope == scope && level <= subscription.level)
+            .map(|subscription| subscription.subscriber.clone())
+            .collect()
+    });
+    if subscribers.is_empty() {
+        return;
+    }
+
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut time) };
+    let message = fmt::format(args);
+    let record = Record {
+        scope,
+        level,
+        time,
+        file,
+        line,
+        message: &message,
+    };
+    for subscriber in subscribers.iter() {
+        // a subscriber logging from its own write must not recurse into itself
+        if let Ok(mut subscriber) = subscriber.try_borrow_mut() {
+            subscriber.write(&record);
+        }
+    }
+}
+
+/// Subscribe the default output.
+///
+/// RUDIMENTO_LOG picks it: "stderr" (default), "journal" or a file path.
+/// RUDIMENTO_LOG_SCOPES lists the scopes as "scope[=level],...", "*" is every
+/// scope, the default is "*=info".
+pub fn init_from_env() -> Result<(), String> {
+    let subscriber: Rc<RefCell<dyn Subscriber>> = match env::var("RUDIMENTO_LOG") {
+        Err(_) => Rc::new(RefCell::new(StreamSubscriber::stderr())),
+        Ok(ref output) if output == "stderr" => Rc::new(RefCell::new(StreamSubscriber::stderr())),
+        Ok(ref output) if output == "journal" => Rc::new(RefCell::new(JournalSubscriber)),
+        Ok(path) => {
+            let file = StreamSubscriber::file(Path::new(&path))
+                .map_err(|e| format!("log: could not open {}: {}", path, e))?;
+            Rc::new(RefCell::new(file))
+        },
+    };
+
+    // later items override the level of earlier ones
+    let mut levels: Vec<(String, Level)> = Vec::new();
+    let list = env::var("RUDIMENTO_LOG_SCOPES").unwrap_or_else(|_| "*=info".to_string());
+    for item in list.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+        let (scope, level) = match item.find('=') {
+            Some(i) => {
+                let level = Level::from_str(&item[i + 1..])
+                    .ok_or_else(|| format!("log: unknown level \"{}\"", &item[i + 1..]))?;
+                (&item[..i], level)
+            },
+            None => (item, Level::Info),
+        };
+        let names = if scope == "*" {
+            scopes().into_iter().map(|(name, _)| name).collect()
+        } else {
+            vec![scope.to_string()]
+        };
+        for name in names {
+            levels.retain(|&(ref other, _)| *other != name);
+            levels.push((name, level));
+        }
+    }
+
+    for (scope, level) in levels {
+        subscribe(&scope, level, subscriber.clone())?;
+    }
+    Ok(())
+}
@@ -6,38 +6,171 @@ extern crate libudev;
 extern crate dbus;
 extern crate drm as libdrm;
 extern crate wayland_server;
+extern crate wayland_protocols;
+extern crate wayland_commons;
+#[macro_use]
+extern crate wayland_sys;
 #[macro_use]
 extern crate mashup;
 extern crate gbm;
 
+#[macro_use]
+mod log;
 mod compositor;
+mod region;
+mod output;
+mod surface;
+mod dmabuf;
 mod systemd;
 mod backend;
 mod launcher;
 mod renderer;
 mod wayland;
+mod signals;
 mod egl;
 mod gl {
     include!(concat!(env!("OUT_DIR"), "/gl_bindings.rs"));
 }
 
+use std::error::Error as StdError;
+
+use wayland_server::calloop::EventLoop;
+
 use backend::drm;
 // use backend::Backend;
+use launcher::{Launcher, LauncherError};
+use launcher::direct::DirectLauncher;
+use launcher::logind::LogindLauncher;
+use launcher::seatd::SeatdLauncher;
+use signals::SignalHandler;
+use wayland::policy::ClientPolicy;
+use wayland::WaylandError;
+use wayland::server::RudimentoServer;
 
-fn main() {
+/// The launcher called `name`, or the first one that works: logind if there
+/// is a session, seatd if it runs, the VT directly otherwise
+fn create_launcher(name: Option<&str>, tty: Option<u32>, seat: &str) -> Result<Box<dyn Launcher>, LauncherError> {
+    let create = |name: &str| -> Result<Box<dyn Launcher>, LauncherError> {
+        match name {
+            "logind" => LogindLauncher::new(tty, seat.to_string(), true).map(|l| Box::new(l) as Box<dyn Launcher>),
+            "seatd" => SeatdLauncher::new(tty).map(|l| Box::new(l) as Box<dyn Launcher>),
+            "direct" => DirectLauncher::new(tty).map(|l| Box::new(l) as Box<dyn Launcher>),
+            _ => Err(LauncherError::Unknown(name.to_string())),
+        }
+    };
+
+    if let Some(name) = name {
+        return create(name);
+    }
+
+    let mut errors = Vec::new();
+    for &name in ["logind", "seatd", "direct"].iter() {
+        match create(name) {
+            Ok(launcher) => return Ok(launcher),
+            Err(e) => errors.push((name, e)),
+        }
+    }
+    Err(LauncherError::NoLauncher(errors))
+}
+
+fn run() -> Result<(), Box<dyn StdError>> {
     let use_pixman = false;
     let use_egldevice = false;
     let tty = None; //Some(2);
+    let launcher_name = None; //Some("seatd");
+    let seat = std::env::var("XDG_SEAT").unwrap_or_else(|_| "seat0".to_string());
+    let client_policy = ClientPolicy::from_env()?;
+
+    let event_loop = EventLoop::<()>::new()
+        .map_err(|e| WaylandError::io("Could not create the event loop".to_string(), e))?;
+    // before anything spawns threads, they inherit the blocked signals
+    let signals = SignalHandler::new();
+    let signal_source = SignalHandler::insert(&signals, &event_loop.handle())?;
 
-    {
-        let _backend = match drm::DRMBackend::new(tty, use_pixman, use_egldevice) {
-            Ok(b) => b,
-            Err(error) => {
-                println!("{}", error.to_string());
-                println!("exiting now");
-                std::process::exit(1);
+    let launcher = create_launcher(launcher_name, tty, &seat)?;
+    launcher.connect()?;
+
+    let mut backend = drm::DRMBackend::new(&*launcher, use_pixman, use_egldevice)?;
+    let mut server = RudimentoServer::new_from_drm(event_loop, &mut backend, client_policy)?;
+    // only wakes the loop, the launcher is dispatched every time
+    let _launcher_readable = match backend.launcher_fd() {
+        Some(fd) => Some(server.watch_fd(fd)?),
+        None => None,
+    };
+    // sd-login saw the session or the seat change
+    let monitors_readable = launcher.monitor_fds().into_iter()
+        .map(|fd| server.watch_fd(fd))
+        .collect::<Result<Vec<_>, _>>()?;
+    // for the helpers
+    std::env::set_var("WAYLAND_DISPLAY", &server.socket_name);
+
+    let mut result = Ok(());
+    while !signals.terminated() {
+        if let Err(e) = server.dispatch(backend.launcher_timeout()) {
+            result = Err(e.into());
+            break;
+        }
+        // the outputs with a completed page flip can take a new frame
+        if server.drm_readable.replace(false) {
+            if let Err(e) = backend.on_drm_input() {
+                log_error!("drm", "{}", e);
+            }
+        }
+        // wlr-randr and the like
+        server.configure_outputs(&mut backend);
+        if signals.take_renderer_switch() {
+            server.switch_renderer(&mut backend);
+        }
+        server.import_dmabufs(&mut backend);
+        // also without input, for the launcher's timeouts
+        if let Err(e) = backend.on_launcher_input() {
+            result = Err(e);
+            break;
+        }
+        if monitors_readable.iter().fold(false, |readable, flag| flag.replace(false) || readable) {
+            match backend.on_session_change() {
+                Ok(true) => server.publish_heads(&mut backend),
+                Ok(false) => {},
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                },
             }
-        };
+        }
+        if let Err(e) = backend.repaint() {
+            log_error!("drm", "repaint failed: {}", e);
+        }
+        server.update_surface_outputs(&backend);
+        // the frame callbacks and buffer releases of the repaint too
+        server.flush_clients();
+    }
+
+    // Teardown in order: the clients with the server, the outputs, the
+    // renderer and DRM master with the backend, then the launcher gives the
+    // VT back.
+    drop(server);
+    drop(backend);
+    drop(launcher);
+    signal_source.remove();
+    result
+}
+
+fn main() {
+    if let Err(error) = log::init_from_env() {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+
+    // everything is dropped, the VT restored, before exiting
+    if let Err(error) = run() {
+        log_error!("compositor", "{}", error);
+        let mut source = error.source();
+        while let Some(error) = source {
+            log_error!("compositor", "caused by: {}", error);
+            source = error.source();
+        }
+        log_error!("compositor", "exiting now");
+        std::process::exit(1);
     }
 
     // parse args
@@ -46,6 +179,4 @@ fn main() {
 
     // if version, print version
 
-
-    println!("Hello, world!");
 }
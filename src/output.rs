@@ -0,0 +1,280 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wayland_server::Resource;
+use wayland_server::protocol::wl_output;
+use wayland_protocols::presentation_time::server::wp_presentation_feedback::WpPresentationFeedback;
+
+use region::{Rectangle, Region};
+use wayland::output::OutputResources;
+
+/// A monitor as seen by the compositor (weston_output).
+///
+/// The backend creates it from a crtc and a mode, the renderer hangs its own
+/// per output state (framebuffers, EGL surfaces) on `renderer_state`.
+pub struct Output {
+    pub name: String,
+    /// backend handle of the output, the crtc id for the DRM backend
+    pub id: u32,
+    /// position in the global compositor space
+    pub x: i32,
+    pub y: i32,
+    /// current mode size in pixels
+    pub width: i32,
+    pub height: i32,
+    /// refresh rate in mHz
+    pub refresh: u32,
+    /// output pixels per unit of the global space
+    pub scale: f64,
+    pub transform: wl_output::Transform,
+    /// the display engine applies `transform` while scanning out, the
+    /// renderer paints the frame upright
+    pub hardware_transform: bool,
+    /// damage accumulated since the last repaint, in global coordinates
+    pub damage: Region,
+    /// presentation feedback of the frame being flipped
+    pub presentation_feedback: Vec<Resource<WpPresentationFeedback>>,
+    /// the wl_output resources of the clients, see `Outputs::resources`
+    pub wl_outputs: OutputResources,
+    /// renderer private data, see `Renderer::output_create`
+    pub renderer_state: Option<Box<dyn Any>>,
+}
+
+impl Output {
+    pub fn new(name: String, id: u32, width: i32, height: i32, refresh: u32) -> Self {
+        Self {
+            name,
+            id,
+            x: 0,
+            y: 0,
+            width,
+            height,
+            refresh,
+            scale: 1.0,
+            transform: wl_output::Transform::Normal,
+            hardware_transform: false,
+            damage: Region::new(),
+            presentation_feedback: Vec::new(),
+            wl_outputs: Rc::new(RefCell::new(Vec::new())),
+            renderer_state: None,
+        }
+    }
+
+    /// The area of the global space shown on this output
+    pub fn geometry(&self) -> Rectangle {
+        let (width, height) = transformed_size(self.width, self.height, self.transform);
+        let (width, height) = logical_size(width, height, self.scale);
+        Rectangle::new(self.x, self.y, width, height)
+    }
+
+    /// How the renderer has to paint the global space, the transform the
+    /// display engine takes care of left out
+    pub fn space(&self) -> OutputSpace {
+        OutputSpace {
+            x: self.x,
+            y: self.y,
+            scale: self.scale,
+            transform: if self.hardware_transform { wl_output::Transform::Normal } else { self.transform },
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Duration of a refresh cycle in nanoseconds, 0 if unknown
+    pub fn refresh_nsec(&self) -> u32 {
+        if self.refresh == 0 {
+            return 0;
+        }
+        (1_000_000_000_000u64 / u64::from(self.refresh)) as u32
+    }
+
+    /// Schedule the whole output for repaint
+    pub fn damage_all(&mut self) {
+        let geometry = self.geometry();
+        self.damage.union_rect(geometry);
+    }
+}
+
+/// Size in the global space of `width`x`height` pixels shown at `scale`,
+/// rounded up so that the last pixels are covered too
+pub fn logical_size(width: i32, height: i32, scale: f64) -> (i32, i32) {
+    ((f64::from(width) / scale).ceil() as i32, (f64::from(height) / scale).ceil() as i32)
+}
+
+/// Size of a `width`x`height` buffer once `transform` is applied
+pub fn transformed_size(width: i32, height: i32, transform: wl_output::Transform) -> (i32, i32) {
+    use self::wl_output::Transform::*;
+
+    match transform {
+        _90 | _270 | Flipped90 | Flipped270 => (height, width),
+        Normal | _180 | Flipped | Flipped180 => (width, height),
+    }
+}
+
+/// How the global space maps to the pixels of an output, what the renderers
+/// paint with.
+///
+/// The global space is scaled into the frame, the upright picture of
+/// `transformed_size`, and the frame is rotated and flipped into the pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputSpace {
+    /// global position of the top left corner
+    pub x: i32,
+    pub y: i32,
+    pub scale: f64,
+    pub transform: wl_output::Transform,
+    /// in pixels
+    pub width: i32,
+    pub height: i32,
+}
+
+impl OutputSpace {
+    pub fn bounds(&self) -> Rectangle {
+        Rectangle::new(0, 0, self.width, self.height)
+    }
+
+    /// The frame size, in output pixels too
+    pub fn frame_size(&self) -> (i32, i32) {
+        transformed_size(self.width, self.height, self.transform)
+    }
+
+    /// The output pixel a point of the frame lands on (wlroots'
+    /// wlr_box_transform with the inverted output transform)
+    pub fn frame_to_pixel(&self, x: f64, y: f64) -> (f64, f64) {
+        use self::wl_output::Transform::*;
+
+        let (width, height) = self.frame_size();
+        let (width, height) = (f64::from(width), f64::from(height));
+        match self.transform {
+            Normal => (x, y),
+            _90 => (y, width - x),
+            _180 => (width - x, height - y),
+            _270 => (height - y, x),
+            Flipped => (width - x, y),
+            Flipped90 => (y, x),
+            Flipped180 => (x, height - y),
+            Flipped270 => (height - y, width - x),
+        }
+    }
+
+    /// Inverse of `frame_to_pixel`
+    pub fn pixel_to_frame(&self, x: f64, y: f64) -> (f64, f64) {
+        use self::wl_output::Transform::*;
+
+        let (width, height) = self.frame_size();
+        let (width, height) = (f64::from(width), f64::from(height));
+        match self.transform {
+            Normal => (x, y),
+            _90 => (width - y, x),
+            _180 => (width - x, height - y),
+            _270 => (y, height - x),
+            Flipped => (width - x, y),
+            Flipped90 => (y, x),
+            Flipped180 => (x, height - y),
+            Flipped270 => (width - y, height - x),
+        }
+    }
+
+    /// `rect` of the frame in output pixels
+    pub fn frame_rect_to_pixels(&self, rect: &Rectangle) -> Rectangle {
+        let (x1, y1) = self.frame_to_pixel(f64::from(rect.x), f64::from(rect.y));
+        let (x2, y2) = self.frame_to_pixel(f64::from(rect.x2()), f64::from(rect.y2()));
+        let (x1, x2) = (x1.min(x2) as i32, x1.max(x2) as i32);
+        let (y1, y2) = (y1.min(y2) as i32, y1.max(y2) as i32);
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+    }
+
+    /// `rect` in the frame, the edges rounded to the nearest pixel: two
+    /// surfaces sharing an edge in the global space share it on the output
+    /// too, whatever the scale
+    pub fn to_frame(&self, rect: &Rectangle) -> Rectangle {
+        let x1 = (f64::from(rect.x - self.x) * self.scale).round() as i32;
+        let y1 = (f64::from(rect.y - self.y) * self.scale).round() as i32;
+        let x2 = (f64::from(rect.x2() - self.x) * self.scale).round() as i32;
+        let y2 = (f64::from(rect.y2() - self.y) * self.scale).round() as i32;
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+    }
+
+    /// `rect` in output pixels, see `to_frame`
+    pub fn to_pixels(&self, rect: &Rectangle) -> Rectangle {
+        self.frame_rect_to_pixels(&self.to_frame(rect))
+    }
+
+    /// The output pixels `rect` touches, rounded outwards
+    pub fn damage_to_pixels(&self, rect: &Rectangle) -> Option<Rectangle> {
+        let x1 = (f64::from(rect.x - self.x) * self.scale).floor() as i32;
+        let y1 = (f64::from(rect.y - self.y) * self.scale).floor() as i32;
+        let x2 = (f64::from(rect.x2() - self.x) * self.scale).ceil() as i32;
+        let y2 = (f64::from(rect.y2() - self.y) * self.scale).ceil() as i32;
+        self.frame_rect_to_pixels(&Rectangle::new(x1, y1, x2 - x1, y2 - y1)).intersect(&self.bounds())
+    }
+
+    /// `damage` (global coordinates) in output pixels
+    pub fn region_to_pixels(&self, damage: &Region) -> Region {
+        let mut region = Region::new();
+        for rect in damage.rects().iter().filter_map(|rect| self.damage_to_pixels(rect)) {
+            region.union_rect(rect);
+        }
+        region
+    }
+}
+
+/// A mode a head can be set to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mode {
+    pub width: i32,
+    pub height: i32,
+    /// refresh rate in mHz, 0 for any
+    pub refresh: u32,
+    pub preferred: bool,
+}
+
+/// How an enabled head shows the global space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadState {
+    pub mode: Mode,
+    pub x: i32,
+    pub y: i32,
+    pub transform: wl_output::Transform,
+    pub scale: f64,
+}
+
+impl HeadState {
+    /// The area of the global space the head shows
+    pub fn geometry(&self) -> Rectangle {
+        let (width, height) = transformed_size(self.mode.width, self.mode.height, self.transform);
+        let (width, height) = logical_size(width, height, self.scale);
+        Rectangle::new(self.x, self.y, width, height)
+    }
+}
+
+/// A connected monitor (weston_head), whether an output drives it or not
+#[derive(Debug, Clone, PartialEq)]
+pub struct Head {
+    /// connector name, "HDMI-A-1"
+    pub name: String,
+    pub description: String,
+    /// in millimeters, 0 if unknown
+    pub physical_width: i32,
+    pub physical_height: i32,
+    pub modes: Vec<Mode>,
+    /// `None` while disabled
+    pub state: Option<HeadState>,
+}
+
+impl Head {
+    /// The mode to enable the head with when nobody picked one
+    pub fn default_mode(&self) -> Option<Mode> {
+        self.modes.iter().find(|mode| mode.preferred)
+            .or_else(|| self.modes.first())
+            .cloned()
+    }
+}
+
+/// What the head called `name` should become, `None` disables it
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadConfiguration {
+    pub name: String,
+    pub state: Option<HeadState>,
+}
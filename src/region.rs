@@ -0,0 +1,158 @@
+use std::cmp::{min, max};
+
+/// Axis aligned rectangle, in whatever coordinate space the owner uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rectangle {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rectangle {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn x2(&self) -> i32 {
+        self.x + self.width
+    }
+
+    pub fn y2(&self) -> i32 {
+        self.y + self.height
+    }
+
+    pub fn intersect(&self, other: &Rectangle) -> Option<Rectangle> {
+        let x1 = max(self.x, other.x);
+        let y1 = max(self.y, other.y);
+        let x2 = min(self.x2(), other.x2());
+        let y2 = min(self.y2(), other.y2());
+
+        let rect = Rectangle::new(x1, y1, x2 - x1, y2 - y1);
+        if rect.is_empty() {
+            None
+        } else {
+            Some(rect)
+        }
+    }
+
+    pub fn contains_rect(&self, other: &Rectangle) -> bool {
+        other.x >= self.x && other.y >= self.y
+            && other.x2() <= self.x2() && other.y2() <= self.y2()
+    }
+
+    pub fn bounding(&self, other: &Rectangle) -> Rectangle {
+        let x1 = min(self.x, other.x);
+        let y1 = min(self.y, other.y);
+        let x2 = max(self.x2(), other.x2());
+        let y2 = max(self.y2(), other.y2());
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+    }
+
+    pub fn translate(&self, dx: i32, dy: i32) -> Rectangle {
+        Rectangle::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+}
+
+/// A set of rectangles, what pixman_region32 is for weston.
+///
+/// Rectangles can overlap, the region is their union. It is meant for damage
+/// tracking, where painting twice the same pixel is harmless.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Region {
+    rects: Vec<Rectangle>,
+}
+
+impl Region {
+    pub fn new() -> Self {
+        Self {
+            rects: Vec::new(),
+        }
+    }
+
+    pub fn from_rect(rect: Rectangle) -> Self {
+        let mut region = Self::new();
+        region.union_rect(rect);
+        region
+    }
+
+    pub fn rects(&self) -> &[Rectangle] {
+        &self.rects
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    pub fn union_rect(&mut self, rect: Rectangle) {
+        if rect.is_empty() {
+            return;
+        }
+        if self.rects.iter().any(|r| r.contains_rect(&rect)) {
+            return;
+        }
+        self.rects.retain(|r| !rect.contains_rect(r));
+        self.rects.push(rect);
+    }
+
+    pub fn union(&mut self, other: &Region) {
+        for rect in other.rects.iter() {
+            self.union_rect(*rect);
+        }
+    }
+
+    /// Remove `hole` from the region, splitting the rectangles it cuts
+    pub fn subtract_rect(&mut self, hole: &Rectangle) {
+        let mut rects = Vec::with_capacity(self.rects.len());
+        for rect in self.rects.drain(..) {
+            let cut = match rect.intersect(hole) {
+                Some(cut) => cut,
+                None => {
+                    rects.push(rect);
+                    continue;
+                },
+            };
+            // bands above and below the hole, then the sides
+            rects.push(Rectangle::new(rect.x, rect.y, rect.width, cut.y - rect.y));
+            rects.push(Rectangle::new(rect.x, cut.y2(), rect.width, rect.y2() - cut.y2()));
+            rects.push(Rectangle::new(rect.x, cut.y, cut.x - rect.x, cut.height));
+            rects.push(Rectangle::new(cut.x2(), cut.y, rect.x2() - cut.x2(), cut.height));
+        }
+        rects.retain(|rect| !rect.is_empty());
+        self.rects = rects;
+    }
+
+    pub fn intersect_rect(&self, clip: &Rectangle) -> Region {
+        Region {
+            rects: self.rects.iter()
+                .filter_map(|rect| rect.intersect(clip))
+                .collect(),
+        }
+    }
+
+    pub fn translate(&mut self, dx: i32, dy: i32) {
+        for rect in self.rects.iter_mut() {
+            *rect = rect.translate(dx, dy);
+        }
+    }
+
+    /// Smallest rectangle containing the whole region
+    pub fn extents(&self) -> Option<Rectangle> {
+        let mut rects = self.rects.iter();
+        let first = *rects.next()?;
+        Some(rects.fold(first, |acc, rect| acc.bounding(rect)))
+    }
+}
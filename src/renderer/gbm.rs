@@ -1,34 +1,88 @@
-use renderer::Renderer;
-use backend::drm::DRMDevice;
+use renderer::{Renderer, RendererError, Scanout};
+use renderer::software::{self, Canvas, Content, DamageHistory};
+use backend::drm::DRMDeviceFd;
+use dmabuf::{self, Dmabuf};
+use output::Output;
+use region::{Rectangle, Region};
+use surface::{Buffer, Surface};
 // use std::os::unix::io::{AsRawFd, RawFd};
-use egl::types::*;
 use libc;
-use std::ffi::{CString, CStr};
-use super::image;
+use std::ffi::CStr;
+use std::io;
+use std::ptr;
 use gbm;
-use libdrm;
+use gbm::{AsRaw, BufferObject, BufferObjectFlags, Format};
+use libdrm::control::{framebuffer, ResourceInfo};
+use wayland_server::protocol::wl_shm;
 
+// gbm.h, gbm 0.5's map hands out map_data instead of the mapping
+const GBM_BO_TRANSFER_WRITE: u32 = 1 << 1;
+
+extern "C" {
+    fn gbm_bo_map(bo: *mut libc::c_void, x: u32, y: u32, width: u32, height: u32, flags: u32,
+                  stride: *mut u32, map_data: *mut *mut libc::c_void) -> *mut libc::c_void;
+    fn gbm_bo_unmap(bo: *mut libc::c_void, map_data: *mut libc::c_void);
+}
+
+/// Renderer scanning out of GBM buffer objects.
+///
+/// There is no GL on GBM yet, the outputs are composited in system memory and
+/// written to the buffer objects.
 pub struct GBMRenderer {
-    gbm: gbm::Device<DRMDevice>,
+    /// on a dup of the DRM fd, closed with it
+    gbm: gbm::Device<DRMDeviceFd>,
+}
+
+/// Double buffered scanout of an output
+struct GBMOutputState {
+    canvas: Canvas,
+    buffers: Vec<(BufferObject<()>, framebuffer::Info)>,
+    current: usize,
+    damage: DamageHistory,
+}
+
+/// Copy `rect` of `canvas` to the same place in `bo`
+fn write_rect(bo: &BufferObject<()>, canvas: &Canvas, rect: &Rectangle) -> Result<(), RendererError> {
+    let mut stride = 0;
+    let mut map_data = ptr::null_mut();
+    let mapped = unsafe {
+        gbm_bo_map(bo.as_raw() as *mut _, rect.x as u32, rect.y as u32, rect.width as u32, rect.height as u32,
+                   GBM_BO_TRANSFER_WRITE, &mut stride, &mut map_data)
+    };
+    if mapped.is_null() {
+        return Err(RendererError::gbm("Could not map Buffer Object", io::Error::last_os_error()));
+    }
+
+    let row_len = rect.width as usize * 4;
+    for (row, y) in (rect.y..rect.y2()).enumerate() {
+        let src = (y * canvas.stride() + rect.x * 4) as usize;
+        unsafe {
+            let dst = (mapped as *mut u8).add(row * stride as usize);
+            ptr::copy_nonoverlapping(canvas.data()[src..src + row_len].as_ptr(), dst, row_len);
+        }
+    }
+
+    unsafe { gbm_bo_unmap(bo.as_raw() as *mut _, map_data) };
+    Ok(())
 }
 
 impl GBMRenderer {
-    pub fn new(drm_device: DRMDevice) -> Result<Box<Self>, String> {
+    pub fn new(drm_device: DRMDeviceFd) -> Result<Box<Self>, RendererError> {
 
-        let libname = CString::new("libglapi.so.0").expect("CString::new failed");
-        let r: *mut libc::c_void = unsafe { 
-            libc::dlopen(libname.as_ptr(), libc::RTLD_LAZY | libc::RTLD_GLOBAL)
+        let libname = b"libglapi.so.0\0";
+        let r: *mut libc::c_void = unsafe {
+            libc::dlopen(libname.as_ptr() as *const libc::c_char, libc::RTLD_LAZY | libc::RTLD_GLOBAL)
         };
 
         if r.is_null() {
             let error = unsafe { CStr::from_ptr(libc::dlerror()) };
-            return Err(format!("Error loading \"libglapi.so.0\" dlerror:\n{:?}", error))
+            return Err(RendererError::new(format!("Error loading \"libglapi.so.0\": {}", error.to_string_lossy())))
         }
 
         let gbm = gbm::Device::new(drm_device)
         // unsafe: device has to outlive file descriptor
         // let gbm = unsafe { gbm::Device::new_from_fd(drm_devide.as_raw_fd()) }
-            .map_err(|e| format!("Could not create GDB Device: {}", e))?;
+            .map_err(|e| RendererError::gbm("Could not create GBM Device", e))?;
 
         //TODO init renderer
         //		EGLint format[3] = {
@@ -58,270 +112,176 @@ impl GBMRenderer {
         }))
     }
 
-    pub fn test(&self, drm_device: &DRMDevice) {
-        use std::thread;
-        use std::time::Duration;
-        use gbm::{Format, BufferObjectFlags};
-        use libdrm::control::{crtc, framebuffer};
-        use libdrm::control::Device as libdrm_device;
-        use libdrm::control::ResourceInfo;
-
-        // Get a set of all modesetting resource handles (excluding planes):
-        let res_handles = drm_device.resource_handles().unwrap();
-
-        println!("\nConnector Informations");
-        // Print all connector information
-        for &con in res_handles.connectors() {
-            let info: libdrm::control::connector::Info 
-                = drm_device.resource_info(con).unwrap();
+    fn output_state(output: &Output) -> Result<&GBMOutputState, RendererError> {
+        output.renderer_state.as_ref()
+            .and_then(|state| state.downcast_ref::<GBMOutputState>())
+            .ok_or_else(|| RendererError::new(format!("Output {} not created by the GBM renderer", output.name)))
+    }
 
-            println!("{:?}", info);
-        }
+    /// Copy the pixels of the dmabuf attached to `surface` into its content
+    fn upload_dmabuf(&self, surface: &mut Surface) -> Result<(), RendererError> {
+        let dmabuf = match surface.buffer {
+            Some(Buffer::Dmabuf(ref dmabuf)) => dmabuf.clone(),
+            _ => return Err(RendererError::from_str("Surface has no dmabuf attached")),
+        };
+        let state = dmabuf.renderer_state.borrow();
+        let bo = state.as_ref()
+            .and_then(|state| state.downcast_ref::<BufferObject<()>>())
+            .ok_or_else(|| RendererError::from_str("dmabuf not imported by the GBM renderer"))?;
+
+        surface.wait_acquire_fence();
+
+        let opaque = dmabuf.format != Format::ARGB8888.as_ffi();
+        let mut image = Canvas::new(dmabuf.width, dmabuf.height);
+        let rect = Rectangle::new(0, 0, dmabuf.width, dmabuf.height);
+        bo.map(&self.gbm, 0, 0, dmabuf.width as u32, dmabuf.height as u32, |mapped| {
+            image.upload(wl_shm::Format::Argb8888, mapped.buffer(), mapped.stride() as i32, &rect)
+        })
+            .map_err(|e| RendererError::gbm("Could not map dmabuf", e))?
+            .map_err(|e| RendererError::gbm("Could not map dmabuf", e))??;
+
+        surface.buffer_width = dmabuf.width;
+        surface.buffer_height = dmabuf.height;
+        surface.renderer_state = Some(Box::new(Content::Image {
+            image,
+            opaque,
+        }));
+        Ok(())
+    }
+}
 
-        let con = res_handles.connectors().iter().next()
-            .expect("No Card Connector found");
-        let connector_info: libdrm::control::connector::Info
-            = drm_device.resource_info(*con).unwrap();
-        let mode = connector_info.modes()[0];
-        let (hdisplay, vdisplay) = mode.size();
+impl Renderer for GBMRenderer {
+    fn name(&self) -> &'static str {
+        "gbm"
+    }
 
-        println!("\nCRTCs Informations");
-        // Print all CRTC information
-        for &crtc in res_handles.crtcs() {
-            let info: drm::control::crtc::Info 
-                = drm_device.resource_info(crtc).unwrap();
+    fn shm_formats(&self) -> Vec<wl_shm::Format> {
+        vec![wl_shm::Format::Rgb565]
+    }
 
-            println!("{:?}", info);
+    fn output_create(&mut self, output: &mut Output) -> Result<(), RendererError> {
+        let mut buffers = Vec::new();
+        for _ in 0..2 {
+            let bo = self.gbm.create_buffer_object::<()>(
+                output.width as u32, output.height as u32,
+                Format::XRGB8888,
+                BufferObjectFlags::SCANOUT | BufferObjectFlags::WRITE,
+            )
+                .map_err(|e| RendererError::gbm("Could not create Buffer Object", e))?;
+            let fb_info = framebuffer::create(&self.gbm, &bo)
+                .map_err(|e| RendererError::gbm("framebuffer create failed", e))?;
+            buffers.push((bo, fb_info));
         }
 
-        //TODO maybe select a better one than the first
-        let crtc_handle = res_handles.crtcs().iter().next()
-            .expect("No CRTC handle found");
+        output.renderer_state = Some(Box::new(GBMOutputState {
+            canvas: Canvas::new(output.width, output.height),
+            buffers,
+            current: 0,
+            damage: DamageHistory::default(),
+        }));
+        output.damage_all();
+        Ok(())
+    }
 
-        let mut bo = self.gbm.create_buffer_object::<()>(
-            hdisplay.into(), vdisplay.into(),
-            Format::XRGB8888,
-            BufferObjectFlags::SCANOUT | BufferObjectFlags::WRITE,
-            )
-            .expect("Could not create Buffer Object");
-
-        // write something to it (usually use import or egl rendering instead)
-        let buffer = {
-            let mut buffer = Vec::<u8>::new();
-            for i in 0..vdisplay {
-                for j in 0..hdisplay {
-                    // XRGB8888
-                    buffer.push(if i % 2 == 0 { 0   } else { 0   }); // Blue
-                    buffer.push(if i % 2 == 0 { 255 } else { 0   }); // Green
-                    buffer.push(if i % 2 == 0 { 0   } else { 0   }); // Red
-                    buffer.push(if j % 2 == 0 { 0   } else { 255 }); // Nothing
-                }
-            }
-            buffer
+    fn output_destroy(&mut self, output: &mut Output) {
+        let state = match output.renderer_state.take() {
+            Some(state) => state,
+            None => return,
         };
-        bo.write(&buffer)
-            .expect("Buffer Object write failed")
-            .expect("Buffer Object write failed 2");
-
-        // create a framebuffer from our buffer
-        let fb_info = framebuffer::create(&self.gbm, &bo)
-            .expect("framebuffer create failed");
-
-        // display it (and get a crtc, mode and connector before)
-        crtc::set(&self.gbm, *crtc_handle, fb_info.handle(), &[*con], (0, 0), Some(mode))
-            .expect("display framebuffer to the crtc failed");
+        if let Ok(state) = state.downcast::<GBMOutputState>() {
+            for (_bo, fb_info) in state.buffers.iter() {
+                let _ = framebuffer::destroy(&self.gbm, fb_info.handle());
+            }
+        }
+    }
 
-        thread::sleep(Duration::from_millis(100));
+    fn repaint_output(&mut self, output: &mut Output, surfaces: &[&Surface], damage: &Region)
+        -> Result<Scanout, RendererError>
+    {
+        let space = output.space();
+        let state = output.renderer_state.as_mut()
+            .and_then(|state| state.downcast_mut::<GBMOutputState>())
+            .ok_or_else(|| RendererError::from_str("Output not created by the GBM renderer"))?;
+
+        software::composite(&mut state.canvas, &space, surfaces, damage);
+
+        // the back buffer misses the damage of the frames since it was written
+        state.current = (state.current + 1) % state.buffers.len();
+        let missing = state.damage.next_frame(space.region_to_pixels(damage), state.buffers.len(), space.bounds());
+        let (ref bo, ref fb_info) = state.buffers[state.current];
+        for rect in missing.rects() {
+            write_rect(bo, &state.canvas, rect)?;
+        }
 
+        Ok(Scanout::Framebuffer(fb_info.handle()))
+    }
 
-        let mut bo2 = self.gbm.create_buffer_object::<()>(
-            hdisplay.into(), vdisplay.into(),
-            Format::XRGB8888,
-            BufferObjectFlags::SCANOUT | BufferObjectFlags::WRITE,
-            )
-            .expect("Could not create Buffer Object");
-
-        // write something to it (usually use import or egl rendering instead)
-        let buffer = {
-            let mut buffer = Vec::<u8>::new();
-            for _i in 0..vdisplay {
-                for j in 0..hdisplay {
-                    // XRGB8888
-                    buffer.push(255);//if i % 2 == 0 { 0   } else { 0   }); // Blue
-                    buffer.push(255);//if i % 2 == 0 { 255 } else { 0   }); // Green
-                    buffer.push(255);//if i % 2 == 0 { 0   } else { 255 }); // Red
-                    buffer.push(if j % 2 == 0 { 0   } else { 255 }); // Nothing
-                }
-            }
-            buffer
-        };
-        bo2.write(&buffer)
-            .expect("Buffer Object write failed")
-            .expect("Buffer Object write failed 2");
+    fn attach(&mut self, surface: &mut Surface) -> Result<(), RendererError> {
+        match surface.buffer {
+            None => {
+                surface.renderer_state = None;
+                surface.buffer_width = 0;
+                surface.buffer_height = 0;
+                Ok(())
+            },
+            Some(Buffer::Shm(_)) => software::upload_shm(surface, true),
+            Some(Buffer::Dmabuf(_)) => self.upload_dmabuf(surface),
+            Some(Buffer::Egl(_)) =>
+                Err(RendererError::unsupported("GBM renderer cannot read EGL buffers".to_string())),
+        }
+    }
 
+    fn flush_damage(&mut self, surface: &mut Surface) -> Result<(), RendererError> {
+        match surface.buffer {
+            Some(Buffer::Shm(_)) => software::upload_shm(surface, false),
+            Some(Buffer::Dmabuf(_)) => self.upload_dmabuf(surface),
+            _ => Ok(()),
+        }
+    }
 
-        let fb_info_2 = framebuffer::create(&self.gbm, &bo2)
-            .expect("framebuffer create failed");
+    fn surface_set_color(&mut self, surface: &mut Surface, red: f32, green: f32, blue: f32, alpha: f32) {
+        surface.renderer_state = Some(Box::new(Content::color(red, green, blue, alpha)));
+    }
 
-        crtc::set(&self.gbm, *crtc_handle, fb_info_2.handle(), &[*con], (0, 0), Some(mode))
-            .expect("display framebuffer to the crtc failed");
+    fn read_pixels(&mut self, output: &Output, format: wl_shm::Format, rect: Rectangle, pixels: &mut [u8])
+        -> Result<(), RendererError>
+    {
+        Self::output_state(output)?.canvas.read(format, &rect, pixels)
+    }
 
-        thread::sleep(Duration::from_millis(100));
-        
-        // https://github.com/dvdhrm/docs/commit/87d3698967a148174cdaa97a068b23ca2387c798
-        // https://docs.rs/drm/0.3.4/drm/control/crtc/fn.page_flip.html
-        crtc::page_flip(&self.gbm, *crtc_handle, fb_info.handle(), &[crtc::PageFlipFlags::PageFlipEvent])
-            .expect("Page Flip schedule failed");
-
-        thread::sleep(Duration::from_millis(100));
-
-        { // Test VSYNC
-
-            let vdisplay = vdisplay as usize;
-            let hdisplay = hdisplay as usize;
-
-            let threads_n = 8_usize;
-            let thread_handles = (0..threads_n).into_iter().map(|thread_id| {
-                thread::Builder::new().name(format!("vsync test builder {}", thread_id))
-                    .spawn(move || {
-                        let mut mother_buffer = Vec::<u8>::new();
-                        for _i in 0..vdisplay {
-                            for _j in 0..hdisplay {
-                                // XRGB8888
-                                mother_buffer.push(0); // Blue
-                                mother_buffer.push(255); // Green
-                                mother_buffer.push(0); // Red
-                                mother_buffer.push(255); // Nothing
-                            }
-                        }
-                        let mut buffers = Vec::new();
-                        let partition = (128/threads_n) as usize;
-                        for c in (partition*thread_id)..(partition*(thread_id+1)) {
-                            let mut i = 0;
-                            let mut j = 0;
-                            let mut quartet_counter = 0;
-                            for byte in mother_buffer.iter_mut() {
-                                quartet_counter = quartet_counter + 1;
-
-                                j = j+quartet_counter/4;
-                                quartet_counter = quartet_counter%4;
-
-                                i = i + j/hdisplay;
-                                j = j%hdisplay;
-
-                                let is_black = (c*3 + j) < (hdisplay/3) || (c*3 + j) > (hdisplay*2/3);
-                                let color: u8 = if is_black { 0 } else { 255 };
-                                *byte = color;
-                            }
-                            
-                            buffers.push(mother_buffer.clone());
-                        }
-                        buffers
-                }).expect("Could not start thread ")
-            }).collect::<Vec<_>>();
-
-            let buffers = thread_handles.into_iter().flat_map(|handle| {
-                handle.join().expect("Error running thread")
-            }).collect::<Vec<_>>();
-
-            let mut bos = vec![(bo, fb_info), (bo2,fb_info_2)];
-            let mut counter = 0;
-            for _ in 0..20 {
-                for buffer in buffers.iter() {
-                    counter += 1;
-                    let (bo, fb_info) = &mut bos[counter%2];
-                    bo.write(&buffer)
-                        .expect("Buffer Object write failed")
-                        .expect("Buffer Object write failed 2");
-                    crtc::set(&self.gbm, *crtc_handle, fb_info.handle(), &[*con], (0, 0), Some(mode))
-                        .expect("display framebuffer to the crtc failed");
-                }
-            }
+    fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> Result<(), RendererError> {
+        if dmabuf.planes.len() != 1 {
+            return Err(RendererError::unsupported("GBM renderer imports only single plane dmabufs".to_string()));
+        }
+        let modifier = dmabuf.modifier();
+        if modifier != dmabuf::DRM_FORMAT_MOD_INVALID && modifier != dmabuf::DRM_FORMAT_MOD_LINEAR {
+            return Err(RendererError::unsupported(format!("Unsupported dmabuf modifier {:#x}", modifier)));
         }
+        let format = match Format::from_ffi(dmabuf.format) {
+            Some(format) if self.query_dmabuf_formats().contains(&dmabuf.format) => format,
+            _ => return Err(RendererError::unsupported(format!("Unsupported dmabuf format {:#x}", dmabuf.format))),
+        };
+
+        let plane = &dmabuf.planes[0];
+        let bo = self.gbm.import_buffer_object_from_dma_buf::<()>(
+            plane.fd,
+            dmabuf.width as u32,
+            dmabuf.height as u32,
+            plane.stride,
+            format,
+            BufferObjectFlags::LINEAR,
+        )
+            .map_err(|e| RendererError::gbm("Could not import dmabuf", e))?;
+
+        *dmabuf.renderer_state.borrow_mut() = Some(Box::new(bo));
+        Ok(())
     }
-}
 
-impl Renderer for GBMRenderer {
-    fn image_target_texture_2d(&self) {}
-
-    fn create_image(
-        &self,
-        context: Option<EGLContext>,
-        target: EGLenum,
-        buffer: EGLClientBuffer,
-        attrib_list: &Vec<EGLint>
-    ) -> Result<Box<dyn image::Image>, ::egl::EGLError>
-    {
-        panic!("not implemented yet");
+    fn query_dmabuf_formats(&self) -> Vec<u32> {
+        vec![Format::ARGB8888.as_ffi(), Format::XRGB8888.as_ffi()]
     }
 
-    fn swap_buffers_with_damage(&self) {}
-    fn create_platform_window(&self) {}
-	// const has_unpack_subimage: bool;
-    
-    // const has_bind_display: bool;
-    fn bind_display(&self) {}
-    fn unbind_display(&self) {}
-    fn query_buffer(&self) {}
-
-    // const has_context_priority: bool;
-	// const has_egl_image_external: bool;
-	// const has_egl_buffer_age: bool;
-	// const has_configless_context: bool;
-	// const has_surfaceless_context: bool;
-
-    //nvidia stuff
-    // const has_egl_output_base: bool;
-	// const has_egl_output_drm: bool;
-	// const has_egl_output_drm_flip_event: bool;
-    fn get_output_layers(&self) {}
-    fn query_output_layer_attrib(&self) {}
-
-    // const has_egl_stream: bool;
-    fn create_stream(&self) {}
-    fn destroy_stream(&self) {}
-    fn query_stream(&self) {}
-
-    // const has_egl_stream_producer_eglsurface: bool;
-    fn create_stream_producer_surface(&self) {}
-
-    // const has_egl_stream_consumer_egloutput: bool;
-    fn stream_consumer_output(&self) {}
-
-    //nvidia egl stream attrib
-    // const has_egl_stream_attrib: bool;
-    fn create_stream_attrib(&self) {}
-    // const has_egl_stream_acquire_mode: bool;
-    fn stream_consumer_acquire_attrib(&self) {}
-
-    // const has_egl_stream_consumer_gltexture: bool;
-    // const has_egl_wayland_eglstream: bool;
-    fn stream_consumer_gltexture(&self) {}
-
-    // const has_dmabuf_import: bool;
-    // struct wl_list dmabuf_images;
-
-    // const has_gl_texture_rg: bool;
-    // struct gl_shader texture_shader_rgba;
-	// struct gl_shader texture_shader_rgbx;
-	// struct gl_shader texture_shader_egl_external;
-	// struct gl_shader texture_shader_y_uv;
-	// struct gl_shader texture_shader_y_u_v;
-	// struct gl_shader texture_shader_y_xuxv;
-	// struct gl_shader invert_color_shader;
-	// struct gl_shader solid_shader;
-	// struct gl_shader *current_shader;
-
-	// fn destroy_signal_ref(&self) -> &wayland::signal::Signal { &self.destroy_signal }
-    // fn output_destroy_listener_ref(&self) -> &wayland::listener::Listener { &self.output_destroy_listener }
-
-    // const has_dmabuf_import_modifiers: bool;
-    fn query_dmabuf_formats(&self) {}
-    fn query_dmabuf_modifiers(&self) {}
-
-    // const has_native_fence_sync: bool;
-    fn create_sync(&self) {}
-    fn destroy_sync(&self) {}
-    fn dup_native_fence_fd(&self) {}
+    fn query_dmabuf_modifiers(&self, _format: u32) -> Vec<u64> {
+        vec![dmabuf::DRM_FORMAT_MOD_LINEAR]
+    }
 }
-
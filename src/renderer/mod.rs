@@ -3,87 +3,165 @@ pub mod egl;
 pub mod gbm;
 pub mod image;
 pub mod gl;
+pub mod software;
 
-// use wayland;
-use egl::types::*;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
 
+use libc::c_int;
+use libdrm::control::framebuffer;
+use wayland_server::protocol::wl_shm;
+
+use dmabuf::Dmabuf;
+use egl::EGLError;
+use output::Output;
+use region::{Rectangle, Region};
+use surface::Surface;
+
+#[derive(Debug)]
+pub enum RendererError {
+    /// An EGL or GL call failed
+    Egl(EGLError),
+    /// A gbm or dumb buffer operation failed
+    Gbm {
+        context: String,
+        source: Box<dyn StdError>,
+    },
+    /// The renderer can't handle this format, buffer type or modifier
+    Unsupported(String),
+    Message(String),
+}
+
+impl RendererError {
+    pub fn new(description: String) -> Self {
+        RendererError::Message(description)
+    }
+
+    pub fn from_str(description: &str) -> Self {
+        Self::new(description.to_string())
+    }
+
+    pub fn unsupported(description: String) -> Self {
+        RendererError::Unsupported(description)
+    }
+
+    pub fn gbm<E: StdError + 'static>(context: &str, source: E) -> Self {
+        RendererError::Gbm {
+            context: context.to_string(),
+            source: Box::new(source),
+        }
+    }
+
+    /// The errno of the failed buffer operation
+    pub fn errno(&self) -> Option<c_int> {
+        match *self {
+            RendererError::Gbm { ref source, .. } =>
+                source.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error()),
+            _ => None,
+        }
+    }
+
+    /// The EGL error code of the failed call
+    pub fn egl_code(&self) -> Option<i32> {
+        match *self {
+            RendererError::Egl(ref e) => e.code(),
+            _ => None,
+        }
+    }
+}
+
+impl From<EGLError> for RendererError {
+    fn from(e: EGLError) -> Self {
+        RendererError::Egl(e)
+    }
+}
+
+impl StdError for RendererError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            RendererError::Egl(ref e) => Some(e),
+            RendererError::Gbm { ref source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RendererError::Egl(ref e) => write!(f, "{}", e),
+            RendererError::Gbm { ref context, ref source } => write!(f, "{}: {}", context, source),
+            RendererError::Unsupported(ref description) => write!(f, "{}", description),
+            RendererError::Message(ref description) => write!(f, "{}", description),
+        }
+    }
+}
+
+/// What the backend has to do to show a repainted output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scanout {
+    /// page flip to this framebuffer
+    Framebuffer(framebuffer::Handle),
+    /// the renderer already queued the flip itself (EGLStream)
+    Queued,
+    /// the display engine is still busy with the previous frame, retry later
+    Busy,
+    /// the frame lives in system memory, get it with `read_pixels`
+    ReadPixels,
+}
+
+/// Compositor facing renderer interface (weston_renderer).
+///
+/// The renderer keeps its private data in the `renderer_state` of outputs,
+/// surfaces and dmabufs, so it can be swapped while they stay alive.
 pub trait Renderer {
-    fn image_target_texture_2d(&self);
-    
-    fn create_image(
-        &self,
-        context: Option<EGLContext>, 
-        target: EGLenum,
-        buffer: EGLClientBuffer,
-        attrib_list: &Vec<EGLint>
-    ) -> Result<Box<dyn image::Image>, ::egl::EGLError>;
-
-    fn swap_buffers_with_damage(&self);
-    fn create_platform_window(&self);
-	// const has_unpack_subimage: bool;
-    
-    // const has_bind_display: bool;
-    fn bind_display(&self);
-    fn unbind_display(&self);
-    fn query_buffer(&self);
-
-    // const has_context_priority: bool;
-	// const has_egl_image_external: bool;
-	// const has_egl_buffer_age: bool;
-	// const has_configless_context: bool;
-	// const has_surfaceless_context: bool;
-
-    //nvidia stuff
-    // const has_egl_output_base: bool;
-	// const has_egl_output_drm: bool;
-	// const has_egl_output_drm_flip_event: bool;
-    fn get_output_layers(&self);
-    fn query_output_layer_attrib(&self);
-
-    // const has_egl_stream: bool;
-    fn create_stream(&self);
-    fn destroy_stream(&self);
-    fn query_stream(&self);
-
-    // const has_egl_stream_producer_eglsurface: bool;
-    fn create_stream_producer_surface(&self);
-
-    // const has_egl_stream_consumer_egloutput: bool;
-    fn stream_consumer_output(&self);
-
-    //nvidia egl stream attrib
-    // const has_egl_stream_attrib: bool;
-    fn create_stream_attrib(&self);
-    // const has_egl_stream_acquire_mode: bool;
-    fn stream_consumer_acquire_attrib(&self);
-
-    // const has_egl_stream_consumer_gltexture: bool;
-    // const has_egl_wayland_eglstream: bool;
-    fn stream_consumer_gltexture(&self);
-
-    // const has_dmabuf_import: bool;
-    // struct wl_list dmabuf_images;
-
-    // const has_gl_texture_rg: bool;
-    // struct gl_shader texture_shader_rgba;
-	// struct gl_shader texture_shader_rgbx;
-	// struct gl_shader texture_shader_egl_external;
-	// struct gl_shader texture_shader_y_uv;
-	// struct gl_shader texture_shader_y_u_v;
-	// struct gl_shader texture_shader_y_xuxv;
-	// struct gl_shader invert_color_shader;
-	// struct gl_shader solid_shader;
-	// struct gl_shader *current_shader;
-
-	// fn destroy_signal_ref(&self) -> &wayland::signal::Signal;
-    // fn output_destroy_listener_ref(&self) -> &wayland::listener::Listener;
-
-    // const has_dmabuf_import_modifiers: bool;
-    fn query_dmabuf_formats(&self);
-    fn query_dmabuf_modifiers(&self);
-
-    // const has_native_fence_sync: bool;
-    fn create_sync(&self);
-    fn destroy_sync(&self);
-    fn dup_native_fence_fd(&self);
+    fn name(&self) -> &'static str;
+
+    /// wl_shm formats the renderer can read, on top of ARGB8888 and XRGB8888
+    fn shm_formats(&self) -> Vec<wl_shm::Format>;
+
+    fn output_create(&mut self, output: &mut Output) -> Result<(), RendererError>;
+    fn output_destroy(&mut self, output: &mut Output);
+
+    /// Paint `damage` (global coordinates) of `output`.
+    ///
+    /// `surfaces` are ordered bottom to top.
+    fn repaint_output(&mut self, output: &mut Output, surfaces: &[&Surface], damage: &Region)
+        -> Result<Scanout, RendererError>;
+
+    /// A sync_file signalled once the GPU is done with the last repaint of
+    /// `output`, to be passed to KMS as IN_FENCE_FD.
+    ///
+    /// `None` when the frame is complete as soon as `repaint_output` returns.
+    fn create_fence_fd(&mut self, _output: &Output) -> Option<RawFd> {
+        None
+    }
+
+    /// Take the content of `surface.buffer`, called on commit after a new attach
+    fn attach(&mut self, surface: &mut Surface) -> Result<(), RendererError>;
+
+    /// Upload `surface.damage` from the attached buffer
+    fn flush_damage(&mut self, surface: &mut Surface) -> Result<(), RendererError>;
+
+    /// Replace the content of `surface` with a solid premultiplied color
+    fn surface_set_color(&mut self, surface: &mut Surface, red: f32, green: f32, blue: f32, alpha: f32);
+
+    fn surface_get_content_size(&self, surface: &Surface) -> (i32, i32) {
+        (surface.buffer_width, surface.buffer_height)
+    }
+
+    /// Copy `rect` (output coordinates) of the last repaint into `pixels`,
+    /// tightly packed with a stride of `rect.width * 4`.
+    fn read_pixels(&mut self, output: &Output, format: wl_shm::Format, rect: Rectangle, pixels: &mut [u8])
+        -> Result<(), RendererError>;
+
+    /// Check that `dmabuf` can be used as surface content and prepare it
+    fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> Result<(), RendererError>;
+
+    /// DRM fourcc formats accepted by `import_dmabuf`
+    fn query_dmabuf_formats(&self) -> Vec<u32>;
+
+    fn query_dmabuf_modifiers(&self, format: u32) -> Vec<u64>;
 }
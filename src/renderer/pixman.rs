@@ -1,14 +1,20 @@
-// use renderer::Renderer;
-// use egl::types::*;
+use wayland_server::protocol::wl_shm;
+
+use dmabuf::Dmabuf;
+use output::Output;
+use region::{Rectangle, Region};
+use renderer::{Renderer, RendererError, Scanout};
+use renderer::software::{self, Canvas, Content};
+use surface::{Buffer, Surface};
 use wayland;
-// use wayland_server;
 
 // Capabilites:
-//  WESTON_CAP_ROTATION_ANY
 //  WESTON_CAP_CAPTURE_YFLIP
 //  WESTON_CAP_VIEW_CLIP_MASK
 
 
+/// Software renderer, the outputs are composited in system memory and the
+/// backend copies them to the screen with `read_pixels`.
 pub struct PixmanRenderer {
     // base: weston_renderer,
     // repaint_debug: bool,
@@ -18,109 +24,90 @@ pub struct PixmanRenderer {
 }
 
 impl PixmanRenderer {
-    pub fn new(display: &mut wayland::display::Display) 
-        -> Self
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            destroy_signal: wayland::signal::Signal::new(),
+        })
+    }
+
+    fn output_canvas(output: &Output) -> Result<&Canvas, RendererError> {
+        output.renderer_state.as_ref()
+            .and_then(|state| state.downcast_ref::<Canvas>())
+            .ok_or_else(|| RendererError::new(format!("Output {} not created by the pixman renderer", output.name)))
+    }
+}
+
+impl Renderer for PixmanRenderer {
+    fn name(&self) -> &'static str {
+        "pixman"
+    }
+
+    fn shm_formats(&self) -> Vec<wl_shm::Format> {
+        vec![wl_shm::Format::Rgb565]
+    }
+
+    fn output_create(&mut self, output: &mut Output) -> Result<(), RendererError> {
+        output.renderer_state = Some(Box::new(Canvas::new(output.width, output.height)));
+        output.damage_all();
+        Ok(())
+    }
+
+    fn output_destroy(&mut self, output: &mut Output) {
+        output.renderer_state = None;
+    }
+
+    fn repaint_output(&mut self, output: &mut Output, surfaces: &[&Surface], damage: &Region)
+        -> Result<Scanout, RendererError>
     {
-        use wayland_server::protocol::wl_shm;
-        display.add_shm_format(wl_shm::Format::Rgb565).unwrap();
+        let space = output.space();
+        let canvas = output.renderer_state.as_mut()
+            .and_then(|state| state.downcast_mut::<Canvas>())
+            .ok_or_else(|| RendererError::from_str("Output not created by the pixman renderer"))?;
 
+        software::composite(canvas, &space, surfaces, damage);
+        Ok(Scanout::ReadPixels)
+    }
 
-        Self {
-            destroy_signal: wayland::signal::Signal::new(),
+    fn attach(&mut self, surface: &mut Surface) -> Result<(), RendererError> {
+        match surface.buffer {
+            None => {
+                surface.renderer_state = None;
+                surface.buffer_width = 0;
+                surface.buffer_height = 0;
+                Ok(())
+            },
+            Some(Buffer::Shm(_)) => software::upload_shm(surface, true),
+            Some(Buffer::Egl(_)) | Some(Buffer::Dmabuf(_)) =>
+                Err(RendererError::unsupported("Pixman renderer supports only shm buffers".to_string())),
         }
     }
 
-    pub fn read_pixels(&self) { unimplemented!() }
-    pub fn repaint_output(&self) { unimplemented!() }
-    pub fn flush_damage(&self) { unimplemented!() }
-    pub fn attach(&self) { unimplemented!() }
-    pub fn surface_set_color(&self) { unimplemented!() }
-    pub fn destroy(&self) { unimplemented!() } //TODO drop?
-    pub fn surface_get_content_size(&self) { unimplemented!() }
-    pub fn surface_copy_content(&self) { unimplemented!() }
+    fn flush_damage(&mut self, surface: &mut Surface) -> Result<(), RendererError> {
+        match surface.buffer {
+            Some(Buffer::Shm(_)) => software::upload_shm(surface, false),
+            _ => Ok(()),
+        }
+    }
 
-}
+    fn surface_set_color(&mut self, surface: &mut Surface, red: f32, green: f32, blue: f32, alpha: f32) {
+        surface.renderer_state = Some(Box::new(Content::color(red, green, blue, alpha)));
+    }
+
+    fn read_pixels(&mut self, output: &Output, format: wl_shm::Format, rect: Rectangle, pixels: &mut [u8])
+        -> Result<(), RendererError>
+    {
+        Self::output_canvas(output)?.read(format, &rect, pixels)
+    }
+
+    fn import_dmabuf(&mut self, _dmabuf: &Dmabuf) -> Result<(), RendererError> {
+        Err(RendererError::unsupported("Pixman renderer cannot import dmabufs".to_string()))
+    }
 
-// impl Renderer for PixmanRenderer {
-//     fn image_target_texture_2d(&self) {}
-
-//     fn create_image(
-//         &self,
-//         context: Option<EGLContext>, 
-//         target: EGLenum,
-//         buffer: EGLClientBuffer,
-//         attrib_list: &Vec<EGLint>
-//     ) -> EGLImageKHR
-//     {
-//         panic!("not implemented yet");
-//     }
-
-//     fn swap_buffers_with_damage(&self) {}
-//     fn create_platform_window(&self) {}
-// 	// const has_unpack_subimage: bool;
-    
-//     // const has_bind_display: bool;
-//     fn bind_display(&self) {}
-//     fn unbind_display(&self) {}
-//     fn query_buffer(&self) {}
-
-//     // const has_context_priority: bool;
-// 	// const has_egl_image_external: bool;
-// 	// const has_egl_buffer_age: bool;
-// 	// const has_configless_context: bool;
-// 	// const has_surfaceless_context: bool;
-
-//     //nvidia stuff
-//     // const has_egl_output_base: bool;
-// 	// const has_egl_output_drm: bool;
-// 	// const has_egl_output_drm_flip_event: bool;
-//     fn get_output_layers(&self) {}
-//     fn query_output_layer_attrib(&self) {}
-
-//     // const has_egl_stream: bool;
-//     fn create_stream(&self) {}
-//     fn destroy_stream(&self) {}
-//     fn query_stream(&self) {}
-
-//     // const has_egl_stream_producer_eglsurface: bool;
-//     fn create_stream_producer_surface(&self) {}
-
-//     // const has_egl_stream_consumer_egloutput: bool;
-//     fn stream_consumer_output(&self) {}
-
-//     //nvidia egl stream attrib
-//     // const has_egl_stream_attrib: bool;
-//     fn create_stream_attrib(&self) {}
-//     // const has_egl_stream_acquire_mode: bool;
-//     fn stream_consumer_acquire_attrib(&self) {}
-
-//     // const has_egl_stream_consumer_gltexture: bool;
-//     // const has_egl_wayland_eglstream: bool;
-//     fn stream_consumer_gltexture(&self) {}
-
-//     // const has_dmabuf_import: bool;
-//     // struct wl_list dmabuf_images;
-
-//     // const has_gl_texture_rg: bool;
-//     // struct gl_shader texture_shader_rgba;
-// 	// struct gl_shader texture_shader_rgbx;
-// 	// struct gl_shader texture_shader_egl_external;
-// 	// struct gl_shader texture_shader_y_uv;
-// 	// struct gl_shader texture_shader_y_u_v;
-// 	// struct gl_shader texture_shader_y_xuxv;
-// 	// struct gl_shader invert_color_shader;
-// 	// struct gl_shader solid_shader;
-// 	// struct gl_shader *current_shader;
-
-// 	// fn destroy_signal_ref(&self) -> &wayland::signal::Signal { &self.destroy_signal }
-//     // fn output_destroy_listener_ref(&self) -> &wayland::listener::Listener { &self.output_destroy_listener }
-
-//     // const has_dmabuf_import_modifiers: bool;
-//     fn query_dmabuf_formats(&self) {}
-//     fn query_dmabuf_modifiers(&self) {}
-
-//     // const has_native_fence_sync: bool;
-//     fn create_sync(&self) {}
-//     fn destroy_sync(&self) {}
-//     fn dup_native_fence_fd(&self) {}
-// }
+    fn query_dmabuf_formats(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    fn query_dmabuf_modifiers(&self, _format: u32) -> Vec<u64> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::VecDeque;
+
+use wayland_server::protocol::{wl_output, wl_shm};
+
+use output::OutputSpace;
+use region::{Rectangle, Region};
+use renderer::RendererError;
+use surface::{Buffer, Surface};
+
+/// frames of damage kept for the scanout buffers, older ones are copied whole
+const BUFFER_DAMAGE_COUNT: usize = 2;
+
+/// 32bpp image in system memory, bytes are B, G, R, A (wl_shm ARGB8888)
+/// with premultiplied alpha.
+pub struct Canvas {
+    width: i32,
+    height: i32,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(width: i32, height: i32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> i32 {
+        self.width * 4
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn offset(&self, x: i32, y: i32) -> usize {
+        (y * self.stride() + x * 4) as usize
+    }
+
+    /// Convert `rect` of a client buffer into the canvas, at the same position
+    pub fn upload(&mut self, format: wl_shm::Format, src: &[u8], src_stride: i32, rect: &Rectangle)
+        -> Result<(), RendererError>
+    {
+        let rect = match rect.intersect(&Rectangle::new(0, 0, self.width, self.height)) {
+            Some(rect) => rect,
+            None => return Ok(()),
+        };
+
+        for y in rect.y..rect.y2() {
+            let dst = self.offset(rect.x, y);
+            let dst = &mut self.data[dst..dst + rect.width as usize * 4];
+            match format {
+                wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => {
+                    let start = (y * src_stride + rect.x * 4) as usize;
+                    dst.copy_from_slice(&src[start..start + rect.width as usize * 4]);
+                },
+                wl_shm::Format::Rgb565 => {
+                    let start = (y * src_stride + rect.x * 2) as usize;
+                    let row = &src[start..start + rect.width as usize * 2];
+                    for (pixel, rgb) in dst.chunks_mut(4).zip(row.chunks(2)) {
+                        let rgb = u16::from(rgb[0]) | u16::from(rgb[1]) << 8;
+                        let r = ((rgb >> 11) & 0x1f) as u8;
+                        let g = ((rgb >> 5) & 0x3f) as u8;
+                        let b = (rgb & 0x1f) as u8;
+                        pixel[0] = b << 3 | b >> 2;
+                        pixel[1] = g << 2 | g >> 4;
+                        pixel[2] = r << 3 | r >> 2;
+                        pixel[3] = 0xff;
+                    }
+                },
+                format => return Err(RendererError::unsupported(format!("Unsupported shm format {:?}", format))),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy `rect` into `pixels` converting to `format`
+    pub fn read(&self, format: wl_shm::Format, rect: &Rectangle, pixels: &mut [u8]) -> Result<(), RendererError> {
+        let bounds = Rectangle::new(0, 0, self.width, self.height);
+        if !bounds.contains_rect(rect) {
+            return Err(RendererError::from_str("read_pixels rectangle outside of the output"));
+        }
+        let row_len = rect.width as usize * 4;
+        if pixels.len() < row_len * rect.height as usize {
+            return Err(RendererError::from_str("read_pixels destination too small"));
+        }
+
+        let swap_rb = match format {
+            wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => false,
+            wl_shm::Format::Abgr8888 | wl_shm::Format::Xbgr8888 => true,
+            format => return Err(RendererError::unsupported(format!("Unsupported read_pixels format {:?}", format))),
+        };
+
+        for (row, y) in (rect.y..rect.y2()).enumerate() {
+            let src = self.offset(rect.x, y);
+            let dst = &mut pixels[row * row_len..(row + 1) * row_len];
+            dst.copy_from_slice(&self.data[src..src + row_len]);
+            if swap_rb {
+                for pixel in dst.chunks_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Blend `content` stretched over `target` (frame coordinates of `space`)
+    /// into `clip` (canvas pixels). `source` is the part of the image to
+    /// show, x, y, width and height in image pixels.
+    fn paint(&mut self, content: &Content, space: &OutputSpace, target: &Rectangle, source: (f64, f64, f64, f64), clip: &Rectangle) {
+        let row_len = clip.width as usize * 4;
+        let (image, opaque) = match *content {
+            Content::Color(color) => {
+                for y in clip.y..clip.y2() {
+                    let dst = self.offset(clip.x, y);
+                    for pixel in self.data[dst..dst + row_len].chunks_mut(4) {
+                        blend(pixel, &color);
+                    }
+                }
+                return;
+            },
+            Content::Image { ref image, opaque } => (image, opaque),
+        };
+
+        let (sx, sy) = (source.2 / f64::from(target.width), source.3 / f64::from(target.height));
+        let copy = space.transform == wl_output::Transform::Normal
+            && sx == 1.0 && sy == 1.0 && source.0.fract() == 0.0 && source.1.fract() == 0.0;
+        let clip = if copy {
+            // pixel for pixel, the image has to cover the whole clip
+            let origin = (target.x - source.0 as i32, target.y - source.1 as i32);
+            match clip.intersect(&Rectangle::new(origin.0, origin.1, image.width, image.height)) {
+                Some(clip) => clip,
+                None => return,
+            }
+        } else {
+            *clip
+        };
+        let row_len = clip.width as usize * 4;
+
+        let mut pixel_buffer = [0u8; 4];
+        for y in clip.y..clip.y2() {
+            let dst = self.offset(clip.x, y);
+            let dst = &mut self.data[dst..dst + row_len];
+            if copy {
+                let src = image.offset(clip.x - target.x + source.0 as i32, y - target.y + source.1 as i32);
+                let src = &image.data[src..src + row_len];
+                if opaque {
+                    dst.copy_from_slice(src);
+                    for pixel in dst.chunks_mut(4) {
+                        pixel[3] = 0xff;
+                    }
+                } else {
+                    for (pixel, src) in dst.chunks_mut(4).zip(src.chunks(4)) {
+                        blend(pixel, src);
+                    }
+                }
+                continue;
+            }
+
+            // sample at the pixel centers
+            for (x, pixel) in (clip.x..clip.x2()).zip(dst.chunks_mut(4)) {
+                let (frame_x, frame_y) = space.pixel_to_frame(f64::from(x) + 0.5, f64::from(y) + 0.5);
+                let u = source.0 + (frame_x - f64::from(target.x)) * sx;
+                let v = source.1 + (frame_y - f64::from(target.y)) * sy;
+                image.sample(u, v, source, &mut pixel_buffer);
+                if opaque {
+                    pixel_buffer[3] = 0xff;
+                }
+                blend(pixel, &pixel_buffer);
+            }
+        }
+    }
+
+    /// Bilinear interpolation of the four pixels around `(u, v)`, clamped to
+    /// the edges of `source` so the crop does not bleed in its neighbours
+    fn sample(&self, u: f64, v: f64, source: (f64, f64, f64, f64), pixel: &mut [u8; 4]) {
+        let min_x = source.0.floor().max(0.0) as i32;
+        let min_y = source.1.floor().max(0.0) as i32;
+        let max_x = ((source.0 + source.2).ceil() as i32).min(self.width) - 1;
+        let max_y = ((source.1 + source.3).ceil() as i32).min(self.height) - 1;
+        if max_x < min_x || max_y < min_y {
+            *pixel = [0; 4];
+            return;
+        }
+
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (x0, y0) = (u.floor(), v.floor());
+        let (fx, fy) = (u - x0, v - y0);
+        let clamp_x = |x: f64| (x as i32).max(min_x).min(max_x);
+        let clamp_y = |y: f64| (y as i32).max(min_y).min(max_y);
+        let (x0, x1, y0, y1) = (clamp_x(x0), clamp_x(x0 + 1.0), clamp_y(y0), clamp_y(y0 + 1.0));
+
+        let (top_left, top_right) = (self.offset(x0, y0), self.offset(x1, y0));
+        let (bottom_left, bottom_right) = (self.offset(x0, y1), self.offset(x1, y1));
+        for c in 0..4 {
+            let top = f64::from(self.data[top_left + c]) * (1.0 - fx) + f64::from(self.data[top_right + c]) * fx;
+            let bottom = f64::from(self.data[bottom_left + c]) * (1.0 - fx) + f64::from(self.data[bottom_right + c]) * fx;
+            pixel[c] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+    }
+}
+
+// premultiplied OVER
+fn blend(dst: &mut [u8], src: &[u8]) {
+    let alpha = u32::from(src[3]);
+    if alpha == 0xff {
+        dst.copy_from_slice(&src[..4]);
+    } else if alpha != 0 {
+        for c in 0..4 {
+            dst[c] = (u32::from(src[c]) + u32::from(dst[c]) * (0xff - alpha) / 0xff) as u8;
+        }
+    }
+}
+
+/// What a software renderer keeps for each surface
+pub enum Content {
+    Image {
+        image: Canvas,
+        /// alpha channel has to be ignored (XRGB formats)
+        opaque: bool,
+    },
+    Color([u8; 4]),
+}
+
+impl Content {
+    pub fn color(red: f32, green: f32, blue: f32, alpha: f32) -> Self {
+        let channel = |value: f32| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+        Content::Color([channel(blue), channel(green), channel(red), channel(alpha)])
+    }
+}
+
+/// Shared `Renderer::attach` and `Renderer::flush_damage` for shm buffers.
+///
+/// Resets the surface content when the size or format of the buffer changed.
+pub fn upload_shm(surface: &mut Surface, full: bool) -> Result<(), RendererError> {
+    let shm = match surface.buffer {
+        Some(Buffer::Shm(ref shm)) => shm.clone(),
+        _ => return Err(RendererError::from_str("Surface has no shm buffer attached")),
+    };
+    let format = shm.format()
+        .ok_or_else(|| RendererError::from_str("Unknown shm buffer format"))?;
+    let opaque = format != wl_shm::Format::Argb8888;
+    let (width, height) = (shm.width(), shm.height());
+
+    let reuse = match surface.renderer_state.as_ref().and_then(|state| state.downcast_ref::<Content>()) {
+        Some(Content::Image { ref image, opaque: was_opaque }) =>
+            image.width() == width && image.height() == height && *was_opaque == opaque,
+        _ => false,
+    };
+    if !reuse {
+        surface.renderer_state = Some(Box::new(Content::Image {
+            image: Canvas::new(width, height),
+            opaque,
+        }));
+    }
+    surface.buffer_width = width;
+    surface.buffer_height = height;
+
+    let damage = if full || !reuse {
+        Region::from_rect(Rectangle::new(0, 0, width, height))
+    } else {
+        surface.damage.clone()
+    };
+
+    let image = match surface.renderer_state.as_mut().and_then(|state| state.downcast_mut::<Content>()) {
+        Some(Content::Image { ref mut image, .. }) => image,
+        _ => unreachable!(),
+    };
+    shm.with_data(|data| {
+        for rect in damage.rects() {
+            image.upload(format, data, shm.stride(), rect)?;
+        }
+        Ok(())
+    })
+}
+
+/// Repaint `damage` (global coordinates) of the output `space` into `canvas`.
+///
+/// Surfaces without software content are skipped.
+pub fn composite(canvas: &mut Canvas, space: &OutputSpace, surfaces: &[&Surface], damage: &Region) {
+    for local in space.region_to_pixels(damage).rects() {
+        canvas.paint(&Content::Color([0, 0, 0, 0xff]), space, local, (0.0, 0.0, 0.0, 0.0), local);
+
+        for surface in surfaces.iter() {
+            let content = match surface.renderer_state.as_ref().and_then(|state| state.downcast_ref::<Content>()) {
+                Some(content) => content,
+                None => continue,
+            };
+            let target = space.to_frame(&surface.geometry());
+            let clip = match space.frame_rect_to_pixels(&target).intersect(local) {
+                Some(clip) => clip,
+                None => continue,
+            };
+            canvas.paint(content, space, &target, surface.source_rect(), &clip);
+        }
+    }
+}
+
+/// Damage of the last frames, in output pixels, for scanout buffers written
+/// in turn: what the canvas has to copy into the next one
+#[derive(Default)]
+pub struct DamageHistory {
+    frames: VecDeque<Region>,
+    /// frames written since the buffers were created
+    written: usize,
+}
+
+impl DamageHistory {
+    /// Record `damage` of the new frame, going to the next of `buffers`, and
+    /// return what that buffer misses: the damage of the frames since it was
+    /// last written, all of `bounds` if it never was or that is too long ago
+    pub fn next_frame(&mut self, damage: Region, buffers: usize, bounds: Rectangle) -> Region {
+        let missing = if self.written < buffers || buffers - 1 > self.frames.len() {
+            Region::from_rect(bounds)
+        } else {
+            let mut missing = damage.clone();
+            for old_damage in self.frames.iter().take(buffers - 1) {
+                missing.union(old_damage);
+            }
+            missing.intersect_rect(&bounds)
+        };
+        self.frames.push_front(damage);
+        self.frames.truncate(BUFFER_DAMAGE_COUNT);
+        self.written = self.written.saturating_add(1);
+        missing
+    }
+
+    /// The buffers are gone, the next ones start blank
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.written = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output::transformed_size;
+    use wayland_server::protocol::wl_output::Transform;
+
+    /// Composite a 3x2 surface, pixels labelled
+    ///
+    ///     1 2 3
+    ///     4 5 6
+    ///
+    /// on an output showing it whole under `transform`, the labels by row of
+    /// output pixels
+    fn composite_labels(transform: Transform) -> Vec<Vec<u8>> {
+        let mut pixels = Vec::new();
+        for label in 1..7 {
+            pixels.extend_from_slice(&[label, 0, 0, 0xff]);
+        }
+        let mut image = Canvas::new(3, 2);
+        image.upload(wl_shm::Format::Argb8888, &pixels, 12, &Rectangle::new(0, 0, 3, 2)).unwrap();
+
+        let mut surface = Surface::new();
+        surface.width = 3;
+        surface.height = 2;
+        surface.buffer_width = 3;
+        surface.buffer_height = 2;
+        surface.renderer_state = Some(Box::new(Content::Image { image, opaque: true }));
+
+        let (width, height) = transformed_size(3, 2, transform);
+        let space = OutputSpace { x: 0, y: 0, scale: 1.0, transform, width, height };
+        let mut canvas = Canvas::new(width, height);
+        composite(&mut canvas, &space, &[&surface], &Region::from_rect(Rectangle::new(0, 0, 3, 2)));
+
+        canvas.data().chunks(canvas.stride() as usize)
+            .map(|row| row.chunks(4).map(|pixel| pixel[0]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn composite_normal() {
+        assert_eq!(composite_labels(Transform::Normal), vec![vec![1, 2, 3], vec![4, 5, 6]]);
+    }
+
+    #[test]
+    fn composite_rotated() {
+        assert_eq!(composite_labels(Transform::_90), vec![vec![3, 6], vec![2, 5], vec![1, 4]]);
+        assert_eq!(composite_labels(Transform::_180), vec![vec![6, 5, 4], vec![3, 2, 1]]);
+        assert_eq!(composite_labels(Transform::_270), vec![vec![4, 1], vec![5, 2], vec![6, 3]]);
+    }
+
+    #[test]
+    fn composite_flipped() {
+        assert_eq!(composite_labels(Transform::Flipped), vec![vec![3, 2, 1], vec![6, 5, 4]]);
+        assert_eq!(composite_labels(Transform::Flipped90), vec![vec![1, 4], vec![2, 5], vec![3, 6]]);
+        assert_eq!(composite_labels(Transform::Flipped180), vec![vec![4, 5, 6], vec![1, 2, 3]]);
+        assert_eq!(composite_labels(Transform::Flipped270), vec![vec![6, 3], vec![5, 2], vec![4, 1]]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Signals through the event loop (signalfd): SIGINT and SIGTERM stop the
+//! compositor, SIGUSR1 switches between the GL and pixman renderers (only
+//! EGLStream outputs have GL so far), SIGCHLD reaps the helpers it spawned
+//! (weston_watch_process).
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+use std::ptr;
+use std::rc::Rc;
+
+use libc::{self, c_int, pid_t};
+use wayland_server::calloop::{LoopHandle, Source};
+use wayland_server::calloop::signals::{Signal, Signals};
+
+use wayland::WaylandError;
+
+pub struct SignalHandler {
+    /// The signal that asked to stop
+    terminate: Cell<Option<Signal>>,
+    /// SIGUSR1 came since the last `take_renderer_switch`
+    switch_renderer: Cell<bool>,
+    /// Spawned helpers, called with the exit status once reaped
+    children: RefCell<HashMap<pid_t, Box<dyn FnMut(ExitStatus)>>>,
+}
+
+impl SignalHandler {
+    pub fn new() -> Rc<Self> {
+        Rc::new(SignalHandler {
+            terminate: Cell::new(None),
+            switch_renderer: Cell::new(false),
+            children: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Block the signals and watch them on the event loop of `handle`,
+    /// removing the source unblocks them again
+    pub fn insert<Data: 'static>(handler: &Rc<Self>, handle: &LoopHandle<Data>) -> Result<Source<Signals>, WaylandError> {
+        let signals = Signals::new(&[Signal::SIGINT, Signal::SIGTERM, Signal::SIGUSR1, Signal::SIGCHLD])
+            .map_err(|e| WaylandError::io("Could not create the signalfd".to_string(), e))?;
+
+        let handler = handler.clone();
+        handle.insert_source(signals, move |event, _| match event.signal() {
+            Signal::SIGCHLD => handler.reap_children(),
+            Signal::SIGUSR1 => handler.switch_renderer.set(true),
+            signal => {
+                log_info!("compositor", "caught {:?}, shutting down", signal);
+                handler.terminate.set(Some(signal));
+            },
+        })
+            .map_err(|e| WaylandError::io("Could not watch the signalfd".to_string(), e.error))
+    }
+
+    /// SIGINT or SIGTERM came, the event loop has to stop
+    pub fn terminated(&self) -> bool {
+        self.terminate.get().is_some()
+    }
+
+    /// SIGUSR1 asked for the other renderer, cleared by the call
+    pub fn take_renderer_switch(&self) -> bool {
+        self.switch_renderer.replace(false)
+    }
+
+    /// Run `command` as a helper of the compositor, `on_exit` gets its status
+    /// when it is reaped
+    pub fn spawn<F>(&self, command: &mut Command, on_exit: F) -> io::Result<pid_t>
+        where F: FnMut(ExitStatus) + 'static
+    {
+        // the signal mask survives exec, the helper would not see SIGTERM
+        unsafe {
+            command.pre_exec(|| {
+                let mut mask: libc::sigset_t = ::std::mem::zeroed();
+                libc::sigemptyset(&mut mask);
+                if libc::pthread_sigmask(libc::SIG_SETMASK, &mask, ptr::null_mut()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        let pid = child.id() as pid_t;
+        self.children.borrow_mut().insert(pid, Box::new(on_exit));
+        log_info!("compositor", "spawned {:?} as pid {}", command, pid);
+        Ok(pid)
+    }
+
+    /// One SIGCHLD can stand for several children
+    fn reap_children(&self) {
+        loop {
+            let mut status: c_int = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+
+            let status = ExitStatus::from_raw(status);
+            // take the callback out, it could spawn another helper
+            let on_exit = self.children.borrow_mut().remove(&pid);
+            match on_exit {
+                Some(mut on_exit) => {
+                    log_info!("compositor", "helper {} exited: {}", pid, status);
+                    on_exit(status);
+                },
+                None => log_debug!("compositor", "reaped unknown child {}: {}", pid, status),
+            }
+        }
+    }
+}
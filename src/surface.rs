@@ -0,0 +1,326 @@
+use std::any::Any;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::slice;
+
+use libc;
+use wayland_server::Resource;
+use wayland_server::protocol::{wl_buffer, wl_callback, wl_shm, wl_surface};
+use wayland_server::sys::server::{wl_resource, wl_shm_buffer};
+use wayland_server::sys::server as ffi;
+use wayland_protocols::presentation_time::server::wp_presentation_feedback::WpPresentationFeedback;
+
+use dmabuf::Dmabuf;
+use region::{Rectangle, Region};
+use wayland::protocols::fractional_scale_v1::server::wp_fractional_scale_v1::WpFractionalScaleV1;
+
+/// A `wl_buffer` backed by wl_shm memory
+#[derive(Debug, Clone)]
+pub struct ShmBuffer {
+    raw: *mut wl_shm_buffer,
+}
+
+impl ShmBuffer {
+    /// Returns `None` if `resource` is not a wl_shm buffer
+    pub unsafe fn from_resource(resource: *mut wl_resource) -> Option<Self> {
+        let raw = ffi::wl_shm_buffer_get(resource);
+        if raw.is_null() {
+            None
+        } else {
+            Some(Self {
+                raw,
+            })
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        unsafe { ffi::wl_shm_buffer_get_width(self.raw) }
+    }
+
+    pub fn height(&self) -> i32 {
+        unsafe { ffi::wl_shm_buffer_get_height(self.raw) }
+    }
+
+    pub fn stride(&self) -> i32 {
+        unsafe { ffi::wl_shm_buffer_get_stride(self.raw) }
+    }
+
+    pub fn format(&self) -> Option<wl_shm::Format> {
+        wl_shm::Format::from_raw(unsafe { ffi::wl_shm_buffer_get_format(self.raw) })
+    }
+
+    /// Access the buffer pixels.
+    ///
+    /// The client can truncate the pool under our feet, begin/end access turn
+    /// the SIGBUS into an error on the client.
+    pub fn with_data<F, T>(&self, f: F) -> T
+        where F: FnOnce(&[u8]) -> T
+    {
+        unsafe {
+            ffi::wl_shm_buffer_begin_access(self.raw);
+            let data = ffi::wl_shm_buffer_get_data(self.raw) as *const u8;
+            let size = (self.stride() * self.height()) as usize;
+            let result = f(slice::from_raw_parts(data, size));
+            ffi::wl_shm_buffer_end_access(self.raw);
+            result
+        }
+    }
+}
+
+/// Content attached to a surface
+#[derive(Clone)]
+pub enum Buffer {
+    Shm(ShmBuffer),
+    /// wl_drm or EGLStream buffer, only the EGL renderer knows what to do with it
+    Egl(*mut wl_resource),
+    Dmabuf(Rc<Dmabuf>),
+}
+
+impl Buffer {
+    pub unsafe fn from_resource(resource: *mut wl_resource) -> Self {
+        if let Some(shm) = ShmBuffer::from_resource(resource) {
+            return Buffer::Shm(shm);
+        }
+        // the zwp_linux_dmabuf_v1 buffers carry their dmabuf
+        let buffer = Resource::<wl_buffer::WlBuffer>::from_c_ptr(resource);
+        match buffer.user_data::<Rc<Dmabuf>>() {
+            Some(dmabuf) => Buffer::Dmabuf(dmabuf.clone()),
+            None => Buffer::Egl(resource),
+        }
+    }
+
+    /// Size of the buffer, if it can be known without the renderer
+    pub fn size(&self) -> Option<(i32, i32)> {
+        match *self {
+            Buffer::Shm(ref shm) => Some((shm.width(), shm.height())),
+            Buffer::Egl(_) => None,
+            Buffer::Dmabuf(ref dmabuf) => Some((dmabuf.width, dmabuf.height)),
+        }
+    }
+}
+
+/// wp_viewport crop and scale
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Viewport {
+    /// x, y, width and height of the part of the buffer to show, in buffer
+    /// pixels divided by the buffer scale
+    pub source: Option<(f64, f64, f64, f64)>,
+    /// surface size to scale it to
+    pub destination: Option<(i32, i32)>,
+}
+
+/// Compositor side of a `wl_surface`
+pub struct Surface {
+    /// the `wl_surface`, to tell the client which outputs show it
+    pub resource: Option<Resource<wl_surface::WlSurface>>,
+    /// position in the global compositor space
+    pub x: i32,
+    pub y: i32,
+    /// size in the global space, see `update_size`
+    pub width: i32,
+    pub height: i32,
+    /// size of the attached content in pixels, set by `Renderer::attach`
+    pub buffer_width: i32,
+    pub buffer_height: i32,
+    /// wl_surface.set_buffer_scale
+    pub buffer_scale: i32,
+    pub viewport: Viewport,
+    pub buffer: Option<Buffer>,
+    /// the `wl_buffer` behind `buffer`, `buffer` must not be read once it is dead
+    pub buffer_resource: Option<Resource<wl_buffer::WlBuffer>>,
+    /// `buffer` changed since the last `Renderer::attach`
+    pub buffer_attached: bool,
+    /// damage not yet flushed to the renderer, in buffer pixels
+    pub damage: Region,
+    /// area the client declared opaque, in surface coordinates
+    pub opaque: Region,
+    /// area accepting input in surface coordinates, `None` for all of it
+    pub input: Option<Region>,
+    /// sync_file the client signals once done writing `buffer`, owned
+    pub acquire_fence: Option<RawFd>,
+    /// sync_file signalled once the last repaint stopped reading `buffer`, owned
+    pub release_fence: Option<RawFd>,
+    /// callbacks to complete after the next repaint
+    pub frame_callbacks: Vec<Resource<wl_callback::WlCallback>>,
+    /// presentation feedback of the committed content, moves to the output
+    /// that repaints it
+    pub presentation_feedback: Vec<Resource<WpPresentationFeedback>>,
+    /// names of the outputs the client was told show the surface
+    pub outputs: Vec<String>,
+    /// wp_fractional_scale_v1 of the surface
+    pub fractional_scale: Option<Resource<WpFractionalScaleV1>>,
+    /// last preferred scale sent, in 120ths, 0 before the first
+    pub preferred_scale: u32,
+    /// what the surface is for, once given a role it can't take another
+    pub role: Option<&'static str>,
+    /// renderer private data (textures, shadow copies)
+    pub renderer_state: Option<Box<dyn Any>>,
+}
+
+impl Surface {
+    pub fn new() -> Self {
+        Self {
+            resource: None,
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            buffer_width: 0,
+            buffer_height: 0,
+            buffer_scale: 1,
+            viewport: Viewport::default(),
+            buffer: None,
+            buffer_resource: None,
+            buffer_attached: false,
+            damage: Region::new(),
+            opaque: Region::new(),
+            input: None,
+            acquire_fence: None,
+            release_fence: None,
+            frame_callbacks: Vec::new(),
+            presentation_feedback: Vec::new(),
+            outputs: Vec::new(),
+            fractional_scale: None,
+            preferred_scale: 0,
+            role: None,
+            renderer_state: None,
+        }
+    }
+
+    /// The area covered by the surface in the global space
+    pub fn geometry(&self) -> Rectangle {
+        Rectangle::new(self.x, self.y, self.width, self.height)
+    }
+
+    pub fn damage_all(&mut self) {
+        let rect = Rectangle::new(0, 0, self.buffer_width, self.buffer_height);
+        self.damage.union_rect(rect);
+    }
+
+    /// Size the surface after its buffer, buffer scale and viewport
+    pub fn update_size(&mut self) {
+        let (width, height) = match (self.buffer.is_some(), self.viewport.destination, self.viewport.source) {
+            (false, _, _) => (0, 0),
+            (true, Some(destination), _) => destination,
+            // the size is checked to be integer on commit
+            (true, None, Some((_, _, width, height))) => (width as i32, height as i32),
+            (true, None, None) => (self.buffer_width / self.buffer_scale, self.buffer_height / self.buffer_scale),
+        };
+        self.width = width;
+        self.height = height;
+    }
+
+    /// The part of the buffer shown, x, y, width and height in buffer pixels
+    pub fn source_rect(&self) -> (f64, f64, f64, f64) {
+        let scale = f64::from(self.buffer_scale);
+        match self.viewport.source {
+            Some((x, y, width, height)) => (x * scale, y * scale, width * scale, height * scale),
+            None => (0.0, 0.0, f64::from(self.buffer_width), f64::from(self.buffer_height)),
+        }
+    }
+
+    /// Whether the buffer is shown pixel for pixel, one surface unit being
+    /// `scale` output pixels
+    pub fn is_pixel_aligned(&self, scale: f64) -> bool {
+        let (x, y, width, height) = self.source_rect();
+        x.fract() == 0.0 && y.fract() == 0.0
+            && width == f64::from(self.width) * scale && height == f64::from(self.height) * scale
+    }
+
+    /// The buffer pixels `rect` (surface coordinates) touches
+    pub fn surface_to_buffer_rect(&self, rect: &Rectangle) -> Rectangle {
+        if self.width <= 0 || self.height <= 0 {
+            return Rectangle::new(0, 0, 0, 0);
+        }
+        let (x, y, width, height) = self.source_rect();
+        let (sx, sy) = (width / f64::from(self.width), height / f64::from(self.height));
+        let x1 = (x + f64::from(rect.x) * sx).floor() as i32;
+        let y1 = (y + f64::from(rect.y) * sy).floor() as i32;
+        let x2 = (x + f64::from(rect.x2()) * sx).ceil() as i32;
+        let y2 = (y + f64::from(rect.y2()) * sy).ceil() as i32;
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+            .intersect(&Rectangle::new(0, 0, self.buffer_width, self.buffer_height))
+            .unwrap_or_else(|| Rectangle::new(0, 0, 0, 0))
+    }
+
+    /// The surface area `rect` (buffer pixels) shows up in, rounded outwards
+    pub fn buffer_to_surface_rect(&self, rect: &Rectangle) -> Rectangle {
+        let (x, y, width, height) = self.source_rect();
+        if width <= 0.0 || height <= 0.0 {
+            return Rectangle::new(0, 0, 0, 0);
+        }
+        let (sx, sy) = (f64::from(self.width) / width, f64::from(self.height) / height);
+        let x1 = ((f64::from(rect.x) - x) * sx).floor() as i32;
+        let y1 = ((f64::from(rect.y) - y) * sy).floor() as i32;
+        let x2 = ((f64::from(rect.x2()) - x) * sx).ceil() as i32;
+        let y2 = ((f64::from(rect.y2()) - y) * sy).ceil() as i32;
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+            .intersect(&Rectangle::new(0, 0, self.width, self.height))
+            .unwrap_or_else(|| Rectangle::new(0, 0, 0, 0))
+    }
+
+    /// The attached buffer, if its `wl_buffer` is still alive
+    pub fn live_buffer(&self) -> Option<&Buffer> {
+        match self.buffer_resource {
+            Some(ref resource) if !resource.is_alive() => None,
+            _ => self.buffer.as_ref(),
+        }
+    }
+
+    pub fn set_acquire_fence(&mut self, fence: Option<RawFd>) {
+        if let Some(fd) = self.acquire_fence.take() {
+            unsafe { libc::close(fd) };
+        }
+        self.acquire_fence = fence;
+    }
+
+    pub fn set_release_fence(&mut self, fence: Option<RawFd>) {
+        if let Some(fd) = self.release_fence.take() {
+            unsafe { libc::close(fd) };
+        }
+        self.release_fence = fence;
+    }
+
+    /// Block until the client is done writing the buffer, for renderers
+    /// reading it with the CPU.
+    ///
+    /// Gives up after a second, a stuck client must not freeze the compositor.
+    pub fn wait_acquire_fence(&self) -> bool {
+        let fd = match self.acquire_fence {
+            Some(fd) => fd,
+            None => return true,
+        };
+
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let r = unsafe { libc::poll(&mut pollfd, 1, 1000) };
+            if r < 0 && ::std::io::Error::last_os_error().kind() == ::std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            if r <= 0 {
+                log_warn!("wayland", "Timeout or error waiting on the acquire fence {}", fd);
+            }
+            return r > 0;
+        }
+    }
+
+    /// Complete the frame callbacks, `time` in milliseconds
+    pub fn send_frame_done(&mut self, time: u32) {
+        for callback in self.frame_callbacks.drain(..) {
+            if callback.is_alive() {
+                callback.send(wl_callback::Event::Done { callback_data: time });
+            }
+        }
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        self.set_acquire_fence(None);
+        self.set_release_fence(None);
+    }
+}
@@ -0,0 +1,5 @@
+use libc::{c_int, iovec};
+
+extern "C" {
+    pub fn sd_journal_sendv(iov: *const iovec, n: c_int) -> c_int;
+}
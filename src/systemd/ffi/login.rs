@@ -18,7 +18,7 @@ extern "C" {
     pub fn sd_peer_get_slice(fd: c_int, slice: *mut *mut c_char) -> c_int;
     pub fn sd_peer_get_user_slice(fd: c_int, slice: *mut *mut c_char) -> c_int;
     pub fn sd_peer_get_machine_name(fd: c_int, machine: *mut *mut c_char) -> c_int;
-    pub fn sd_peer_get_cgroup(pid: pid_t, cgroup: *mut *mut c_char) -> c_int;
+    pub fn sd_peer_get_cgroup(fd: c_int, cgroup: *mut *mut c_char) -> c_int;
     pub fn sd_uid_get_state(uid: uid_t, state: *mut *mut c_char) -> c_int;
     pub fn sd_uid_get_display(uid: uid_t, session: *mut *mut c_char) -> c_int;
     pub fn sd_uid_is_on_seat(uid: uid_t, require_active: c_int, seat: *const c_char) -> c_int;
@@ -0,0 +1,25 @@
+use std::io;
+
+use libc::{c_int, c_void, iovec};
+
+use systemd::ffi::journal as ffi;
+
+/// Send one entry with the `fields` to the journal, MESSAGE and PRIORITY
+/// should be among them
+pub fn send(fields: &[(&str, &str)]) -> Result<(), String> {
+    let fields: Vec<String> = fields.iter()
+        .map(|&(name, value)| format!("{}={}", name, value))
+        .collect();
+    let iov: Vec<iovec> = fields.iter()
+        .map(|field| iovec {
+            iov_base: field.as_ptr() as *mut c_void,
+            iov_len: field.len(),
+        })
+        .collect();
+
+    let r = unsafe { ffi::sd_journal_sendv(iov.as_ptr(), iov.len() as c_int) };
+    if r < 0 {
+        return Err(format!("journal: failed to send: {}", io::Error::from_raw_os_error(-r)));
+    }
+    Ok(())
+}
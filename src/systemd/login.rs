@@ -1,12 +1,153 @@
-use std::ffi::CString;
+use std::error::Error as StdError;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
 use std::result::Result;
+use std::slice;
 use std::string::String;
+use std::time::Duration;
 
-use libc::{pid_t, c_int, c_char, c_uint};
+use libc;
+use libc::{pid_t, uid_t, c_int, c_char, c_short, c_uint};
 use libc::getpid;
 
 use systemd::ffi::login;
 
+/// Copy a string allocated by libsystemd and free it
+unsafe fn take_string(string: *mut c_char) -> String {
+    if string.is_null() {
+        return String::new();
+    }
+    let owned = CStr::from_ptr(string).to_string_lossy().into_owned();
+    libc::free(string as *mut libc::c_void);
+    owned
+}
+
+/// Copy a NULL terminated string array allocated by libsystemd and free it
+unsafe fn take_string_array(array: *mut *mut c_char) -> Vec<String> {
+    let mut strings = Vec::new();
+    if array.is_null() {
+        return strings;
+    }
+    let mut i = 0;
+    while !(*array.offset(i)).is_null() {
+        strings.push(take_string(*array.offset(i)));
+        i += 1;
+    }
+    libc::free(array as *mut libc::c_void);
+    strings
+}
+
+/// Copy an array of `len` items allocated by libsystemd and free it
+unsafe fn take_array<T: Copy>(array: *mut T, len: c_int) -> Vec<T> {
+    if array.is_null() {
+        return Vec::new();
+    }
+    let items = slice::from_raw_parts(array, len.max(0) as usize).to_vec();
+    libc::free(array as *mut libc::c_void);
+    items
+}
+
+/// A failed sd-login call
+#[derive(Debug)]
+pub enum LoginError {
+    /// The call returned -errno
+    Call {
+        what: String,
+        source: io::Error,
+    },
+    /// A session, seat or machine name with a NUL byte in it
+    InvalidName(String),
+}
+
+impl LoginError {
+    /// The errno the call failed with
+    pub fn errno(&self) -> Option<c_int> {
+        match *self {
+            LoginError::Call { ref source, .. } => source.raw_os_error(),
+            LoginError::InvalidName(_) => None,
+        }
+    }
+}
+
+impl StdError for LoginError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            LoginError::Call { ref source, .. } => Some(source),
+            LoginError::InvalidName(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoginError::Call { ref what, ref source } => write!(f, "logind: failed to get {}: {}", what, source),
+            LoginError::InvalidName(ref name) => write!(f, "logind: invalid name \"{}\"", name),
+        }
+    }
+}
+
+fn c_string(string: &str) -> Result<CString, LoginError> {
+    CString::new(string).map_err(|_| LoginError::InvalidName(string.to_string()))
+}
+
+/// `r` is the negative errno sd-login returned
+fn login_error(what: &str, r: c_int) -> LoginError {
+    LoginError::Call {
+        what: what.to_string(),
+        source: io::Error::from_raw_os_error(-r),
+    }
+}
+
+/// Call a getter returning a newly allocated string
+fn get_string<F>(what: &str, getter: F) -> Result<String, LoginError>
+    where F: FnOnce(*mut *mut c_char) -> c_int
+{
+    let mut string: *mut c_char = ptr::null_mut();
+    let r = getter(&mut string);
+    if r < 0 {
+        Err(login_error(what, r))
+    } else {
+        Ok(unsafe { take_string(string) })
+    }
+}
+
+/// Call a getter returning a newly allocated string array
+fn get_string_array<F>(what: &str, getter: F) -> Result<Vec<String>, LoginError>
+    where F: FnOnce(*mut *mut *mut c_char) -> c_int
+{
+    let mut array: *mut *mut c_char = ptr::null_mut();
+    let r = getter(&mut array);
+    if r < 0 {
+        Err(login_error(what, r))
+    } else {
+        Ok(unsafe { take_string_array(array) })
+    }
+}
+
+fn get_uid<F>(what: &str, getter: F) -> Result<uid_t, LoginError>
+    where F: FnOnce(*mut uid_t) -> c_int
+{
+    let mut uid: uid_t = 0;
+    let r = getter(&mut uid);
+    if r < 0 {
+        Err(login_error(what, r))
+    } else {
+        Ok(uid)
+    }
+}
+
+fn get_bool(what: &str, r: c_int) -> Result<bool, LoginError> {
+    if r < 0 {
+        Err(login_error(what, r))
+    } else {
+        Ok(r > 0)
+    }
+}
+
 /// This function returns the systemd session for the given pid
 ///
 /// # Examples
@@ -21,91 +162,428 @@ use systemd::ffi::login;
 ///     Err(e) => panic! ,
 /// }
 /// ```
-pub fn pid_get_session(pid: pid_t) -> Result<CString, String> {
-    let mut session_c_char: *mut c_char = 0 as *mut c_char;
+pub fn pid_get_session(pid: pid_t) -> Result<String, LoginError> {
+    get_string("session of pid", |session| unsafe { login::sd_pid_get_session(pid, session) })
+}
 
-    let r: c_int = unsafe {
-        login::sd_pid_get_session(pid, &mut session_c_char as *mut *mut c_char)
+pub fn get_session() -> Result<String, LoginError> {
+    let pid: pid_t = unsafe {getpid()};
+    pid_get_session(pid)
+}
+
+pub fn pid_get_owner_uid(pid: pid_t) -> Result<uid_t, LoginError> {
+    get_uid("owner uid of pid", |uid| unsafe { login::sd_pid_get_owner_uid(pid, uid) })
+}
+
+pub fn pid_get_unit(pid: pid_t) -> Result<String, LoginError> {
+    get_string("unit of pid", |unit| unsafe { login::sd_pid_get_unit(pid, unit) })
+}
+
+pub fn pid_get_user_unit(pid: pid_t) -> Result<String, LoginError> {
+    get_string("user unit of pid", |unit| unsafe { login::sd_pid_get_user_unit(pid, unit) })
+}
+
+pub fn pid_get_slice(pid: pid_t) -> Result<String, LoginError> {
+    get_string("slice of pid", |slice| unsafe { login::sd_pid_get_slice(pid, slice) })
+}
+
+pub fn pid_get_user_slice(pid: pid_t) -> Result<String, LoginError> {
+    get_string("user slice of pid", |slice| unsafe { login::sd_pid_get_user_slice(pid, slice) })
+}
+
+pub fn pid_get_machine_name(pid: pid_t) -> Result<String, LoginError> {
+    get_string("machine of pid", |machine| unsafe { login::sd_pid_get_machine_name(pid, machine) })
+}
+
+pub fn pid_get_cgroup(pid: pid_t) -> Result<String, LoginError> {
+    get_string("cgroup of pid", |cgroup| unsafe { login::sd_pid_get_cgroup(pid, cgroup) })
+}
+
+/// The session of the process at the other end of the socket `fd`
+/// (a wayland client)
+pub fn peer_get_session(fd: RawFd) -> Result<String, LoginError> {
+    get_string("session of peer", |session| unsafe { login::sd_peer_get_session(fd, session) })
+}
+
+pub fn peer_get_owner_uid(fd: RawFd) -> Result<uid_t, LoginError> {
+    get_uid("owner uid of peer", |uid| unsafe { login::sd_peer_get_owner_uid(fd, uid) })
+}
+
+pub fn peer_get_unit(fd: RawFd) -> Result<String, LoginError> {
+    get_string("unit of peer", |unit| unsafe { login::sd_peer_get_unit(fd, unit) })
+}
+
+pub fn peer_get_user_unit(fd: RawFd) -> Result<String, LoginError> {
+    get_string("user unit of peer", |unit| unsafe { login::sd_peer_get_user_unit(fd, unit) })
+}
+
+pub fn peer_get_slice(fd: RawFd) -> Result<String, LoginError> {
+    get_string("slice of peer", |slice| unsafe { login::sd_peer_get_slice(fd, slice) })
+}
+
+pub fn peer_get_user_slice(fd: RawFd) -> Result<String, LoginError> {
+    get_string("user slice of peer", |slice| unsafe { login::sd_peer_get_user_slice(fd, slice) })
+}
+
+pub fn peer_get_machine_name(fd: RawFd) -> Result<String, LoginError> {
+    get_string("machine of peer", |machine| unsafe { login::sd_peer_get_machine_name(fd, machine) })
+}
+
+pub fn peer_get_cgroup(fd: RawFd) -> Result<String, LoginError> {
+    get_string("cgroup of peer", |cgroup| unsafe { login::sd_peer_get_cgroup(fd, cgroup) })
+}
+
+/// One of "offline", "lingering", "online", "active" or "closing"
+pub fn uid_get_state(uid: uid_t) -> Result<String, LoginError> {
+    get_string("state of user", |state| unsafe { login::sd_uid_get_state(uid, state) })
+}
+
+/// The graphical session of the user
+pub fn uid_get_display(uid: uid_t) -> Result<String, LoginError> {
+    get_string("display of user", |session| unsafe { login::sd_uid_get_display(uid, session) })
+}
+
+pub fn uid_is_on_seat(uid: uid_t, require_active: bool, seat_id: &str) -> Result<bool, LoginError> {
+    let seat_id_cstring = c_string(seat_id)?;
+    let r = unsafe {
+        login::sd_uid_is_on_seat(uid, require_active as c_int, seat_id_cstring.as_ptr())
     };
+    get_bool("user seat", r)
+}
 
-    if r < 0 {
-        // error
-        let detail_message: &str;
-
-        use libc::{ESRCH, EBADF, ENODATA, EINVAL, ENOMEM};
-        if r == -ESRCH {
-            detail_message = "The specified PID does not refer to a running process.";
-        } else if r == -EBADF {
-            detail_message = "The specified socket file descriptor was invalid.";
-        } else if r == -ENODATA {
-            detail_message = "The given field is not specified for the described process or peer.";
-        } else if r == -EINVAL {
-            detail_message = "An input parameter was invalid (out of range, or NULL, where that is not accepted).";
-        } else if r == -ENOMEM {
-            detail_message = "Memory allocation failed.";
-        } else {
-            detail_message = "Unknown error.";
-        }
+pub fn uid_get_sessions(uid: uid_t, require_active: bool) -> Result<Vec<String>, LoginError> {
+    get_string_array("sessions of user", |sessions| unsafe {
+        login::sd_uid_get_sessions(uid, require_active as c_int, sessions)
+    })
+}
 
-        Err(format!("logind: failed to get session from pid. {}", detail_message))
+pub fn uid_get_seats(uid: uid_t, require_active: bool) -> Result<Vec<String>, LoginError> {
+    get_string_array("seats of user", |seats| unsafe {
+        login::sd_uid_get_seats(uid, require_active as c_int, seats)
+    })
+}
+
+pub fn session_is_active(session_id: &str) -> Result<bool, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    let r = unsafe { login::sd_session_is_active(session_id_cstring.as_ptr()) };
+    get_bool("session activity", r)
+}
 
+pub fn session_is_remote(session_id: &str) -> Result<bool, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    let r = unsafe { login::sd_session_is_remote(session_id_cstring.as_ptr()) };
+    get_bool("session remoteness", r)
+}
+
+/// One of "online", "active" or "closing"
+pub fn session_get_state(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session state", |state| unsafe {
+        login::sd_session_get_state(session_id_cstring.as_ptr(), state)
+    })
+}
+
+pub fn session_get_uid(session_id: &str) -> Result<uid_t, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_uid("session uid", |uid| unsafe {
+        login::sd_session_get_uid(session_id_cstring.as_ptr(), uid)
+    })
+}
+
+pub fn session_get_seat(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session seat", |seat| unsafe {
+        login::sd_session_get_seat(session_id_cstring.as_ptr(), seat)
+    })
+}
+
+pub fn session_get_service(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session service", |service| unsafe {
+        login::sd_session_get_service(session_id_cstring.as_ptr(), service)
+    })
+}
+
+/// One of "unspecified", "tty", "x11", "wayland" or "mir"
+pub fn session_get_type(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session type", |session_type| unsafe {
+        login::sd_session_get_type(session_id_cstring.as_ptr(), session_type)
+    })
+}
+
+/// One of "user", "greeter", "lock-screen" or "background"
+pub fn session_get_class(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session class", |class| unsafe {
+        login::sd_session_get_class(session_id_cstring.as_ptr(), class)
+    })
+}
+
+pub fn session_get_desktop(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session desktop", |desktop| unsafe {
+        login::sd_session_get_desktop(session_id_cstring.as_ptr(), desktop)
+    })
+}
+
+pub fn session_get_display(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session display", |display| unsafe {
+        login::sd_session_get_display(session_id_cstring.as_ptr(), display)
+    })
+}
+
+pub fn session_get_remote_host(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session remote host", |host| unsafe {
+        login::sd_session_get_remote_host(session_id_cstring.as_ptr(), host)
+    })
+}
+
+pub fn session_get_remote_user(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session remote user", |user| unsafe {
+        login::sd_session_get_remote_user(session_id_cstring.as_ptr(), user)
+    })
+}
+
+pub fn session_get_tty(session_id: &str) -> Result<String, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    get_string("session tty", |tty| unsafe {
+        login::sd_session_get_tty(session_id_cstring.as_ptr(), tty)
+    })
+}
+
+/// Fails with ENODATA when the session is not on a VT
+pub fn session_get_vt(session_id: &str) -> Result<u32, LoginError> {
+    let session_id_cstring = c_string(session_id)?;
+    let mut vt: c_uint = 0;
+
+    let r = unsafe { login::sd_session_get_vt(session_id_cstring.as_ptr(), &mut vt) };
+    if r < 0 {
+        Err(login_error("session VT", r))
     } else {
-        let session = unsafe {CString::from_raw(session_c_char)};
-        Ok(session)
+        Ok(vt)
     }
 }
 
-pub fn get_session() -> Result<CString, String> {
-    let pid: pid_t = unsafe {getpid()};
-    pid_get_session(pid)
+/// The active session on the seat and its user
+pub fn seat_get_active(seat_id: &str) -> Result<(String, uid_t), LoginError> {
+    let seat_id_cstring = c_string(seat_id)?;
+    let mut session: *mut c_char = ptr::null_mut();
+    let mut uid: uid_t = 0;
+
+    let r = unsafe { login::sd_seat_get_active(seat_id_cstring.as_ptr(), &mut session, &mut uid) };
+    if r < 0 {
+        Err(login_error("active session of seat", r))
+    } else {
+        Ok((unsafe { take_string(session) }, uid))
+    }
 }
 
-pub fn session_get_seat(session_id: &String) -> Result<CString, String> {
-    let mut seat_c_char: *mut c_char = 0 as *mut c_char;
-    let session_id_cstring = CString::new(session_id.as_str()).unwrap();
+/// The sessions on the seat and the users owning them
+pub fn seat_get_sessions(seat_id: &str) -> Result<(Vec<String>, Vec<uid_t>), LoginError> {
+    let seat_id_cstring = c_string(seat_id)?;
+    let mut sessions: *mut *mut c_char = ptr::null_mut();
+    let mut uids: *mut uid_t = ptr::null_mut();
+    let mut n_uids: c_uint = 0;
 
-    let r: c_int = unsafe {
-        login::sd_session_get_seat(session_id_cstring.as_ptr() as *const c_char, &mut seat_c_char as *mut *mut c_char)
+    let r = unsafe {
+        login::sd_seat_get_sessions(seat_id_cstring.as_ptr(), &mut sessions, &mut uids, &mut n_uids)
     };
-
     if r < 0 {
-        // error
-        let detail_message: &str;
-
-        use libc::{ENXIO, ENODATA, EINVAL, ENOMEM};
-        if r == -ENXIO {
-            detail_message = "The specified session does not exist.";
-        } else if r == -ENODATA {
-            detail_message = "The given field is not specified for the described session.";
-        } else if r == -EINVAL {
-            detail_message = "An input parameter was invalid (out of range, or NULL, where that is not accepted).";
-        } else if r == -ENOMEM {
-            detail_message = "Memory allocation failed.";
-        } else {
-            detail_message = "Unknown error.";
-        }
+        Err(login_error("sessions of seat", r))
+    } else {
+        unsafe { Ok((take_string_array(sessions), take_array(uids, n_uids as c_int))) }
+    }
+}
 
-        Err(format!("logind: failed to get session seat. {}", detail_message))
+pub fn seat_can_multi_session(seat_id: &str) -> Result<bool, LoginError> {
+    let seat_id_cstring = c_string(seat_id)?;
+    let r = unsafe { login::sd_seat_can_multi_session(seat_id_cstring.as_ptr()) };
+    get_bool("seat multi session capability", r)
+}
+
+pub fn seat_can_tty(seat_id: &str) -> Result<bool, LoginError> {
+    let seat_id_cstring = c_string(seat_id)?;
+    let r = unsafe { login::sd_seat_can_tty(seat_id_cstring.as_ptr()) };
+    get_bool("seat tty capability", r)
+}
+
+pub fn seat_can_graphical(seat_id: &str) -> Result<bool, LoginError> {
+    let seat_id_cstring = c_string(seat_id)?;
+    let r = unsafe { login::sd_seat_can_graphical(seat_id_cstring.as_ptr()) };
+    get_bool("seat graphics capability", r)
+}
+
+pub fn machine_get_class(machine: &str) -> Result<String, LoginError> {
+    let machine_cstring = c_string(machine)?;
+    get_string("machine class", |class| unsafe {
+        login::sd_machine_get_class(machine_cstring.as_ptr(), class)
+    })
+}
+
+pub fn machine_get_ifindices(machine: &str) -> Result<Vec<c_int>, LoginError> {
+    let machine_cstring = c_string(machine)?;
+    let mut ifindices: *mut c_int = ptr::null_mut();
 
+    let r = unsafe { login::sd_machine_get_ifindices(machine_cstring.as_ptr(), &mut ifindices) };
+    if r < 0 {
+        Err(login_error("machine network interfaces", r))
     } else {
-        let seat = unsafe {CString::from_raw(seat_c_char)};
-        Ok(seat)
+        Ok(unsafe { take_array(ifindices, r) })
     }
 }
 
-pub fn session_get_vt(session_id: &String) -> Result<u32, String> {
-    let r: c_int;
-    let mut vt: c_uint = 0;
-    let session_id_cstring = CString::new(session_id.as_str()).unwrap();
+pub fn get_seats() -> Result<Vec<String>, LoginError> {
+    get_string_array("seats", |seats| unsafe { login::sd_get_seats(seats) })
+}
 
-    unsafe {
-        r = login::sd_session_get_vt(session_id_cstring.as_ptr() as *const c_char, &mut vt as *mut c_uint);
-    }
+pub fn get_sessions() -> Result<Vec<String>, LoginError> {
+    get_string_array("sessions", |sessions| unsafe { login::sd_get_sessions(sessions) })
+}
+
+pub fn get_uids() -> Result<Vec<uid_t>, LoginError> {
+    let mut uids: *mut uid_t = ptr::null_mut();
 
+    let r = unsafe { login::sd_get_uids(&mut uids) };
     if r < 0 {
-        Err("logind: session not running on a VT".to_string())
+        Err(login_error("users", r))
     } else {
-        Ok(vt)
+        Ok(unsafe { take_array(uids, r) })
     }
 }
 
+pub fn get_machine_names() -> Result<Vec<String>, LoginError> {
+    get_string_array("machines", |machines| unsafe { login::sd_get_machine_names(machines) })
+}
+
+/// What a `LoginMonitor` watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorCategory {
+    /// Sessions appearing, going away or changing state (active, ...)
+    Session,
+    /// Seats appearing, going away or gaining/losing devices
+    Seat,
+    /// Users logging in and out
+    Uid,
+}
+
+impl MonitorCategory {
+    fn as_c_str(&self) -> &'static [u8] {
+        match *self {
+            MonitorCategory::Session => b"session\0",
+            MonitorCategory::Seat => b"seat\0",
+            MonitorCategory::Uid => b"uid\0",
+        }
+    }
+}
+
+/// Wrapper around `sd_login_monitor`, one per category since logind does
+/// not say what changed.
+///
+/// Watch `fd()` on the event loop (for `events()`), then call `dispatch` and
+/// query the new state with the functions above.
+pub struct LoginMonitor {
+    monitor: *mut login::sd_login_monitor,
+    category: MonitorCategory,
+}
+
+impl LoginMonitor {
+    pub fn new(category: MonitorCategory) -> Result<LoginMonitor, LoginError> {
+        let mut monitor: *mut login::sd_login_monitor = ptr::null_mut();
+
+        let r: c_int = unsafe {
+            login::sd_login_monitor_new(category.as_c_str().as_ptr() as *const c_char, &mut monitor)
+        };
+
+        if r < 0 {
+            Err(login_error(&format!("a {:?} monitor", category), r))
+        } else {
+            Ok(LoginMonitor { monitor, category })
+        }
+    }
+
+    pub fn category(&self) -> MonitorCategory {
+        self.category
+    }
+
+    /// File descriptor to poll for `events()`
+    pub fn fd(&self) -> Result<RawFd, LoginError> {
+        let r: c_int = unsafe { login::sd_login_monitor_get_fd(self.monitor) };
+        if r < 0 {
+            Err(login_error(&format!("the {:?} monitor fd", self.category), r))
+        } else {
+            Ok(r)
+        }
+    }
+
+    /// poll() events to wait for on `fd()`
+    pub fn events(&self) -> Result<c_short, LoginError> {
+        let r: c_int = unsafe { login::sd_login_monitor_get_events(self.monitor) };
+        if r < 0 {
+            Err(login_error(&format!("the {:?} monitor events", self.category), r))
+        } else {
+            Ok(r as c_short)
+        }
+    }
+
+    /// Time left before the monitor has to be flushed even without input
+    pub fn timeout(&self) -> Result<Option<Duration>, LoginError> {
+        let mut timeout_usec: u64 = 0;
+        let r: c_int = unsafe {
+            login::sd_login_monitor_get_timeout(self.monitor, &mut timeout_usec)
+        };
+        if r < 0 {
+            return Err(login_error(&format!("the {:?} monitor timeout", self.category), r));
+        }
+        if timeout_usec == u64::max_value() {
+            return Ok(None);
+        }
+
+        // absolute CLOCK_MONOTONIC time
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        let now_usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+
+        Ok(Some(Duration::from_micros(timeout_usec.saturating_sub(now_usec))))
+    }
+
+    /// Whether something changed since the last flush, without waiting
+    pub fn is_ready(&self) -> Result<bool, LoginError> {
+        let mut pollfd = libc::pollfd { fd: self.fd()?, events: self.events()?, revents: 0 };
+        let r = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if r < 0 {
+            Err(LoginError::Call {
+                what: format!("the state of the {:?} monitor", self.category),
+                source: io::Error::last_os_error(),
+            })
+        } else {
+            Ok(r > 0)
+        }
+    }
+
+    /// Reset the wakeup state
+    pub fn flush(&self) -> Result<(), LoginError> {
+        let r: c_int = unsafe { login::sd_login_monitor_flush(self.monitor) };
+        if r < 0 {
+            Err(login_error(&format!("the {:?} monitor reset", self.category), r))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Flush the monitor and tell `handler` its category changed
+    pub fn dispatch(&self, handler: &mut dyn FnMut(MonitorCategory)) -> Result<(), LoginError> {
+        self.flush()?;
+        handler(self.category);
+        Ok(())
+    }
+}
+
+impl Drop for LoginMonitor {
+    fn drop(&mut self) {
+        unsafe { login::sd_login_monitor_unref(self.monitor) };
+    }
+}
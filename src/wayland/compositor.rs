@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+use libc;
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::{wl_buffer, wl_callback, wl_compositor, wl_region, wl_surface};
+use wayland_protocols::presentation_time::server::wp_presentation_feedback::WpPresentationFeedback;
+use wayland_protocols::viewporter::server::wp_viewport::{self, WpViewport};
+use wayland_protocols::unstable::linux_explicit_synchronization::v1::server::{
+    zwp_linux_buffer_release_v1::ZwpLinuxBufferReleaseV1,
+    zwp_linux_surface_synchronization_v1::ZwpLinuxSurfaceSynchronizationV1,
+};
+
+use compositor::Compositor;
+use region::{Rectangle, Region};
+use surface::{Buffer, Surface, Viewport};
+use wayland::{explicit_sync, presentation};
+
+/// Double buffered `wl_surface` state, applied on commit
+#[derive(Default)]
+pub struct PendingState {
+    /// `None` if there was no attach since the last commit, `Some(None)` to
+    /// remove the content
+    pub buffer: Option<Option<Resource<wl_buffer::WlBuffer>>>,
+    pub dx: i32,
+    pub dy: i32,
+    /// in surface coordinates
+    pub damage: Region,
+    /// in buffer pixels
+    pub buffer_damage: Region,
+    pub buffer_scale: Option<i32>,
+    /// the viewport as the requests since the last commit left it
+    pub viewport: Option<Viewport>,
+    pub opaque: Option<Region>,
+    pub input: Option<Option<Region>>,
+    pub frame_callbacks: Vec<Resource<wl_callback::WlCallback>>,
+    pub presentation_feedback: Vec<Resource<WpPresentationFeedback>>,
+    /// zwp_linux_surface_synchronization_v1.set_acquire_fence, owned
+    pub acquire_fence: Option<RawFd>,
+    pub buffer_release: Option<Resource<ZwpLinuxBufferReleaseV1>>,
+}
+
+impl PendingState {
+    pub fn set_acquire_fence(&mut self, fence: Option<RawFd>) {
+        if let Some(fd) = self.acquire_fence.take() {
+            unsafe { libc::close(fd) };
+        }
+        self.acquire_fence = fence;
+    }
+}
+
+impl Drop for PendingState {
+    fn drop(&mut self) {
+        self.set_acquire_fence(None);
+    }
+}
+
+/// User data of the `wl_surface` resources
+pub struct SurfaceData {
+    pub surface: Rc<RefCell<Surface>>,
+    pub pending: RefCell<PendingState>,
+    pub synchronization: RefCell<Option<Resource<ZwpLinuxSurfaceSynchronizationV1>>>,
+    pub viewport: RefCell<Option<Resource<WpViewport>>>,
+    /// release object of the buffer currently attached
+    pub buffer_release: RefCell<Option<Resource<ZwpLinuxBufferReleaseV1>>>,
+}
+
+impl SurfaceData {
+    pub fn from_resource(resource: &Resource<wl_surface::WlSurface>) -> Option<Rc<SurfaceData>> {
+        resource.user_data::<Rc<SurfaceData>>().cloned()
+    }
+}
+
+/// Advertise `wl_compositor`, the surfaces it creates are mapped into
+/// `compositor` once they have a buffer.
+pub fn init_compositor(display: &mut Display, compositor: Rc<RefCell<Compositor>>) -> Global<wl_compositor::WlCompositor> {
+    let token = display.get_token();
+    display.create_global::<wl_compositor::WlCompositor, _>(4, move |new_compositor, _version| {
+        let compositor = compositor.clone();
+        let request_token = token.clone();
+        new_compositor.implement_nonsend(
+            move |request, _| match request {
+                wl_compositor::Request::CreateSurface { id } =>
+                    create_surface(id, compositor.clone(), &request_token),
+                wl_compositor::Request::CreateRegion { id } =>
+                    create_region(id, &request_token),
+            },
+            None::<fn(_)>,
+            (),
+            &token,
+        );
+    })
+}
+
+fn create_region(id: NewResource<wl_region::WlRegion>, token: &DisplayToken) {
+    id.implement_nonsend(
+        |request, region: Resource<wl_region::WlRegion>| {
+            let data = match region.user_data::<RefCell<Region>>() {
+                Some(data) => data,
+                None => return,
+            };
+            match request {
+                wl_region::Request::Destroy => {},
+                wl_region::Request::Add { x, y, width, height } =>
+                    data.borrow_mut().union_rect(Rectangle::new(x, y, width, height)),
+                wl_region::Request::Subtract { x, y, width, height } =>
+                    data.borrow_mut().subtract_rect(&Rectangle::new(x, y, width, height)),
+            }
+        },
+        None::<fn(_)>,
+        RefCell::new(Region::new()),
+        token,
+    );
+}
+
+fn region_content(region: Option<Resource<wl_region::WlRegion>>) -> Option<Region> {
+    region
+        .and_then(|region| region.user_data::<RefCell<Region>>().map(|data| data.borrow().clone()))
+}
+
+fn create_surface(id: NewResource<wl_surface::WlSurface>, compositor: Rc<RefCell<Compositor>>, token: &DisplayToken) {
+    let data = Rc::new(SurfaceData {
+        surface: Rc::new(RefCell::new(Surface::new())),
+        pending: RefCell::new(PendingState::default()),
+        synchronization: RefCell::new(None),
+        viewport: RefCell::new(None),
+        buffer_release: RefCell::new(None),
+    });
+    let surface = data.surface.clone();
+
+    let request_token = token.clone();
+    let request_compositor = compositor.clone();
+    let destroy_data = data.clone();
+    let resource = id.implement_nonsend(
+        move |request, resource: Resource<wl_surface::WlSurface>| {
+            let data = match SurfaceData::from_resource(&resource) {
+                Some(data) => data,
+                None => return,
+            };
+            match request {
+                wl_surface::Request::Destroy => {},
+                wl_surface::Request::Attach { buffer, x, y } => {
+                    let mut pending = data.pending.borrow_mut();
+                    pending.buffer = Some(buffer);
+                    pending.dx = x;
+                    pending.dy = y;
+                },
+                wl_surface::Request::Damage { x, y, width, height } =>
+                    data.pending.borrow_mut().damage.union_rect(Rectangle::new(x, y, width, height)),
+                wl_surface::Request::DamageBuffer { x, y, width, height } =>
+                    data.pending.borrow_mut().buffer_damage.union_rect(Rectangle::new(x, y, width, height)),
+                wl_surface::Request::Frame { callback } => {
+                    let callback = callback.implement_nonsend(|_, _| {}, None::<fn(_)>, (), &request_token);
+                    data.pending.borrow_mut().frame_callbacks.push(callback);
+                },
+                wl_surface::Request::SetOpaqueRegion { region } =>
+                    data.pending.borrow_mut().opaque = Some(region_content(region).unwrap_or_default()),
+                wl_surface::Request::SetInputRegion { region } =>
+                    data.pending.borrow_mut().input = Some(region_content(region)),
+                wl_surface::Request::Commit => commit(&data, &request_compositor),
+                wl_surface::Request::SetBufferTransform { .. } => {
+                    //TODO buffer transforms, the content is shown as is
+                },
+                wl_surface::Request::SetBufferScale { scale } => {
+                    if scale < 1 {
+                        resource.post_error(wl_surface::Error::InvalidScale.to_raw(),
+                            format!("buffer scale must be at least one, not {}", scale));
+                        return;
+                    }
+                    data.pending.borrow_mut().buffer_scale = Some(scale);
+                },
+            }
+        },
+        Some(move |_| {
+            presentation::discard(&mut destroy_data.pending.borrow_mut().presentation_feedback);
+            presentation::discard(&mut destroy_data.surface.borrow_mut().presentation_feedback);
+            release_buffer(&destroy_data);
+            let mut compositor = compositor.borrow_mut();
+            if compositor.cursor.is_surface(&destroy_data.surface) {
+                compositor.cursor.set(None, 0, 0);
+            }
+            compositor.remove_surface(&destroy_data.surface);
+        }),
+        data,
+        token,
+    );
+    surface.borrow_mut().resource = Some(resource);
+}
+
+/// Tell the client the current buffer is not used anymore (weston_buffer_reference)
+fn release_buffer(data: &SurfaceData) {
+    let mut surface = data.surface.borrow_mut();
+    if let Some(buffer) = surface.buffer_resource.take() {
+        if buffer.is_alive() {
+            buffer.send(wl_buffer::Event::Release);
+        }
+    }
+    surface.buffer = None;
+    surface.set_acquire_fence(None);
+
+    let fence = surface.release_fence.take();
+    explicit_sync::send_release(data.buffer_release.borrow_mut().take(), fence);
+}
+
+/// The viewport errors that depend on the whole pending state
+fn check_viewport(data: &SurfaceData) -> bool {
+    let viewport_resource = match *data.viewport.borrow() {
+        Some(ref viewport) => viewport.clone(),
+        None => return true,
+    };
+    let pending = data.pending.borrow();
+    let surface = data.surface.borrow();
+    let viewport = pending.viewport.unwrap_or(surface.viewport);
+    let (x, y, width, height) = match viewport.source {
+        Some(source) => source,
+        None => return true,
+    };
+
+    if viewport.destination.is_none() && (width.fract() != 0.0 || height.fract() != 0.0) {
+        viewport_resource.post_error(wp_viewport::Error::BadSize.to_raw(),
+            format!("source size {}x{} is not integer and there is no destination size", width, height));
+        return false;
+    }
+
+    let buffer_size = match pending.buffer {
+        Some(Some(ref buffer)) => unsafe { Buffer::from_resource(buffer.c_ptr()) }.size(),
+        Some(None) => None,
+        None if surface.buffer.is_some() => Some((surface.buffer_width, surface.buffer_height)),
+        None => None,
+    };
+    if let Some((buffer_width, buffer_height)) = buffer_size {
+        let scale = f64::from(pending.buffer_scale.unwrap_or(surface.buffer_scale));
+        if x + width > f64::from(buffer_width) / scale || y + height > f64::from(buffer_height) / scale {
+            viewport_resource.post_error(wp_viewport::Error::OutOfBuffer.to_raw(),
+                format!("source rectangle {}x{}+{}+{} extends outside of the buffer", width, height, x, y));
+            return false;
+        }
+    }
+
+    true
+}
+
+fn commit(data: &SurfaceData, compositor: &Rc<RefCell<Compositor>>) {
+    if !explicit_sync::check_commit(data) || !check_viewport(data) {
+        return;
+    }
+
+    let mut pending = data.pending.borrow_mut();
+    let (old_geometry, dx, dy) = (data.surface.borrow().geometry(), pending.dx, pending.dy);
+    if let Some(buffer) = pending.buffer.take() {
+        let same_buffer = match (&buffer, &data.surface.borrow().buffer_resource) {
+            (&Some(ref new), &Some(ref old)) => new.equals(old),
+            _ => false,
+        };
+        if same_buffer {
+            // the new release object takes over, the buffer stays in use
+            let fence = data.surface.borrow().release_fence
+                .map(|fd| unsafe { libc::dup(fd) })
+                .and_then(|fd| if fd < 0 { None } else { Some(fd) });
+            explicit_sync::send_release(data.buffer_release.borrow_mut().take(), fence);
+        } else {
+            release_buffer(data);
+        }
+
+        let mut surface = data.surface.borrow_mut();
+        match buffer {
+            Some(buffer) => {
+                let content = unsafe { Buffer::from_resource(buffer.c_ptr()) };
+                // EGL buffers get their size from the renderer on attach
+                if let Some((width, height)) = content.size() {
+                    surface.buffer_width = width;
+                    surface.buffer_height = height;
+                }
+                surface.buffer = Some(content);
+                surface.buffer_resource = Some(buffer);
+            },
+            None => {
+                surface.buffer = None;
+                surface.buffer_resource = None;
+            },
+        }
+        surface.buffer_attached = true;
+        surface.x += pending.dx;
+        surface.y += pending.dy;
+        surface.set_acquire_fence(pending.acquire_fence.take());
+        *data.buffer_release.borrow_mut() = pending.buffer_release.take();
+    }
+    pending.dx = 0;
+    pending.dy = 0;
+
+    let mapped = {
+        let mut surface = data.surface.borrow_mut();
+        if let Some(scale) = pending.buffer_scale.take() {
+            surface.buffer_scale = scale;
+        }
+        if let Some(viewport) = pending.viewport.take() {
+            surface.viewport = viewport;
+        }
+        // the old size has to be repainted too
+        let old_geometry = surface.geometry();
+        surface.update_size();
+        if surface.geometry() != old_geometry {
+            compositor.borrow_mut().damage.union_rect(old_geometry);
+            surface.damage_all();
+        }
+
+        for rect in pending.damage.rects() {
+            let rect = surface.surface_to_buffer_rect(rect);
+            surface.damage.union_rect(rect);
+        }
+        surface.damage.union(&pending.buffer_damage);
+        pending.damage.clear();
+        pending.buffer_damage.clear();
+        if let Some(opaque) = pending.opaque.take() {
+            surface.opaque = opaque;
+        }
+        if let Some(input) = pending.input.take() {
+            surface.input = input;
+        }
+        surface.frame_callbacks.extend(pending.frame_callbacks.drain(..));
+        // the update not shown yet is replaced by this one
+        presentation::discard(&mut surface.presentation_feedback);
+        surface.presentation_feedback.extend(pending.presentation_feedback.drain(..));
+        surface.buffer.is_some()
+    };
+
+    let mut compositor = compositor.borrow_mut();
+    if compositor.cursor.is_surface(&data.surface) {
+        // shown by the backend on top of everything
+        compositor.cursor.committed(old_geometry, dx, dy);
+    } else if mapped && data.surface.borrow().role.is_none() {
+        // a cursor no longer in use stays hidden
+        compositor.add_surface(&data.surface);
+    } else {
+        compositor.remove_surface(&data.surface);
+    }
+}
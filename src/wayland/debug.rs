@@ -0,0 +1,73 @@
+//! weston_debug_v1: a debugging client (weston-debug) hands over an fd and
+//! gets the messages of a log scope written to it until it destroys the
+//! stream. Privileged, see `policy`.
+//!
+//! Unlike weston the fd is made non-blocking: a client that stops reading
+//! loses messages instead of stalling the compositor.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::rc::Rc;
+
+use libc;
+use wayland_server::{Display, DisplayToken, Global, NewResource};
+
+use log::{self, Level, StreamSubscriber};
+use wayland::policy::{self, ClientPolicy};
+use wayland::protocols::weston_debug::server::weston_debug_stream_v1::{self, WestonDebugStreamV1};
+use wayland::protocols::weston_debug::server::weston_debug_v1::{self, WestonDebugV1};
+
+pub fn init_debug(display: &mut Display, policy: Rc<ClientPolicy>) -> Global<WestonDebugV1> {
+    let token = display.get_token();
+    display.create_global_with_filter::<WestonDebugV1, _, _>(
+        1,
+        move |new_resource, _version| bind_debug(new_resource, &token),
+        policy::global_filter(policy, "weston_debug_v1"),
+    )
+}
+
+fn bind_debug(new_resource: NewResource<WestonDebugV1>, token: &DisplayToken) {
+    let request_token = token.clone();
+    let debug = new_resource.implement_nonsend(
+        move |request, _| match request {
+            weston_debug_v1::Request::Destroy => {},
+            weston_debug_v1::Request::Subscribe { name, streamfd, stream } =>
+                subscribe(&name, streamfd, stream, &request_token),
+        },
+        None::<fn(_)>,
+        (),
+        token,
+    );
+
+    for (name, description) in log::scopes() {
+        debug.send(weston_debug_v1::Event::Available { name, description: Some(description) });
+    }
+}
+
+fn subscribe(name: &str, fd: RawFd, id: NewResource<WestonDebugStreamV1>, token: &DisplayToken) {
+    let file = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        File::from_raw_fd(fd)
+    };
+
+    // dropping the subscriber closes the fd
+    let subscription = log::subscribe(name, Level::Debug, Rc::new(RefCell::new(StreamSubscriber::new(file))));
+    let subscription_id = subscription.as_ref().ok().cloned();
+    let stream = id.implement_nonsend(
+        |request, _| match request {
+            weston_debug_stream_v1::Request::Destroy => {},
+        },
+        Some(move |_| if let Some(subscription_id) = subscription_id {
+            log::unsubscribe(subscription_id);
+        }),
+        (),
+        token,
+    );
+
+    match subscription {
+        Ok(_) => log_debug!("wayland", "a debugging client subscribed to \"{}\"", name),
+        Err(message) => stream.send(weston_debug_stream_v1::Event::Failure { message: Some(message) }),
+    }
+}
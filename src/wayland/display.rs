@@ -22,7 +22,7 @@ impl Display {
     pub fn add_shm_format(&mut self, format: wl_shm::Format) -> Result<(), WaylandError> {
         let r = unsafe { wayland_server::sys::server::wl_display_add_shm_format(&mut self.raw, format.to_raw()) };
         if r.is_null() {
-            Err(WaylandError::from_str("wl_display_add_shm_format error"))
+            Err(WaylandError::ShmFormat(format))
         } else {
             Ok(()) // Ok((r))
         }
@@ -1,34 +1,66 @@
+use std::error::Error as StdError;
 use std::fmt;
 use std::ffi::CStr;
+use std::io;
+
+use wayland_server::protocol::wl_shm;
 
 #[derive(Debug)]
-pub struct WaylandError {
-    message: String,
+pub enum WaylandError {
+    /// libwayland could not advertise the shm format
+    ShmFormat(wl_shm::Format),
+    /// Creating the socket, the event loop or a fd source failed
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    Message(String),
 }
 
 impl WaylandError {
     pub fn from_string(message: String) -> Self {
-        Self {
-            message,
-        }
+        WaylandError::Message(message)
     }
 
     pub fn from_str(message: &str) -> Self {
-        Self {
-            message: message.to_string(),
-        }
+        Self::from_string(message.to_string())
     }
 
     pub fn from_cstr(message: &CStr) -> Self {
-        //TODO make this better
-        Self {
-            message: format!("{:?}", message),
+        Self::from_string(message.to_string_lossy().into_owned())
+    }
+
+    pub fn io(context: String, source: io::Error) -> Self {
+        WaylandError::Io {
+            context,
+            source,
+        }
+    }
+
+    /// The errno of the failed system call, if it came to that
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            WaylandError::Io { ref source, .. } => source.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl StdError for WaylandError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            WaylandError::Io { ref source, .. } => Some(source),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for WaylandError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "WaylandError: {}", self.message)
+        match *self {
+            WaylandError::ShmFormat(format) => write!(f, "WaylandError: could not add shm format {:?}", format),
+            WaylandError::Io { ref context, ref source } => write!(f, "WaylandError: {}: {}", context, source),
+            WaylandError::Message(ref message) => write!(f, "WaylandError: {}", message),
+        }
     }
-}
\ No newline at end of file
+}
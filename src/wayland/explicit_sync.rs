@@ -0,0 +1,195 @@
+//! zwp_linux_explicit_synchronization_v1: clients hand an acquire fence with
+//! their buffers and get a release fence back, instead of relying on the
+//! implicit fences of the dmabufs.
+
+use std::os::unix::io::RawFd;
+
+use libc;
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::wl_surface;
+use wayland_protocols::unstable::linux_explicit_synchronization::v1::server::{
+    zwp_linux_buffer_release_v1,
+    zwp_linux_explicit_synchronization_v1,
+    zwp_linux_surface_synchronization_v1,
+};
+use wayland_protocols::unstable::linux_explicit_synchronization::v1::server::zwp_linux_buffer_release_v1::ZwpLinuxBufferReleaseV1;
+use wayland_protocols::unstable::linux_explicit_synchronization::v1::server::zwp_linux_explicit_synchronization_v1::ZwpLinuxExplicitSynchronizationV1;
+use wayland_protocols::unstable::linux_explicit_synchronization::v1::server::zwp_linux_surface_synchronization_v1::ZwpLinuxSurfaceSynchronizationV1;
+
+use surface::Buffer;
+use wayland::compositor::SurfaceData;
+
+// SYNC_IOC_FILE_INFO, _IOWR('>', 4, struct sync_file_info)
+const SYNC_IOC_FILE_INFO: libc::c_ulong = 0xC038_3E04;
+
+#[repr(C)]
+struct SyncFileInfo {
+    name: [libc::c_char; 32],
+    status: i32,
+    flags: u32,
+    num_fences: u32,
+    pad: u32,
+    sync_fence_info: u64,
+}
+
+/// Check that `fd` is a sync_file (linux_sync_file_is_valid in weston)
+fn is_sync_file(fd: RawFd) -> bool {
+    let mut info: SyncFileInfo = unsafe { ::std::mem::zeroed() };
+    unsafe { libc::ioctl(fd, SYNC_IOC_FILE_INFO, &mut info as *mut SyncFileInfo) >= 0 }
+}
+
+pub fn init_explicit_synchronization(display: &mut Display) -> Global<ZwpLinuxExplicitSynchronizationV1> {
+    let token = display.get_token();
+    display.create_global::<ZwpLinuxExplicitSynchronizationV1, _>(2, move |new_resource, _version| {
+        let request_token = token.clone();
+        new_resource.implement_nonsend(
+            move |request, resource| match request {
+                zwp_linux_explicit_synchronization_v1::Request::Destroy => {},
+                zwp_linux_explicit_synchronization_v1::Request::GetSynchronization { id, surface } =>
+                    get_synchronization(&resource, id, surface, &request_token),
+            },
+            None::<fn(_)>,
+            (),
+            &token,
+        );
+    })
+}
+
+fn get_synchronization(
+    resource: &Resource<ZwpLinuxExplicitSynchronizationV1>,
+    id: NewResource<ZwpLinuxSurfaceSynchronizationV1>,
+    surface: Resource<wl_surface::WlSurface>,
+    token: &DisplayToken,
+) {
+    let data = match SurfaceData::from_resource(&surface) {
+        Some(data) => data,
+        None => return,
+    };
+    let exists = data.synchronization.borrow().as_ref()
+        .map(|synchronization| synchronization.is_alive())
+        .unwrap_or(false);
+    if exists {
+        resource.post_error(
+            zwp_linux_explicit_synchronization_v1::Error::SynchronizationExists.to_raw(),
+            "the surface already has a synchronization object".to_string());
+        return;
+    }
+
+    let request_surface = surface.clone();
+    let request_token = token.clone();
+    let synchronization = id.implement_nonsend(
+        move |request, resource| handle_request(request, &resource, &request_surface, &request_token),
+        Some(move |_| {
+            // fences set since the last commit are discarded
+            if let Some(data) = SurfaceData::from_resource(&surface) {
+                data.pending.borrow_mut().set_acquire_fence(None);
+                *data.synchronization.borrow_mut() = None;
+            }
+        }),
+        (),
+        token,
+    );
+    *data.synchronization.borrow_mut() = Some(synchronization);
+}
+
+fn handle_request(
+    request: zwp_linux_surface_synchronization_v1::Request,
+    resource: &Resource<ZwpLinuxSurfaceSynchronizationV1>,
+    surface: &Resource<wl_surface::WlSurface>,
+    token: &DisplayToken,
+) {
+    use self::zwp_linux_surface_synchronization_v1::{Error, Request};
+
+    let data = if surface.is_alive() { SurfaceData::from_resource(surface) } else { None };
+
+    match request {
+        Request::Destroy => {},
+        Request::SetAcquireFence { fd } => {
+            let data = match data {
+                Some(data) => data,
+                None => {
+                    unsafe { libc::close(fd) };
+                    resource.post_error(Error::NoSurface.to_raw(), "the surface was destroyed".to_string());
+                    return;
+                }
+            };
+            if !is_sync_file(fd) {
+                unsafe { libc::close(fd) };
+                resource.post_error(Error::InvalidFence.to_raw(), "the fence is not a sync_file".to_string());
+                return;
+            }
+            let mut pending = data.pending.borrow_mut();
+            if pending.acquire_fence.is_some() {
+                unsafe { libc::close(fd) };
+                resource.post_error(Error::DuplicateFence.to_raw(), "an acquire fence is already set".to_string());
+                return;
+            }
+            pending.set_acquire_fence(Some(fd));
+        },
+        Request::GetRelease { release } => {
+            let data = match data {
+                Some(data) => data,
+                None => {
+                    resource.post_error(Error::NoSurface.to_raw(), "the surface was destroyed".to_string());
+                    return;
+                }
+            };
+            let mut pending = data.pending.borrow_mut();
+            if pending.buffer_release.is_some() {
+                resource.post_error(Error::DuplicateRelease.to_raw(), "a release object is already requested".to_string());
+                return;
+            }
+            pending.buffer_release = Some(release.implement_nonsend(|_, _| {}, None::<fn(_)>, (), token));
+        },
+    }
+}
+
+/// Validate the explicit sync state of a commit, false if a protocol error was posted
+pub fn check_commit(data: &SurfaceData) -> bool {
+    use self::zwp_linux_surface_synchronization_v1::Error;
+
+    let pending = data.pending.borrow();
+    if pending.acquire_fence.is_none() && pending.buffer_release.is_none() {
+        return true;
+    }
+    let synchronization = match *data.synchronization.borrow() {
+        Some(ref synchronization) => synchronization.clone(),
+        None => return true,
+    };
+
+    let buffer = match pending.buffer {
+        Some(Some(ref buffer)) => buffer,
+        _ => {
+            synchronization.post_error(Error::NoBuffer.to_raw(), "no buffer attached".to_string());
+            return false;
+        }
+    };
+    // shm buffers are read with the CPU, a fence would be meaningless
+    if pending.acquire_fence.is_some() {
+        if let Buffer::Shm(_) = unsafe { Buffer::from_resource(buffer.c_ptr()) } {
+            synchronization.post_error(Error::UnsupportedBuffer.to_raw(),
+                "explicit synchronization is not supported for wl_shm buffers".to_string());
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Tell the client it can reuse the buffer, once `fence` signals if there is one.
+///
+/// Takes ownership of `fence`.
+pub fn send_release(release: Option<Resource<ZwpLinuxBufferReleaseV1>>, fence: Option<RawFd>) {
+    if let Some(release) = release {
+        if release.is_alive() {
+            match fence {
+                Some(fd) => release.send(zwp_linux_buffer_release_v1::Event::FencedRelease { fence: fd }),
+                None => release.send(zwp_linux_buffer_release_v1::Event::ImmediateRelease),
+            }
+        }
+    }
+    // libwayland duplicates the fd when sending
+    if let Some(fd) = fence {
+        unsafe { libc::close(fd) };
+    }
+}
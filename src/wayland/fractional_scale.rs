@@ -0,0 +1,60 @@
+//! wp_fractional_scale_v1: tells the clients the scale of the outputs their
+//! surfaces are on, so they can draw at that scale and use wp_viewport to
+//! give the surface its size. See `Outputs::update_surfaces`.
+
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::wl_surface;
+
+use wayland::compositor::SurfaceData;
+use wayland::protocols::fractional_scale_v1::server::wp_fractional_scale_manager_v1::{self, WpFractionalScaleManagerV1};
+use wayland::protocols::fractional_scale_v1::server::wp_fractional_scale_v1::{self, WpFractionalScaleV1};
+
+pub fn init_fractional_scale(display: &mut Display) -> Global<WpFractionalScaleManagerV1> {
+    let token = display.get_token();
+    display.create_global::<WpFractionalScaleManagerV1, _>(1, move |new_resource, _version| {
+        let request_token = token.clone();
+        new_resource.implement_nonsend(
+            move |request, manager: Resource<WpFractionalScaleManagerV1>| match request {
+                wp_fractional_scale_manager_v1::Request::Destroy => {},
+                wp_fractional_scale_manager_v1::Request::GetFractionalScale { id, surface } =>
+                    get_fractional_scale(&manager, id, surface, &request_token),
+            },
+            None::<fn(_)>,
+            (),
+            &token,
+        );
+    })
+}
+
+fn get_fractional_scale(
+    manager: &Resource<WpFractionalScaleManagerV1>,
+    id: NewResource<WpFractionalScaleV1>,
+    surface: Resource<wl_surface::WlSurface>,
+    token: &DisplayToken,
+) {
+    let data = match SurfaceData::from_resource(&surface) {
+        Some(data) => data,
+        None => return,
+    };
+    if data.surface.borrow().fractional_scale.as_ref().map_or(false, |fractional_scale| fractional_scale.is_alive()) {
+        manager.post_error(wp_fractional_scale_manager_v1::Error::FractionalScaleExists.to_raw(),
+            "the surface already has a fractional scale object".to_string());
+        return;
+    }
+
+    let destroy_surface = data.surface.clone();
+    let fractional_scale = id.implement_nonsend(
+        |request, _| match request {
+            wp_fractional_scale_v1::Request::Destroy => {},
+        },
+        Some(move |_| destroy_surface.borrow_mut().fractional_scale = None),
+        (),
+        token,
+    );
+
+    let mut surface = data.surface.borrow_mut();
+    if surface.preferred_scale != 0 {
+        fractional_scale.send(wp_fractional_scale_v1::Event::PreferredScale { scale: surface.preferred_scale });
+    }
+    surface.fractional_scale = Some(fractional_scale);
+}
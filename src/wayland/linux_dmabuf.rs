@@ -0,0 +1,284 @@
+//! zwp_linux_dmabuf_v1: clients hand their buffers over as dmabufs. The
+//! params are checked here, the imports wait in `take_imports` for the main
+//! loop to give them to the renderer.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use libc;
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::wl_buffer;
+use wayland_protocols::unstable::linux_dmabuf::v1::server::{zwp_linux_buffer_params_v1, zwp_linux_dmabuf_v1};
+use wayland_protocols::unstable::linux_dmabuf::v1::server::zwp_linux_buffer_params_v1::ZwpLinuxBufferParamsV1;
+use wayland_protocols::unstable::linux_dmabuf::v1::server::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1;
+
+use dmabuf::{Dmabuf, DmabufPlane, DRM_FORMAT_MOD_INVALID, MAX_DMABUF_PLANES};
+
+/// The DRM formats the renderer imports, with their modifiers
+pub type DmabufFormats = Vec<(u32, Vec<u64>)>;
+
+/// A dmabuf waiting for the renderer
+pub struct DmabufImport {
+    pub dmabuf: Rc<Dmabuf>,
+    params: Resource<ZwpLinuxBufferParamsV1>,
+    /// the wl_buffer of create_immed, create makes it once imported
+    buffer: Option<Resource<wl_buffer::WlBuffer>>,
+    token: DisplayToken,
+}
+
+impl DmabufImport {
+    /// The renderer took the dmabuf, the client gets its wl_buffer
+    pub fn imported(self) {
+        if self.buffer.is_some() || !self.params.is_alive() {
+            return;
+        }
+        let new_buffer = self.params.client()
+            .and_then(|client| client.create_resource::<wl_buffer::WlBuffer>(1));
+        if let Some(new_buffer) = new_buffer {
+            let buffer = implement_buffer(new_buffer, &self.dmabuf, &self.token);
+            self.params.send(zwp_linux_buffer_params_v1::Event::Created { buffer });
+        }
+    }
+
+    /// The renderer can't use the dmabuf. A create_immed wl_buffer stays
+    /// around without content, attaching it shows nothing.
+    pub fn failed(self) {
+        if self.params.is_alive() {
+            self.params.send(zwp_linux_buffer_params_v1::Event::Failed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ParamsState {
+    planes: [Option<DmabufPlane>; MAX_DMABUF_PLANES],
+    used: bool,
+}
+
+impl Drop for ParamsState {
+    fn drop(&mut self) {
+        // the planes no dmabuf took
+        for plane in self.planes.iter().flat_map(|plane| plane.iter()) {
+            unsafe { libc::close(plane.fd) };
+        }
+    }
+}
+
+pub struct LinuxDmabuf {
+    formats: Rc<RefCell<DmabufFormats>>,
+    imports: Rc<RefCell<Vec<DmabufImport>>>,
+}
+
+impl LinuxDmabuf {
+    /// Advertise the dmabuf `formats` of the renderer
+    pub fn new(display: &mut Display, formats: DmabufFormats) -> Self {
+        let formats = Rc::new(RefCell::new(formats));
+        let imports = Rc::new(RefCell::new(Vec::new()));
+
+        let token = display.get_token();
+        let global_formats = formats.clone();
+        let global_imports = imports.clone();
+        let _: Global<ZwpLinuxDmabufV1> = display.create_global(3, move |new_resource, _version| {
+            bind_dmabuf(new_resource, &global_formats.borrow(), &global_imports, &token);
+        });
+
+        LinuxDmabuf {
+            formats,
+            imports,
+        }
+    }
+
+    /// The renderer changed, the clients binding from now on get its formats
+    pub fn set_formats(&self, formats: DmabufFormats) {
+        *self.formats.borrow_mut() = formats;
+    }
+
+    /// The dmabufs the clients created since the last call
+    pub fn take_imports(&self) -> Vec<DmabufImport> {
+        self.imports.borrow_mut().drain(..).collect()
+    }
+}
+
+fn bind_dmabuf(
+    new_resource: NewResource<ZwpLinuxDmabufV1>,
+    formats: &DmabufFormats,
+    imports: &Rc<RefCell<Vec<DmabufImport>>>,
+    token: &DisplayToken,
+) {
+    let request_imports = imports.clone();
+    let request_token = token.clone();
+    let dmabuf = new_resource.implement_nonsend(
+        move |request, _| match request {
+            zwp_linux_dmabuf_v1::Request::Destroy => {},
+            zwp_linux_dmabuf_v1::Request::CreateParams { params_id } =>
+                create_params(params_id, &request_imports, &request_token),
+        },
+        None::<fn(_)>,
+        (),
+        token,
+    );
+
+    for &(format, ref modifiers) in formats.iter() {
+        if dmabuf.version() < 3 {
+            dmabuf.send(zwp_linux_dmabuf_v1::Event::Format { format });
+            continue;
+        }
+        let invalid = [DRM_FORMAT_MOD_INVALID];
+        let modifiers = if modifiers.is_empty() { &invalid[..] } else { &modifiers[..] };
+        for &modifier in modifiers.iter() {
+            dmabuf.send(zwp_linux_dmabuf_v1::Event::Modifier {
+                format,
+                modifier_hi: (modifier >> 32) as u32,
+                modifier_lo: modifier as u32,
+            });
+        }
+    }
+}
+
+fn create_params(
+    id: NewResource<ZwpLinuxBufferParamsV1>,
+    imports: &Rc<RefCell<Vec<DmabufImport>>>,
+    token: &DisplayToken,
+) {
+    let state = RefCell::new(ParamsState::default());
+    let request_imports = imports.clone();
+    let request_token = token.clone();
+    id.implement_nonsend(
+        move |request, params| handle_params_request(request, &params, &state, &request_imports, &request_token),
+        None::<fn(_)>,
+        (),
+        token,
+    );
+}
+
+fn handle_params_request(
+    request: zwp_linux_buffer_params_v1::Request,
+    params: &Resource<ZwpLinuxBufferParamsV1>,
+    state: &RefCell<ParamsState>,
+    imports: &Rc<RefCell<Vec<DmabufImport>>>,
+    token: &DisplayToken,
+) {
+    use self::zwp_linux_buffer_params_v1::{Error, Request};
+
+    match request {
+        Request::Destroy => {},
+        Request::Add { fd, plane_idx, offset, stride, modifier_hi, modifier_lo } => {
+            let mut state = state.borrow_mut();
+            let error = if state.used {
+                Some((Error::AlreadyUsed, "the params were already used".to_string()))
+            } else if plane_idx as usize >= MAX_DMABUF_PLANES {
+                Some((Error::PlaneIdx, format!("plane index {} is too high", plane_idx)))
+            } else if state.planes[plane_idx as usize].is_some() {
+                Some((Error::PlaneSet, format!("plane {} was already set", plane_idx)))
+            } else {
+                None
+            };
+            if let Some((error, message)) = error {
+                unsafe { libc::close(fd) };
+                params.post_error(error.to_raw(), message);
+                return;
+            }
+            state.planes[plane_idx as usize] = Some(DmabufPlane {
+                fd,
+                offset,
+                stride,
+                modifier: (u64::from(modifier_hi) << 32) | u64::from(modifier_lo),
+            });
+        },
+        Request::Create { width, height, format, flags } => {
+            if let Some(dmabuf) = create_dmabuf(params, state, width, height, format, flags) {
+                imports.borrow_mut().push(DmabufImport {
+                    dmabuf: Rc::new(dmabuf),
+                    params: params.clone(),
+                    buffer: None,
+                    token: token.clone(),
+                });
+            }
+        },
+        Request::CreateImmed { buffer_id, width, height, format, flags } => {
+            if let Some(dmabuf) = create_dmabuf(params, state, width, height, format, flags) {
+                // the import runs before the next repaint attaches the buffer
+                let dmabuf = Rc::new(dmabuf);
+                let buffer = implement_buffer(buffer_id, &dmabuf, token);
+                imports.borrow_mut().push(DmabufImport {
+                    dmabuf,
+                    params: params.clone(),
+                    buffer: Some(buffer),
+                    token: token.clone(),
+                });
+            }
+        },
+    }
+}
+
+/// Check the planes added to `params` and make a dmabuf of them
+/// (params_create_common in weston)
+fn create_dmabuf(
+    params: &Resource<ZwpLinuxBufferParamsV1>,
+    state: &RefCell<ParamsState>,
+    width: i32,
+    height: i32,
+    format: u32,
+    flags: u32,
+) -> Option<Dmabuf> {
+    use self::zwp_linux_buffer_params_v1::Error;
+
+    let mut state = state.borrow_mut();
+    if state.used {
+        params.post_error(Error::AlreadyUsed.to_raw(), "the params were already used".to_string());
+        return None;
+    }
+    state.used = true;
+
+    let count = state.planes.iter().take_while(|plane| plane.is_some()).count();
+    if count == 0 || state.planes[count..].iter().any(|plane| plane.is_some()) {
+        params.post_error(Error::Incomplete.to_raw(), "the planes are not contiguous from 0".to_string());
+        return None;
+    }
+    if width < 1 || height < 1 {
+        params.post_error(Error::InvalidDimensions.to_raw(), format!("invalid size {}x{}", width, height));
+        return None;
+    }
+
+    let modifier = state.planes[0].as_ref().map_or(DRM_FORMAT_MOD_INVALID, |plane| plane.modifier);
+    for (index, plane) in state.planes[..count].iter().flat_map(|plane| plane.iter()).enumerate() {
+        if plane.modifier != modifier {
+            params.post_error(Error::InvalidFormat.to_raw(), format!("plane {} has another modifier", index));
+            return None;
+        }
+        let end = u64::from(plane.offset) + u64::from(plane.stride) * height as u64;
+        if end > u64::from(u32::max_value()) {
+            params.post_error(Error::OutOfBounds.to_raw(), format!("size of plane {} overflows", index));
+            return None;
+        }
+        // not every kernel can tell the size of a dmabuf
+        let size = unsafe { libc::lseek(plane.fd, 0, libc::SEEK_END) };
+        if size < 0 {
+            continue;
+        }
+        if u64::from(plane.offset) >= size as u64 || (index == 0 && end > size as u64) {
+            params.post_error(Error::OutOfBounds.to_raw(), format!("plane {} is out of the dmabuf", index));
+            return None;
+        }
+    }
+
+    let planes = state.planes[..count].iter_mut().filter_map(|plane| plane.take()).collect();
+    Some(Dmabuf::new(width, height, format, flags, planes))
+}
+
+/// The wl_buffer of `dmabuf`, `Buffer::from_resource` finds the dmabuf in
+/// its user data
+fn implement_buffer(
+    new_buffer: NewResource<wl_buffer::WlBuffer>,
+    dmabuf: &Rc<Dmabuf>,
+    token: &DisplayToken,
+) -> Resource<wl_buffer::WlBuffer> {
+    new_buffer.implement_nonsend(
+        |request, _| match request {
+            wl_buffer::Request::Destroy => {},
+        },
+        None::<fn(_)>,
+        dmabuf.clone(),
+        token,
+    )
+}
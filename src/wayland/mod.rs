@@ -3,6 +3,18 @@ pub mod display;
 pub mod signal;
 pub mod listener;
 pub mod server;
+pub mod compositor;
+pub mod explicit_sync;
+pub mod linux_dmabuf;
+pub mod presentation;
+pub mod viewporter;
+pub mod fractional_scale;
+pub mod output;
+pub mod seat;
+pub mod protocols;
+pub mod policy;
+pub mod output_management;
+pub mod debug;
 mod error;
 
 pub use self::error::WaylandError;
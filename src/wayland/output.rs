@@ -0,0 +1,225 @@
+//! wl_output: a global for each enabled head, with its mode, position and
+//! integer scale. The surfaces are told which outputs show them with
+//! wl_surface enter and leave, and the scale they would look best at with
+//! wp_fractional_scale_v1.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::{wl_output, wl_surface};
+
+use output::Head;
+use region::Rectangle;
+use surface::Surface;
+use wayland::protocols::fractional_scale_v1::server::wp_fractional_scale_v1;
+
+/// The wl_output resources of an output global, shared with the backend
+/// output for the presentation feedback
+pub type OutputResources = Rc<RefCell<Vec<Resource<wl_output::WlOutput>>>>;
+
+struct OutputGlobal {
+    /// as last published, always enabled
+    head: Rc<RefCell<Head>>,
+    resources: OutputResources,
+    global: Global<wl_output::WlOutput>,
+}
+
+impl OutputGlobal {
+    fn name(&self) -> String {
+        self.head.borrow().name.clone()
+    }
+
+    fn geometry(&self) -> Rectangle {
+        self.head.borrow().state.map(|state| state.geometry()).unwrap_or_else(|| Rectangle::new(0, 0, 0, 0))
+    }
+
+    fn scale(&self) -> f64 {
+        self.head.borrow().state.map(|state| state.scale).unwrap_or(1.0)
+    }
+}
+
+pub struct Outputs {
+    token: DisplayToken,
+    outputs: Vec<OutputGlobal>,
+    /// outputs gone since the last `update_surfaces`, the surfaces on them
+    /// still have to leave
+    removed: Vec<(String, OutputResources)>,
+}
+
+impl Outputs {
+    pub fn new(display: &Display) -> Self {
+        Outputs {
+            token: display.get_token(),
+            outputs: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Publish the enabled `heads`, the clients get what changed
+    pub fn update_heads(&mut self, display: &mut Display, heads: &[Head]) {
+        let enabled: Vec<&Head> = heads.iter().filter(|head| head.state.is_some()).collect();
+
+        let mut index = 0;
+        while index < self.outputs.len() {
+            let name = self.outputs[index].name();
+            if enabled.iter().any(|head| head.name == name) {
+                index += 1;
+                continue;
+            }
+            let output = self.outputs.remove(index);
+            output.global.destroy();
+            self.removed.push((name, output.resources));
+        }
+
+        for head in enabled {
+            match self.outputs.iter().find(|output| output.head.borrow().name == head.name) {
+                Some(output) => {
+                    if *output.head.borrow() != *head {
+                        *output.head.borrow_mut() = head.clone();
+                        for resource in output.resources.borrow().iter() {
+                            send_head(resource, head);
+                        }
+                    }
+                },
+                None => {
+                    let output = self.create_global(display, head);
+                    self.outputs.push(output);
+                },
+            }
+        }
+    }
+
+    fn create_global(&self, display: &mut Display, head: &Head) -> OutputGlobal {
+        let head = Rc::new(RefCell::new(head.clone()));
+        let resources: OutputResources = Rc::new(RefCell::new(Vec::new()));
+
+        let bind_head = head.clone();
+        let bind_resources = resources.clone();
+        let token = self.token.clone();
+        let global = display.create_global::<wl_output::WlOutput, _>(3, move |new_resource, _version| {
+            bind_output(new_resource, &bind_head.borrow(), &bind_resources, &token);
+        });
+
+        OutputGlobal {
+            head,
+            resources,
+            global,
+        }
+    }
+
+    /// The wl_output resources of the output called `name`, while it has a
+    /// global
+    pub fn resources(&self, name: &str) -> Option<OutputResources> {
+        self.outputs.iter()
+            .find(|output| output.name() == name)
+            .map(|output| output.resources.clone())
+    }
+
+    /// Send enter and leave to the surfaces that moved across the outputs,
+    /// and their new preferred scale, the largest of the outputs showing them
+    pub fn update_surfaces(&mut self, surfaces: &[Rc<RefCell<Surface>>]) {
+        for surface in surfaces.iter() {
+            let mut surface = surface.borrow_mut();
+            let resource = match surface.resource {
+                Some(ref resource) if resource.is_alive() => resource.clone(),
+                _ => continue,
+            };
+
+            let geometry = surface.geometry();
+            let showing: Vec<&OutputGlobal> = self.outputs.iter()
+                .filter(|output| output.geometry().intersect(&geometry).is_some())
+                .collect();
+            let names: Vec<String> = showing.iter().map(|output| output.name()).collect();
+
+            for name in surface.outputs.iter().filter(|name| !names.contains(name)) {
+                let resources = self.outputs.iter()
+                    .find(|output| output.name() == *name)
+                    .map(|output| output.resources.clone())
+                    .or_else(|| self.removed.iter().find(|removed| removed.0 == *name).map(|removed| removed.1.clone()));
+                if let Some(resources) = resources {
+                    send_to_client(&resource, &resources, |output| wl_surface::Event::Leave { output });
+                }
+            }
+            for output in showing.iter().filter(|output| !surface.outputs.contains(&output.name())) {
+                send_to_client(&resource, &output.resources, |output| wl_surface::Event::Enter { output });
+            }
+            surface.outputs = names;
+
+            let scale = showing.iter().map(|output| output.scale()).fold(0.0, f64::max);
+            let preferred = (scale * 120.0).round() as u32;
+            if preferred == 0 || preferred == surface.preferred_scale {
+                continue;
+            }
+            surface.preferred_scale = preferred;
+            if let Some(ref fractional_scale) = surface.fractional_scale {
+                if fractional_scale.is_alive() {
+                    fractional_scale.send(wp_fractional_scale_v1::Event::PreferredScale { scale: preferred });
+                }
+            }
+        }
+
+        self.removed.clear();
+    }
+}
+
+fn bind_output(new_resource: NewResource<wl_output::WlOutput>, head: &Head, resources: &OutputResources, token: &DisplayToken) {
+    let destroy_resources = resources.clone();
+    let resource = new_resource.implement_nonsend(
+        |request, _| match request {
+            wl_output::Request::Release => {},
+        },
+        Some(move |resource: Resource<wl_output::WlOutput>| {
+            destroy_resources.borrow_mut().retain(|other| !other.equals(&resource));
+        }),
+        (),
+        token,
+    );
+    send_head(&resource, head);
+    resources.borrow_mut().push(resource);
+}
+
+/// Describe the current state of `head`
+fn send_head(resource: &Resource<wl_output::WlOutput>, head: &Head) {
+    let state = match head.state {
+        Some(state) => state,
+        None => return,
+    };
+
+    resource.send(wl_output::Event::Geometry {
+        x: state.x,
+        y: state.y,
+        physical_width: head.physical_width,
+        physical_height: head.physical_height,
+        subpixel: wl_output::Subpixel::Unknown,
+        make: "unknown".to_string(),
+        model: head.description.clone(),
+        transform: state.transform,
+    });
+    let mut flags = wl_output::Mode::Current;
+    if state.mode.preferred {
+        flags |= wl_output::Mode::Preferred;
+    }
+    resource.send(wl_output::Event::Mode {
+        flags,
+        width: state.mode.width,
+        height: state.mode.height,
+        refresh: state.mode.refresh as i32,
+    });
+    if resource.version() >= 2 {
+        // clients without wp_fractional_scale_v1 draw bigger and get scaled down
+        resource.send(wl_output::Event::Scale { factor: state.scale.ceil() as i32 });
+        resource.send(wl_output::Event::Done);
+    }
+}
+
+/// Send `event` for each of `outputs` bound by the client of `surface`
+fn send_to_client<F>(surface: &Resource<wl_surface::WlSurface>, outputs: &OutputResources, event: F)
+    where F: Fn(Resource<wl_output::WlOutput>) -> wl_surface::Event
+{
+    for output in outputs.borrow().iter() {
+        if output.is_alive() && output.same_client_as(surface) {
+            surface.send(event(output.clone()));
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! zwlr_output_manager_v1: lets tools like wlr-randr and kanshi list the
+//! heads and their modes, and test or apply a new configuration of all of
+//! them at once.
+//!
+//! The requests can't reach the backend from the handlers, the configurations
+//! wait in `take_requests` for the main loop to hand them over.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::wl_output;
+
+use output::{Head, HeadConfiguration, HeadState, Mode};
+use wayland::policy::{self, ClientPolicy};
+use wayland::protocols::wlr_output_management_v1::server::{
+    zwlr_output_configuration_head_v1,
+    zwlr_output_configuration_v1,
+    zwlr_output_head_v1,
+    zwlr_output_manager_v1,
+    zwlr_output_mode_v1,
+};
+use wayland::protocols::wlr_output_management_v1::server::zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1;
+use wayland::protocols::wlr_output_management_v1::server::zwlr_output_configuration_v1::ZwlrOutputConfigurationV1;
+use wayland::protocols::wlr_output_management_v1::server::zwlr_output_head_v1::ZwlrOutputHeadV1;
+use wayland::protocols::wlr_output_management_v1::server::zwlr_output_manager_v1::ZwlrOutputManagerV1;
+use wayland::protocols::wlr_output_management_v1::server::zwlr_output_mode_v1::ZwlrOutputModeV1;
+
+/// User data of the `zwlr_output_mode_v1` resources
+struct ModeData {
+    head: String,
+    mode: Mode,
+}
+
+/// A head as one manager resource knows it
+struct HeadResources {
+    name: String,
+    head: Resource<ZwlrOutputHeadV1>,
+    modes: Vec<Resource<ZwlrOutputModeV1>>,
+}
+
+struct Manager {
+    resource: Resource<ZwlrOutputManagerV1>,
+    heads: Vec<HeadResources>,
+}
+
+/// A configuration a client asked to test or apply
+pub struct ConfigurationRequest {
+    resource: Resource<ZwlrOutputConfigurationV1>,
+    /// of the heads the client configured them from
+    serial: u32,
+    pub heads: Vec<HeadConfiguration>,
+    pub test_only: bool,
+}
+
+impl ConfigurationRequest {
+    pub fn succeeded(&self) {
+        if self.resource.is_alive() {
+            self.resource.send(zwlr_output_configuration_v1::Event::Succeeded);
+        }
+    }
+
+    pub fn failed(&self) {
+        if self.resource.is_alive() {
+            self.resource.send(zwlr_output_configuration_v1::Event::Failed);
+        }
+    }
+
+    /// The heads changed since the client looked at them
+    pub fn cancelled(&self) {
+        if self.resource.is_alive() {
+            self.resource.send(zwlr_output_configuration_v1::Event::Cancelled);
+        }
+    }
+}
+
+struct State {
+    token: DisplayToken,
+    /// as last published
+    heads: Vec<Head>,
+    serial: u32,
+    managers: Vec<Manager>,
+    requests: Vec<ConfigurationRequest>,
+}
+
+/// What a `zwlr_output_configuration_head_v1` set so far
+#[derive(Default)]
+struct HeadSettings {
+    mode: Option<Mode>,
+    position: Option<(i32, i32)>,
+    transform: Option<wl_output::Transform>,
+    scale: Option<f64>,
+}
+
+/// A `zwlr_output_configuration_v1` being filled
+struct Configuration {
+    serial: u32,
+    /// apply or test came already
+    used: bool,
+    /// the head names, `None` to disable
+    heads: Vec<(String, Option<Rc<RefCell<HeadSettings>>>)>,
+}
+
+pub struct OutputManagement {
+    state: Rc<RefCell<State>>,
+    _global: Global<ZwlrOutputManagerV1>,
+}
+
+impl OutputManagement {
+    /// The global is privileged, `policy` decides who sees it
+    pub fn new(display: &mut Display, policy: Rc<ClientPolicy>) -> Self {
+        let state = Rc::new(RefCell::new(State {
+            token: display.get_token(),
+            heads: Vec::new(),
+            serial: 1,
+            managers: Vec::new(),
+            requests: Vec::new(),
+        }));
+
+        let bind_state = state.clone();
+        let global = display.create_global_with_filter::<ZwlrOutputManagerV1, _, _>(
+            2,
+            move |new_resource, _version| bind_manager(&bind_state, new_resource),
+            policy::global_filter(policy, "zwlr_output_manager_v1"),
+        );
+
+        OutputManagement {
+            state,
+            _global: global,
+        }
+    }
+
+    /// Publish `heads`, the managers get what changed and a new serial
+    pub fn update_heads(&self, heads: Vec<Head>) {
+        let mut state = self.state.borrow_mut();
+        if state.heads == heads {
+            return;
+        }
+        state.serial = state.serial.wrapping_add(1);
+
+        let State { ref token, heads: ref old_heads, serial, ref mut managers, .. } = *state;
+        for manager in managers.iter_mut() {
+            // the heads that went away
+            manager.heads.retain(|resources| {
+                if heads.iter().any(|head| head.name == resources.name) {
+                    return true;
+                }
+                finish_head(resources);
+                false
+            });
+
+            for head in heads.iter() {
+                let old_head = old_heads.iter().find(|old_head| old_head.name == head.name);
+                let position = manager.heads.iter().position(|resources| resources.name == head.name);
+                match (old_head, position) {
+                    (Some(old_head), Some(position)) => update_head(&mut manager.heads[position], old_head, head, token),
+                    _ => {
+                        if let Some(resources) = send_head(&manager.resource, head, token) {
+                            manager.heads.push(resources);
+                        }
+                    },
+                }
+            }
+            manager.resource.send(zwlr_output_manager_v1::Event::Done { serial });
+        }
+
+        state.heads = heads;
+    }
+
+    /// The configurations the clients tested or applied since the last call
+    pub fn take_requests(&self) -> Vec<ConfigurationRequest> {
+        self.state.borrow_mut().requests.drain(..).collect()
+    }
+
+    /// Whether `request` was made from the heads as they are now
+    pub fn is_current(&self, request: &ConfigurationRequest) -> bool {
+        request.serial == self.state.borrow().serial
+    }
+}
+
+fn bind_manager(state: &Rc<RefCell<State>>, new_resource: NewResource<ZwlrOutputManagerV1>) {
+    let request_state = state.clone();
+    let destroy_state = state.clone();
+    let token = state.borrow().token.clone();
+    let resource = new_resource.implement_nonsend(
+        move |request, resource: Resource<ZwlrOutputManagerV1>| match request {
+            zwlr_output_manager_v1::Request::CreateConfiguration { id, serial } =>
+                create_configuration(&request_state, id, serial),
+            zwlr_output_manager_v1::Request::Stop => {
+                remove_manager(&request_state, &resource);
+                resource.send(zwlr_output_manager_v1::Event::Finished);
+            },
+        },
+        Some(move |resource: Resource<ZwlrOutputManagerV1>| remove_manager(&destroy_state, &resource)),
+        (),
+        &token,
+    );
+
+    let mut state = state.borrow_mut();
+    let mut manager = Manager {
+        resource: resource.clone(),
+        heads: Vec::new(),
+    };
+    for head in state.heads.iter() {
+        if let Some(resources) = send_head(&resource, head, &state.token) {
+            manager.heads.push(resources);
+        }
+    }
+    resource.send(zwlr_output_manager_v1::Event::Done { serial: state.serial });
+    state.managers.push(manager);
+}
+
+fn remove_manager(state: &Rc<RefCell<State>>, resource: &Resource<ZwlrOutputManagerV1>) {
+    state.borrow_mut().managers.retain(|manager| !manager.resource.equals(resource));
+}
+
+/// Introduce `head` with its modes and state to `manager`
+fn send_head(manager: &Resource<ZwlrOutputManagerV1>, head: &Head, token: &DisplayToken) -> Option<HeadResources> {
+    let client = manager.client()?;
+    let resource = client.create_resource::<ZwlrOutputHeadV1>(manager.version())?
+        .implement_nonsend(|_, _| {}, None::<fn(_)>, head.name.clone(), token);
+    manager.send(zwlr_output_manager_v1::Event::Head { head: resource.clone() });
+
+    resource.send(zwlr_output_head_v1::Event::Name { name: head.name.clone() });
+    resource.send(zwlr_output_head_v1::Event::Description { description: head.description.clone() });
+    if head.physical_width > 0 && head.physical_height > 0 {
+        resource.send(zwlr_output_head_v1::Event::PhysicalSize {
+            width: head.physical_width,
+            height: head.physical_height,
+        });
+    }
+    let mut resources = HeadResources {
+        name: head.name.clone(),
+        head: resource,
+        modes: Vec::new(),
+    };
+    send_modes(&mut resources, head, token);
+    send_state(&resources, None, head.state.as_ref());
+
+    Some(resources)
+}
+
+/// Tell the client about the modes of `head`, once per mode object
+fn send_modes(resources: &mut HeadResources, head: &Head, token: &DisplayToken) {
+    let client = match resources.head.client() {
+        Some(client) => client,
+        None => return,
+    };
+    for mode in head.modes.iter() {
+        let data = ModeData {
+            head: head.name.clone(),
+            mode: *mode,
+        };
+        let resource = match client.create_resource::<ZwlrOutputModeV1>(resources.head.version()) {
+            Some(new_resource) => new_resource.implement_nonsend(|_, _| {}, None::<fn(_)>, data, token),
+            None => continue,
+        };
+        resources.head.send(zwlr_output_head_v1::Event::Mode { mode: resource.clone() });
+        resource.send(zwlr_output_mode_v1::Event::Size { width: mode.width, height: mode.height });
+        if mode.refresh > 0 {
+            resource.send(zwlr_output_mode_v1::Event::Refresh { refresh: mode.refresh as i32 });
+        }
+        if mode.preferred {
+            resource.send(zwlr_output_mode_v1::Event::Preferred);
+        }
+        resources.modes.push(resource);
+    }
+}
+
+/// Send the properties of `new` that differ from `old`
+fn send_state(resources: &HeadResources, old: Option<&HeadState>, new: Option<&HeadState>) {
+    let head = &resources.head;
+    let new = match new {
+        Some(new) => new,
+        None => {
+            head.send(zwlr_output_head_v1::Event::Enabled { enabled: 0 });
+            return;
+        },
+    };
+    if old.is_none() {
+        head.send(zwlr_output_head_v1::Event::Enabled { enabled: 1 });
+    }
+
+    if old.map(|old| old.mode) != Some(new.mode) {
+        let current = resources.modes.iter()
+            .find(|mode| mode.user_data::<ModeData>().map(|data| data.mode == new.mode).unwrap_or(false));
+        if let Some(mode) = current {
+            head.send(zwlr_output_head_v1::Event::CurrentMode { mode: mode.clone() });
+        }
+    }
+    if old.map(|old| (old.x, old.y)) != Some((new.x, new.y)) {
+        head.send(zwlr_output_head_v1::Event::Position { x: new.x, y: new.y });
+    }
+    if old.map(|old| old.transform) != Some(new.transform) {
+        head.send(zwlr_output_head_v1::Event::Transform { transform: new.transform });
+    }
+    if old.map(|old| old.scale) != Some(new.scale) {
+        head.send(zwlr_output_head_v1::Event::Scale { scale: new.scale });
+    }
+}
+
+fn update_head(resources: &mut HeadResources, old: &Head, new: &Head, token: &DisplayToken) {
+    let modes_changed = old.modes != new.modes;
+    if modes_changed {
+        for mode in resources.modes.drain(..) {
+            mode.send(zwlr_output_mode_v1::Event::Finished);
+        }
+        send_modes(resources, new, token);
+    }
+    // the current mode has to point at one of the new mode objects
+    let old_state = if modes_changed { None } else { old.state.as_ref() };
+    if old_state != new.state.as_ref() {
+        send_state(resources, old_state, new.state.as_ref());
+    }
+}
+
+fn finish_head(resources: &HeadResources) {
+    for mode in resources.modes.iter() {
+        mode.send(zwlr_output_mode_v1::Event::Finished);
+    }
+    resources.head.send(zwlr_output_head_v1::Event::Finished);
+}
+
+fn create_configuration(state: &Rc<RefCell<State>>, id: NewResource<ZwlrOutputConfigurationV1>, serial: u32) {
+    let configuration = Rc::new(RefCell::new(Configuration {
+        serial,
+        used: false,
+        heads: Vec::new(),
+    }));
+
+    let request_state = state.clone();
+    let token = state.borrow().token.clone();
+    let request_token = token.clone();
+    id.implement_nonsend(
+        move |request, resource: Resource<ZwlrOutputConfigurationV1>| {
+            use self::zwlr_output_configuration_v1::{Error, Request};
+
+            if configuration.borrow().used {
+                if let Request::Destroy = request {
+                    return;
+                }
+                resource.post_error(Error::AlreadyUsed.to_raw(),
+                    "the configuration was already applied or tested".to_string());
+                return;
+            }
+
+            let head_name = |head: &Resource<ZwlrOutputHeadV1>| head.user_data::<String>().cloned().unwrap_or_default();
+            match request {
+                Request::EnableHead { id, head } => {
+                    let name = head_name(&head);
+                    if !add_head(&configuration, &resource, &name) {
+                        return;
+                    }
+                    let settings = Rc::new(RefCell::new(HeadSettings::default()));
+                    configuration.borrow_mut().heads.push((name.clone(), Some(settings.clone())));
+                    implement_configuration_head(id, name, settings, &request_token);
+                },
+                Request::DisableHead { head } => {
+                    let name = head_name(&head);
+                    if add_head(&configuration, &resource, &name) {
+                        configuration.borrow_mut().heads.push((name, None));
+                    }
+                },
+                Request::Apply => finish_configuration(&request_state, &configuration, &resource, false),
+                Request::Test => finish_configuration(&request_state, &configuration, &resource, true),
+                Request::Destroy => {},
+            }
+        },
+        None::<fn(_)>,
+        (),
+        &token,
+    );
+}
+
+/// Check that the configuration does not have the head `name` yet
+fn add_head(configuration: &Rc<RefCell<Configuration>>, resource: &Resource<ZwlrOutputConfigurationV1>, name: &str) -> bool {
+    if configuration.borrow().heads.iter().any(|&(ref other, _)| other == name) {
+        resource.post_error(zwlr_output_configuration_v1::Error::AlreadyConfiguredHead.to_raw(),
+            format!("head {} is already configured", name));
+        return false;
+    }
+    true
+}
+
+fn implement_configuration_head(
+    id: NewResource<ZwlrOutputConfigurationHeadV1>,
+    head: String,
+    settings: Rc<RefCell<HeadSettings>>,
+    token: &DisplayToken,
+) {
+    id.implement_nonsend(
+        move |request, resource: Resource<ZwlrOutputConfigurationHeadV1>| {
+            use self::zwlr_output_configuration_head_v1::{Error, Request};
+
+            let mut settings = settings.borrow_mut();
+            let already_set = match request {
+                Request::SetMode { .. } | Request::SetCustomMode { .. } => settings.mode.is_some(),
+                Request::SetPosition { .. } => settings.position.is_some(),
+                Request::SetTransform { .. } => settings.transform.is_some(),
+                Request::SetScale { .. } => settings.scale.is_some(),
+            };
+            if already_set {
+                resource.post_error(Error::AlreadySet.to_raw(), "the property is already set".to_string());
+                return;
+            }
+
+            match request {
+                Request::SetMode { mode } => match mode.user_data::<ModeData>() {
+                    Some(data) if data.head == head => settings.mode = Some(data.mode),
+                    _ => resource.post_error(Error::InvalidMode.to_raw(), format!("the mode is not one of {}", head)),
+                },
+                Request::SetCustomMode { width, height, refresh } => {
+                    if width <= 0 || height <= 0 || refresh < 0 {
+                        resource.post_error(Error::InvalidCustomMode.to_raw(),
+                            format!("invalid mode {}x{}@{}mHz", width, height, refresh));
+                        return;
+                    }
+                    settings.mode = Some(Mode {
+                        width,
+                        height,
+                        refresh: refresh as u32,
+                        preferred: false,
+                    });
+                },
+                Request::SetPosition { x, y } => settings.position = Some((x, y)),
+                Request::SetTransform { transform } => settings.transform = Some(transform),
+                Request::SetScale { scale } => {
+                    if scale <= 0.0 {
+                        resource.post_error(Error::InvalidScale.to_raw(), format!("invalid scale {}", scale));
+                        return;
+                    }
+                    settings.scale = Some(scale);
+                },
+            }
+        },
+        None::<fn(_)>,
+        (),
+        token,
+    );
+}
+
+/// Queue the configuration for the backend, the heads it does not set keep
+/// their current state
+fn finish_configuration(
+    state: &Rc<RefCell<State>>,
+    configuration: &Rc<RefCell<Configuration>>,
+    resource: &Resource<ZwlrOutputConfigurationV1>,
+    test_only: bool,
+) {
+    let mut configuration = configuration.borrow_mut();
+    configuration.used = true;
+
+    let mut state = state.borrow_mut();
+    if let Some(head) = state.heads.iter()
+        .find(|head| !configuration.heads.iter().any(|&(ref name, _)| *name == head.name))
+    {
+        resource.post_error(zwlr_output_configuration_v1::Error::UnconfiguredHead.to_raw(),
+            format!("head {} is not configured", head.name));
+        return;
+    }
+
+    let mut heads = Vec::new();
+    for &(ref name, ref settings) in configuration.heads.iter() {
+        let settings = match *settings {
+            Some(ref settings) => settings.borrow(),
+            None => {
+                heads.push(HeadConfiguration { name: name.clone(), state: None });
+                continue;
+            },
+        };
+        let head = state.heads.iter().find(|head| head.name == *name);
+        let current = head.and_then(|head| head.state);
+        let mode = match settings.mode.or_else(|| current.map(|current| current.mode)).or_else(|| head.and_then(Head::default_mode)) {
+            Some(mode) => mode,
+            // a head gone since, the serial is outdated anyway
+            None => continue,
+        };
+        let (x, y) = settings.position.or_else(|| current.map(|current| (current.x, current.y))).unwrap_or((0, 0));
+        heads.push(HeadConfiguration {
+            name: name.clone(),
+            state: Some(HeadState {
+                mode,
+                x,
+                y,
+                transform: settings.transform.or_else(|| current.map(|current| current.transform))
+                    .unwrap_or(wl_output::Transform::Normal),
+                scale: settings.scale.or_else(|| current.map(|current| current.scale)).unwrap_or(1.0),
+            }),
+        });
+    }
+
+    state.requests.push(ConfigurationRequest {
+        resource: resource.clone(),
+        serial: configuration.serial,
+        heads,
+        test_only,
+    });
+}
@@ -0,0 +1,371 @@
+//! Which clients may bind the privileged globals (output management,
+//! weston-debug): the credentials of the peer at the other end of the client
+//! socket are checked against an allow-list.
+//!
+//! The allow-list is read from the file RUDIMENTO_POLICY names, see
+//! `ClientPolicy::from_config` for the format. Without it only the clients of
+//! the compositor's own session get the privileged globals.
+
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use libc::{c_int, gid_t, pid_t, uid_t};
+use wayland_server::Client;
+use wayland_server::sys::server::wl_client;
+
+use systemd::login;
+
+// not bound by wayland-sys
+extern "C" {
+    fn wl_client_get_fd(client: *mut wl_client) -> c_int;
+    fn wl_client_get_credentials(client: *mut wl_client, pid: *mut pid_t, uid: *mut uid_t, gid: *mut gid_t);
+}
+
+/// The globals created with `global_filter`: they reconfigure the outputs or
+/// expose the compositor's internals
+pub const PRIVILEGED_GLOBALS: &[&str] = &[
+    "zwlr_output_manager_v1",
+    "weston_debug_v1",
+];
+
+#[derive(Debug)]
+pub enum PolicyError {
+    /// The file RUDIMENTO_POLICY names can't be read
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A rule of the configuration does not parse, `line` counts from 1
+    InvalidRule {
+        line: usize,
+        message: String,
+    },
+    /// A rule names a global that is not privileged (or does not exist), it
+    /// would grant nothing
+    UnknownGlobal {
+        line: usize,
+        global: String,
+    },
+}
+
+impl StdError for PolicyError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            PolicyError::Read { ref source, .. } => Some(source),
+            PolicyError::InvalidRule { .. } | PolicyError::UnknownGlobal { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyError::Read { ref path, ref source } =>
+                write!(f, "policy: cannot read {}: {}", path.as_os_str().to_string_lossy(), source),
+            PolicyError::InvalidRule { line, ref message } => write!(f, "policy: line {}: {}", line, message),
+            PolicyError::UnknownGlobal { line, ref global } =>
+                write!(f, "policy: line {}: {} is not a privileged global", line, global),
+        }
+    }
+}
+
+/// Who is at the other end of a client socket, looked up once per client
+#[derive(Debug, Clone)]
+pub struct ClientCredentials {
+    pub pid: pid_t,
+    pub uid: uid_t,
+    pub gid: gid_t,
+    /// logind session, clients started outside of a session have none
+    pub session: Option<String>,
+    /// systemd unit (e.g. "app-foo.scope")
+    pub unit: Option<String>,
+}
+
+impl ClientCredentials {
+    /// None if the kernel can't tell who the peer is
+    pub fn from_fd(fd: RawFd) -> Option<Self> {
+        let mut ucred = ::libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut len = ::std::mem::size_of::<::libc::ucred>() as ::libc::socklen_t;
+        let result = unsafe {
+            ::libc::getsockopt(fd, ::libc::SOL_SOCKET, ::libc::SO_PEERCRED,
+                               &mut ucred as *mut ::libc::ucred as *mut ::libc::c_void, &mut len)
+        };
+        if result < 0 || len as usize != ::std::mem::size_of::<::libc::ucred>() {
+            log_warn!("wayland", "policy: no credentials for client fd {}: {}", fd, ::std::io::Error::last_os_error());
+            return None;
+        }
+
+        Some(ClientCredentials {
+            pid: ucred.pid,
+            uid: login::peer_get_owner_uid(fd).unwrap_or(ucred.uid),
+            gid: ucred.gid,
+            session: login::peer_get_session(fd).ok(),
+            unit: login::peer_get_unit(fd).ok(),
+        })
+    }
+
+    /// The credentials of `client`, cached in its data map
+    pub fn of_client(client: &Client) -> Option<ClientCredentials> {
+        client.data_map().insert_if_missing(|| {
+            let fd = unsafe { wl_client_get_fd(client.c_ptr()) };
+            ClientCredentials::from_fd(fd).map(|mut credentials| {
+                // libwayland already asked the kernel, trust it over sd-login
+                let (mut pid, mut uid, mut gid) = (0, 0, 0);
+                unsafe { wl_client_get_credentials(client.c_ptr(), &mut pid, &mut uid, &mut gid) };
+                credentials.pid = pid;
+                credentials.gid = gid;
+                if credentials.uid != uid {
+                    log_warn!("wayland", "policy: client {} has uid {} but its logind owner is {}", pid, uid, credentials.uid);
+                    credentials.uid = uid;
+                }
+                credentials
+            })
+        });
+        client.data_map().get::<Option<ClientCredentials>>().unwrap().clone()
+    }
+}
+
+/// What a rule of the allow-list matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMatch {
+    Any,
+    Uid(uid_t),
+    Unit(String),
+    Session(String),
+    /// The session the compositor runs in
+    OwnSession,
+}
+
+impl ClientMatch {
+    fn matches(&self, credentials: &ClientCredentials, own_session: Option<&String>) -> bool {
+        match *self {
+            ClientMatch::Any => true,
+            ClientMatch::Uid(uid) => credentials.uid == uid,
+            ClientMatch::Unit(ref unit) => credentials.unit.as_ref() == Some(unit),
+            ClientMatch::Session(ref session) => credentials.session.as_ref() == Some(session),
+            ClientMatch::OwnSession => own_session.is_some() && credentials.session.as_ref() == own_session,
+        }
+    }
+}
+
+/// A client matching `client` may bind `global` ("*" for all privileged ones)
+#[derive(Debug, Clone)]
+pub struct AllowRule {
+    pub global: String,
+    pub client: ClientMatch,
+}
+
+/// Allow-list of the clients that may bind privileged globals, the others
+/// are open to everybody.
+#[derive(Debug, Clone)]
+pub struct ClientPolicy {
+    privileged: Vec<String>,
+    rules: Vec<AllowRule>,
+    own_session: Option<String>,
+}
+
+impl ClientPolicy {
+    /// Deny all privileged globals
+    pub fn new() -> Self {
+        ClientPolicy {
+            privileged: PRIVILEGED_GLOBALS.iter().map(|global| global.to_string()).collect(),
+            rules: Vec::new(),
+            own_session: login::get_session().ok(),
+        }
+    }
+
+    /// Privileged globals only for the clients of our own session
+    pub fn own_session_only() -> Self {
+        let mut policy = ClientPolicy::new();
+        policy.allow("*", ClientMatch::OwnSession);
+        policy
+    }
+
+    /// The policy of the file RUDIMENTO_POLICY names, `own_session_only`
+    /// without it
+    pub fn from_env() -> Result<Self, PolicyError> {
+        let path = match env::var_os("RUDIMENTO_POLICY") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(ClientPolicy::own_session_only()),
+        };
+        let config = fs::read_to_string(&path)
+            .map_err(|source| PolicyError::Read { path, source })?;
+        ClientPolicy::from_config(&config)
+    }
+
+    /// Parse rules, one per line: `<global|*> <any|uid=N|unit=NAME|session=ID|own-session>`,
+    /// the global one of `PRIVILEGED_GLOBALS`. Empty lines and lines starting
+    /// with '#' are skipped.
+    pub fn from_config(config: &str) -> Result<Self, PolicyError> {
+        let mut policy = ClientPolicy::new();
+        for (number, line) in config.lines().enumerate() {
+            let invalid = |message: String| PolicyError::InvalidRule { line: number + 1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (global, client) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(global), Some(client), None) => (global, client),
+                _ => return Err(invalid("expected \"<global> <client>\"".to_string())),
+            };
+            if global != "*" && !policy.is_privileged(global) {
+                return Err(PolicyError::UnknownGlobal { line: number + 1, global: global.to_string() });
+            }
+            let client = match client.find('=').map(|i| (&client[..i], &client[i + 1..])) {
+                None if client == "any" => ClientMatch::Any,
+                None if client == "own-session" => ClientMatch::OwnSession,
+                Some(("uid", uid)) => match uid.parse() {
+                    Ok(uid) => ClientMatch::Uid(uid),
+                    Err(_) => return Err(invalid(format!("invalid uid \"{}\"", uid))),
+                },
+                Some(("unit", unit)) => ClientMatch::Unit(unit.to_string()),
+                Some(("session", session)) => ClientMatch::Session(session.to_string()),
+                _ => return Err(invalid(format!("unknown client \"{}\"", client))),
+            };
+            policy.allow(global, client);
+        }
+        Ok(policy)
+    }
+
+    pub fn allow(&mut self, global: &str, client: ClientMatch) {
+        self.rules.push(AllowRule { global: global.to_string(), client });
+    }
+
+    /// Also restrict `global`
+    pub fn add_privileged(&mut self, global: &str) {
+        if !self.is_privileged(global) {
+            self.privileged.push(global.to_string());
+        }
+    }
+
+    pub fn is_privileged(&self, global: &str) -> bool {
+        self.privileged.iter().any(|privileged| privileged == global)
+    }
+
+    pub fn is_allowed(&self, global: &str, credentials: &ClientCredentials) -> bool {
+        if !self.is_privileged(global) {
+            return true;
+        }
+        self.rules.iter()
+            .filter(|rule| rule.global == "*" || rule.global == global)
+            .any(|rule| rule.client.matches(credentials, self.own_session.as_ref()))
+    }
+
+    /// A client without credentials gets none of the privileged globals
+    pub fn client_allowed(&self, global: &str, client: &Client) -> bool {
+        let credentials = match ClientCredentials::of_client(client) {
+            Some(credentials) => credentials,
+            None => {
+                let allowed = !self.is_privileged(global);
+                if !allowed {
+                    log_warn!("wayland", "policy: denied {} to a client without credentials", global);
+                }
+                return allowed;
+            },
+        };
+        let allowed = self.is_allowed(global, &credentials);
+        if !allowed {
+            log_warn!("wayland", "policy: denied {} to client {} (uid {}, session {:?}, unit {:?})",
+                     global, credentials.pid, credentials.uid, credentials.session, credentials.unit);
+        }
+        allowed
+    }
+}
+
+/// Filter for `Display::create_global_with_filter`, hides `global` from the
+/// clients the policy does not allow
+pub fn global_filter(policy: Rc<ClientPolicy>, global: &'static str) -> impl FnMut(Client) -> bool {
+    move |client| policy.client_allowed(global, &client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(uid: uid_t, session: Option<&str>, unit: Option<&str>) -> ClientCredentials {
+        ClientCredentials {
+            pid: 1,
+            uid,
+            gid: 1,
+            session: session.map(|session| session.to_string()),
+            unit: unit.map(|unit| unit.to_string()),
+        }
+    }
+
+    #[test]
+    fn config_accepts_rules() {
+        let policy = ClientPolicy::from_config("\
+            # comments and empty lines are skipped\n\
+            \n\
+            zwlr_output_manager_v1 uid=1000\n\
+            \tweston_debug_v1   unit=debug.scope  \n\
+            * session=c2\n\
+            zwlr_output_manager_v1 own-session\n\
+            weston_debug_v1 any\n").unwrap();
+
+        let rules: Vec<(&str, ClientMatch)> = policy.rules.iter()
+            .map(|rule| (rule.global.as_str(), rule.client.clone()))
+            .collect();
+        assert_eq!(rules, vec![
+            ("zwlr_output_manager_v1", ClientMatch::Uid(1000)),
+            ("weston_debug_v1", ClientMatch::Unit("debug.scope".to_string())),
+            ("*", ClientMatch::Session("c2".to_string())),
+            ("zwlr_output_manager_v1", ClientMatch::OwnSession),
+            ("weston_debug_v1", ClientMatch::Any),
+        ]);
+
+        assert!(policy.is_allowed("zwlr_output_manager_v1", &credentials(1000, None, None)));
+        assert!(!policy.is_allowed("zwlr_output_manager_v1", &credentials(1001, None, None)));
+        assert!(policy.is_allowed("zwlr_output_manager_v1", &credentials(1001, Some("c2"), None)));
+        assert!(policy.is_allowed("weston_debug_v1", &credentials(1001, None, None)));
+        // not privileged, open to everybody
+        assert!(policy.is_allowed("wl_compositor", &credentials(1001, None, None)));
+    }
+
+    #[test]
+    fn config_rejects_invalid_lines() {
+        let invalid = [
+            "zwlr_output_manager_v1",
+            "zwlr_output_manager_v1 uid=1000 extra",
+            "zwlr_output_manager_v1 uid=root",
+            "zwlr_output_manager_v1 uid=-1",
+            "zwlr_output_manager_v1 gid=100",
+            "zwlr_output_manager_v1 everybody",
+        ];
+        for line in invalid.iter() {
+            let config = format!("# first line\n{}\n", line);
+            match ClientPolicy::from_config(&config) {
+                Err(PolicyError::InvalidRule { line: 2, .. }) => {},
+                Err(error) => panic!("\"{}\": {}", line, error),
+                Ok(_) => panic!("accepted \"{}\"", line),
+            }
+        }
+    }
+
+    #[test]
+    fn config_rejects_unknown_globals() {
+        for global in ["zwlr_output_manger_v1", "zwlr_screencopy_manager_v1", "wl_seat"].iter() {
+            let config = format!("# first line\n{} any\n", global);
+            match ClientPolicy::from_config(&config) {
+                Err(PolicyError::UnknownGlobal { line: 2, global: ref name }) if name == global => {},
+                Err(error) => panic!("\"{}\": {}", global, error),
+                Ok(_) => panic!("accepted \"{}\"", global),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_config_denies_privileged_globals() {
+        let policy = ClientPolicy::from_config("").unwrap();
+        assert!(!policy.is_allowed("zwlr_output_manager_v1", &credentials(0, None, None)));
+        assert!(!policy.is_allowed("weston_debug_v1", &credentials(0, None, None)));
+        assert!(policy.is_allowed("wl_seat", &credentials(0, None, None)));
+    }
+}
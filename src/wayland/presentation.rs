@@ -0,0 +1,97 @@
+//! wp_presentation: tells the clients when their content updates reached the
+//! screen, with timestamps in the compositor's presentation clock.
+//!
+//! The feedbacks follow the content update: pending state, the surface on
+//! commit, the output on repaint, and the client once the page flip completed.
+//!
+//! `Kind::ZeroCopy` is never reported: the client buffers are always
+//! composited, none is scanned out directly.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use libc;
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::wl_surface;
+use wayland_protocols::presentation_time::server::wp_presentation::{self, WpPresentation};
+use wayland_protocols::presentation_time::server::wp_presentation_feedback::{self, WpPresentationFeedback};
+
+use compositor::Compositor;
+use wayland::compositor::SurfaceData;
+use wayland::output::OutputResources;
+
+pub use wayland_protocols::presentation_time::server::wp_presentation_feedback::Kind;
+
+pub fn init_presentation(display: &mut Display, compositor: Rc<RefCell<Compositor>>) -> Global<WpPresentation> {
+    let clock_id = compositor.borrow().presentation_clock() as u32;
+    let token = display.get_token();
+    display.create_global::<WpPresentation, _>(1, move |new_resource, _version| {
+        let request_token = token.clone();
+        let presentation = new_resource.implement_nonsend(
+            move |request, _| match request {
+                wp_presentation::Request::Destroy => {},
+                wp_presentation::Request::Feedback { surface, callback } =>
+                    feedback(surface, callback, &request_token),
+            },
+            None::<fn(_)>,
+            (),
+            &token,
+        );
+        presentation.send(wp_presentation::Event::ClockId { clk_id: clock_id });
+    })
+}
+
+fn feedback(
+    surface: Resource<wl_surface::WlSurface>,
+    callback: NewResource<WpPresentationFeedback>,
+    token: &DisplayToken,
+) {
+    let feedback = callback.implement_nonsend(|_, _| {}, None::<fn(_)>, (), token);
+    match SurfaceData::from_resource(&surface) {
+        Some(data) => data.pending.borrow_mut().presentation_feedback.push(feedback),
+        None => discard(&mut vec![feedback]),
+    }
+}
+
+/// The content update was replaced or its surface destroyed before it was shown
+pub fn discard(feedbacks: &mut Vec<Resource<WpPresentationFeedback>>) {
+    for feedback in feedbacks.drain(..) {
+        if feedback.is_alive() {
+            feedback.send(wp_presentation_feedback::Event::Discarded);
+        }
+    }
+}
+
+/// The content updates are on screen since `time`, on the output the clients
+/// bound as `outputs`.
+///
+/// `refresh` is the output refresh period in nanoseconds (0 if unknown), `seq`
+/// the vblank counter and `flags` the `Kind` bits that apply.
+pub fn presented(
+    feedbacks: &mut Vec<Resource<WpPresentationFeedback>>,
+    outputs: &OutputResources,
+    time: &libc::timespec,
+    refresh: u32,
+    seq: u64,
+    flags: u32,
+) {
+    let tv_sec = time.tv_sec as u64;
+    for feedback in feedbacks.drain(..) {
+        if feedback.is_alive() {
+            for output in outputs.borrow().iter() {
+                if output.is_alive() && output.same_client_as(&feedback) {
+                    feedback.send(wp_presentation_feedback::Event::SyncOutput { output: output.clone() });
+                }
+            }
+            feedback.send(wp_presentation_feedback::Event::Presented {
+                tv_sec_hi: (tv_sec >> 32) as u32,
+                tv_sec_lo: tv_sec as u32,
+                tv_nsec: time.tv_nsec as u32,
+                refresh,
+                seq_hi: (seq >> 32) as u32,
+                seq_lo: seq as u32,
+                flags,
+            });
+        }
+    }
+}
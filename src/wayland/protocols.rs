@@ -0,0 +1,54 @@
+//! Protocols wayland-protocols does not ship, generated by build.rs from
+//! the XML in protocols/ the same way wayland-protocols does.
+
+#![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#![allow(non_upper_case_globals, non_snake_case, unused_imports)]
+
+pub mod wlr_output_management_v1 {
+    pub mod c_interfaces {
+        pub use wayland_server::sys::protocol_interfaces::wl_output_interface;
+        include!(concat!(env!("OUT_DIR"), "/wlr-output-management-unstable-v1_c_interfaces.rs"));
+    }
+
+    pub mod server {
+        pub(crate) use wayland_server::{NewResource, Resource, ResourceMap};
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::{AnonymousObject, Interface, MessageGroup};
+        pub(crate) use wayland_commons::wire::{Argument, MessageDesc, ArgumentType, Message};
+        pub(crate) use wayland_sys as sys;
+        pub(crate) use wayland_server::protocol::wl_output;
+        include!(concat!(env!("OUT_DIR"), "/wlr-output-management-unstable-v1_c_server_api.rs"));
+    }
+}
+
+pub mod fractional_scale_v1 {
+    pub mod c_interfaces {
+        pub use wayland_server::sys::protocol_interfaces::wl_surface_interface;
+        include!(concat!(env!("OUT_DIR"), "/fractional-scale-v1_c_interfaces.rs"));
+    }
+
+    pub mod server {
+        pub(crate) use wayland_server::{NewResource, Resource, ResourceMap};
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::{AnonymousObject, Interface, MessageGroup};
+        pub(crate) use wayland_commons::wire::{Argument, MessageDesc, ArgumentType, Message};
+        pub(crate) use wayland_sys as sys;
+        pub(crate) use wayland_server::protocol::wl_surface;
+        include!(concat!(env!("OUT_DIR"), "/fractional-scale-v1_c_server_api.rs"));
+    }
+}
+
+pub mod weston_debug {
+    pub mod c_interfaces {
+        include!(concat!(env!("OUT_DIR"), "/weston-debug_c_interfaces.rs"));
+    }
+
+    pub mod server {
+        pub(crate) use wayland_server::{NewResource, Resource, ResourceMap};
+        pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+        pub(crate) use wayland_commons::{AnonymousObject, Interface, MessageGroup};
+        pub(crate) use wayland_commons::wire::{Argument, MessageDesc, ArgumentType, Message};
+        pub(crate) use wayland_sys as sys;
+        include!(concat!(env!("OUT_DIR"), "/weston-debug_c_server_api.rs"));
+    }
+}
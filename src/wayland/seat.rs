@@ -0,0 +1,251 @@
+//! wl_seat: a pointer only seat. There are no input devices yet, the pointer
+//! stays where it is and the surface under it gets the focus; the client of
+//! that surface can set the cursor image with wl_pointer.set_cursor.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::{wl_keyboard, wl_pointer, wl_seat, wl_surface, wl_touch};
+
+use compositor::Compositor;
+use region::Rectangle;
+use surface::Surface;
+use wayland::compositor::SurfaceData;
+
+const CURSOR_ROLE: &str = "cursor";
+
+#[derive(Default)]
+struct PointerState {
+    /// the wl_pointer resources of all the clients
+    pointers: Vec<Resource<wl_pointer::WlPointer>>,
+    focus: Option<Resource<wl_surface::WlSurface>>,
+    /// of the last enter, set_cursor has to quote it
+    enter_serial: u32,
+}
+
+impl PointerState {
+    /// Send `event` to the pointers of the client of `surface`
+    fn send<F>(&self, surface: &Resource<wl_surface::WlSurface>, event: F)
+        where F: Fn() -> wl_pointer::Event
+    {
+        for pointer in self.pointers.iter() {
+            if pointer.is_alive() && pointer.same_client_as(surface) {
+                pointer.send(event());
+                if pointer.version() >= 5 {
+                    pointer.send(wl_pointer::Event::Frame);
+                }
+            }
+        }
+    }
+}
+
+pub struct Seat {
+    state: Rc<RefCell<PointerState>>,
+    compositor: Rc<RefCell<Compositor>>,
+}
+
+impl Seat {
+    /// Advertise the seat `name`, its cursor is shown through `compositor`
+    pub fn new(display: &mut Display, compositor: Rc<RefCell<Compositor>>, name: &str) -> Self {
+        let state = Rc::new(RefCell::new(PointerState::default()));
+
+        let token = display.get_token();
+        let global_state = state.clone();
+        let global_compositor = compositor.clone();
+        let name = name.to_string();
+        let _: Global<wl_seat::WlSeat> = display.create_global(5, move |new_resource, _version| {
+            bind_seat(new_resource, &name, &global_state, &global_compositor, &token);
+        });
+
+        Seat {
+            state,
+            compositor,
+        }
+    }
+
+    /// Give the pointer focus to the surface under the pointer, the cursor
+    /// of the client losing it goes
+    pub fn update_pointer_focus(&mut self, display: &Display) {
+        use wayland_server::sys::server::wl_display_next_serial;
+
+        let mut compositor = self.compositor.borrow_mut();
+        let focus = surface_at(&compositor.surfaces, compositor.cursor.x, compositor.cursor.y);
+        let mut state = self.state.borrow_mut();
+        let unchanged = match (&state.focus, &focus) {
+            (&Some(ref old), &Some(ref new)) => old.equals(new),
+            (&None, &None) => true,
+            _ => false,
+        };
+        if unchanged {
+            return;
+        }
+
+        if let Some(old) = state.focus.take() {
+            if old.is_alive() {
+                let serial = unsafe { wl_display_next_serial(display.c_ptr()) };
+                state.send(&old, || wl_pointer::Event::Leave { serial, surface: old.clone() });
+            }
+            compositor.cursor.set(None, 0, 0);
+        }
+        if let Some(new) = focus {
+            let serial = unsafe { wl_display_next_serial(display.c_ptr()) };
+            let (x, y) = {
+                let surface = SurfaceData::from_resource(&new).map(|data| data.surface.clone());
+                surface.map_or((0, 0), |surface| {
+                    let surface = surface.borrow();
+                    (compositor.cursor.x - surface.x, compositor.cursor.y - surface.y)
+                })
+            };
+            state.send(&new, || wl_pointer::Event::Enter {
+                serial,
+                surface: new.clone(),
+                surface_x: f64::from(x),
+                surface_y: f64::from(y),
+            });
+            state.enter_serial = serial;
+            state.focus = Some(new);
+        }
+    }
+}
+
+/// The topmost surface taking input at `x`, `y`
+fn surface_at(surfaces: &[Rc<RefCell<Surface>>], x: i32, y: i32) -> Option<Resource<wl_surface::WlSurface>> {
+    let point = Rectangle::new(x, y, 1, 1);
+    surfaces.iter().rev()
+        .map(|surface| surface.borrow())
+        .find(|surface| {
+            let local = point.translate(-surface.x, -surface.y);
+            surface.geometry().contains_rect(&point)
+                && surface.input.as_ref().map_or(true, |input| input.rects().iter().any(|rect| rect.contains_rect(&local)))
+        })
+        .and_then(|surface| surface.resource.clone())
+        .filter(|resource| resource.is_alive())
+}
+
+fn bind_seat(
+    new_resource: NewResource<wl_seat::WlSeat>,
+    name: &str,
+    state: &Rc<RefCell<PointerState>>,
+    compositor: &Rc<RefCell<Compositor>>,
+    token: &DisplayToken,
+) {
+    let request_state = state.clone();
+    let request_compositor = compositor.clone();
+    let request_token = token.clone();
+    let seat = new_resource.implement_nonsend(
+        move |request, _| match request {
+            wl_seat::Request::GetPointer { id } =>
+                get_pointer(id, &request_state, &request_compositor, &request_token),
+            // no keyboards nor touch screens, the objects never get events
+            wl_seat::Request::GetKeyboard { id } => {
+                id.implement_nonsend(|_: wl_keyboard::Request, _| {}, None::<fn(_)>, (), &request_token);
+            },
+            wl_seat::Request::GetTouch { id } => {
+                id.implement_nonsend(|_: wl_touch::Request, _| {}, None::<fn(_)>, (), &request_token);
+            },
+            wl_seat::Request::Release => {},
+        },
+        None::<fn(_)>,
+        (),
+        token,
+    );
+
+    seat.send(wl_seat::Event::Capabilities { capabilities: wl_seat::Capability::Pointer });
+    if seat.version() >= 2 {
+        seat.send(wl_seat::Event::Name { name: name.to_string() });
+    }
+}
+
+fn get_pointer(
+    id: NewResource<wl_pointer::WlPointer>,
+    state: &Rc<RefCell<PointerState>>,
+    compositor: &Rc<RefCell<Compositor>>,
+    token: &DisplayToken,
+) {
+    let request_state = state.clone();
+    let request_compositor = compositor.clone();
+    let destroy_state = state.clone();
+    let pointer = id.implement_nonsend(
+        move |request, pointer: Resource<wl_pointer::WlPointer>| match request {
+            wl_pointer::Request::SetCursor { serial, surface, hotspot_x, hotspot_y } =>
+                set_cursor(&pointer, serial, surface, hotspot_x, hotspot_y, &request_state, &request_compositor),
+            wl_pointer::Request::Release => {},
+        },
+        Some(move |pointer: Resource<wl_pointer::WlPointer>| {
+            destroy_state.borrow_mut().pointers.retain(|other| !other.equals(&pointer));
+        }),
+        (),
+        token,
+    );
+
+    let mut state = state.borrow_mut();
+    // a late pointer of the focused client gets the enter too
+    if let Some(ref focus) = state.focus {
+        if focus.is_alive() && pointer.same_client_as(focus) {
+            let position = SurfaceData::from_resource(focus).map(|data| {
+                let surface = data.surface.borrow();
+                let compositor = compositor.borrow();
+                (compositor.cursor.x - surface.x, compositor.cursor.y - surface.y)
+            });
+            if let Some((x, y)) = position {
+                pointer.send(wl_pointer::Event::Enter {
+                    serial: state.enter_serial,
+                    surface: focus.clone(),
+                    surface_x: f64::from(x),
+                    surface_y: f64::from(y),
+                });
+                if pointer.version() >= 5 {
+                    pointer.send(wl_pointer::Event::Frame);
+                }
+            }
+        }
+    }
+    state.pointers.push(pointer);
+}
+
+/// Show `surface` as the cursor, for the client with the pointer focus only
+fn set_cursor(
+    pointer: &Resource<wl_pointer::WlPointer>,
+    serial: u32,
+    surface: Option<Resource<wl_surface::WlSurface>>,
+    hotspot_x: i32,
+    hotspot_y: i32,
+    state: &Rc<RefCell<PointerState>>,
+    compositor: &Rc<RefCell<Compositor>>,
+) {
+    {
+        let state = state.borrow();
+        let focused = state.focus.as_ref().map_or(false, |focus| focus.is_alive() && pointer.same_client_as(focus));
+        if !focused || serial != state.enter_serial {
+            return;
+        }
+    }
+
+    let surface = match surface {
+        Some(surface) => surface,
+        None => {
+            compositor.borrow_mut().cursor.set(None, 0, 0);
+            return;
+        },
+    };
+    let data = match SurfaceData::from_resource(&surface) {
+        Some(data) => data,
+        None => return,
+    };
+    {
+        let mut surface = data.surface.borrow_mut();
+        match surface.role {
+            Some(role) if role != CURSOR_ROLE => {
+                pointer.post_error(wl_pointer::Error::Role.to_raw(),
+                    format!("the surface already has the {} role", role));
+                return;
+            },
+            _ => surface.role = Some(CURSOR_ROLE),
+        }
+    }
+
+    let mut compositor = compositor.borrow_mut();
+    compositor.remove_surface(&data.surface);
+    compositor.cursor.set(Some(data.surface.clone()), hotspot_x, hotspot_y);
+}
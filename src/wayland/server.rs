@@ -1,37 +1,241 @@
+use std::cell::{Cell, RefCell};
 use std::ffi::OsString;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
 use std::time::Duration;
-use drm::DRMDevice;
+use drm::DRMBackend;
+use compositor::Compositor;
+use wayland;
+use wayland::WaylandError;
+use wayland::linux_dmabuf::LinuxDmabuf;
+use wayland::output::Outputs;
+use wayland::output_management::OutputManagement;
+use wayland::policy::ClientPolicy;
+use wayland::seat::Seat;
+use wayland_server::calloop::EventLoop;
+use wayland_server::protocol::wl_shm;
 
 pub struct RudimentoServer {
     pub display: wayland_server::Display,
-    pub event_loop: wayland_server::calloop::EventLoop<()>,
-    pub socket_name: OsString
+    pub event_loop: EventLoop<()>,
+    pub socket_name: OsString,
+    /// Who may bind the privileged globals
+    pub client_policy: Rc<ClientPolicy>,
+    /// The DRM device has page flip events
+    pub drm_readable: Rc<Cell<bool>>,
+    /// zwlr_output_manager_v1, what the heads look like to the clients
+    pub output_management: OutputManagement,
+    /// the wl_output globals of the enabled heads
+    pub outputs: Outputs,
+    pub seat: Seat,
+    /// the client dmabufs waiting for the renderer
+    pub linux_dmabuf: LinuxDmabuf,
+    /// dup of libwayland's event loop fd, see `watch_wl_event_loop`
+    wl_event_loop_fd: RawFd,
 }
 
 impl RudimentoServer {
-    pub fn new_from_drm(drm: DRMDevice) -> Self {
-        let event_loop = wayland_server::calloop::EventLoop::<()>::new().unwrap();
+    /// Serve the clients of `backend` on `event_loop`, which the signals and
+    /// the launcher may already be watched on
+    pub fn new_from_drm(event_loop: EventLoop<()>, backend: &mut DRMBackend, client_policy: ClientPolicy) -> Result<Self, WaylandError> {
         let mut display = wayland_server::Display::new(event_loop.handle());
         let socket_name = display
             .add_socket_auto()
-            .expect("Failed to create a server socket.");
+            .map_err(|e| WaylandError::io("Failed to create a server socket".to_string(), e))?;
 
-        Self {
+        let client_policy = Rc::new(client_policy);
+        let output_management = OutputManagement::new(&mut display, client_policy.clone());
+        wayland::debug::init_debug(&mut display, client_policy.clone());
+        let outputs = Outputs::new(&display);
+        let seat = Seat::new(&mut display, backend.compositor(), &backend.seat_name());
+        let linux_dmabuf = LinuxDmabuf::new(&mut display, backend.dmabuf_formats());
+
+        let mut server = Self {
             display,
             event_loop,
             socket_name,
+            client_policy,
+            drm_readable: Rc::new(Cell::new(false)),
+            output_management,
+            outputs,
+            seat,
+            linux_dmabuf,
+            wl_event_loop_fd: -1,
+        };
+        server.publish_heads(backend);
+        server.wl_event_loop_fd = server.watch_wl_event_loop()?;
+        server.drm_readable = server.watch_fd(backend.drm_fd())?;
+        server.init_globals(backend.compositor(), &backend.shm_formats());
+        log_info!("wayland", "listening on {}", server.socket_name.to_string_lossy());
+
+        Ok(server)
+    }
+
+    /// wayland-server watches the fd of libwayland's event loop edge
+    /// triggered, but one wl_event_loop_dispatch handles at most 32 events:
+    /// what is left waited for the next client to write (hence the second
+    /// dispatch the loop used to need). A level triggered watch of a dup keeps
+    /// dispatching until it is drained.
+    fn watch_wl_event_loop(&self) -> Result<RawFd, WaylandError> {
+        use wayland_server::calloop::generic::Generic;
+        use wayland_server::calloop::mio::{PollOpt, Ready};
+        use wayland_server::sys::server::{wl_display_get_event_loop, wl_event_loop_dispatch, wl_event_loop_get_fd};
+
+        let wl_event_loop = unsafe { wl_display_get_event_loop(self.display.c_ptr()) };
+        let fd = unsafe { ::libc::dup(wl_event_loop_get_fd(wl_event_loop)) };
+        if fd < 0 {
+            return Err(WaylandError::io("Could not dup the wl_event_loop fd".to_string(), io::Error::last_os_error()));
+        }
+
+        let mut source = Generic::from_raw_fd(fd);
+        source.set_interest(Ready::readable());
+        source.set_pollopts(PollOpt::level());
+        match self.event_loop.handle().insert_source(source, move |_, _| unsafe {
+            wl_event_loop_dispatch(wl_event_loop, 0);
+        }) {
+            Ok(_) => Ok(fd),
+            Err(error) => {
+                unsafe { ::libc::close(fd) };
+                Err(WaylandError::io("Could not watch the wl_event_loop fd".to_string(), error.error))
+            },
         }
     }
-    
-    pub fn answer(&mut self) {
-        self.event_loop
-            .dispatch(Some(Duration::from_millis(10)), &mut ())
-            .unwrap();
-        self.display.flush_clients();
-        // TODO: find out why native_lib requires two dispatches
+
+    /// Advertise wl_shm with the extra `shm_formats`, wl_compositor,
+    /// explicit synchronization, presentation time and the scaling globals
+    pub fn init_globals(&mut self, compositor: Rc<RefCell<Compositor>>, shm_formats: &[wl_shm::Format]) {
+        use wayland_server::sys::server::{wl_display_init_shm, wl_display_add_shm_format};
+
+        unsafe {
+            if wl_display_init_shm(self.display.c_ptr()) < 0 {
+                log_error!("wayland", "Could not initialize wl_shm");
+            }
+            for format in shm_formats.iter() {
+                wl_display_add_shm_format(self.display.c_ptr(), format.to_raw());
+            }
+        }
+
+        wayland::compositor::init_compositor(&mut self.display, compositor.clone());
+        wayland::explicit_sync::init_explicit_synchronization(&mut self.display);
+        wayland::presentation::init_presentation(&mut self.display, compositor);
+        wayland::viewporter::init_viewporter(&mut self.display);
+        wayland::fractional_scale::init_fractional_scale(&mut self.display);
+    }
+
+    /// Tell the clients about the heads as the backend has them now
+    pub fn publish_heads(&mut self, backend: &mut DRMBackend) {
+        let heads = backend.heads();
+        self.outputs.update_heads(&mut self.display, &heads);
+        self.output_management.update_heads(heads);
+        backend.set_output_resources(&self.outputs);
+    }
+
+    /// Tell the clients which outputs show their surfaces and at which scale,
+    /// after the repaint placed them, and which surface has the pointer
+    pub fn update_surface_outputs(&mut self, backend: &DRMBackend) {
+        let compositor = backend.compositor();
+        self.outputs.update_surfaces(&compositor.borrow().surfaces);
+        self.seat.update_pointer_focus(&self.display);
+    }
+
+    /// Give the dmabufs the clients created to the renderer, before the
+    /// repaint attaches them
+    pub fn import_dmabufs(&mut self, backend: &mut DRMBackend) {
+        for import in self.linux_dmabuf.take_imports() {
+            match backend.import_dmabuf(&import.dmabuf) {
+                Ok(()) => import.imported(),
+                Err(e) => {
+                    log_warn!("wayland", "dmabuf import failed: {}", e);
+                    import.failed();
+                },
+            }
+        }
+    }
+
+    /// Switch the renderer of `backend`, the clients binding linux-dmabuf
+    /// from now on get the formats of the new one
+    pub fn switch_renderer(&mut self, backend: &mut DRMBackend) {
+        match backend.switch_renderer() {
+            Ok(()) => self.linux_dmabuf.set_formats(backend.dmabuf_formats()),
+            Err(e) => log_error!("drm", "renderer switch failed: {}", e),
+        }
+    }
+
+    /// Test or apply the output configurations the clients sent, those made
+    /// from outdated heads are cancelled
+    pub fn configure_outputs(&mut self, backend: &mut DRMBackend) {
+        for request in self.output_management.take_requests() {
+            if !self.output_management.is_current(&request) {
+                request.cancelled();
+                continue;
+            }
+
+            let result = if request.test_only {
+                backend.test_output_configuration(&request.heads)
+            } else {
+                backend.apply_output_configuration(&request.heads)
+            };
+            match result {
+                Ok(()) => request.succeeded(),
+                Err(e) => {
+                    log_warn!("wayland", "output configuration {}: {}", if request.test_only { "test failed" } else { "failed" }, e);
+                    request.failed();
+                },
+            }
+            if !request.test_only {
+                self.publish_heads(backend);
+            }
+        }
+    }
+
+    /// Watch `fd` on the event loop, the returned flag is raised when it
+    /// becomes readable and has to be cleared by whoever consumes the input
+    /// (the launcher and DRM callbacks can't borrow the backend themselves).
+    pub fn watch_fd(&self, fd: RawFd) -> Result<Rc<Cell<bool>>, WaylandError> {
+        use wayland_server::calloop::generic::Generic;
+        use wayland_server::calloop::mio::{PollOpt, Ready};
+
+        let readable = Rc::new(Cell::new(false));
+        let mut source = Generic::from_raw_fd(fd);
+        source.set_interest(Ready::readable());
+        source.set_pollopts(PollOpt::level());
+
+        let flag = readable.clone();
+        match self.event_loop.handle().insert_source(source, move |_, _| flag.set(true)) {
+            Ok(_) => Ok(readable),
+            Err(error) => Err(WaylandError::io(format!("Could not watch fd {}", fd), error.error)),
+        }
+    }
+
+    /// Wait up to `timeout` (forever if None) for anything to do and run the
+    /// handlers of the clients, signals and timers
+    pub fn dispatch(&mut self, timeout: Option<Duration>) -> Result<(), WaylandError> {
         self.event_loop
-            .dispatch(Some(Duration::from_millis(10)), &mut ())
-            .unwrap();
+            .dispatch(timeout, &mut ())
+            .map_err(|e| WaylandError::io("Event loop dispatch failed".to_string(), e))
+    }
+
+    /// Send what the handlers and the repaint queued, last thing before
+    /// going back to sleep
+    pub fn flush_clients(&mut self) {
         self.display.flush_clients();
     }
-}
\ No newline at end of file
+
+    /// Disconnect all the clients, their resources go while the renderer
+    /// is still there
+    pub fn destroy_clients(&mut self) {
+        use wayland_server::sys::server::wl_display_destroy_clients;
+
+        unsafe { wl_display_destroy_clients(self.display.c_ptr()) };
+    }
+}
+
+impl Drop for RudimentoServer {
+    fn drop(&mut self) {
+        self.destroy_clients();
+        if self.wl_event_loop_fd >= 0 {
+            unsafe { ::libc::close(self.wl_event_loop_fd) };
+        }
+    }
+}
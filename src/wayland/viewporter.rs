@@ -0,0 +1,108 @@
+//! wp_viewporter: lets the clients crop their buffers and scale them to a
+//! surface size of their choosing, e.g. to draw at a fractional scale.
+//!
+//! The viewport is double buffered state of the surface, the checks that
+//! depend on the buffer wait for the commit.
+
+use std::rc::Rc;
+
+use wayland_server::{Display, DisplayToken, Global, NewResource, Resource};
+use wayland_server::protocol::wl_surface;
+use wayland_protocols::viewporter::server::wp_viewport::{self, WpViewport};
+use wayland_protocols::viewporter::server::wp_viewporter::{self, WpViewporter};
+
+use surface::Viewport;
+use wayland::compositor::SurfaceData;
+
+pub fn init_viewporter(display: &mut Display) -> Global<WpViewporter> {
+    let token = display.get_token();
+    display.create_global::<WpViewporter, _>(1, move |new_resource, _version| {
+        let request_token = token.clone();
+        new_resource.implement_nonsend(
+            move |request, viewporter: Resource<WpViewporter>| match request {
+                wp_viewporter::Request::Destroy => {},
+                wp_viewporter::Request::GetViewport { id, surface } =>
+                    get_viewport(&viewporter, id, surface, &request_token),
+            },
+            None::<fn(_)>,
+            (),
+            &token,
+        );
+    })
+}
+
+fn get_viewport(
+    viewporter: &Resource<WpViewporter>,
+    id: NewResource<WpViewport>,
+    surface: Resource<wl_surface::WlSurface>,
+    token: &DisplayToken,
+) {
+    let data = match SurfaceData::from_resource(&surface) {
+        Some(data) => data,
+        None => return,
+    };
+    if data.viewport.borrow().as_ref().map_or(false, |viewport| viewport.is_alive()) {
+        viewporter.post_error(wp_viewporter::Error::ViewportExists.to_raw(),
+            "the surface already has a viewport".to_string());
+        return;
+    }
+
+    let request_surface = surface.clone();
+    let viewport = id.implement_nonsend(
+        move |request, viewport: Resource<WpViewport>| {
+            let data = match SurfaceData::from_resource(&request_surface) {
+                Some(ref data) if request_surface.is_alive() => data.clone(),
+                _ => {
+                    viewport.post_error(wp_viewport::Error::NoSurface.to_raw(),
+                        "the surface of the viewport was destroyed".to_string());
+                    return;
+                },
+            };
+            match request {
+                wp_viewport::Request::Destroy => {},
+                wp_viewport::Request::SetSource { x, y, width, height } => {
+                    let unset = x == -1.0 && y == -1.0 && width == -1.0 && height == -1.0;
+                    if !unset && (x < 0.0 || y < 0.0 || width <= 0.0 || height <= 0.0) {
+                        viewport.post_error(wp_viewport::Error::BadValue.to_raw(),
+                            format!("invalid source rectangle {}x{}+{}+{}", width, height, x, y));
+                        return;
+                    }
+                    set_pending(&data, |pending| {
+                        pending.source = if unset { None } else { Some((x, y, width, height)) };
+                    });
+                },
+                wp_viewport::Request::SetDestination { width, height } => {
+                    let unset = width == -1 && height == -1;
+                    if !unset && (width <= 0 || height <= 0) {
+                        viewport.post_error(wp_viewport::Error::BadValue.to_raw(),
+                            format!("invalid destination size {}x{}", width, height));
+                        return;
+                    }
+                    set_pending(&data, |pending| {
+                        pending.destination = if unset { None } else { Some((width, height)) };
+                    });
+                },
+            }
+        },
+        // the crop and scale go with the next commit
+        Some(move |_| {
+            if !surface.is_alive() {
+                return;
+            }
+            if let Some(data) = SurfaceData::from_resource(&surface) {
+                *data.viewport.borrow_mut() = None;
+                data.pending.borrow_mut().viewport = Some(Viewport::default());
+            }
+        }),
+        (),
+        token,
+    );
+    *data.viewport.borrow_mut() = Some(viewport);
+}
+
+/// Change the viewport the next commit applies
+fn set_pending<F: FnOnce(&mut Viewport)>(data: &Rc<SurfaceData>, f: F) {
+    let current = data.surface.borrow().viewport;
+    let mut pending = data.pending.borrow_mut();
+    f(pending.viewport.get_or_insert(current));
+}